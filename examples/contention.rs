@@ -0,0 +1,82 @@
+//! A stress test for metrics updated concurrently by many threads.
+//!
+//! Each scenario runs `THREADS` threads for `DURATION`, and prints the total number of
+//! updates per second:
+//!
+//! ```text
+//! cargo run --release --example contention
+//! ```
+
+extern crate tacho;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tacho::{Scope, StatBackend, StatConfig};
+
+const THREADS: usize = 8;
+const DURATION: Duration = Duration::from_secs(2);
+
+fn main() {
+    let (metrics, _reporter) = tacho::new();
+
+    let counter = metrics.counter("shared_counter");
+    run("shared counter", &metrics, move |_| {
+        let counter = counter.clone();
+        Box::new(move |_| counter.incr(1))
+    });
+
+    let stat = metrics.stat("shared_stat");
+    run("shared stat (histogram)", &metrics, move |_| {
+        let stat = stat.clone();
+        Box::new(move |i| stat.add(i))
+    });
+
+    let config = StatConfig::default().backend(StatBackend::AtomicBuckets);
+    let stat = metrics.stat_with_config("shared_atomic_stat", config);
+    run("shared stat (atomic buckets)", &metrics, move |_| {
+        let stat = stat.clone();
+        Box::new(move |i| stat.add(i))
+    });
+
+    run("distinct stats (histogram)", &metrics, |scope| {
+        let stat = scope.stat("distinct_stat");
+        Box::new(move |i| stat.add(i))
+    });
+}
+
+/// Runs `THREADS` threads, each of which repeatedly invokes the update built by `mk` from
+/// a scope labeled with the thread's index.
+fn run<F>(name: &str, metrics: &Scope, mk: F)
+where
+    F: Fn(Scope) -> Box<dyn Fn(u64) + Send>,
+{
+    let done = Arc::new(AtomicBool::new(false));
+    let threads = (0..THREADS)
+        .map(|t| {
+            let update = mk(metrics.clone().labeled("thread", t));
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut n = 0u64;
+                while !done.load(Ordering::Relaxed) {
+                    for i in 0..1_000 {
+                        update(i);
+                    }
+                    n += 1_000;
+                }
+                n
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let t0 = Instant::now();
+    thread::sleep(DURATION);
+    done.store(true, Ordering::Relaxed);
+    let total: u64 = threads
+        .into_iter()
+        .map(|t| t.join().expect("thread panicked"))
+        .sum();
+    let secs = t0.elapsed().as_secs_f64();
+    println!("{:<32} {:>14.0} updates/s", name, total as f64 / secs);
+}
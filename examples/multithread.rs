@@ -8,10 +8,10 @@ extern crate tacho;
 extern crate tokio_core;
 extern crate tokio_timer;
 
-use futures::{BoxFuture, Future, Stream};
+use futures::{Future, Stream};
 use futures::sync::oneshot;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tacho::Timing;
 use tokio_core::reactor::Core;
 use tokio_timer::Timer;
@@ -33,15 +33,15 @@ fn main() {
 
     let metrics = metrics.clone().labeled("test", "multithread");
     let loop_iter_us = metrics.stat("loop_iter_us");
-    for (i, work_done_tx) in vec![(0, work_done_tx0), (1, work_done_tx1)] {
-        let metrics = metrics.clone().labeled("thread".into(), format!("{}", i));
-        let loop_counter = metrics.counter("loop_counter".into());
-        let current_iter = metrics.gauge("current_iter".into());
+    for (i, work_done_tx) in [(0, work_done_tx0), (1, work_done_tx1)] {
+        let metrics = metrics.clone().labeled("thread", i);
+        let loop_counter = metrics.counter("loop_counter");
+        let current_iter = metrics.gauge("current_iter");
         let loop_iter_us = loop_iter_us.clone();
         thread::spawn(move || {
             let mut prior = None;
             for i in 0..10_000_000 {
-                let t0 = Timing::start();
+                let t0 = Instant::now();
                 current_iter.set(i);
                 loop_counter.incr(1);
                 if let Some(p) = prior {
@@ -62,7 +62,7 @@ fn main() {
 }
 
 /// Prints a report every `interval` and when the `done` is satisfied.
-fn reporter<D>(interval: Duration, done: D, reporter: tacho::Reporter) -> BoxFuture<(), ()>
+fn reporter<D>(interval: Duration, done: D, reporter: tacho::Reporter) -> Box<dyn Future<Item = (), Error = ()>>
 where
     D: Future<Item = (), Error = ()> + Send + 'static,
 {
@@ -77,7 +77,7 @@ where
             })
     };
     let done = done.map(move |_| { print_report(&reporter.peek()); });
-    periodic.select(done).map(|_| {}).map_err(|_| {}).boxed()
+    Box::new(periodic.select(done).map(|_| {}).map_err(|_| {}))
 }
 
 fn print_report(report: &tacho::Report) {
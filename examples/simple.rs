@@ -5,7 +5,7 @@ extern crate tokio_core;
 extern crate tokio_timer;
 
 use futures::{Future, future};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tacho::Timing;
 use tokio_core::reactor::Core;
 use tokio_timer::Timer;
@@ -21,7 +21,7 @@ fn main() {
             .map(move |_| {
                 let r = reporter.peek();
                 println!("# metrics:");
-                println!("");
+                println!();
                 println!("{}", tacho::prometheus::string(&r).unwrap());
             })
     });
@@ -30,15 +30,15 @@ fn main() {
     core.run(reported).expect("reactor failed");
 }
 
-fn do_work(metrics: tacho::Scope) -> future::BoxFuture<(), ()> {
+fn do_work(metrics: tacho::Scope) -> Box<dyn Future<Item = (), Error = ()>> {
     let metrics = metrics.labeled("labelkey", "labelval");
-    let iter_time_us = metrics.stat("iter_time_us".into());
+    let iter_time_us = metrics.stat("iter_time_us");
     let timer = Timer::default();
     let work = future::loop_fn(100, move |n| {
         // Clones are shallow, minimizing allocation.
         let iter_time_us = iter_time_us.clone();
 
-        let start = Timing::start();
+        let start = Instant::now();
         timer
             .sleep(Duration::from_millis(20 * (n % 5)))
             .map_err(|_| {})
@@ -49,5 +49,5 @@ fn do_work(metrics: tacho::Scope) -> future::BoxFuture<(), ()> {
                 future::Loop::Continue(n - 1)
             })
     });
-    work.map(|_| {}).boxed()
+    Box::new(work.map(|_| {}))
 }
@@ -0,0 +1,205 @@
+//! A precision-reduced histogram backed by an array of atomic counters.
+//!
+//! Values below 16 are counted exactly. Larger values are counted into log-linear buckets:
+//! each power of two is split into 8 sub-buckets, so values are tracked to within 12.5%.
+//! Recording a value is a single atomic increment (plus an update of the sum), so stats
+//! backed by `AtomicHistogram` never contend on a lock.
+
+use super::{push_exemplar, Exemplar, HistogramWithSum, HISTOGRAM_PRECISION};
+use std::cmp;
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Values below `LINEAR` are counted exactly.
+const LINEAR: u64 = 16;
+
+/// The number of sub-buckets per power of two, as a power of two.
+const SUB_BITS: u32 = 3;
+
+pub struct AtomicHistogram {
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    high: u64,
+    /// The bounds the histogram was created with, if any.
+    bounds: Option<(u64, u64)>,
+    /// Whether values greater than `high` are clamped (or dropped).
+    clamp: bool,
+    /// Counts values greater than `high`.
+    overflowed: AtomicU64,
+    exemplars: Mutex<VecDeque<Exemplar>>,
+    /// When the histogram was created or last cleared.
+    created: Mutex<SystemTime>,
+}
+
+impl AtomicHistogram {
+    /// Creates a histogram that records values up to the upper bound, if any; larger
+    /// values are counted in the highest bucket if `clamp` is true, and are dropped
+    /// otherwise.
+    pub fn new(bounds: Option<(u64, u64)>, clamp: bool) -> AtomicHistogram {
+        let high = bounds.map(|(_, h)| h).unwrap_or(u64::MAX);
+        let n = index(high) + 1;
+        AtomicHistogram {
+            buckets: (0..n).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            high,
+            bounds,
+            clamp,
+            overflowed: AtomicU64::new(0),
+            exemplars: Mutex::new(VecDeque::new()),
+            created: Mutex::new(SystemTime::now()),
+        }
+    }
+
+    /// Approximates the memory used by the histogram, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        mem::size_of::<Self>() + self.buckets.len() * mem::size_of::<AtomicU64>()
+    }
+
+    /// The bounds the histogram was created with, if any.
+    pub fn bounds(&self) -> Option<(u64, u64)> {
+        self.bounds
+    }
+
+    pub fn push_exemplar(&self, exemplar: Exemplar) {
+        let mut exemplars = self.exemplars.lock().expect("failed to obtain lock for stat");
+        push_exemplar(&mut exemplars, exemplar);
+    }
+
+    pub fn record(&self, v: u64) {
+        if v > self.high {
+            self.overflowed.fetch_add(1, Ordering::AcqRel);
+            if !self.clamp {
+                return;
+            }
+        }
+        let i = cmp::min(index(v), self.buckets.len() - 1);
+        self.buckets[i].fetch_add(1, Ordering::AcqRel);
+        let _ = self.sum.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |s| Some(s.saturating_add(v)),
+        );
+    }
+
+    /// Copies the current counts into a `HistogramWithSum`, optionally resetting them.
+    ///
+    /// Each bucket's values are recorded as the highest value in the bucket, though no
+    /// higher than the histogram's upper bound (which the top bucket may extend past).
+    ///
+    /// The count is derived from the buckets as they are loaded, so it agrees with them even
+    /// as values are recorded concurrently. The sum is loaded afterward, so it may include a
+    /// few values recorded meanwhile.
+    pub fn snapshot(&self, clear: bool) -> HistogramWithSum {
+        let mut h = HistogramWithSum::new(None, HISTOGRAM_PRECISION);
+        for (i, b) in self.buckets.iter().enumerate() {
+            let n = if clear {
+                b.swap(0, Ordering::AcqRel)
+            } else {
+                b.load(Ordering::Acquire)
+            };
+            if n > 0 {
+                h.record_n(cmp::min(highest(i), self.high), n);
+            }
+        }
+        let load = |a: &AtomicU64| if clear {
+            a.swap(0, Ordering::AcqRel)
+        } else {
+            a.load(Ordering::Acquire)
+        };
+        h.sum = load(&self.sum);
+        h.overflowed = load(&self.overflowed);
+        h.bounds = self.bounds;
+        let mut exemplars = self.exemplars.lock().expect("failed to obtain lock for stat");
+        h.exemplars = if clear {
+            mem::take(&mut *exemplars)
+        } else {
+            exemplars.clone()
+        };
+        let mut created = self.created.lock().expect("failed to obtain lock for stat");
+        h.created = *created;
+        if clear {
+            *created = SystemTime::now();
+        }
+        h
+    }
+}
+
+/// Returns the index of the bucket holding `v`.
+fn index(v: u64) -> usize {
+    if v < LINEAR {
+        return v as usize;
+    }
+    let exp = 63 - v.leading_zeros();
+    let sub = (v >> (exp - SUB_BITS)) & ((1 << SUB_BITS) - 1);
+    (LINEAR + u64::from(exp - 4) * (1 << SUB_BITS) + sub) as usize
+}
+
+/// Returns the highest value held by the bucket at index `i`.
+fn highest(i: usize) -> u64 {
+    let i = i as u64;
+    if i < LINEAR {
+        return i;
+    }
+    let exp = (i - LINEAR) / (1 << SUB_BITS) + 4;
+    let sub = (i - LINEAR) % (1 << SUB_BITS);
+    let shift = exp - u64::from(SUB_BITS);
+    let lowest = ((1 << SUB_BITS) + sub) << shift;
+    lowest + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        for v in 0..16 {
+            assert_eq!(index(v), v as usize);
+            assert_eq!(highest(index(v)), v);
+        }
+        assert_eq!(index(16), 16);
+        assert_eq!(index(17), 16);
+        assert_eq!(highest(16), 17);
+        assert_eq!(index(31), 23);
+        assert_eq!(highest(23), 31);
+        assert_eq!(index(32), 24);
+        assert_eq!(highest(index(u64::MAX)), u64::MAX);
+
+        // Each value's bucket holds it, and is within 12.5% of it.
+        for v in &[100, 1_000, 12_345, 1_000_000, 1 << 40] {
+            let h = highest(index(*v));
+            assert!(h >= *v);
+            assert!(h - *v <= *v / 8);
+        }
+    }
+
+    #[test]
+    fn test_atomic_histogram() {
+        let h = AtomicHistogram::new(Some((1, 1_000)), true);
+        for v in &[1, 5, 100, 5_000] {
+            h.record(*v);
+        }
+
+        let snap = h.snapshot(true);
+        assert_eq!(snap.count(), 4);
+        assert_eq!(snap.sum(), 5_106);
+        assert_eq!(snap.min(), 1);
+        // Values above `high` are counted in the highest bucket, which reports `high`.
+        assert!(highest(index(1_000)) > 1_000);
+        assert_eq!(snap.max(), 1_000);
+        assert_eq!(snap.overflowed(), 1);
+
+        let snap = h.snapshot(false);
+        assert_eq!(snap.count(), 0);
+        assert_eq!(snap.overflowed(), 0);
+
+        let h = AtomicHistogram::new(Some((1, 1_000)), false);
+        h.record(5_000);
+        let snap = h.snapshot(false);
+        assert_eq!(snap.count(), 0);
+        assert_eq!(snap.overflowed(), 1);
+    }
+}
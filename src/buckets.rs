@@ -0,0 +1,62 @@
+//! Helpers for building bucket bounds, e.g. for `Reporter::with_heatmap`.
+
+use super::TimeUnit;
+use std::time::Duration;
+
+/// Converts durations to bucket bounds in the given unit, as a Stat with that unit records
+/// them.
+///
+/// Bounds are sorted and deduplicated, so durations that are equal in `unit` (e.g. 1.2ms
+/// and 1.4ms in `TimeUnit::Millis`) yield a single bound.
+pub fn duration(ds: &[Duration], unit: TimeUnit) -> Vec<u64> {
+    let mut bounds = ds.iter().map(|d| unit.convert(*d)).collect::<Vec<_>>();
+    bounds.sort();
+    bounds.dedup();
+    bounds
+}
+
+/// Returns up to `count` bounds, starting at `start` and each `factor` times the last.
+///
+/// Bounds are rounded to the nearest integer; bounds that round to the prior bound are
+/// skipped, so fewer than `count` bounds may be returned for small factors.
+pub fn exponential(start: u64, factor: f64, count: usize) -> Vec<u64> {
+    assert!(start > 0, "start must be positive");
+    assert!(factor > 1.0, "factor must be greater than 1");
+    let mut bounds = Vec::with_capacity(count);
+    let mut b = start as f64;
+    for _ in 0..count {
+        let v = b.round() as u64;
+        if bounds.last().map(|l| *l < v).unwrap_or(true) {
+            bounds.push(v);
+        }
+        b *= factor;
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration() {
+        let ds = [
+            Duration::from_millis(100),
+            Duration::from_micros(1_400),
+            Duration::from_micros(1_200),
+            Duration::from_secs(1),
+        ];
+        assert_eq!(duration(&ds, TimeUnit::Millis), vec![1, 100, 1_000]);
+        assert_eq!(
+            duration(&ds, TimeUnit::Micros),
+            vec![1_200, 1_400, 100_000, 1_000_000]
+        );
+    }
+
+    #[test]
+    fn test_exponential() {
+        assert_eq!(exponential(1, 2.0, 5), vec![1, 2, 4, 8, 16]);
+        assert_eq!(exponential(10, 10.0, 3), vec![10, 100, 1_000]);
+        assert_eq!(exponential(1, 1.5, 5), vec![1, 2, 3, 5]);
+    }
+}
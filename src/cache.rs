@@ -0,0 +1,263 @@
+//! Caches of metrics, and metrics for caches.
+//!
+//! ## Scope caches
+//!
+//! A `ScopeCache` memoizes labeled child scopes, e.g. per endpoint. Building a labeled
+//! scope (and looking up its metrics in the registry) on every request is comparatively
+//! expensive. A `ScopeCache` instead builds each key's scope and metric handles once, and
+//! holds them until the key falls out of the cache.
+//!
+//! Since the cache holds metric handles, a key's metrics are not pruned by
+//! `Reporter::take` while the key is cached. Once a key is evicted (or removed), its handles
+//! are dropped, and its metrics are pruned as usual.
+//!
+//! ## Cache metrics
+//!
+//! `Metrics` is a standard set of metrics for caches:
+//!
+//! - `hits`, `misses`, and `lookups`: counters of lookups, and of those that hit or missed;
+//! - `hit_ppm`: the ratio of hits to lookups, in parts per million, computed as each report
+//!   is taken (see `Scope::ratio_with_gauge`);
+//! - `evictions`: a counter of entries evicted;
+//! - `size`: a gauge of the entries in the cache;
+//! - `load_us`: the time taken to load each missing entry.
+//!
+//! ```
+//! let (metrics, reporter) = tacho::new();
+//! let cache = tacho::cache::Metrics::new(&metrics.prefixed("cache"));
+//! cache.hit();
+//! cache.miss();
+//! cache.set_size(1);
+//!
+//! let report = reporter.peek();
+//! assert_eq!(report.get_counter("lookups", &[]), Some(2));
+//! assert_eq!(report.get_gauge("hit_ppm", &[]), Some(500_000));
+//! ```
+
+#[cfg(feature = "async")]
+use super::Timed;
+use super::{Counter, Gauge, Ratio, Scope, Timer};
+#[cfg(feature = "async")]
+use futures::Future;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// A bounded, least-recently-used cache of values built from a `Scope`.
+pub struct ScopeCache<K, V = Scope> {
+    scope: Scope,
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    /// Keys ordered by when they were last used.
+    lru: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> ScopeCache<K, V> {
+    /// Creates a cache that holds at most `capacity` values built from `scope`.
+    pub fn new(scope: Scope, capacity: usize) -> ScopeCache<K, V> {
+        assert!(capacity > 0, "capacity must be positive");
+        ScopeCache {
+            scope,
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value cached for `key`, building it from the cache's scope if necessary.
+    ///
+    /// If the cache is full, the least-recently-used value is evicted.
+    pub fn get_or_create<F>(&mut self, key: K, mk: F) -> &V
+    where
+        F: FnOnce(Scope) -> V,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(&mut (_, ref mut used)) = self.entries.get_mut(&key) {
+            self.lru.remove(used);
+            *used = tick;
+        } else {
+            if self.entries.len() == self.capacity {
+                self.evict();
+            }
+            let v = mk(self.scope.clone());
+            self.entries.insert(key.clone(), (v, tick));
+        }
+        self.lru.insert(tick, key.clone());
+
+        &self.entries[&key].0
+    }
+
+    /// Removes a key's value, returning it.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(v, used)| {
+            self.lru.remove(&used);
+            v
+        })
+    }
+
+    /// Removes all values.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict(&mut self) {
+        let oldest = self.lru.keys().next().cloned();
+        if let Some(used) = oldest {
+            if let Some(key) = self.lru.remove(&used) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Handles on a cache's metrics.
+///
+/// Handles are looked up once, when `Metrics` is created, and are shared by its clones.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    /// Hits, out of lookups.
+    hits: Ratio,
+    misses: Counter,
+    evictions: Counter,
+    size: Gauge,
+    load_us: Timer,
+}
+
+impl Metrics {
+    pub fn new(scope: &Scope) -> Metrics {
+        Metrics {
+            hits: scope.ratio_with_gauge("hits", "lookups", "hit_ppm"),
+            misses: scope.counter("misses"),
+            evictions: scope.counter("evictions"),
+            size: scope.gauge("size"),
+            load_us: scope.timer_us("load_us"),
+        }
+    }
+
+    pub fn hit(&self) {
+        self.hits.incr(true);
+    }
+
+    pub fn miss(&self) {
+        self.hits.incr(false);
+        self.misses.incr(1);
+    }
+
+    pub fn evicted(&self, n: usize) {
+        self.evictions.incr(n);
+    }
+
+    /// Sets the number of entries in the cache.
+    pub fn set_size(&self, n: usize) {
+        self.size.set(n);
+    }
+
+    /// Records the time taken to load an entry, since `t0`.
+    pub fn record_load_since(&self, t0: Instant) {
+        self.load_us.record_since(t0);
+    }
+
+    pub fn record_load(&self, d: Duration) {
+        self.load_us.record(d);
+    }
+
+    /// Records the time taken for `fut`, which loads an entry, to complete (successfully or
+    /// not), measured from when it is first polled.
+    #[cfg(feature = "async")]
+    pub fn observe_load<F: Future>(&self, fut: F) -> Timed<F> {
+        self.load_us.time(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_cache() {
+        let (metrics, mut reporter) = ::new();
+        let mut cache = ScopeCache::new(metrics, 2);
+        let mut built = 0;
+        let mut hit = |cache: &mut ScopeCache<&'static str, _>, endpoint| {
+            cache
+                .get_or_create(endpoint, |scope| {
+                    built += 1;
+                    scope.labeled("endpoint", endpoint).counter("requests")
+                })
+                .incr(1);
+        };
+
+        hit(&mut cache, "a");
+        hit(&mut cache, "b");
+        hit(&mut cache, "a");
+        // Evicts "b", the least-recently used.
+        hit(&mut cache, "c");
+        assert_eq!(cache.len(), 2);
+        hit(&mut cache, "a");
+        hit(&mut cache, "b");
+        assert_eq!(built, 4);
+
+        // "c" was evicted, so its counter is pruned.
+        reporter.take();
+        let report = reporter.take();
+        let mut endpoints = report
+            .counters()
+            .keys()
+            .map(|k| k.labels().get("endpoint").unwrap().to_string())
+            .collect::<Vec<_>>();
+        endpoints.sort();
+        assert_eq!(endpoints, vec!["a", "b"]);
+
+        assert!(cache.remove(&"a").is_some());
+        assert!(cache.remove(&"a").is_none());
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_metrics() {
+        let (metrics, reporter) = ::new();
+        let cache = Metrics::new(&metrics);
+        cache.hit();
+        cache.hit();
+        cache.hit();
+        cache.miss();
+        cache.evicted(2);
+        cache.set_size(3);
+        cache.record_load(Duration::from_millis(1));
+
+        let report = reporter.peek();
+        assert_eq!(report.get_counter("hits", &[]), Some(3));
+        assert_eq!(report.get_counter("misses", &[]), Some(1));
+        assert_eq!(report.get_counter("lookups", &[]), Some(4));
+        assert_eq!(report.get_gauge("hit_ppm", &[]), Some(750_000));
+        assert_eq!(report.get_counter("evictions", &[]), Some(2));
+        assert_eq!(report.get_gauge("size", &[]), Some(3));
+        assert_eq!(report.get_stat("load_us", &[]).unwrap().max(), 1_000);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_observe_load() {
+        use futures::future;
+
+        let (metrics, reporter) = ::new();
+        let cache = Metrics::new(&metrics);
+        assert_eq!(cache.observe_load(future::ok::<_, ()>(1)).wait(), Ok(1));
+        assert!(cache.observe_load(future::err::<(), _>(())).wait().is_err());
+        assert_eq!(reporter.peek().get_stat("load_us", &[]).unwrap().count(), 2);
+    }
+}
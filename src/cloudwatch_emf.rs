@@ -0,0 +1,270 @@
+//! Renders reports in CloudWatch's Embedded Metric Format (EMF).
+//!
+//! Each line written is a JSON document that CloudWatch Logs extracts metrics from, e.g.
+//! when written to stdout on Lambda or Fargate:
+//!
+//! ```text
+//! {"_aws":{"Timestamp":1500000000000,"CloudWatchMetrics":[{"Namespace":"app",
+//!   "Dimensions":[["service","status"]],"Metrics":[{"Name":"http:requests","Unit":"Count"}]}]},
+//!   "service":"users","status":"200","http:requests":12}
+//! ```
+//!
+//! Since a document has a single set of dimension values, series are grouped into one
+//! document per label set; each series' labels are added to the given `dimensions` (and take
+//! precedence over a dimension of the same name). Documents are split so that each has at
+//! most 100 metrics, as CloudWatch requires. CloudWatch also rejects documents with more
+//! than 30 dimensions, so high-cardinality label sets should not be exported this way.
+//!
+//! Metric names are prefixed as in the `csv` module (joined by `:`). Counters are written
+//! with their cumulative values. Each stat is written as several metrics, suffixed by
+//! `:count`, `:sum`, `:min`, `:max`, `:p50`, `:p90`, `:p99`, and `:p999` (or the quantiles
+//! given to `write_with_quantiles`); and each size stat as `:count` and `:sum`.
+
+use super::{Key, Labels, Quantiles, Report};
+use super::csv::{push_json_string, push_prefix};
+use ordermap::OrderMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most metrics CloudWatch accepts in a single document.
+pub const MAX_METRICS: usize = 100;
+
+/// The stat percentiles written by default.
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// A metric's name, unit, and value.
+type Metric = (String, &'static str, u64);
+
+pub fn string(
+    report: &Report,
+    namespace: &str,
+    dimensions: &[(&str, &str)],
+) -> Result<String, fmt::Error> {
+    let mut out = String::with_capacity(8 * 1024);
+    write(&mut out, report, namespace, dimensions)?;
+    Ok(out)
+}
+
+/// Renders a `Report` as newline-delimited EMF documents.
+pub fn write<W>(
+    out: &mut W,
+    report: &Report,
+    namespace: &str,
+    dimensions: &[(&str, &str)],
+) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let quantiles = Quantiles::new(&PERCENTILES);
+    write_with_quantiles(out, report, namespace, dimensions, &quantiles)
+}
+
+/// Renders a `Report` as newline-delimited EMF documents, writing each of `quantiles` for
+/// each stat.
+pub fn write_with_quantiles<W>(
+    out: &mut W,
+    report: &Report,
+    namespace: &str,
+    dimensions: &[(&str, &str)],
+    quantiles: &Quantiles,
+) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0);
+    write_at(out, report, namespace, dimensions, quantiles, timestamp)
+}
+
+fn write_at<W>(
+    out: &mut W,
+    report: &Report,
+    namespace: &str,
+    dimensions: &[(&str, &str)],
+    quantiles: &Quantiles,
+    timestamp: u64,
+) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    // Series are grouped by their labels, since each document has one set of dimensions.
+    let mut groups: OrderMap<&Labels, Vec<Metric>> = OrderMap::new();
+    for (k, v) in report.counters() {
+        group(&mut groups, k).push((name(k, None), "Count", *v as u64));
+    }
+    for (k, v) in report.gauges() {
+        group(&mut groups, k).push((name(k, None), "None", *v as u64));
+    }
+    for (k, h) in report.stats() {
+        let metrics = group(&mut groups, k);
+        let count = h.count();
+        metrics.push((name(k, Some("count")), "Count", count));
+        if count > 0 {
+            metrics.push((name(k, Some("sum")), "None", h.sum()));
+            metrics.push((name(k, Some("min")), "None", h.min()));
+            metrics.push((name(k, Some("max")), "None", h.max()));
+            for (p, suffix) in quantiles.iter() {
+                metrics.push((name(k, Some(suffix)), "None", h.percentile(p)));
+            }
+        }
+    }
+    for (k, s) in report.sizes() {
+        let metrics = group(&mut groups, k);
+        metrics.push((name(k, Some("count")), "Count", s.count()));
+        metrics.push((name(k, Some("sum")), "Bytes", s.sum()));
+    }
+
+    for (labels, metrics) in &groups {
+        let mut dims: Vec<(&str, String)> = dimensions
+            .iter()
+            .filter(|&&(d, _)| labels.get(d).is_none())
+            .map(|&(d, v)| (d, v.to_string()))
+            .collect();
+        dims.extend(labels.iter().map(|(k, v)| (k, v.to_string())));
+        for chunk in metrics.chunks(MAX_METRICS) {
+            write_document(out, namespace, &dims, chunk, timestamp)?;
+        }
+    }
+    Ok(())
+}
+
+fn group<'a, 'b>(
+    groups: &'a mut OrderMap<&'b Labels, Vec<Metric>>,
+    k: &'b Key,
+) -> &'a mut Vec<Metric> {
+    groups.entry(k.labels()).or_insert_with(Vec::new)
+}
+
+fn name(k: &Key, suffix: Option<&str>) -> String {
+    let mut name = String::new();
+    push_prefix(&mut name, k.prefix());
+    if !name.is_empty() {
+        name.push(':');
+    }
+    name.push_str(k.name());
+    if let Some(suffix) = suffix {
+        name.push(':');
+        name.push_str(suffix);
+    }
+    name
+}
+
+fn write_document<W>(
+    out: &mut W,
+    namespace: &str,
+    dims: &[(&str, String)],
+    metrics: &[Metric],
+    timestamp: u64,
+) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let mut doc = String::with_capacity(256 + 64 * metrics.len());
+    doc.push_str("{\"_aws\":{\"Timestamp\":");
+    doc.push_str(&timestamp.to_string());
+    doc.push_str(",\"CloudWatchMetrics\":[{\"Namespace\":");
+    push_json_string(&mut doc, namespace);
+    doc.push_str(",\"Dimensions\":[[");
+    for (i, &(d, _)) in dims.iter().enumerate() {
+        if i > 0 {
+            doc.push(',');
+        }
+        push_json_string(&mut doc, d);
+    }
+    doc.push_str("]],\"Metrics\":[");
+    for (i, &(ref name, unit, _)) in metrics.iter().enumerate() {
+        if i > 0 {
+            doc.push(',');
+        }
+        doc.push_str("{\"Name\":");
+        push_json_string(&mut doc, name);
+        doc.push_str(",\"Unit\":\"");
+        doc.push_str(unit);
+        doc.push_str("\"}");
+    }
+    doc.push_str("]}]}");
+    for &(d, ref v) in dims {
+        doc.push(',');
+        push_json_string(&mut doc, d);
+        doc.push(':');
+        push_json_string(&mut doc, v);
+    }
+    for &(ref name, _, v) in metrics {
+        doc.push(',');
+        push_json_string(&mut doc, name);
+        doc.push(':');
+        doc.push_str(&v.to_string());
+    }
+    doc.push('}');
+    writeln!(out, "{}", doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let (metrics, reporter) = ::new();
+        let http = metrics.prefixed("http");
+        http.clone().labeled("status", 200).counter("requests").incr(12);
+        http.gauge("conns").set(3);
+        http.stat("latency_ms");
+
+        let mut out = String::new();
+        let dims = [("service", "users"), ("status", "ignored")];
+        let quantiles = Quantiles::new(&PERCENTILES);
+        write_at(&mut out, &reporter.peek(), "app", &dims, &quantiles, 1_500_000_000_000).unwrap();
+        let docs = out.lines().collect::<Vec<_>>();
+        assert_eq!(
+            docs,
+            vec![
+                "{\"_aws\":{\"Timestamp\":1500000000000,\"CloudWatchMetrics\":[{\
+                 \"Namespace\":\"app\",\"Dimensions\":[[\"service\",\"status\"]],\
+                 \"Metrics\":[{\"Name\":\"http:requests\",\"Unit\":\"Count\"}]}]},\
+                 \"service\":\"users\",\"status\":\"200\",\"http:requests\":12}",
+                "{\"_aws\":{\"Timestamp\":1500000000000,\"CloudWatchMetrics\":[{\
+                 \"Namespace\":\"app\",\"Dimensions\":[[\"service\",\"status\"]],\
+                 \"Metrics\":[{\"Name\":\"http:conns\",\"Unit\":\"None\"},\
+                 {\"Name\":\"http:latency_ms:count\",\"Unit\":\"Count\"}]}]},\
+                 \"service\":\"users\",\"status\":\"ignored\",\
+                 \"http:conns\":3,\"http:latency_ms:count\":0}",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_metrics() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("requests").incr(1);
+        for shard in 0..2 {
+            let shard = metrics.clone().labeled("shard", shard);
+            shard.stat("latency_ms").add_values(&[1, 2, 3]);
+            for i in 0..125 {
+                let name: &'static str = Box::leak(format!("depth_{}", i).into_boxed_str());
+                shard.gauge(name).set(i);
+            }
+        }
+
+        let out = string(&reporter.peek(), "app", &[]).unwrap();
+        // Each shard has 125 gauges and a stat's 8 metrics, split across two documents.
+        let counts = out.lines()
+            .map(|l| l.matches("\"Name\"").count())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![1, 100, 33, 100, 33]);
+    }
+
+    #[test]
+    fn test_write_with_quantiles() {
+        let (metrics, reporter) = ::new();
+        metrics.stat("latency_ms").add_values(&[1, 2, 3]);
+
+        let mut out = String::new();
+        let quantiles = "75,99.99".parse().unwrap();
+        write_with_quantiles(&mut out, &reporter.peek(), "app", &[], &quantiles).unwrap();
+        assert!(out.contains("\"latency_ms:p75\":2,\"latency_ms:p9999\":3}"), "{}", out);
+        assert!(!out.contains("p50"), "{}", out);
+    }
+}
@@ -0,0 +1,330 @@
+//! Reporting settings read from the environment, so operators may change them without
+//! recompiling.
+//!
+//! `from_env` reads the following variables:
+//!
+//! - `TACHO_FORMAT`: the output format, one of `prometheus`, `openmetrics`, `native` (with
+//!   the `prometheus` feature), `csv`, or `dropwizard`;
+//! - `TACHO_INTERVAL`: the reporting interval, e.g. `500ms`, `10s`, or `1m` (or a number of
+//!   seconds);
+//! - `TACHO_SERIES_BUDGET`: the most series in each report (see
+//!   `Reporter::with_series_budget`);
+//! - `TACHO_SNAPSHOT_THREADS`: the threads on which stats are snapshotted (see
+//!   `Reporter::with_snapshot_threads`);
+//! - `TACHO_KIND_CONFLICTS`: `allow`, `reuse`, or `reject` (see `KindConflicts`);
+//! - `TACHO_LABEL_VALUES`: `allow`, `sanitize:<max_len>`, or `reject:<max_len>` (see
+//!   `LabelValues`);
+//! - `TACHO_QUANTILES`: the percentiles written for each stat by the `csv`, `dropwizard`,
+//!   and `cloudwatch_emf` exporters, e.g. `50,90,99,99.9` (see `Quantiles`).
+//!
+//! Unset variables leave the application's defaults in place. Settings are applied by
+//! `Config::builder`, `Config::reporter`, and `Config::formatter` (or, for CloudWatch EMF,
+//! by passing `Config::quantiles` to `cloudwatch_emf::write_with_quantiles`), so anything
+//! configured in code afterwards takes precedence over the environment. Any other
+//! `TACHO_*` variable, or a value that cannot be parsed, is an error, so that typos are
+//! caught at startup:
+//!
+//! ```
+//! use tacho::config::{self, Format};
+//!
+//! let config = config::from_vars(vec![
+//!     ("TACHO_FORMAT", "csv"),
+//!     ("TACHO_INTERVAL", "10s"),
+//!     ("TACHO_SERIES_BUDGET", "10000"),
+//! ]).unwrap();
+//! assert_eq!(config.format, Some(Format::Csv));
+//!
+//! let (metrics, reporter) = config.builder(tacho::Builder::new()).build();
+//! let reporter = config.reporter(reporter);
+//! # drop((metrics, reporter));
+//!
+//! let err = config::from_vars(vec![("TACHO_INTREVAL", "10s")]).unwrap_err();
+//! assert_eq!(err.to_string(), "TACHO_INTREVAL=10s: unknown variable");
+//! ```
+
+#[cfg(feature = "prometheus")]
+use super::prometheus;
+use super::{csv, dropwizard, AlignedSchedule, Builder, Formatter, KindConflicts, LabelValues,
+            Quantiles, Reporter};
+use std::env;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+const PREFIX: &str = "TACHO_";
+
+/// Settings read from the environment. Each is `None` if its variable is unset.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub format: Option<Format>,
+    pub interval: Option<Duration>,
+    pub series_budget: Option<usize>,
+    pub snapshot_threads: Option<usize>,
+    pub kind_conflicts: Option<KindConflicts>,
+    pub label_values: Option<LabelValues>,
+    pub quantiles: Option<Quantiles>,
+}
+
+/// An output format, as named by `TACHO_FORMAT`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    #[cfg(feature = "prometheus")]
+    Prometheus,
+    #[cfg(feature = "prometheus")]
+    OpenMetrics,
+    #[cfg(feature = "prometheus")]
+    Native,
+    Csv,
+    Dropwizard,
+}
+
+impl Format {
+    /// Returns a new formatter for this format.
+    pub fn formatter(self) -> Box<dyn Formatter> {
+        match self {
+            #[cfg(feature = "prometheus")]
+            Format::Prometheus => Box::new(prometheus::Config::default()),
+            #[cfg(feature = "prometheus")]
+            Format::OpenMetrics => Box::new(prometheus::openmetrics::OpenMetrics),
+            #[cfg(feature = "prometheus")]
+            Format::Native => Box::new(prometheus::native::Native),
+            Format::Csv => Box::new(csv::Csv::new()),
+            Format::Dropwizard => Box::new(dropwizard::Dropwizard::new()),
+        }
+    }
+}
+
+/// Describes a variable that could not be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub var: String,
+    pub value: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}: {}", self.var, self.value, self.reason)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// Reads settings from the process's `TACHO_*` environment variables.
+pub fn from_env() -> Result<Config, ConfigError> {
+    from_vars(env::vars_os().filter_map(|(k, v)| {
+        let k = k.into_string().ok()?;
+        if !k.starts_with(PREFIX) {
+            return None;
+        }
+        Some((k, v.to_string_lossy().into_owned()))
+    }))
+}
+
+/// Reads settings from `vars`, ignoring those without the `TACHO_` prefix.
+pub fn from_vars<I, K, V>(vars: I) -> Result<Config, ConfigError>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut config = Config::default();
+    for (k, v) in vars {
+        let (k, v) = (k.as_ref(), v.as_ref());
+        if !k.starts_with(PREFIX) {
+            continue;
+        }
+        let res = match &k[PREFIX.len()..] {
+            "FORMAT" => parse_format(v).map(|f| config.format = Some(f)),
+            "INTERVAL" => parse_duration(v).map(|d| config.interval = Some(d)),
+            "SERIES_BUDGET" => parse_positive(v).map(|n| config.series_budget = Some(n)),
+            "SNAPSHOT_THREADS" => parse_positive(v).map(|n| config.snapshot_threads = Some(n)),
+            "KIND_CONFLICTS" => parse_kind_conflicts(v).map(|p| config.kind_conflicts = Some(p)),
+            "LABEL_VALUES" => parse_label_values(v).map(|p| config.label_values = Some(p)),
+            "QUANTILES" => v.parse().map(|q| config.quantiles = Some(q)),
+            _ => Err("unknown variable"),
+        };
+        res.map_err(|reason| {
+            ConfigError {
+                var: k.to_owned(),
+                value: v.to_owned(),
+                reason,
+            }
+        })?;
+    }
+    Ok(config)
+}
+
+impl Config {
+    /// Applies the registry's settings to `builder`.
+    pub fn builder(&self, mut builder: Builder) -> Builder {
+        if let Some(p) = self.kind_conflicts {
+            builder = builder.kind_conflicts(p);
+        }
+        if let Some(p) = self.label_values {
+            builder = builder.label_values(p);
+        }
+        builder
+    }
+
+    /// Applies the reporter's settings to `reporter`.
+    pub fn reporter(&self, mut reporter: Reporter) -> Reporter {
+        if let Some(n) = self.series_budget {
+            reporter = reporter.with_series_budget(n);
+        }
+        if let Some(n) = self.snapshot_threads {
+            reporter = reporter.with_snapshot_threads(n);
+        }
+        reporter
+    }
+
+    /// Returns the formatter for the configured format, or for `default` if none is set,
+    /// writing the configured quantiles if it supports them.
+    pub fn formatter(&self, default: Format) -> Box<dyn Formatter> {
+        let format = self.format.unwrap_or(default);
+        match (format, self.quantiles.clone()) {
+            (Format::Csv, Some(q)) => Box::new(csv::Csv::with_quantiles(q)),
+            (Format::Dropwizard, Some(q)) => Box::new(dropwizard::Dropwizard::with_quantiles(q)),
+            _ => format.formatter(),
+        }
+    }
+
+    /// Returns the configured quantiles, or `default` if none are set.
+    pub fn quantiles(&self, default: &[f64]) -> Quantiles {
+        self.quantiles.clone().unwrap_or_else(|| Quantiles::new(default))
+    }
+
+    /// Returns a schedule for the configured interval, or for `default` if none is set.
+    pub fn schedule(&self, default: Duration) -> AlignedSchedule {
+        AlignedSchedule::new(self.interval.unwrap_or(default))
+    }
+}
+
+fn parse_format(v: &str) -> Result<Format, &'static str> {
+    match &*v.to_ascii_lowercase() {
+        #[cfg(feature = "prometheus")]
+        "prometheus" => Ok(Format::Prometheus),
+        #[cfg(feature = "prometheus")]
+        "openmetrics" => Ok(Format::OpenMetrics),
+        #[cfg(feature = "prometheus")]
+        "native" => Ok(Format::Native),
+        "csv" => Ok(Format::Csv),
+        "dropwizard" => Ok(Format::Dropwizard),
+        _ => Err("unknown format"),
+    }
+}
+
+/// Parses a duration with a unit (`ms`, `s`, or `m`); a bare number is in seconds.
+fn parse_duration(v: &str) -> Result<Duration, &'static str> {
+    let v = v.trim();
+    let (n, unit) = match v.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => v.split_at(i),
+        None => (v, "s"),
+    };
+    let n = n.parse::<u64>().map_err(|_| "invalid duration")?;
+    let d = match unit {
+        "ms" => Duration::from_millis(n),
+        "s" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n.saturating_mul(60)),
+        _ => return Err("invalid duration unit"),
+    };
+    if d == Duration::from_secs(0) {
+        return Err("duration must be positive");
+    }
+    Ok(d)
+}
+
+fn parse_positive(v: &str) -> Result<usize, &'static str> {
+    match v.trim().parse::<usize>() {
+        Ok(0) => Err("must be positive"),
+        Ok(n) => Ok(n),
+        Err(_) => Err("invalid number"),
+    }
+}
+
+fn parse_kind_conflicts(v: &str) -> Result<KindConflicts, &'static str> {
+    match &*v.to_ascii_lowercase() {
+        "allow" => Ok(KindConflicts::Allow),
+        "reuse" => Ok(KindConflicts::Reuse),
+        "reject" => Ok(KindConflicts::Reject),
+        _ => Err("unknown policy"),
+    }
+}
+
+fn parse_label_values(v: &str) -> Result<LabelValues, &'static str> {
+    let v = v.to_ascii_lowercase();
+    let mut parts = v.splitn(2, ':');
+    let policy = parts.next().unwrap_or("");
+    let max_len = parts.next().map(parse_positive);
+    match (policy, max_len) {
+        ("allow", None) => Ok(LabelValues::Allow),
+        ("sanitize", Some(n)) => n.map(|max_len| LabelValues::Sanitize { max_len }),
+        ("reject", Some(n)) => n.map(|max_len| LabelValues::Reject { max_len }),
+        ("sanitize", None) | ("reject", None) => Err("missing maximum length"),
+        _ => Err("unknown policy"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_vars() {
+        let config = from_vars(vec![
+            ("HOME", "/root"),
+            ("TACHO_FORMAT", "Dropwizard"),
+            ("TACHO_INTERVAL", "500ms"),
+            ("TACHO_SNAPSHOT_THREADS", "4"),
+            ("TACHO_KIND_CONFLICTS", "reject"),
+            ("TACHO_LABEL_VALUES", "sanitize:64"),
+            ("TACHO_QUANTILES", "50,99.9"),
+        ]).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                format: Some(Format::Dropwizard),
+                interval: Some(Duration::from_millis(500)),
+                series_budget: None,
+                snapshot_threads: Some(4),
+                kind_conflicts: Some(KindConflicts::Reject),
+                label_values: Some(LabelValues::Sanitize { max_len: 64 }),
+                quantiles: Some(Quantiles::new(&[50.0, 99.9])),
+            }
+        );
+        assert_eq!(from_vars(Vec::<(&str, &str)>::new()).unwrap(), Config::default());
+
+        let err = |k, v| from_vars(vec![(k, v)]).unwrap_err().reason;
+        assert_eq!(err("TACHO_FORMAT", "xml"), "unknown format");
+        assert_eq!(err("TACHO_INTERVAL", "10h"), "invalid duration unit");
+        assert_eq!(err("TACHO_INTERVAL", "0s"), "duration must be positive");
+        assert_eq!(err("TACHO_SERIES_BUDGET", "0"), "must be positive");
+        assert_eq!(err("TACHO_SERIES_BUDGET", "lots"), "invalid number");
+        assert_eq!(err("TACHO_LABEL_VALUES", "reject"), "missing maximum length");
+        assert_eq!(err("TACHO_QUANTILES", "0.5,101"), "percentile out of range");
+        assert_eq!(err("TACHO_QUANTIELS", "0.5"), "unknown variable");
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = from_vars(vec![
+            ("TACHO_KIND_CONFLICTS", "reject"),
+            ("TACHO_SERIES_BUDGET", "1"),
+            ("TACHO_QUANTILES", "75"),
+        ]).unwrap();
+        let (metrics, reporter) = config.builder(Builder::new()).build();
+        let reporter = config.reporter(reporter);
+        let _c = metrics.counter("a");
+        assert!(metrics.try_gauge("a").is_err());
+        let _b = metrics.counter("b");
+        assert_eq!(reporter.peek().counters().len(), 1);
+
+        let mut out = Vec::new();
+        config.formatter(Format::Csv).write(&mut out, &reporter.peek()).unwrap();
+        assert!(out.starts_with(b"prefix,name,labels,type,value,count,sum,min,max,p75\n"));
+        assert_eq!(config.quantiles(&[50.0]), Quantiles::new(&[75.0]));
+        assert_eq!(Config::default().quantiles(&[50.0]), Quantiles::new(&[50.0]));
+    }
+}
@@ -0,0 +1,152 @@
+//! Propagates an ambient `Scope` through futures, so that library code may record metrics
+//! against the current request's scope without it being threaded through every call.
+//!
+//! ```
+//! extern crate futures;
+//! extern crate tacho;
+//!
+//! use futures::Future;
+//! use futures::future::lazy;
+//!
+//! fn lookup() -> Result<(), ()> {
+//!     // Library code records against whatever scope its caller attached.
+//!     if let Some(scope) = tacho::context::current() {
+//!         scope.counter("lookups").incr(1);
+//!     }
+//!     Ok(())
+//! }
+//!
+//! fn main() {
+//!     let (metrics, reporter) = tacho::new();
+//!     let metrics = metrics.labeled("route", "users");
+//!     tacho::context::with_scope(metrics, lazy(lookup)).wait().unwrap();
+//!     assert_eq!(reporter.peek().counters().len(), 1);
+//! }
+//! ```
+//!
+//! The scope is only set while the wrapped future is being polled (including any futures
+//! it polls), so it does not leak into other tasks running on the same thread. Outside of
+//! `with_scope`, `current()` returns `None`: callers should fall back to a scope they own,
+//! or skip recording.
+
+use super::Scope;
+use futures::{Future, Poll};
+use std::cell::RefCell;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Scope>> = const { RefCell::new(None) };
+}
+
+/// Returns the scope attached to the future currently being polled, if any.
+pub fn current() -> Option<Scope> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Attaches `scope` to `fut`, so that `current()` returns it whenever `fut` is polled.
+///
+/// Scopes nest: an inner `with_scope` replaces the outer scope while its future is polled.
+pub fn with_scope<F: Future>(scope: Scope, fut: F) -> WithScope<F> {
+    WithScope {
+        scope: Some(scope),
+        inner: fut,
+    }
+}
+
+/// A future that sets the ambient scope while polling its inner future.
+pub struct WithScope<F> {
+    /// Held outside of polls, so that it is not cloned each time.
+    scope: Option<Scope>,
+    inner: F,
+}
+
+impl<F: Future> Future for WithScope<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let _reset = Reset::enter(&mut self.scope);
+        self.inner.poll()
+    }
+}
+
+/// Restores the previous scope (and returns the entered one to its owner) when dropped, even
+/// if the inner future panics.
+struct Reset<'a> {
+    owner: &'a mut Option<Scope>,
+    prior: Option<Scope>,
+}
+
+impl<'a> Reset<'a> {
+    fn enter(owner: &'a mut Option<Scope>) -> Reset<'a> {
+        let prior = CURRENT.with(|c| c.replace(owner.take()));
+        Reset { owner, prior }
+    }
+}
+
+impl Drop for Reset<'_> {
+    fn drop(&mut self) {
+        *self.owner = CURRENT.with(|c| c.replace(self.prior.take()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Async;
+    use futures::future::{lazy, poll_fn};
+
+    #[test]
+    fn test_with_scope() {
+        let (metrics, reporter) = ::new();
+        assert!(current().is_none());
+
+        let mut polls = 0;
+        let outer = metrics.clone().prefixed("outer");
+        let fut = poll_fn(|| -> Poll<(), ()> {
+            current().unwrap().counter("polls").incr(1);
+            polls += 1;
+            if polls < 3 {
+                Ok(Async::NotReady)
+            } else {
+                Ok(Async::Ready(()))
+            }
+        });
+        let mut fut = with_scope(outer, fut);
+        assert_eq!(fut.poll(), Ok(Async::NotReady));
+        // The scope is unset between polls.
+        assert!(current().is_none());
+        assert_eq!(fut.poll(), Ok(Async::NotReady));
+        assert_eq!(fut.poll(), Ok(Async::Ready(())));
+
+        let inner = metrics.clone().prefixed("inner");
+        let nested = with_scope(
+            metrics.prefixed("outer"),
+            lazy(move || {
+                with_scope(inner, lazy(|| {
+                    current().unwrap().counter("nested").incr(1);
+                    Ok::<_, ()>(())
+                })).wait()?;
+                current().unwrap().counter("after").incr(1);
+                Ok::<_, ()>(())
+            }),
+        );
+        nested.wait().unwrap();
+        assert!(current().is_none());
+
+        let report = reporter.peek();
+        let mut counters = report
+            .counters()
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect::<Vec<_>>();
+        counters.sort();
+        assert_eq!(
+            counters,
+            vec![
+                ("inner:nested".to_string(), 1),
+                ("outer:after".to_string(), 1),
+                ("outer:polls".to_string(), 3),
+            ]
+        );
+    }
+}
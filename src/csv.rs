@@ -0,0 +1,262 @@
+//! Renders reports as CSV, e.g. for loading into a spreadsheet.
+//!
+//! Each series is written as a row:
+//!
+//! ```text
+//! prefix,name,labels,type,value,count,sum,min,max,p50,p90,p99,p999
+//! http,requests,"{""status"":""200""}",counter,12,,,,,,,,
+//! http,latency_ms,{},stat,,12,340,3,95,22,61,95,95
+//! ```
+//!
+//! Prefixes are joined by `:` and labels are encoded as a JSON object. Counters and gauges
+//! have only a value; stats and size stats have only a distribution (size stats do not
+//! track percentiles). Other percentiles may be written instead with `write_with_quantiles`
+//! or `Csv::with_quantiles`.
+
+use super::{Formatter, HistogramWithSum, Key, Prefix, Quantiles, Report};
+use super::format::with_fmt;
+use std::fmt;
+use std::io;
+
+const HEADER: &str = "prefix,name,labels,type,value,count,sum,min,max";
+
+/// The percentiles written by default.
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+pub fn string(report: &Report) -> Result<String, fmt::Error> {
+    let mut out = String::with_capacity(8 * 1024);
+    write(&mut out, report)?;
+    Ok(out)
+}
+
+/// Renders a `Report` as CSV, with a header row.
+pub fn write<W>(out: &mut W, report: &Report) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    write_with_quantiles(out, report, &Quantiles::new(&PERCENTILES))
+}
+
+/// Renders a `Report` as CSV, with a column for each of `quantiles`.
+pub fn write_with_quantiles<W>(out: &mut W, report: &Report, quantiles: &Quantiles) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    out.write_str(HEADER)?;
+    for (_, suffix) in quantiles.iter() {
+        write!(out, ",{}", suffix)?;
+    }
+    writeln!(out)?;
+
+    for (k, v) in report.counters() {
+        write_key(out, k, "counter")?;
+        write!(out, ",{},,,,", v)?;
+        write_empty(out, quantiles.len())?;
+    }
+
+    for (k, v) in report.gauges() {
+        write_key(out, k, "gauge")?;
+        write!(out, ",{},,,,", v)?;
+        write_empty(out, quantiles.len())?;
+    }
+
+    for (k, h) in report.stats() {
+        write_key(out, k, "stat")?;
+        write_stat(out, h, quantiles)?;
+    }
+
+    for (k, s) in report.sizes() {
+        write_key(out, k, "size")?;
+        write!(out, ",,{},{},,", s.count(), s.sum())?;
+        write_empty(out, quantiles.len())?;
+    }
+
+    Ok(())
+}
+
+/// Renders reports as CSV via the `Formatter` interface.
+#[derive(Clone, Debug)]
+pub struct Csv {
+    quantiles: Quantiles,
+}
+
+impl Csv {
+    pub fn new() -> Csv {
+        Csv::with_quantiles(Quantiles::new(&PERCENTILES))
+    }
+
+    /// Writes a column for each of `quantiles`, rather than the default percentiles.
+    pub fn with_quantiles(quantiles: Quantiles) -> Csv {
+        Csv { quantiles }
+    }
+}
+
+impl Default for Csv {
+    fn default() -> Csv {
+        Csv::new()
+    }
+}
+
+impl Formatter for Csv {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        with_fmt(out, |mut out| write_with_quantiles(&mut out, report, &self.quantiles))
+    }
+}
+
+/// Writes the prefix, name, labels, and type columns.
+fn write_key<W>(out: &mut W, k: &Key, kind: &str) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let mut prefix = String::new();
+    push_prefix(&mut prefix, k.prefix());
+    write_field(out, &prefix)?;
+    out.write_char(',')?;
+    write_field(out, k.name())?;
+    out.write_char(',')?;
+
+    let mut labels = String::from("{");
+    for (i, (k, v)) in k.labels().iter().enumerate() {
+        if i > 0 {
+            labels.push(',');
+        }
+        push_json_string(&mut labels, k);
+        labels.push(':');
+        push_json_string(&mut labels, &v.to_string());
+    }
+    labels.push('}');
+    write_field(out, &labels)?;
+
+    write!(out, ",{}", kind)
+}
+
+fn write_stat<W>(out: &mut W, h: &HistogramWithSum, quantiles: &Quantiles) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let count = h.count();
+    write!(out, ",,{}", count)?;
+    if count == 0 {
+        out.write_str(",,,")?;
+        return write_empty(out, quantiles.len());
+    }
+    write!(out, ",{},{},{}", h.sum(), h.min(), h.max())?;
+    for (p, _) in quantiles.iter() {
+        write!(out, ",{}", h.percentile(p))?;
+    }
+    writeln!(out)
+}
+
+/// Ends a row with `n` empty columns.
+fn write_empty<W>(out: &mut W, n: usize) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    for _ in 0..n {
+        out.write_char(',')?;
+    }
+    writeln!(out)
+}
+
+/// Joins prefix segments with `:`.
+pub(super) fn push_prefix(out: &mut String, prefix: &Prefix) {
+    if let Prefix::Node(ref node) = *prefix {
+        push_prefix(out, node.prefix());
+        if !out.is_empty() {
+            out.push(':');
+        }
+        out.push_str(node.value().as_str());
+    }
+}
+
+pub(super) fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes a field, quoting it if necessary.
+fn write_field<W>(out: &mut W, v: &str) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    if !v.contains(&[',', '"', '\n', '\r'][..]) {
+        return out.write_str(v);
+    }
+    out.write_char('"')?;
+    out.write_str(&v.replace('"', "\"\""))?;
+    out.write_char('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let (metrics, reporter) = ::new();
+        let metrics = metrics.prefixed("http");
+        metrics
+            .clone()
+            .labeled("status", 200)
+            .labeled("path", "/a,\"b\"")
+            .counter("requests")
+            .incr(12);
+        metrics.gauge("conns").set(3);
+        let mut latency = metrics.stat("latency_ms");
+        latency.add_values(&[1, 2, 3, 4]);
+        metrics.size_stat("payload_bytes").add(10);
+        metrics.stat("idle_ms");
+
+        let out = string(&reporter.peek()).unwrap();
+        let mut lines = out.lines();
+        let header = "prefix,name,labels,type,value,count,sum,min,max,p50,p90,p99,p999";
+        assert_eq!(lines.next(), Some(header));
+        assert_eq!(
+            lines.next(),
+            Some(
+                "http,requests,\"{\"\"path\"\":\"\"/a,\\\"\"b\\\"\"\"\",\
+                 \"\"status\"\":\"\"200\"\"}\",counter,12,,,,,,,,",
+            )
+        );
+        assert_eq!(lines.next(), Some("http,conns,{},gauge,3,,,,,,,,"));
+        assert_eq!(lines.next(), Some("http,latency_ms,{},stat,,4,10,1,4,2,4,4,4"));
+        assert_eq!(lines.next(), Some("http,idle_ms,{},stat,,0,,,,,,,"));
+        assert_eq!(lines.next(), Some("http,payload_bytes,{},size,,1,10,,,,,,"));
+        assert_eq!(lines.next(), None);
+    }
+    #[test]
+    fn test_write_with_quantiles() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("requests").incr(1);
+        metrics.stat("latency_ms").add_values(&[1, 2, 3, 4]);
+        metrics.stat("idle_ms");
+        metrics.size_stat("payload_bytes").add(10);
+
+        let mut out = Vec::new();
+        let csv = Csv::with_quantiles("75,100".parse().unwrap());
+        reporter.peek().to_writer(&mut out, &csv).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "prefix,name,labels,type,value,count,sum,min,max,p75,p100",
+                ",requests,{},counter,1,,,,,,",
+                ",latency_ms,{},stat,,4,10,1,4,3,4",
+                ",idle_ms,{},stat,,0,,,,,",
+                ",payload_bytes,{},size,,1,10,,,,",
+            ]
+        );
+    }
+}
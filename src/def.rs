@@ -0,0 +1,382 @@
+//! Declares metrics ahead of time, so that a crate's metrics may be catalogued.
+//!
+//! A `MetricDef` describes a metric without creating it, so definitions may be declared
+//! as constants and bound to a `Scope` later, in bulk:
+//!
+//! ```
+//! use tacho::MetricDef;
+//!
+//! const REQUESTS: MetricDef = MetricDef::counter("requests").help("Requests received");
+//! const LATENCY: MetricDef = MetricDef::timer_ms("latency_ms").help("Time to respond");
+//! const METRICS: &[MetricDef] = &[REQUESTS, LATENCY];
+//!
+//! let (scope, _reporter) = tacho::new();
+//! let metrics = scope.bind(METRICS);
+//! metrics.counter(&REQUESTS).incr(1);
+//!
+//! // Renders a markdown table of the metrics, e.g. for a crate's documentation.
+//! let docs = tacho::def::docs_table(METRICS);
+//! assert!(docs.contains("| `requests` | counter | Requests received |"));
+//! ```
+//!
+//! In larger codebases, each module may declare its metrics with `declare_metrics!`, and
+//! the crate may check with `assert_unique_names!` that no two modules' metrics collide:
+//!
+//! ```
+//! #[macro_use]
+//! extern crate tacho;
+//!
+//! mod server {
+//!     declare_metrics! {
+//!         REQUESTS = counter("requests", "Requests received");
+//!         LATENCY = timer_ms("latency_ms");
+//!     }
+//! }
+//!
+//! mod client {
+//!     declare_metrics! {
+//!         CONNECTS = counter("connects");
+//!     }
+//! }
+//!
+//! assert_unique_names!(server::METRICS, client::METRICS);
+//!
+//! fn main() {
+//!     let (scope, _reporter) = tacho::new();
+//!     scope.bind(server::METRICS).counter(&server::REQUESTS).incr(1);
+//! }
+//! ```
+
+use super::{Counter, Gauge, Scope, Stat, Timer};
+use ordermap::OrderMap;
+use std::fmt;
+
+/// The kind of metric described by a `MetricDef`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Stat,
+    /// A Timer that records durations in microseconds.
+    TimerUs,
+    /// A Timer that records durations in milliseconds.
+    TimerMs,
+}
+
+impl fmt::Display for MetricKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Stat => "stat",
+            MetricKind::TimerUs => "timer (us)",
+            MetricKind::TimerMs => "timer (ms)",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Describes a metric that may be bound to a `Scope`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MetricDef {
+    name: &'static str,
+    kind: MetricKind,
+    help: &'static str,
+}
+
+impl MetricDef {
+    pub const fn counter(name: &'static str) -> MetricDef {
+        MetricDef::new(name, MetricKind::Counter)
+    }
+
+    pub const fn gauge(name: &'static str) -> MetricDef {
+        MetricDef::new(name, MetricKind::Gauge)
+    }
+
+    pub const fn stat(name: &'static str) -> MetricDef {
+        MetricDef::new(name, MetricKind::Stat)
+    }
+
+    pub const fn timer_us(name: &'static str) -> MetricDef {
+        MetricDef::new(name, MetricKind::TimerUs)
+    }
+
+    pub const fn timer_ms(name: &'static str) -> MetricDef {
+        MetricDef::new(name, MetricKind::TimerMs)
+    }
+
+    const fn new(name: &'static str, kind: MetricKind) -> MetricDef {
+        MetricDef {
+            name,
+            kind,
+            help: "",
+        }
+    }
+
+    /// Describes the metric, e.g. for `docs_table`.
+    pub const fn help(mut self, help: &'static str) -> Self {
+        self.help = help;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn kind(&self) -> MetricKind {
+        self.kind
+    }
+
+    pub fn help_text(&self) -> &'static str {
+        self.help
+    }
+
+    /// Creates the metric in `scope`.
+    pub fn bind(&self, scope: &Scope) -> Bound {
+        match self.kind {
+            MetricKind::Counter => Bound::Counter(scope.counter(self.name)),
+            MetricKind::Gauge => Bound::Gauge(scope.gauge(self.name)),
+            MetricKind::Stat => Bound::Stat(scope.stat(self.name)),
+            MetricKind::TimerUs => Bound::Timer(scope.timer_us(self.name)),
+            MetricKind::TimerMs => Bound::Timer(scope.timer_ms(self.name)),
+        }
+    }
+}
+
+/// A metric created from a `MetricDef`.
+#[derive(Clone)]
+pub enum Bound {
+    Counter(Counter),
+    Gauge(Gauge),
+    Stat(Stat),
+    Timer(Timer),
+}
+
+/// Metrics created from a set of `MetricDef`s, as returned by `Scope::bind`.
+#[derive(Clone)]
+pub struct BoundMetrics(OrderMap<MetricDef, Bound>);
+
+impl BoundMetrics {
+    pub(super) fn new(scope: &Scope, defs: &[MetricDef]) -> BoundMetrics {
+        BoundMetrics(defs.iter().map(|d| (*d, d.bind(scope))).collect())
+    }
+
+    pub fn get(&self, def: &MetricDef) -> Option<&Bound> {
+        self.0.get(def)
+    }
+
+    /// Returns the counter bound for `def`.
+    ///
+    /// Panics if `def` is not a bound counter.
+    pub fn counter(&self, def: &MetricDef) -> &Counter {
+        match self.get(def) {
+            Some(Bound::Counter(c)) => c,
+            _ => panic!("counter {} is not bound", def.name),
+        }
+    }
+
+    /// Returns the gauge bound for `def`.
+    ///
+    /// Panics if `def` is not a bound gauge.
+    pub fn gauge(&self, def: &MetricDef) -> &Gauge {
+        match self.get(def) {
+            Some(Bound::Gauge(g)) => g,
+            _ => panic!("gauge {} is not bound", def.name),
+        }
+    }
+
+    /// Returns the stat bound for `def`.
+    ///
+    /// Panics if `def` is not a bound stat.
+    pub fn stat(&self, def: &MetricDef) -> &Stat {
+        match self.get(def) {
+            Some(Bound::Stat(s)) => s,
+            _ => panic!("stat {} is not bound", def.name),
+        }
+    }
+
+    /// Returns the timer bound for `def`.
+    ///
+    /// Panics if `def` is not a bound timer.
+    pub fn timer(&self, def: &MetricDef) -> &Timer {
+        match self.get(def) {
+            Some(Bound::Timer(t)) => t,
+            _ => panic!("timer {} is not bound", def.name),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Declares `MetricDef` constants, and a `METRICS` constant listing them.
+///
+/// Each declaration names a constructor (`counter`, `gauge`, `stat`, `timer_us`, or
+/// `timer_ms`), the metric's name, and optionally its help text. Declaring two metrics
+/// with the same name fails to compile:
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate tacho;
+///
+/// declare_metrics! {
+///     REQUESTS = counter("requests");
+///     RESPONSES = counter("requests");
+/// }
+///
+/// fn main() {}
+/// ```
+#[macro_export]
+macro_rules! declare_metrics {
+    ($($def:ident = $kind:ident($name:expr $(, $help:expr)*);)*) => {
+        $(
+            pub const $def: $crate::MetricDef = $crate::MetricDef::$kind($name)$(.help($help))*;
+        )*
+        pub const METRICS: &[$crate::MetricDef] = &[$($def),*];
+        const _: () = $crate::def::assert_unique(&[METRICS]);
+    };
+}
+
+/// Fails to compile if any two of the given lists of `MetricDef`s declare the same name.
+///
+/// This may be used at the crate root, listing each module's `METRICS`, or in a test.
+#[macro_export]
+macro_rules! assert_unique_names {
+    ($($defs:expr),+ $(,)*) => {
+        const _: () = $crate::def::assert_unique(&[$($defs),+]);
+    };
+}
+
+/// Panics if any two of `lists`' definitions have the same name.
+///
+/// This is evaluated at compile time by `declare_metrics!` and `assert_unique_names!`.
+#[doc(hidden)]
+pub const fn assert_unique(lists: &[&[MetricDef]]) {
+    let mut i = 0;
+    while i < lists.len() {
+        let mut j = 0;
+        while j < lists[i].len() {
+            let name = lists[i][j].name;
+            // Compare with each later definition, in this list and the following ones.
+            let mut k = i;
+            let mut l = j + 1;
+            while k < lists.len() {
+                while l < lists[k].len() {
+                    if str_eq(name, lists[k][l].name) {
+                        panic!("{}", name);
+                    }
+                    l += 1;
+                }
+                k += 1;
+                l = 0;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Renders a markdown table describing `defs`.
+pub fn docs_table(defs: &[MetricDef]) -> String {
+    let mut out = String::from("| Name | Type | Description |\n|------|------|-------------|\n");
+    for d in defs {
+        out.push_str(&format!(
+            "| `{}` | {} | {} |\n",
+            d.name,
+            d.kind,
+            d.help.replace('|', "\\|")
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const REQUESTS: MetricDef = MetricDef::counter("requests").help("Requests received");
+    const PENDING: MetricDef = MetricDef::gauge("pending");
+    const SIZE: MetricDef = MetricDef::stat("size").help("Bytes | chars");
+    const LATENCY: MetricDef = MetricDef::timer_ms("latency_ms");
+    const METRICS: &[MetricDef] = &[REQUESTS, PENDING, SIZE, LATENCY];
+
+    #[test]
+    fn test_bind() {
+        let (scope, reporter) = ::new();
+        let metrics = scope.prefixed("lib").bind(METRICS);
+        assert_eq!(metrics.len(), 4);
+        metrics.counter(&REQUESTS).incr(2);
+        metrics.gauge(&PENDING).set(3);
+        metrics.stat(&SIZE).add(4);
+        metrics.timer(&LATENCY).record(Duration::from_millis(5));
+
+        let report = reporter.peek();
+        assert_eq!(report.get_counter("requests", &[]), Some(2));
+        assert_eq!(report.get_gauge("pending", &[]), Some(3));
+        assert_eq!(report.get_stat("size", &[]).map(|h| h.max()), Some(4));
+        assert_eq!(report.get_stat("latency_ms", &[]).map(|h| h.max()), Some(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "gauge requests is not bound")]
+    fn test_bind_wrong_kind() {
+        let (scope, _reporter) = ::new();
+        scope.bind(&[REQUESTS]).gauge(&MetricDef::gauge("requests"));
+    }
+
+    mod server {
+        declare_metrics! {
+            REQUESTS = counter("requests", "Requests received");
+            LATENCY = timer_us("latency_us");
+        }
+    }
+
+    assert_unique_names!(server::METRICS, &[PENDING, SIZE]);
+
+    #[test]
+    fn test_declare_metrics() {
+        assert_eq!(server::METRICS, &[server::REQUESTS, server::LATENCY]);
+        assert_eq!(server::REQUESTS.help_text(), "Requests received");
+        assert_eq!(server::LATENCY.kind(), MetricKind::TimerUs);
+    }
+
+    #[test]
+    #[should_panic(expected = "requests")]
+    fn test_assert_unique() {
+        assert_unique(&[&[PENDING], server::METRICS, &[REQUESTS]]);
+    }
+
+    #[test]
+    fn test_docs_table() {
+        assert_eq!(
+            docs_table(METRICS),
+            "| Name | Type | Description |\n\
+             |------|------|-------------|\n\
+             | `requests` | counter | Requests received |\n\
+             | `pending` | gauge |  |\n\
+             | `size` | stat | Bytes \\| chars |\n\
+             | `latency_ms` | timer (ms) |  |\n"
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! Renders reports with Dropwizard-style flat names, in Graphite's plaintext format.
+//!
+//! This eases migrating dashboards built for Dropwizard's `GraphiteReporter`. Each series
+//! is flattened into a dotted name, with its labels appended as `key.value` pairs, and is
+//! written with Dropwizard's suffixes:
+//!
+//! ```text
+//! http.requests.status.200.count 12 1500000000
+//! http.requests.status.200.m1_rate 0.2 1500000000
+//! http.conns 3 1500000000
+//! http.latency_ms.p98 61 1500000000
+//! ```
+//!
+//! Counters are rendered as meters: their count, along with their mean rate and 1-, 5-, and
+//! 15-minute exponentially-weighted moving average rates (per second). Since rates are
+//! computed between reports, a `Dropwizard` should be reused for each report. Gauges are
+//! rendered as-is; stats are rendered as timers (`count`, `min`, `max`, `mean`, `stddev`,
+//! and `p50` through `p999`, unless other quantiles are given to `with_quantiles`); and size
+//! stats as histograms with only a `count` and `mean`.
+
+use super::{Formatter, Key, Quantiles, Report};
+use super::format::with_fmt;
+use ordermap::OrderMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The windows of moving-average rates, in seconds, with their suffixes.
+const WINDOWS: [(f64, &str); 3] = [(60.0, "m1_rate"), (300.0, "m5_rate"), (900.0, "m15_rate")];
+
+/// Timer quantiles written by default, as Dropwizard's reporters do.
+const QUANTILES: [f64; 6] = [50.0, 75.0, 95.0, 98.0, 99.0, 99.9];
+
+/// Renders reports in Dropwizard's naming scheme, tracking counters' rates between reports.
+pub struct Dropwizard {
+    meters: Mutex<OrderMap<Arc<Key>, Meter>>,
+    quantiles: Quantiles,
+}
+
+/// A counter's rates.
+struct Meter {
+    first: Instant,
+    last: Instant,
+    count: usize,
+    /// The counter's total increase since it was first reported, across resets.
+    increase: usize,
+    rates: [f64; 3],
+}
+
+impl Dropwizard {
+    pub fn new() -> Dropwizard {
+        Dropwizard::with_quantiles(Quantiles::new(&QUANTILES))
+    }
+
+    /// Writes each of `quantiles` for timers, rather than Dropwizard's.
+    pub fn with_quantiles(quantiles: Quantiles) -> Dropwizard {
+        Dropwizard {
+            meters: Mutex::new(OrderMap::new()),
+            quantiles,
+        }
+    }
+
+    pub fn string(&self, report: &Report) -> Result<String, fmt::Error> {
+        let mut out = String::with_capacity(8 * 1024);
+        self.write(&mut out, report)?;
+        Ok(out)
+    }
+
+    /// Renders a `Report`, updating counters' rates.
+    pub fn write<W: fmt::Write>(&self, out: &mut W, report: &Report) -> fmt::Result {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.write_at(out, report, Instant::now(), timestamp)
+    }
+
+    fn write_at<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        report: &Report,
+        now: Instant,
+        timestamp: u64,
+    ) -> fmt::Result {
+        let mut meters = self.meters.lock().unwrap();
+        meters.retain(|k, _| report.counters().contains_key(k));
+        for (k, v) in report.counters() {
+            let meter = meters.entry(k.clone()).or_insert_with(|| Meter::new(*v, now));
+            meter.update(*v, now);
+
+            let name = flat_name(k);
+            write_line(out, &name, Some("count"), &v, timestamp)?;
+            write_line(out, &name, Some("mean_rate"), &meter.mean_rate(now), timestamp)?;
+            for (&(_, suffix), rate) in WINDOWS.iter().zip(&meter.rates) {
+                write_line(out, &name, Some(suffix), rate, timestamp)?;
+            }
+        }
+
+        for (k, v) in report.gauges() {
+            write_line(out, &flat_name(k), None, v, timestamp)?;
+        }
+
+        for (k, h) in report.stats() {
+            let name = flat_name(k);
+            let s = h.summary();
+            write_line(out, &name, Some("count"), &s.count, timestamp)?;
+            write_line(out, &name, Some("min"), &s.min, timestamp)?;
+            write_line(out, &name, Some("max"), &s.max, timestamp)?;
+            write_line(out, &name, Some("mean"), &s.mean, timestamp)?;
+            write_line(out, &name, Some("stddev"), &s.stddev, timestamp)?;
+            for (p, suffix) in self.quantiles.iter() {
+                let v = if s.count == 0 { 0 } else { h.percentile(p) };
+                write_line(out, &name, Some(suffix), &v, timestamp)?;
+            }
+        }
+
+        for (k, s) in report.sizes() {
+            let name = flat_name(k);
+            let count = s.count();
+            let mean = if count == 0 {
+                0.0
+            } else {
+                s.sum() as f64 / count as f64
+            };
+            write_line(out, &name, Some("count"), &count, timestamp)?;
+            write_line(out, &name, Some("mean"), &mean, timestamp)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Dropwizard {
+    fn default() -> Dropwizard {
+        Dropwizard::new()
+    }
+}
+
+impl Formatter for Dropwizard {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        with_fmt(out, |mut out| Dropwizard::write(self, &mut out, report))
+    }
+}
+
+impl Meter {
+    fn new(initial: usize, now: Instant) -> Meter {
+        Meter {
+            first: now,
+            last: now,
+            count: initial,
+            increase: 0,
+            rates: [0.0; 3],
+        }
+    }
+
+    fn update(&mut self, count: usize, now: Instant) {
+        let secs = now.duration_since(self.last).as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+        // A counter that decreased was reset, so its entire value is new.
+        let delta = if count >= self.count {
+            count - self.count
+        } else {
+            count
+        };
+        self.increase = self.increase.saturating_add(delta);
+        let rate = delta as f64 / secs;
+        let first = self.last == self.first;
+        for (&(window, _), r) in WINDOWS.iter().zip(self.rates.iter_mut()) {
+            if first {
+                *r = rate;
+            } else {
+                let alpha = 1.0 - (-secs / window).exp();
+                *r += alpha * (rate - *r);
+            }
+        }
+        self.count = count;
+        self.last = now;
+    }
+
+    fn mean_rate(&self, now: Instant) -> f64 {
+        let secs = now.duration_since(self.first).as_secs_f64();
+        if secs <= 0.0 {
+            return 0.0;
+        }
+        self.increase as f64 / secs
+    }
+}
+
+/// Joins a key's prefix, name, and labels with `.`.
+fn flat_name(k: &Key) -> String {
+    let mut name = String::new();
+    let mut push = |part: &str| {
+        if !name.is_empty() {
+            name.push('.');
+        }
+        for c in part.chars() {
+            let valid = c.is_ascii_alphanumeric() || c == '_' || c == '-';
+            name.push(if valid { c } else { '_' });
+        }
+    };
+    for v in k.prefix().values() {
+        push(v);
+    }
+    push(k.name());
+    for (lk, lv) in k.labels().iter() {
+        push(lk);
+        push(&lv.to_string());
+    }
+    name
+}
+
+fn write_line<W, V>(
+    out: &mut W,
+    name: &str,
+    suffix: Option<&str>,
+    v: &V,
+    timestamp: u64,
+) -> fmt::Result
+where
+    W: fmt::Write,
+    V: fmt::Display,
+{
+    match suffix {
+        Some(suffix) => writeln!(out, "{}.{} {} {}", name, suffix, v, timestamp),
+        None => writeln!(out, "{} {} {}", name, v, timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_write() {
+        let (metrics, reporter) = ::new();
+        let metrics = metrics.prefixed("http");
+        let requests = metrics.clone().labeled("status", 200).counter("requests");
+        metrics.gauge("conns").set(3);
+        let mut latency = metrics.stat("latency_ms");
+        latency.add_values(&[10, 20, 30]);
+        metrics.size_stat("payload_bytes").add(10);
+
+        let dw = Dropwizard::new();
+        let t0 = Instant::now();
+        requests.incr(5);
+        let mut out = String::new();
+        dw.write_at(&mut out, &reporter.peek(), t0, 1_500_000_000).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "http.requests.status.200.count 5 1500000000");
+        assert_eq!(lines[1], "http.requests.status.200.mean_rate 0 1500000000");
+        assert_eq!(lines[2], "http.requests.status.200.m1_rate 0 1500000000");
+        assert_eq!(lines[5], "http.conns 3 1500000000");
+        assert_eq!(lines[6], "http.latency_ms.count 3 1500000000");
+        assert_eq!(lines[7], "http.latency_ms.min 10 1500000000");
+        assert_eq!(lines[9], "http.latency_ms.mean 20 1500000000");
+        assert_eq!(lines[14], "http.latency_ms.p98 30 1500000000");
+        assert_eq!(lines[17], "http.payload_bytes.count 1 1500000000");
+        assert_eq!(lines[18], "http.payload_bytes.mean 10 1500000000");
+        assert_eq!(lines.len(), 19);
+
+        // Rates are computed from the increase between reports.
+        requests.incr(20);
+        let mut out = String::new();
+        let t1 = t0 + Duration::from_secs(10);
+        dw.write_at(&mut out, &reporter.peek(), t1, 1_500_000_010).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "http.requests.status.200.count 25 1500000010");
+        assert_eq!(lines[1], "http.requests.status.200.mean_rate 2 1500000010");
+        assert_eq!(lines[2], "http.requests.status.200.m1_rate 2 1500000010");
+
+        // Moving averages decay.
+        let mut out = String::new();
+        let t2 = t1 + Duration::from_secs(60);
+        dw.write_at(&mut out, &reporter.peek(), t2, 1_500_000_070).unwrap();
+        let m1 = out.lines().nth(2).unwrap().split(' ').nth(1).unwrap();
+        let m1 = m1.parse::<f64>().unwrap();
+        assert!((m1 - 2.0 / ::std::f64::consts::E).abs() < 1e-9, "{}", m1);
+    }
+
+    #[test]
+    fn test_write_with_quantiles() {
+        let (metrics, reporter) = ::new();
+        metrics.stat("latency_ms").add_values(&[10, 20, 30]);
+
+        let dw = Dropwizard::with_quantiles("90,99.99".parse().unwrap());
+        let mut out = String::new();
+        dw.write_at(&mut out, &reporter.peek(), Instant::now(), 1_500_000_000).unwrap();
+        let lines = out.lines().skip(5).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec!["latency_ms.p90 30 1500000000", "latency_ms.p9999 30 1500000000"]
+        );
+    }
+
+    #[test]
+    fn test_meter_reset() {
+        let t0 = Instant::now();
+        let mut meter = Meter::new(10, t0);
+        meter.update(30, t0 + Duration::from_secs(10));
+        // The counter was reset, so it increased by 5 rather than decreasing.
+        meter.update(5, t0 + Duration::from_secs(20));
+        assert_eq!(meter.mean_rate(t0 + Duration::from_secs(20)), 25.0 / 20.0);
+    }
+}
@@ -0,0 +1,114 @@
+//! Metrics for short-lived entities, e.g. a connection or a batch job.
+//!
+//! An `Ephemeral` scope records the key of every metric created through it (or through
+//! scopes derived from it). When it is dropped, all of those metrics are removed from the
+//! registry, whether or not handles to them are still held: such handles silently stop
+//! recording, and the metrics are omitted from subsequent reports.
+//!
+//! A metric that was already registered through another scope is also removed if it is
+//! created through an ephemeral scope.
+
+use super::{Key, Scope};
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Records the keys of metrics created through an ephemeral scope.
+pub struct Tracker {
+    keys: Mutex<HashSet<Key>>,
+    /// Keys are also recorded by enclosing ephemeral scopes.
+    parent: Option<Arc<Tracker>>,
+}
+
+impl Tracker {
+    pub(super) fn track(&self, key: &Key) {
+        self.keys
+            .lock()
+            .expect("failed to obtain lock on ephemeral keys")
+            .insert(key.clone());
+        if let Some(ref p) = self.parent {
+            p.track(key);
+        }
+    }
+}
+
+/// A scope whose metrics are removed from the registry when it is dropped.
+///
+/// Dereferences to a `Scope`, which may be cloned, labeled, or prefixed as usual.
+pub struct Ephemeral {
+    scope: Scope,
+    tracker: Arc<Tracker>,
+}
+
+impl Ephemeral {
+    pub(super) fn new(scope: &Scope) -> Ephemeral {
+        let tracker = Arc::new(Tracker {
+            keys: Mutex::new(HashSet::new()),
+            parent: scope.ephemeral.clone(),
+        });
+        let mut scope = scope.clone();
+        scope.ephemeral = Some(tracker.clone());
+        Ephemeral { scope, tracker }
+    }
+}
+
+impl Deref for Ephemeral {
+    type Target = Scope;
+    fn deref(&self) -> &Scope {
+        &self.scope
+    }
+}
+
+impl Drop for Ephemeral {
+    fn drop(&mut self) {
+        let keys = match self.tracker.keys.lock() {
+            Ok(mut keys) => keys.drain().collect::<HashSet<_>>(),
+            Err(_) => return,
+        };
+        if keys.is_empty() {
+            return;
+        }
+        let mut reg = match self.scope.registry.write() {
+            Ok(reg) => reg,
+            Err(_) => return,
+        };
+        let hooks = reg.hooks.clone();
+        let keep = |k: &Key| {
+            let remove = keys.contains(k);
+            if remove {
+                hooks.pruned(k);
+            }
+            !remove
+        };
+        reg.counters.retain(&mut |k, _| keep(k));
+        reg.gauges.retain(&mut |k, _| keep(k));
+        reg.stats.retain(&mut |k, _| keep(k));
+        reg.sizes.retain(&mut |k, _| keep(k));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_ephemeral() {
+        let (metrics, reporter) = ::new();
+        let total = metrics.counter("conns_total");
+        let conn = metrics.ephemeral();
+        let leaked = conn.clone().labeled("peer", "a").counter("bytes");
+        leaked.incr(10);
+        conn.stat("latency_ms").add(3);
+        let nested = conn.ephemeral();
+        nested.gauge("pending").set(1);
+        total.incr(1);
+        assert_eq!(reporter.peek().len(), 4);
+
+        drop(nested);
+        assert_eq!(reporter.peek().len(), 3);
+
+        drop(conn);
+        leaked.incr(1);
+        let report = reporter.peek();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report.get_counter("conns_total", &[]), Some(1));
+    }
+}
@@ -0,0 +1,57 @@
+use super::TimeUnit;
+use std::error;
+use std::fmt;
+
+/// Errors returned when metrics cannot be created.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A Stat's histogram could not be created with the requested bounds or precision.
+    InvalidHistogram(&'static str),
+    /// A metric's key is already registered as a different kind of metric (and the
+    /// registry's `KindConflicts` policy does not allow it to be reused).
+    KindConflict {
+        name: &'static str,
+        existing: &'static str,
+        requested: &'static str,
+    },
+    /// A label's value was rejected by the registry's `LabelValues` policy.
+    InvalidLabelValue(&'static str),
+    /// A Stat already exists with bounds other than those requested.
+    BoundsConflict {
+        name: &'static str,
+        existing: Option<(u64, u64)>,
+        requested: (u64, u64),
+    },
+    /// A Stat already exists with a unit other than that requested.
+    UnitConflict {
+        name: &'static str,
+        existing: Option<TimeUnit>,
+        requested: TimeUnit,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidHistogram(e) => write!(f, "invalid histogram: {}", e),
+            Error::KindConflict {
+                name,
+                existing,
+                requested,
+            } => write!(f, "{} is a {}, not a {}", name, existing, requested),
+            Error::InvalidLabelValue(k) => write!(f, "invalid value for label {}", k),
+            Error::BoundsConflict {
+                name,
+                existing,
+                requested,
+            } => write!(f, "{} has bounds {:?}, not {:?}", name, existing, requested),
+            Error::UnitConflict {
+                name,
+                existing,
+                requested,
+            } => write!(f, "{} has unit {:?}, not {:?}", name, existing, requested),
+        }
+    }
+}
+
+impl error::Error for Error {}
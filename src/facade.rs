@@ -0,0 +1,136 @@
+//! An object-safe interface for creating metrics.
+//!
+//! Libraries may accept a `&dyn Metrics` (or a `Box<dyn Metrics>`) rather than a `Scope`,
+//! so that their callers may opt out of metrics entirely with `NoopMetrics`.
+
+use super::{Counter, Gauge, Key, LabelValue, Labels, Prefix, Scope, Stat, TimeUnit, Timer};
+use std::sync::{Arc, Weak};
+
+/// Creates metrics.
+pub trait Metrics: Send + Sync {
+    fn counter(&self, name: &'static str) -> Counter;
+
+    fn gauge(&self, name: &'static str) -> Gauge;
+
+    fn stat(&self, name: &'static str) -> Stat;
+
+    /// Creates a Timer that records durations in microseconds.
+    fn timer_us(&self, name: &'static str) -> Timer;
+
+    /// Creates a Timer that records durations in milliseconds.
+    fn timer_ms(&self, name: &'static str) -> Timer;
+
+    /// Returns metrics scoped under an additional prefix.
+    fn prefixed(&self, value: &'static str) -> Box<dyn Metrics>;
+
+    /// Returns metrics scoped with an additional label.
+    fn labeled(&self, k: &'static str, v: LabelValue) -> Box<dyn Metrics>;
+}
+
+impl Metrics for Scope {
+    fn counter(&self, name: &'static str) -> Counter {
+        Scope::counter(self, name)
+    }
+
+    fn gauge(&self, name: &'static str) -> Gauge {
+        Scope::gauge(self, name)
+    }
+
+    fn stat(&self, name: &'static str) -> Stat {
+        Scope::stat(self, name)
+    }
+
+    fn timer_us(&self, name: &'static str) -> Timer {
+        Scope::timer_us(self, name)
+    }
+
+    fn timer_ms(&self, name: &'static str) -> Timer {
+        Scope::timer_ms(self, name)
+    }
+
+    fn prefixed(&self, value: &'static str) -> Box<dyn Metrics> {
+        Box::new(self.clone().prefixed(value))
+    }
+
+    fn labeled(&self, k: &'static str, v: LabelValue) -> Box<dyn Metrics> {
+        Box::new(self.clone().labeled(k, v))
+    }
+}
+
+/// Creates metrics that discard all values.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoopMetrics;
+
+impl NoopMetrics {
+    /// Noop metrics are not scoped, so they are identified by name only.
+    fn key(name: &'static str) -> Arc<Key> {
+        Arc::new(Key::new(name, Arc::new(Prefix::Root), Arc::new(Labels::default())))
+    }
+
+    fn stat_with_unit(name: &'static str, unit: Option<TimeUnit>) -> Stat {
+        Stat {
+            cell: Weak::new(),
+            key: NoopMetrics::key(name),
+            bounds: None,
+            unit,
+            expected_interval: None,
+            capture: None,
+        }
+    }
+}
+
+impl Metrics for NoopMetrics {
+    fn counter(&self, name: &'static str) -> Counter {
+        Counter(Weak::new(), NoopMetrics::key(name))
+    }
+
+    fn gauge(&self, name: &'static str) -> Gauge {
+        Gauge(Weak::new(), NoopMetrics::key(name))
+    }
+
+    fn stat(&self, name: &'static str) -> Stat {
+        NoopMetrics::stat_with_unit(name, None)
+    }
+
+    fn timer_us(&self, name: &'static str) -> Timer {
+        Timer { stat: NoopMetrics::stat_with_unit(name, Some(TimeUnit::Micros)) }
+    }
+
+    fn timer_ms(&self, name: &'static str) -> Timer {
+        Timer { stat: NoopMetrics::stat_with_unit(name, Some(TimeUnit::Millis)) }
+    }
+
+    fn prefixed(&self, _: &'static str) -> Box<dyn Metrics> {
+        Box::new(NoopMetrics)
+    }
+
+    fn labeled(&self, _: &'static str, _: LabelValue) -> Box<dyn Metrics> {
+        Box::new(NoopMetrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn instrument(metrics: &dyn Metrics) {
+        let metrics = metrics.prefixed("lib").labeled("shard", 3.into());
+        metrics.counter("requests").incr(1);
+        metrics.gauge("pending").set(2);
+        metrics.stat("size").add(3);
+        metrics.timer_ms("latency_ms").record(Duration::from_millis(4));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let (scope, reporter) = ::new();
+        instrument(&scope);
+        let report = reporter.peek();
+        assert_eq!(report.len(), 4);
+        let k = report.counters().keys().next().expect("expected counter");
+        assert_eq!(k.labels().get("shard"), Some(&3.into()));
+
+        instrument(&NoopMetrics);
+    }
+}
@@ -0,0 +1,174 @@
+//! A common interface for rendering reports, so that exporters may be configured with any
+//! output format (including user-supplied ones).
+
+use super::Report;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Renders a `Report`.
+///
+/// Implemented by `prometheus::Config`, `prometheus::native::Native`,
+/// `prometheus::openmetrics::OpenMetrics`, `csv::Csv`, and `dropwizard::Dropwizard`.
+pub trait Formatter: Send + Sync {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()>;
+}
+
+impl<F: Formatter + ?Sized> Formatter for &F {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        (**self).write(out, report)
+    }
+}
+
+impl<F: Formatter + ?Sized> Formatter for Box<F> {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        (**self).write(out, report)
+    }
+}
+
+/// The percentiles of each stat written by the `csv`, `dropwizard`, and `cloudwatch_emf`
+/// exporters, each named by a suffix of its digits (e.g. `p50` or `p999` for 99.9).
+///
+/// Quantiles may be parsed from a comma-separated list, e.g. `50,90,99,99.9`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quantiles(Vec<(f64, String)>);
+
+impl Quantiles {
+    /// Panics if a percentile is not between 0 and 100, or if two have the same suffix.
+    pub fn new(percentiles: &[f64]) -> Quantiles {
+        match Quantiles::checked(percentiles) {
+            Ok(q) => q,
+            Err(reason) => panic!("invalid quantiles: {}", reason),
+        }
+    }
+
+    fn checked(percentiles: &[f64]) -> Result<Quantiles, &'static str> {
+        let mut qs: Vec<(f64, String)> = Vec::with_capacity(percentiles.len());
+        for &p in percentiles {
+            if !(0.0..=100.0).contains(&p) {
+                return Err("percentile out of range");
+            }
+            let suffix = format!("p{}", p).replace('.', "");
+            if qs.iter().any(|(_, s)| *s == suffix) {
+                return Err("duplicate percentile");
+            }
+            qs.push((p, suffix));
+        }
+        Ok(Quantiles(qs))
+    }
+
+    /// Returns the number of percentiles.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterates over each percentile, with its suffix.
+    pub fn iter(&self) -> impl Iterator<Item = (f64, &str)> {
+        self.0.iter().map(|(p, s)| (*p, s.as_str()))
+    }
+}
+
+impl FromStr for Quantiles {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Quantiles, &'static str> {
+        let ps = s.split(',')
+            .map(|p| p.trim().parse::<f64>().map_err(|_| "invalid percentile"))
+            .collect::<Result<Vec<_>, _>>()?;
+        Quantiles::checked(&ps)
+    }
+}
+
+/// Invokes `f` with a `fmt::Write` that writes to `out`, so that text formats may be
+/// streamed to an `io::Write` without first being rendered into a `String`.
+pub(super) fn with_fmt<F>(out: &mut dyn io::Write, f: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn fmt::Write) -> fmt::Result,
+{
+    let mut adapter = Adapter { out, error: None };
+    match f(&mut adapter) {
+        Ok(()) => Ok(()),
+        Err(fmt::Error) => Err(adapter.error.unwrap_or_else(|| {
+            io::Error::other("failed to format report")
+        })),
+    }
+}
+
+struct Adapter<'a> {
+    out: &'a mut dyn io::Write,
+    /// The underlying error, if writing to `out` failed.
+    error: Option<io::Error>,
+}
+
+impl fmt::Write for Adapter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.out.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "prometheus")]
+    use csv::Csv;
+    #[cfg(feature = "prometheus")]
+    use prometheus;
+
+    #[cfg(feature = "prometheus")]
+    struct Failing;
+    #[cfg(feature = "prometheus")]
+    impl io::Write for Failing {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let q = "50, 99.9,100".parse::<Quantiles>().unwrap();
+        let q = q.iter().collect::<Vec<_>>();
+        assert_eq!(q, vec![(50.0, "p50"), (99.9, "p999"), (100.0, "p100")]);
+        assert_eq!("".parse::<Quantiles>(), Err("invalid percentile"));
+        assert_eq!("50,x".parse::<Quantiles>(), Err("invalid percentile"));
+        assert_eq!("101".parse::<Quantiles>(), Err("percentile out of range"));
+        assert_eq!("NaN".parse::<Quantiles>(), Err("percentile out of range"));
+        assert_eq!("99.9,99.90".parse::<Quantiles>(), Err("duplicate percentile"));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_formatters() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("requests").incr(1);
+        metrics.stat("latency_ms").add(3);
+        let report = reporter.peek();
+
+        let formatters: Vec<Box<dyn Formatter>> = vec![
+            Box::new(prometheus::Config::default()),
+            Box::new(prometheus::native::Native),
+            Box::new(Csv::new()),
+        ];
+        let mut outs = Vec::new();
+        for f in &formatters {
+            let mut out = Vec::new();
+            report.to_writer(&mut out, f).unwrap();
+            outs.push(out);
+        }
+        assert_eq!(outs[0], prometheus::string(&report).unwrap().into_bytes());
+        assert_eq!(outs[1], prometheus::native::bytes(&report));
+        assert_eq!(outs[2], ::csv::string(&report).unwrap().into_bytes());
+
+        let err = report.to_writer(&mut Failing, Csv::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,173 @@
+//! Retains per-interval bucket counts for stats, e.g. to render latency heatmaps.
+//!
+//! When enabled via `Reporter::with_heatmap`, each call to `Reporter::take` closes an
+//! interval: the values recorded to each stat since the prior `take` are counted into
+//! fixed buckets and retained for the last N intervals.
+
+use super::{HistogramWithSum, Key};
+use ordermap::OrderMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bucket counts for a single interval.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interval {
+    /// When the interval ended, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The number of values recorded in each bucket.
+    ///
+    /// `counts[i]` holds values in `(bounds[i-1], bounds[i]]`; the final count holds
+    /// values greater than the highest bound.
+    pub counts: Vec<u64>,
+}
+
+/// A stat's recent history of bucket counts, oldest interval first.
+#[derive(Clone, Debug)]
+pub struct Heatmap {
+    bounds: Arc<Vec<u64>>,
+    intervals: VecDeque<Interval>,
+}
+
+impl Heatmap {
+    /// The (inclusive) upper bounds of each bucket.
+    pub fn bounds(&self) -> &[u64] {
+        &self.bounds
+    }
+
+    pub fn intervals(&self) -> &VecDeque<Interval> {
+        &self.intervals
+    }
+
+    /// Renders the heatmap as JSON, suitable for feeding to Grafana:
+    ///
+    /// ```text
+    /// {"bounds":[10,100],"intervals":[{"timestamp":1500000000,"counts":[3,1,0]}]}
+    /// ```
+    pub fn write_json<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        write!(out, "{{\"bounds\":")?;
+        write_json_array(out, &self.bounds)?;
+        write!(out, ",\"intervals\":[")?;
+        for (i, interval) in self.intervals.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{{\"timestamp\":{},\"counts\":", interval.timestamp)?;
+            write_json_array(out, &interval.counts)?;
+            write!(out, "}}")?;
+        }
+        write!(out, "]}}")
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out).expect("failed to format heatmap");
+        out
+    }
+}
+
+fn write_json_array<W: fmt::Write>(out: &mut W, vs: &[u64]) -> fmt::Result {
+    write!(out, "[")?;
+    for (i, v) in vs.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, "{}", v)?;
+    }
+    write!(out, "]")
+}
+
+/// Heatmaps for all stats, stored in the registry.
+pub struct Heatmaps {
+    max_intervals: usize,
+    bounds: Arc<Vec<u64>>,
+    heatmaps: OrderMap<Arc<Key>, Heatmap>,
+}
+
+impl Heatmaps {
+    pub fn new(max_intervals: usize, mut bounds: Vec<u64>) -> Heatmaps {
+        bounds.sort();
+        bounds.dedup();
+        Heatmaps {
+            max_intervals,
+            bounds: Arc::new(bounds),
+            heatmaps: OrderMap::new(),
+        }
+    }
+
+    /// Closes an interval, recording the values captured in `stats`.
+    ///
+    /// Heatmaps for stats not in `stats` are dropped.
+    pub fn record(&mut self, stats: &OrderMap<Arc<Key>, HistogramWithSum>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        self.heatmaps.retain(|k, _| stats.contains_key(k));
+        for (k, h) in stats {
+            let counts = bucket_counts(&self.bounds, h);
+            let bounds = &self.bounds;
+            let heatmap = self.heatmaps.entry(k.clone()).or_insert_with(|| {
+                Heatmap {
+                    bounds: bounds.clone(),
+                    intervals: VecDeque::new(),
+                }
+            });
+            heatmap.intervals.push_back(Interval { timestamp, counts });
+            while heatmap.intervals.len() > self.max_intervals {
+                heatmap.intervals.pop_front();
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> OrderMap<Arc<Key>, Heatmap> {
+        self.heatmaps.clone()
+    }
+}
+
+fn bucket_counts(bounds: &[u64], h: &HistogramWithSum) -> Vec<u64> {
+    let mut counts = Vec::with_capacity(bounds.len() + 1);
+    let mut below = 0;
+    for b in bounds {
+        let n = h.count_below(*b);
+        counts.push(n - below);
+        below = n;
+    }
+    counts.push(h.count() - below);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_heatmap() {
+        let (metrics, reporter) = ::new();
+        let mut reporter = reporter.with_heatmap(2, vec![100, 10]);
+        let mut latency = metrics.stat("latency");
+
+        latency.add_values(&[1, 5, 50, 500]);
+        reporter.take();
+        latency.add_values(&[5]);
+        reporter.take();
+        latency.add_values(&[50, 50]);
+        let report = reporter.take();
+
+        let k = report.stats().keys().next().expect("expected stat");
+        let heatmap = report.heatmap(k).expect("expected heatmap");
+        assert_eq!(heatmap.bounds(), &[10, 100]);
+        let counts = heatmap
+            .intervals()
+            .iter()
+            .map(|i| i.counts.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![vec![1, 0, 0], vec![0, 2, 0]]);
+
+        let ts = heatmap.intervals()[0].timestamp;
+        assert!(heatmap.to_json().starts_with(&format!(
+            "{{\"bounds\":[10,100],\"intervals\":[{{\"timestamp\":{},\"counts\":[1,0,0]}}",
+            ts
+        )));
+    }
+}
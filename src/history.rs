@@ -0,0 +1,275 @@
+//! Retains recent per-interval values in memory, e.g. for debugging without a TSDB.
+//!
+//! When enabled via `Reporter::with_history`, each call to `Reporter::take` (or
+//! `Reporter::take_kinds`) closes an interval: each counter's increase and each stat's
+//! summary over the interval are retained until they are older than the configured
+//! retention. A series' history is available via `Reporter::history`, and all histories may
+//! be dumped as JSON via `Reporter::write_history_json`.
+
+use super::{HistogramWithSum, Key, StatSummary};
+use super::csv::{push_json_string, push_prefix};
+use ordermap::OrderMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A value for a single interval.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Point<T> {
+    /// When the interval ended, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub value: T,
+}
+
+/// A series' recent values, oldest interval first.
+#[derive(Clone, Debug, PartialEq)]
+pub enum History {
+    /// A counter's increase over each interval.
+    Counter(VecDeque<Point<usize>>),
+    /// A stat's summary over each interval.
+    Stat(VecDeque<Point<StatSummary>>),
+}
+
+impl History {
+    pub fn len(&self) -> usize {
+        match *self {
+            History::Counter(ref ps) => ps.len(),
+            History::Stat(ref ps) => ps.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Renders the history as JSON:
+    ///
+    /// ```text
+    /// {"type":"counter","points":[{"timestamp":1500000000,"value":3}]}
+    /// {"type":"stat","points":[{"timestamp":1500000000,"count":2,"sum":7,"min":3,"max":4}]}
+    /// ```
+    pub fn write_json<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        match *self {
+            History::Counter(ref ps) => {
+                write!(out, "{{\"type\":\"counter\",\"points\":[")?;
+                for (i, p) in ps.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    write!(out, "{{\"timestamp\":{},\"value\":{}}}", p.timestamp, p.value)?;
+                }
+            }
+            History::Stat(ref ps) => {
+                write!(out, "{{\"type\":\"stat\",\"points\":[")?;
+                for (i, p) in ps.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ",")?;
+                    }
+                    let s = &p.value;
+                    write!(
+                        out,
+                        "{{\"timestamp\":{},\"count\":{},\"sum\":{},\"min\":{},\"max\":{}}}",
+                        p.timestamp,
+                        s.count,
+                        s.sum,
+                        s.min,
+                        s.max
+                    )?;
+                }
+            }
+        }
+        write!(out, "]}}")
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out).expect("failed to format history");
+        out
+    }
+}
+
+/// Histories for all counters and stats, stored in the registry.
+pub struct Histories {
+    retention: Duration,
+    histories: OrderMap<Arc<Key>, History>,
+    /// Each counter's value as of the last interval.
+    last: OrderMap<Arc<Key>, usize>,
+}
+
+impl Histories {
+    pub fn new(retention: Duration) -> Histories {
+        Histories {
+            retention,
+            histories: OrderMap::new(),
+            last: OrderMap::new(),
+        }
+    }
+
+    /// Closes an interval, recording the values captured in `counters` and `stats` (if
+    /// they were taken).
+    ///
+    /// Points older than the retention period are dropped, as are histories that no
+    /// longer have any points.
+    pub fn record(
+        &mut self,
+        counters: Option<&OrderMap<Arc<Key>, usize>>,
+        stats: Option<&OrderMap<Arc<Key>, HistogramWithSum>>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if let Some(counters) = counters {
+            self.last.retain(|k, _| counters.contains_key(k));
+            for (k, v) in counters {
+                let last = self.last.insert(k.clone(), *v).unwrap_or(0);
+                // A counter that decreased was reset, so its entire value is new.
+                let value = if *v >= last { *v - last } else { *v };
+                let history = self
+                    .histories
+                    .entry(k.clone())
+                    .or_insert_with(|| History::Counter(VecDeque::new()));
+                if let History::Counter(ref mut ps) = *history {
+                    ps.push_back(Point { timestamp, value });
+                }
+            }
+        }
+
+        if let Some(stats) = stats {
+            for (k, h) in stats {
+                let value = h.summary();
+                let history = self
+                    .histories
+                    .entry(k.clone())
+                    .or_insert_with(|| History::Stat(VecDeque::new()));
+                if let History::Stat(ref mut ps) = *history {
+                    ps.push_back(Point { timestamp, value });
+                }
+            }
+        }
+
+        let oldest = timestamp.saturating_sub(self.retention.as_secs());
+        self.histories.retain(|_, history| {
+            match *history {
+                History::Counter(ref mut ps) => expire(ps, oldest),
+                History::Stat(ref mut ps) => expire(ps, oldest),
+            }
+            !history.is_empty()
+        });
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&History> {
+        self.histories.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Arc<Key>, &History)> {
+        self.histories.iter()
+    }
+
+    /// Renders all histories as a JSON array, each with its series' prefix, name, and
+    /// labels (formatted as in the `csv` module):
+    ///
+    /// ```text
+    /// [{"prefix":"http","name":"requests","labels":{},"type":"counter","points":[..]}]
+    /// ```
+    pub fn write_json<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        write!(out, "[")?;
+        for (i, (k, history)) in self.histories.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            let mut head = String::from("{\"prefix\":");
+            let mut prefix = String::new();
+            push_prefix(&mut prefix, k.prefix());
+            push_json_string(&mut head, &prefix);
+            head.push_str(",\"name\":");
+            push_json_string(&mut head, k.name());
+            head.push_str(",\"labels\":{");
+            for (i, (lk, lv)) in k.labels().iter().enumerate() {
+                if i > 0 {
+                    head.push(',');
+                }
+                push_json_string(&mut head, lk);
+                head.push(':');
+                push_json_string(&mut head, &lv.to_string());
+            }
+            head.push_str("},");
+            out.write_str(&head)?;
+
+            // Splice the series' own fields into the same object.
+            let json = history.to_json();
+            out.write_str(&json[1..])?;
+        }
+        write!(out, "]")
+    }
+}
+
+fn expire<T>(ps: &mut VecDeque<Point<T>>, oldest: u64) {
+    while ps.front().map(|p| p.timestamp < oldest).unwrap_or(false) {
+        ps.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let (metrics, reporter) = ::new();
+        let mut reporter = reporter.with_history(Duration::from_secs(60));
+        let metrics = metrics.prefixed("http");
+        let requests = metrics.counter("requests");
+        let mut latency = metrics.stat("latency_ms");
+
+        requests.incr(3);
+        latency.add_values(&[3, 4]);
+        reporter.take();
+        requests.incr(2);
+        reporter.take();
+        requests.reset();
+        requests.incr(1);
+        reporter.take_kinds(::KindMask::COUNTERS);
+
+        let counts = match reporter.history("requests", &[]) {
+            Some(History::Counter(ps)) => ps.iter().map(|p| p.value).collect::<Vec<_>>(),
+            h => panic!("expected counter history: {:?}", h),
+        };
+        assert_eq!(counts, vec![3, 2, 1]);
+
+        let summaries = match reporter.history("latency_ms", &[]) {
+            Some(History::Stat(ps)) => ps.iter().map(|p| p.value).collect::<Vec<_>>(),
+            h => panic!("expected stat history: {:?}", h),
+        };
+        assert_eq!(summaries.len(), 2);
+        assert_eq!((summaries[0].count, summaries[0].sum), (2, 7));
+        assert_eq!(summaries[1].count, 0);
+
+        let mut json = String::new();
+        reporter.write_history_json(&mut json).unwrap();
+        let ts = match reporter.history("requests", &[]) {
+            Some(History::Counter(ps)) => ps[0].timestamp,
+            _ => unreachable!(),
+        };
+        assert!(json.starts_with(&format!(
+            "[{{\"prefix\":\"http\",\"name\":\"requests\",\"labels\":{{}},\
+             \"type\":\"counter\",\"points\":[{{\"timestamp\":{},\"value\":3}}",
+            ts
+        )));
+        assert!(json.contains("\"type\":\"stat\",\"points\":[{\"timestamp\":"));
+        assert!(json.contains("\"count\":2,\"sum\":7,\"min\":3,\"max\":4}"));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut ps = (0..5)
+            .map(|t| Point { timestamp: t, value: 0 })
+            .collect::<VecDeque<_>>();
+        expire(&mut ps, 3);
+        assert_eq!(ps.iter().map(|p| p.timestamp).collect::<Vec<_>>(), vec![3, 4]);
+        expire(&mut ps, 10);
+        assert!(ps.is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+//! Checks a report for series that exporters would mangle or that are expensive to keep.
+//!
+//! Unlike `prometheus::validate`, which checks rendered text, `validate` checks a `Report`
+//! directly, so it applies to every exporter. It's meant to be run occasionally (e.g. from
+//! a deployment's admin server or CI), since it formats every series' name:
+//!
+//! ```
+//! let (metrics, reporter) = tacho::new();
+//! metrics.counter("requests").incr(1);
+//! metrics.gauge("open.conns").set(2);
+//!
+//! let violations = tacho::validate(&reporter.peek());
+//! assert_eq!(violations.len(), 1);
+//! assert_eq!(violations[0].to_string(), "invalid name: open.conns");
+//! ```
+
+use super::{Key, Prefix, Report, Series};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// The number of series a metric family may have before `validate` reports it.
+pub const DEFAULT_SERIES_BUDGET: usize = 1_000;
+
+/// The largest integer that every exporter (i.e. those that write values as doubles) can
+/// represent exactly.
+const MAX_EXACT: u64 = 1 << 53;
+
+/// A problem found by `validate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// Distinct series that are exported with the same name and labels, either because
+    /// exporters replace invalid characters or because a key is registered as two kinds.
+    DuplicateSeries {
+        /// The series' name and labels, as exported.
+        series: String,
+        keys: Vec<Arc<Key>>,
+    },
+    /// A series' name (including its prefix) or one of its label names contains characters
+    /// that exporters replace.
+    InvalidName(Arc<Key>),
+    /// A series' value cannot be exported accurately.
+    ///
+    /// Values are integers, so they cannot be NaN; but values above 2^53 lose precision as
+    /// doubles, stats may have dropped or clamped values, and a stat's sum may saturate.
+    Overflow {
+        key: Arc<Key>,
+        reason: &'static str,
+    },
+    /// A metric family (i.e. the series sharing a prefix and name) has more series than its
+    /// budget.
+    OverBudget {
+        prefix: Arc<Prefix>,
+        name: &'static str,
+        series: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::DuplicateSeries { ref series, ref keys } => {
+                write!(f, "{} keys are exported as {}", keys.len(), series)
+            }
+            Violation::InvalidName(ref k) => write!(f, "invalid name: {}", k),
+            Violation::Overflow { ref key, reason } => write!(f, "{}: {}", key, reason),
+            Violation::OverBudget {
+                ref prefix,
+                name,
+                series,
+            } => {
+                for v in prefix.values() {
+                    write!(f, "{}:", v)?;
+                }
+                write!(f, "{} has {} series", name, series)
+            }
+        }
+    }
+}
+
+/// Checks `report`, allowing each metric family `DEFAULT_SERIES_BUDGET` series.
+pub fn validate(report: &Report) -> Vec<Violation> {
+    validate_with_budget(report, DEFAULT_SERIES_BUDGET)
+}
+
+/// Checks `report`, allowing each metric family `max_series` series.
+///
+/// Violations are returned grouped by kind: duplicates, names, values, and then budgets.
+pub fn validate_with_budget(report: &Report, max_series: usize) -> Vec<Violation> {
+    let mut invalid = Vec::new();
+    let mut overflows = Vec::new();
+    let mut exported = BTreeMap::<String, Vec<Arc<Key>>>::new();
+    let mut families = HashMap::<(Arc<Prefix>, &'static str), HashSet<Arc<Key>>>::new();
+
+    for s in report.iter_series() {
+        let k = s.key();
+        let (series, valid) = exported_series(k);
+        if !valid {
+            invalid.push(Violation::InvalidName(k.clone()));
+        }
+        exported.entry(series).or_default().push(k.clone());
+        families
+            .entry((k.prefix().clone(), k.name()))
+            .or_default()
+            .insert(k.clone());
+        if let Some(reason) = overflow(&s) {
+            overflows.push(Violation::Overflow {
+                key: k.clone(),
+                reason,
+            });
+        }
+    }
+
+    let mut over = families
+        .into_iter()
+        .map(|((prefix, name), keys)| (prefix, name, keys.len()))
+        .filter(|&(_, _, n)| n > max_series)
+        .collect::<Vec<_>>();
+    over.sort_by_key(|&(_, _, n)| cmp::Reverse(n));
+    let over = over.into_iter().map(|(prefix, name, series)| {
+        Violation::OverBudget {
+            prefix,
+            name,
+            series,
+        }
+    });
+
+    exported
+        .into_iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .map(|(series, keys)| Violation::DuplicateSeries { series, keys })
+        .chain(invalid)
+        .chain(overflows)
+        .chain(over)
+        .collect()
+}
+
+/// Formats a key as exporters would, returning false if any characters were replaced.
+fn exported_series(k: &Key) -> (String, bool) {
+    let mut out = String::new();
+    let mut valid = true;
+    let mut leading = true;
+    for v in k.prefix().values().into_iter().chain(Some(k.name())) {
+        if !leading {
+            out.push(':');
+        }
+        valid &= push_name(&mut out, v, leading, true);
+        leading = false;
+    }
+    out.push('{');
+    for (i, (lk, lv)) in k.labels().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        valid &= push_name(&mut out, lk, true, false);
+        out.push_str(&format!("={:?}", lv.to_string()));
+    }
+    out.push('}');
+    (out, valid)
+}
+
+/// Appends a name, replacing invalid characters with `_`; returns false if any were.
+fn push_name(out: &mut String, s: &str, leading: bool, colons: bool) -> bool {
+    let mut valid = !s.is_empty();
+    for (i, c) in s.chars().enumerate() {
+        let ok = c.is_ascii_alphabetic() || c == '_' || (colons && c == ':') ||
+            (c.is_ascii_digit() && !(leading && i == 0));
+        valid &= ok;
+        out.push(if ok { c } else { '_' });
+    }
+    if s.is_empty() {
+        out.push('_');
+    }
+    valid
+}
+
+fn overflow(s: &Series) -> Option<&'static str> {
+    match *s {
+        Series::Counter(_, v) | Series::Gauge(_, v) if v as u64 > MAX_EXACT => {
+            Some("value exceeds 2^53")
+        }
+        Series::Stat(_, h) if h.sum() == u64::MAX => Some("sum saturated"),
+        Series::Stat(_, h) if h.max() > MAX_EXACT => Some("value exceeds 2^53"),
+        Series::Stat(_, h) if h.overflowed() > 0 => Some("values exceeded the stat's bounds"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let (metrics, reporter) = ::new();
+        let _ok = metrics.counter("requests");
+        let _kinds = (metrics.counter("conns"), metrics.gauge("conns"));
+        let _mangled = (metrics.counter("a_b"), metrics.counter("a.b"));
+        metrics.clone().labeled("bad-label", "x").gauge("c").set(1);
+        metrics.gauge("big").set(1 << 60);
+        let bounded = metrics.stat_with_bounds("bounded", 1, 1_000);
+        bounded.add(1_000_000);
+        let users = (0..3)
+            .map(|i| metrics.clone().labeled("user", i).counter("logins"))
+            .collect::<Vec<_>>();
+
+        let report = reporter.peek();
+        assert!(!validate(&report).iter().any(|v| matches!(*v, Violation::OverBudget { .. })));
+
+        let violations = validate_with_budget(&report, 2);
+        let s = violations.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            s,
+            vec![
+                "2 keys are exported as a_b{}",
+                "2 keys are exported as conns{}",
+                "invalid name: a.b",
+                "invalid name: c{bad-label=\"x\"}",
+                "big: value exceeds 2^53",
+                "bounded: values exceeded the stat's bounds",
+                "logins has 3 series",
+            ]
+        );
+        drop(users);
+    }
+}
@@ -0,0 +1,89 @@
+//! Series that describe the process itself.
+
+use super::{Counter, Gauge, LabelValue, Scope};
+use std::time::Instant;
+
+/// Registers the gauge `name`, with a constant value of 1, labeled with the process's
+/// `version` and `git_sha`.
+///
+/// Following the `*_build_info` idiom, this exposes build metadata that may be joined onto
+/// other series:
+///
+/// ```text
+/// myapp_build_info{git_sha="0a1b2c3", version="1.2.3"} 1
+/// ```
+pub fn build_info<V, G>(scope: &Scope, name: &'static str, version: V, git_sha: G)
+where
+    V: Into<LabelValue>,
+    G: Into<LabelValue>,
+{
+    scope
+        .clone()
+        .labeled("version", version)
+        .labeled("git_sha", git_sha)
+        .constant(name, 1);
+}
+
+/// Registers the gauge `uptime_seconds`, holding the number of seconds since `heartbeat`
+/// was called, and the counter `heartbeats_total`, incremented for each report.
+///
+/// Both series are updated as reports are obtained, and are never pruned, so they show that
+/// the process is alive and being scraped even when it is otherwise idle.
+pub fn heartbeat(scope: &Scope) {
+    let hb = Heartbeat {
+        start: Instant::now(),
+        uptime: scope.gauge("uptime_seconds"),
+        beats: scope.counter("heartbeats_total"),
+    };
+    let mut reg = scope.registry.write().expect(
+        "failed to obtain lock on registry",
+    );
+    if !reg.heartbeats.iter().any(|h| h.uptime.0.ptr_eq(&hb.uptime.0)) {
+        reg.heartbeats.push(hb);
+    }
+}
+
+/// Updates heartbeat series, stored in the registry.
+pub struct Heartbeat {
+    start: Instant,
+    uptime: Gauge,
+    beats: Counter,
+}
+
+impl Heartbeat {
+    pub(super) fn beat(&self) {
+        self.uptime.set(self.start.elapsed().as_secs() as usize);
+        self.beats.incr(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_build_info() {
+        let (metrics, mut reporter) = ::new();
+        build_info(&metrics, "myapp_build_info", "1.2.3", "0a1b2c3");
+        build_info(&metrics, "myapp_build_info", "1.2.3", "0a1b2c3");
+
+        // Constant series are never pruned.
+        reporter.take();
+        let out = ::prometheus::string(&reporter.take()).unwrap();
+        assert_eq!(out, "myapp_build_info{git_sha=\"0a1b2c3\", version=\"1.2.3\"} 1\n");
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let (metrics, mut reporter) = ::new();
+        heartbeat(&metrics);
+        heartbeat(&metrics);
+
+        reporter.peek();
+        reporter.take();
+        let report = reporter.take();
+        assert_eq!(report.get_counter("heartbeats_total", &[]), Some(3));
+        assert_eq!(report.get_gauge("uptime_seconds", &[]), Some(0));
+    }
+}
@@ -0,0 +1,308 @@
+use smallvec::SmallVec;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::slice;
+use std::str;
+
+/// Most metrics carry only a handful of labels, which are stored inline.
+type LabelVec = SmallVec<[(&'static str, LabelValue); 4]>;
+
+/// A label's value.
+///
+/// Static strings and integers are stored as-is and are only formatted when exported.
+/// Values are compared (and hashed) by their formatted value, so that, e.g., `Int(200)`
+/// and `Static("200")` label the same series.
+#[derive(Clone, Debug)]
+pub enum LabelValue {
+    Static(&'static str),
+    Int(i64),
+    Owned(String),
+}
+
+impl LabelValue {
+    /// Invokes `f` with the formatted value, without allocating.
+    pub(super) fn with_str<T, F: FnOnce(&str) -> T>(&self, f: F) -> T {
+        match *self {
+            LabelValue::Static(s) => f(s),
+            LabelValue::Owned(ref s) => f(s),
+            LabelValue::Int(n) => {
+                // Large enough for "-9223372036854775808".
+                let mut buf = [0u8; 20];
+                let mut i = buf.len();
+                let mut m = n.unsigned_abs();
+                loop {
+                    i -= 1;
+                    buf[i] = b'0' + (m % 10) as u8;
+                    m /= 10;
+                    if m == 0 {
+                        break;
+                    }
+                }
+                if n < 0 {
+                    i -= 1;
+                    buf[i] = b'-';
+                }
+                f(str::from_utf8(&buf[i..]).expect("digits are utf8"))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LabelValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LabelValue::Static(s) => f.write_str(s),
+            LabelValue::Owned(ref s) => f.write_str(s),
+            LabelValue::Int(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl PartialEq for LabelValue {
+    fn eq(&self, other: &LabelValue) -> bool {
+        match (self, other) {
+            (&LabelValue::Int(a), &LabelValue::Int(b)) => a == b,
+            _ => self.with_str(|a| other.with_str(|b| a == b)),
+        }
+    }
+}
+
+impl Eq for LabelValue {}
+
+impl PartialOrd for LabelValue {
+    fn partial_cmp(&self, other: &LabelValue) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LabelValue {
+    fn cmp(&self, other: &LabelValue) -> Ordering {
+        self.with_str(|a| other.with_str(|b| a.cmp(b)))
+    }
+}
+
+impl Hash for LabelValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.with_str(|s| s.hash(state))
+    }
+}
+
+impl From<&'static str> for LabelValue {
+    fn from(s: &'static str) -> LabelValue {
+        LabelValue::Static(s)
+    }
+}
+
+impl From<String> for LabelValue {
+    fn from(s: String) -> LabelValue {
+        LabelValue::Owned(s)
+    }
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for LabelValue {
+                fn from(n: $t) -> LabelValue {
+                    LabelValue::Int(i64::from(n))
+                }
+            }
+        )*
+    };
+}
+
+from_int!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! from_wide_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for LabelValue {
+                fn from(n: $t) -> LabelValue {
+                    match i64::try_from(n) {
+                        Ok(n) => LabelValue::Int(n),
+                        Err(_) => LabelValue::Owned(n.to_string()),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_wide_int!(isize, u64, usize);
+
+/// Determines how label values are checked as they are added to a scope.
+///
+/// Any string is a valid label value, but control characters (e.g. newlines and NULs) and
+/// very long values break some exporters and scrapers. Checking values as they are added
+/// keeps bad input (e.g. from request headers) from reaching a report.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum LabelValues {
+    /// Values are used as given.
+    #[default]
+    Allow,
+    /// Control characters are replaced with U+FFFD, and values are truncated (at a
+    /// character boundary) to at most `max_len` bytes.
+    Sanitize { max_len: usize },
+    /// Values with control characters, or of more than `max_len` bytes, are rejected:
+    /// `Scope::try_labeled` fails, and `Scope::labeled` does not add the label.
+    Reject { max_len: usize },
+}
+
+impl LabelValues {
+    /// Returns `v`, sanitized if necessary, or `None` if it is rejected.
+    pub(super) fn check(self, v: LabelValue) -> Option<LabelValue> {
+        let max_len = match self {
+            LabelValues::Allow => return Some(v),
+            LabelValues::Sanitize { max_len } | LabelValues::Reject { max_len } => max_len,
+        };
+        if v.with_str(|s| s.len() <= max_len && !s.chars().any(char::is_control)) {
+            return Some(v);
+        }
+        if let LabelValues::Reject { .. } = self {
+            return None;
+        }
+        let sanitized = v.with_str(|s| {
+            let mut out = String::with_capacity(s.len().min(max_len));
+            for c in s.chars() {
+                let c = if c.is_control() { '\u{fffd}' } else { c };
+                if out.len() + c.len_utf8() > max_len {
+                    break;
+                }
+                out.push(c);
+            }
+            out
+        });
+        Some(LabelValue::Owned(sanitized))
+    }
+}
+
+/// An ordered set of labels.
+///
+/// Labels are kept sorted by key in a small vector (rather than in a map) so that they are
+/// cheap to clone and may still be used in hash keys.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Labels(LabelVec);
+
+impl Labels {
+    /// Returns the value of the label named `k`, if it is set.
+    pub fn get(&self, k: &str) -> Option<&LabelValue> {
+        self.position(k).ok().map(|i| &self.0[i].1)
+    }
+
+    /// Sets a label, returning its prior value, if any.
+    pub fn insert(&mut self, k: &'static str, v: LabelValue) -> Option<LabelValue> {
+        match self.position(k) {
+            Ok(i) => Some(mem::replace(&mut self.0[i].1, v)),
+            Err(i) => {
+                self.0.insert(i, (k, v));
+                None
+            }
+        }
+    }
+
+    /// Removes a label, returning its value, if any.
+    pub fn remove(&mut self, k: &str) -> Option<LabelValue> {
+        self.position(k).ok().map(|i| self.0.remove(i).1)
+    }
+
+    /// Retains only the labels for which `f` returns true.
+    pub fn retain<F: FnMut(&'static str, &LabelValue) -> bool>(&mut self, mut f: F) {
+        self.0.retain(|&mut (k, ref v)| f(k, v));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterates over labels in key order.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter(self.0.iter())
+    }
+
+    fn position(&self, k: &str) -> Result<usize, usize> {
+        self.0.binary_search_by(|&(key, _)| key.cmp(k))
+    }
+}
+
+impl<'a> IntoIterator for &'a Labels {
+    type Item = (&'static str, &'a LabelValue);
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterates over a `Labels` in key order.
+pub struct Iter<'a>(slice::Iter<'a, (&'static str, LabelValue)>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'static str, &'a LabelValue);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|&(k, ref v)| (k, v))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_labels_sorted() {
+        let mut labels = Labels::default();
+        assert_eq!(labels.insert("b", "1".into()), None);
+        assert_eq!(labels.insert("c", "2".into()), None);
+        assert_eq!(labels.insert("a", "3".into()), None);
+        assert_eq!(labels.insert("b", "4".into()), Some("1".into()));
+
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels.get("b"), Some(&"4".into()));
+        assert_eq!(labels.get("d"), None);
+        assert_eq!(labels.remove("d"), None);
+        let keys = labels.iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_label_value() {
+        let cases: Vec<(LabelValue, &str)> = vec![
+            (0.into(), "0"),
+            (200.into(), "200"),
+            ((-42).into(), "-42"),
+            (i64::MIN.into(), "-9223372036854775808"),
+            (u64::MAX.into(), "18446744073709551615"),
+            ("static".into(), "static"),
+            (String::from("owned").into(), "owned"),
+        ];
+        for (v, s) in cases {
+            assert_eq!(v.to_string(), s);
+            v.with_str(|f| assert_eq!(f, s));
+            assert_eq!(v, LabelValue::Owned(s.into()));
+        }
+
+        // Values are ordered by their formatted value.
+        let mut vs = vec![LabelValue::from(9), LabelValue::from("10"), LabelValue::from(100)];
+        vs.sort();
+        assert_eq!(vs, vec![LabelValue::from(10), LabelValue::from(100), "9".into()]);
+
+        let mut a = Labels::default();
+        a.insert("status", 200.into());
+        let mut b = Labels::default();
+        b.insert("status", "200".into());
+        assert_eq!(a, b);
+        let root = Arc::new(::Prefix::Root);
+        let a = ::Key::new("n", root.clone(), Arc::new(a));
+        let b = ::Key::new("n", root, Arc::new(b));
+        assert_eq!(a.hash, b.hash);
+    }
+}
@@ -9,49 +9,209 @@
 //!
 //! ## Performance
 //!
-//! Labels are stored in a `BTreeMap` because they are used as hash keys and, therefore,
-//! need to implement `Hash`.
+//! Labels are stored in a small, sorted vector because they are used as hash keys and,
+//! therefore, need to implement `Hash`. Since most metrics have only a few labels, this
+//! avoids allocating when labels are modified. Label sets are immutable once shared (they
+//! are copied on write), so a `Scope`'s labels are shared by its clones and by the `Key`s
+//! of the metrics it creates.
+//!
+//! Label values that are static strings or integers are stored as-is (see `LabelValue`)
+//! and are only formatted when a report is exported.
+//!
+//! ## Features
+//!
+//! The registry and its handles have no dependency on `futures`. The following features
+//! are enabled by default:
+//!
+//! - `async`: `Timer::time`, `Reporter::watch`, `Reporter::final_flush`,
+//!   `cache::Metrics::observe_load`, and the `context` module.
+//! - `prometheus`: the `prometheus` module.
+//!
+//! The `net` feature (which implies `async`) adds the `net` module, which instruments
+//! `tokio-io` transports. The `sketch` feature adds `StatBackend::Sketch`, which stores
+//! stats in compact, mergeable quantile sketches.
 
 
 #![cfg_attr(test, feature(test))]
 
+#[cfg(feature = "async")]
 extern crate futures;
 extern crate hdrsample;
 #[macro_use]
 extern crate log;
 extern crate ordermap;
+extern crate smallvec;
 #[cfg(test)]
-extern crate test;
+extern crate test as libtest;
+#[cfg(feature = "net")]
+extern crate tokio_io;
 
-use futures::{Future, Poll};
+#[cfg(feature = "async")]
+use futures::{Async, Future, Poll};
 use hdrsample::Histogram;
 use ordermap::OrderMap;
 use std::boxed::Box;
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
-use std::sync::{Arc, Mutex, Weak};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
-
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod atomic_stat;
+pub mod buckets;
+pub mod cache;
+pub mod cloudwatch_emf;
+pub mod config;
+#[cfg(feature = "async")]
+pub mod context;
+pub mod csv;
+pub mod def;
+pub mod dropwizard;
+mod ephemeral;
+mod error;
+mod facade;
+mod format;
+pub mod heatmap;
+pub mod history;
+mod hygiene;
+mod info;
+mod labels;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "prometheus")]
 pub mod prometheus;
+pub mod pool;
+mod prefix;
+mod ratio;
 mod report;
+mod sampled;
+mod schedule;
+mod size;
+#[cfg(feature = "sketch")]
+pub mod sketch;
+pub mod slo;
+mod status;
+mod stopwatch;
+mod storage;
+mod striped;
+pub mod test;
+mod timer_family;
 mod timing;
-
-pub use report::{Reporter, Report};
+mod transform;
+pub mod watch;
+
+pub use cache::ScopeCache;
+pub use def::MetricDef;
+pub use ephemeral::Ephemeral;
+pub use error::Error;
+pub use facade::{Metrics, NoopMetrics};
+pub use format::{Formatter, Quantiles};
+pub use hygiene::{validate, validate_with_budget, Violation, DEFAULT_SERIES_BUDGET};
+pub use info::{build_info, heartbeat};
+pub use labels::{LabelValue, LabelValues, Labels};
+pub use prefix::{format_addr, PrefixBuilder, PrefixValue};
+pub use ratio::Ratio;
+pub use sampled::SampledGauge;
+pub use schedule::AlignedSchedule;
+pub use report::{Cardinality, KindMask, LightReport, MemoryReport, MergeConflict, MergeConflicts,
+                 Reporter, Report, ReportView, Series, StatRef};
+#[cfg(feature = "async")]
+pub use report::FinalFlush;
+pub use size::{SizeSnapshot, SizeStat};
+pub use status::{StatusCounter, STATUS_CLASS};
+pub use stopwatch::Stopwatch;
+pub use storage::Storage;
+pub use striped::StripedCounter;
+pub use timer_family::TimerFamily;
+#[cfg(feature = "async")]
+pub use timer_family::TimedWith;
 pub use timing::Timing;
+pub use transform::GaugeTransform;
+
+pub type CounterStorage = Box<dyn Storage<Arc<AtomicUsize>>>;
+pub type GaugeStorage = Box<dyn Storage<Arc<AtomicUsize>>>;
+pub type StatStorage = Box<dyn Storage<Arc<StatCell>>>;
+pub type SizeStorage = Box<dyn Storage<Arc<size::SizeBuckets>>>;
 
-type Labels = BTreeMap<&'static str, String>;
-type CounterMap = OrderMap<Key, Arc<AtomicUsize>>;
-type GaugeMap = OrderMap<Key, Arc<AtomicUsize>>;
-type StatMap = OrderMap<Key, Arc<Mutex<HistogramWithSum>>>;
+/// Storage is compacted by `Reporter::take` once it can hold this many metrics and is at
+/// most a quarter full.
+const COMPACT_MIN_CAPACITY: usize = 1024;
 
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Prefix {
     Root,
-    Node {
-        prefix: Arc<Prefix>,
-        value: &'static str,
-    },
+    Node(PrefixNode),
+}
+
+/// A segment of a `Prefix`, appended to its parent. See `Prefix::node`.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PrefixNode {
+    prefix: Arc<Prefix>,
+    value: PrefixValue,
+    /// The hash of this prefix chain, computed once at construction.
+    hash: u64,
+}
+
+impl PrefixNode {
+    /// The prefix this segment is appended to.
+    pub fn prefix(&self) -> &Arc<Prefix> {
+        &self.prefix
+    }
+
+    /// The segment's value.
+    pub fn value(&self) -> &PrefixValue {
+        &self.value
+    }
+}
+
+impl Prefix {
+    /// Appends the segment `value` to `prefix`.
+    pub fn node(prefix: Arc<Prefix>, value: PrefixValue) -> Prefix {
+        let mut h = DefaultHasher::new();
+        prefix.hash(&mut h);
+        value.hash(&mut h);
+        let hash = h.finish();
+        Prefix::Node(PrefixNode {
+            prefix,
+            value,
+            hash,
+        })
+    }
+
+    /// Returns the prefix's values, from the root.
+    fn values(&self) -> Vec<&str> {
+        self.segments().into_iter().map(PrefixValue::as_str).collect()
+    }
+
+    /// Returns the prefix's segments, from the root.
+    fn segments(&self) -> Vec<&PrefixValue> {
+        let mut values = Vec::new();
+        let mut p = self;
+        while let Prefix::Node(ref node) = *p {
+            values.push(&node.value);
+            p = &node.prefix;
+        }
+        values.reverse();
+        values
+    }
+
+    /// Returns true if this prefix is `path`, or a descendant of it.
+    fn starts_with(&self, path: &[&str]) -> bool {
+        self.values().starts_with(path)
+    }
+}
+
+impl Hash for Prefix {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Prefix::Root => state.write_u64(0),
+            Prefix::Node(ref node) => state.write_u64(node.hash),
+        }
+    }
 }
 
 
@@ -62,33 +222,394 @@ pub enum Prefix {
 ///
 /// The returned `Reporter` supports consumption of metrics values.
 pub fn new() -> (Scope, Reporter) {
-    let registry = Arc::new(Mutex::new(Registry::default()));
+    Builder::new().build()
+}
 
-    let scope = Scope {
-        labels: Labels::default(),
-        prefix: Arc::new(Prefix::Root),
-        registry: registry.clone(),
-    };
+/// Creates a metrics registry backed by the provided `Storage` for each kind of metric.
+pub fn with_storage(
+    counters: CounterStorage,
+    gauges: GaugeStorage,
+    stats: StatStorage,
+    sizes: SizeStorage,
+) -> (Scope, Reporter) {
+    Builder::new().storage(counters, gauges, stats, sizes).build()
+}
+
+/// Configures a metrics registry.
+#[derive(Default)]
+pub struct Builder {
+    storage: Option<(CounterStorage, GaugeStorage, StatStorage, SizeStorage)>,
+    hooks: Hooks,
+    conflicts: KindConflicts,
+    label_values: LabelValues,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Backs the registry with the provided `Storage` for each kind of metric.
+    pub fn storage(
+        mut self,
+        counters: CounterStorage,
+        gauges: GaugeStorage,
+        stats: StatStorage,
+        sizes: SizeStorage,
+    ) -> Self {
+        self.storage = Some((counters, gauges, stats, sizes));
+        self
+    }
+
+    /// Invokes `f` with the key of each series added to the registry.
+    ///
+    /// Hooks are invoked while the registry is locked, so they must not create metrics.
+    pub fn on_register<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Key) + Send + Sync + 'static,
+    {
+        self.hooks.on_register = Some(Arc::new(f));
+        self
+    }
+
+    /// Invokes `f` with the key of each unused series removed from the registry by
+    /// `Reporter::take`.
+    ///
+    /// Hooks are invoked while the registry is locked, so they must not create metrics.
+    pub fn on_prune<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Key) + Send + Sync + 'static,
+    {
+        self.hooks.on_prune = Some(Arc::new(f));
+        self
+    }
+
+    /// Determines how a metric is created when its key is already registered as another
+    /// kind of metric (e.g. a counter and a gauge with the same name and labels).
+    pub fn kind_conflicts(mut self, policy: KindConflicts) -> Self {
+        self.conflicts = policy;
+        self
+    }
+
+    /// Determines how label values with control characters, or that are too long, are
+    /// handled as they are added to scopes (see `LabelValues`).
+    pub fn label_values(mut self, policy: LabelValues) -> Self {
+        self.label_values = policy;
+        self
+    }
+
+    pub fn build(self) -> (Scope, Reporter) {
+        let registry = Arc::new(RwLock::new(self.build_registry()));
 
-    (scope, report::new(registry))
+        let scope = Scope::with_registry(registry.clone());
+
+        (scope, Reporter::new(registry))
+    }
+
+    /// Builds a `Registry`, e.g. to be shared by scopes created with
+    /// `Scope::with_registry`.
+    pub fn build_registry(self) -> Registry {
+        let (counters, gauges, stats, sizes) = self.storage.unwrap_or_else(|| {
+            (
+                Box::new(OrderMap::new()),
+                Box::new(OrderMap::new()),
+                Box::new(OrderMap::new()),
+                Box::new(OrderMap::new()),
+            )
+        });
+        Registry {
+            counters,
+            gauges,
+            stats,
+            sizes,
+            heatmaps: None,
+            history: None,
+            hooks: self.hooks,
+            conflicts: self.conflicts,
+            label_values: self.label_values,
+            constants: Vec::new(),
+            resetting: Vec::new(),
+            ratios: Vec::new(),
+            sampled: Vec::new(),
+            striped: Vec::new(),
+            stopwatches: Vec::new(),
+            heartbeats: Vec::new(),
+            capture: None,
+        }
+    }
+}
+
+impl Registry {
+    /// Creates an empty registry with the default storage.
+    pub fn new() -> Registry {
+        Builder::new().build_registry()
+    }
+
+    /// Fails if `key` is registered as a kind of metric other than `requested` and the
+    /// registry does not allow kinds to conflict.
+    fn check_kind(&self, key: &Key, requested: &'static str) -> Result<(), Error> {
+        if self.conflicts == KindConflicts::Allow {
+            return Ok(());
+        }
+        let existing = [
+            (COUNTER, self.counters.get(key).is_some()),
+            (GAUGE, self.gauges.get(key).is_some()),
+            (STAT, self.stats.get(key).is_some()),
+            (SIZE_STAT, self.sizes.get(key).is_some()),
+        ];
+        match existing.iter().find(|&&(k, found)| found && k != requested) {
+            Some(&(existing, _)) => Err(Error::KindConflict {
+                name: key.name(),
+                existing,
+                requested,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a handle for the counter registered for `key` (or, if kinds may be reused,
+    /// the gauge), sharing the registry's key.
+    fn find_counter(&self, key: &Key) -> Option<Counter> {
+        if let Some((k, c)) = self.counters.get_key_value(key) {
+            return Some(Counter(Arc::downgrade(c), k.clone()));
+        }
+        if self.conflicts == KindConflicts::Reuse {
+            if let Some((k, g)) = self.gauges.get_key_value(key) {
+                return Some(Counter(Arc::downgrade(g), k.clone()));
+            }
+        }
+        None
+    }
+
+    /// Returns a handle for the gauge registered for `key` (or, if kinds may be reused,
+    /// the counter), sharing the registry's key.
+    fn find_gauge(&self, key: &Key) -> Option<Gauge> {
+        if let Some((k, g)) = self.gauges.get_key_value(key) {
+            return Some(Gauge(Arc::downgrade(g), k.clone()));
+        }
+        if self.conflicts == KindConflicts::Reuse {
+            if let Some((k, c)) = self.counters.get_key_value(key) {
+                return Some(Gauge(Arc::downgrade(c), k.clone()));
+            }
+        }
+        None
+    }
+
+    /// Returns a handle for the stat registered for `key`, if any.
+    ///
+    /// If the stat's bounds or unit differ from those in `config`, this fails if `strict`,
+    /// and otherwise adopts the existing bounds and unit.
+    fn find_stat(
+        &self,
+        key: &Key,
+        config: &StatConfig,
+        strict: bool,
+    ) -> Option<Result<Stat, Error>> {
+        let (k, c) = self.stats.get_key_value(key)?;
+        let bounds = c.bounds();
+        if let Some(requested) = config.bounds {
+            if bounds != Some(requested) {
+                if strict {
+                    return Some(Err(Error::BoundsConflict {
+                        name: key.name,
+                        existing: bounds,
+                        requested,
+                    }));
+                }
+                warn!(
+                    "stat {} has bounds {:?}, not {:?}; using its bounds",
+                    key,
+                    bounds,
+                    requested
+                );
+            }
+        }
+        // Otherwise, durations would be recorded in several units in one histogram.
+        let unit = c.unit();
+        if let Some(requested) = config.unit {
+            if unit != Some(requested) {
+                if strict {
+                    return Some(Err(Error::UnitConflict {
+                        name: key.name,
+                        existing: unit,
+                        requested,
+                    }));
+                }
+                warn!(
+                    "stat {} has unit {:?}, not {:?}; using its unit",
+                    key,
+                    unit,
+                    requested
+                );
+            }
+        }
+        Some(Ok(Stat {
+            cell: Arc::downgrade(c),
+            key: k.clone(),
+            bounds,
+            unit,
+            expected_interval: None,
+            capture: self.capture.clone(),
+        }))
+    }
+
+    /// Releases unused storage capacity.
+    ///
+    /// Unless `force` is true, storage is only shrunk when it is large and mostly empty, so
+    /// that the cost of reallocating is only paid after many metrics have been pruned.
+    fn compact(&mut self, force: bool) {
+        fn shrink<V>(s: &mut dyn Storage<V>, force: bool) {
+            let cap = s.capacity();
+            if force || (cap >= COMPACT_MIN_CAPACITY && s.len() <= cap / 4) {
+                s.shrink_to_fit();
+            }
+        }
+        shrink(&mut *self.counters, force);
+        shrink(&mut *self.gauges, force);
+        shrink(&mut *self.stats, force);
+        shrink(&mut *self.sizes, force);
+        if force {
+            self.constants.shrink_to_fit();
+            self.resetting.shrink_to_fit();
+            self.ratios.shrink_to_fit();
+            self.sampled.shrink_to_fit();
+        }
+    }
+
+    /// Updates gauges that are computed from other metrics, without resetting them.
+    fn update_derived(&self) {
+        for r in &self.ratios {
+            r.update();
+        }
+        for s in &self.sampled {
+            s.update(false);
+        }
+        for s in &self.striped {
+            s.update();
+        }
+        for s in &self.stopwatches {
+            s.update();
+        }
+        for h in &self.heartbeats {
+            h.beat();
+        }
+    }
+
+    /// Updates gauges that are computed from other metrics, resetting the samples of
+    /// sampled gauges that match, and drops those that are no longer in use.
+    fn take_derived(&mut self, matches: &dyn Fn(&Key) -> bool) {
+        self.ratios.retain(|r| r.update());
+        self.sampled.retain(|s| s.update(matches(&s.key)));
+        self.striped.retain(|s| s.update());
+        self.stopwatches.retain(|s| s.update());
+        for h in &self.heartbeats {
+            h.beat();
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+type Hook = Arc<dyn Fn(&Key) + Send + Sync>;
+
+/// Determines how a metric is created when its key is already registered as another kind
+/// of metric.
+///
+/// Counters, gauges, stats, and size stats are stored separately, so by default the same key
+/// may be registered as more than one kind, and is then exported once for each.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum KindConflicts {
+    /// Registers the metric alongside the existing one.
+    #[default]
+    Allow,
+    /// Returns a handle on the existing metric if it has the same representation (i.e. a
+    /// counter may be used as a gauge and vice versa), and fails otherwise.
+    Reuse,
+    /// Fails to create the metric.
+    Reject,
+}
+
+/// The kinds of metric, as named in errors.
+const COUNTER: &str = "counter";
+const GAUGE: &str = "gauge";
+const STAT: &str = "stat";
+const SIZE_STAT: &str = "size stat";
+
+/// Callbacks invoked as series are added to and removed from the registry.
+#[derive(Clone, Default)]
+struct Hooks {
+    on_register: Option<Hook>,
+    on_prune: Option<Hook>,
+}
+
+impl Hooks {
+    fn registered(&self, key: &Key) {
+        if let Some(ref f) = self.on_register {
+            f(key);
+        }
+    }
+
+    fn pruned(&self, key: &Key) {
+        if let Some(ref f) = self.on_prune {
+            f(key);
+        }
+    }
 }
 
 /// Describes a metric.
-#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+///
+/// A `Key`'s hash is computed once, when it is created, so that registry lookups and
+/// report snapshots needn't walk its prefix and labels. Likewise, its name and labels are
+/// rendered for Prometheus once, when the key is first exported.
+#[derive(Clone, Debug)]
 pub struct Key {
     name: &'static str,
     prefix: Arc<Prefix>,
-    labels: Labels,
+    labels: Arc<Labels>,
+    hash: u64,
+    created: SystemTime,
+    #[cfg(feature = "prometheus")]
+    rendered: OnceLock<prometheus::Rendered>,
 }
 impl Key {
-    fn new(name: &'static str, prefix: Arc<Prefix>, labels: Labels) -> Key {
+    fn new(name: &'static str, prefix: Arc<Prefix>, labels: Arc<Labels>) -> Key {
+        let mut h = DefaultHasher::new();
+        name.hash(&mut h);
+        prefix.hash(&mut h);
+        labels.hash(&mut h);
+        let hash = h.finish();
         Key {
             name,
             prefix,
             labels,
+            hash,
+            // Stamped by `registered`, so that looking up an existing metric is cheap.
+            created: UNIX_EPOCH,
+            #[cfg(feature = "prometheus")]
+            rendered: OnceLock::new(),
         }
     }
 
+    /// Stamps the key's creation time, as the registry inserts it.
+    fn registered(mut self) -> Arc<Key> {
+        self.created = SystemTime::now();
+        Arc::new(self)
+    }
+
+    /// When the key was registered.
+    ///
+    /// Handles and reports share the registry's keys, so for a metric or a series in a
+    /// report, this is when the series was registered (e.g. when a counter started
+    /// counting). A key that was never registered (e.g. from `Scope::key`) reports the Unix
+    /// epoch.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
     pub fn name(&self) -> &'static str {
         self.name
     }
@@ -100,11 +621,85 @@ impl Key {
     }
 }
 
-#[derive(Default)]
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        // Keys from the same registry share their prefixes and labels, so comparing pointers
+        // usually avoids comparing contents.
+        self.hash == other.hash && self.name == other.name &&
+            (Arc::ptr_eq(&self.prefix, &other.prefix) || self.prefix == other.prefix) &&
+            (Arc::ptr_eq(&self.labels, &other.labels) || self.labels == other.labels)
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> cmp::Ordering {
+        (self.name, &self.prefix, &self.labels).cmp(&(other.name, &other.prefix, &other.labels))
+    }
+}
+
+/// Formats a key as, e.g., `http:requests{status="200"}`.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for v in self.prefix.values() {
+            write!(f, "{}:", v)?;
+        }
+        f.write_str(self.name)?;
+        if !self.labels.is_empty() {
+            f.write_str("{")?;
+            for (i, (k, v)) in self.labels.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}={:?}", k, v.to_string())?;
+            }
+            f.write_str("}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Stores all metrics.
+///
+/// A `Registry` is usually created (and shared by a `Scope` and a `Reporter`) by
+/// `tacho::new`; but it may be created directly, e.g. by test harnesses, and shared with
+/// `Scope::with_registry` and `Reporter::new`.
 pub struct Registry {
-    counters: CounterMap,
-    gauges: GaugeMap,
-    stats: StatMap,
+    counters: CounterStorage,
+    gauges: GaugeStorage,
+    stats: StatStorage,
+    sizes: SizeStorage,
+    heatmaps: Option<heatmap::Heatmaps>,
+    history: Option<history::Histories>,
+    hooks: Hooks,
+    conflicts: KindConflicts,
+    /// Copied into each root scope, so that labeling needn't lock the registry.
+    label_values: LabelValues,
+    /// Held so that constant series are never pruned.
+    constants: Vec<Gauge>,
+    /// Gauges that are zeroed as they are taken. Only keys are held, so that these gauges
+    /// are pruned as usual.
+    resetting: Vec<Arc<Key>>,
+    ratios: Vec<ratio::RatioGauge>,
+    sampled: Vec<sampled::SampledGauges>,
+    striped: Vec<striped::StripedCounters>,
+    stopwatches: Vec<stopwatch::StopwatchGauge>,
+    heartbeats: Vec<info::Heartbeat>,
+    /// If set, every value recorded to a stat is retained (see `test::capture`).
+    capture: Option<Arc<test::Samples>>,
 }
 
 /// Supports creation of scoped metrics.
@@ -113,131 +708,545 @@ pub struct Registry {
 ///
 /// Labels may be attached to the scope so that all metrics created by the `Scope` are
 /// labeled.
+///
+/// A `Scope`'s prefix and labels are immutable: `labeled` and `prefixed` return a new
+/// `Scope`, so changes to a scope are never observed by its clones.
 #[derive(Clone)]
 pub struct Scope {
-    labels: Labels,
+    labels: Arc<Labels>,
     prefix: Arc<Prefix>,
-    registry: Arc<Mutex<Registry>>,
+    registry: Arc<RwLock<Registry>>,
+    /// Set if the scope was derived from an `Ephemeral` scope.
+    ephemeral: Option<Arc<ephemeral::Tracker>>,
+    label_values: LabelValues,
 }
 
 impl Scope {
+    /// Creates a root scope (i.e. without a prefix or labels) for the given registry.
+    pub fn with_registry(registry: Arc<RwLock<Registry>>) -> Scope {
+        Scope::root(registry)
+    }
+
+    fn root(registry: Arc<RwLock<Registry>>) -> Scope {
+        let label_values = registry
+            .read()
+            .expect("failed to obtain lock on registry")
+            .label_values;
+        Scope {
+            labels: Arc::new(Labels::default()),
+            prefix: Arc::new(Prefix::Root),
+            registry,
+            ephemeral: None,
+            label_values,
+        }
+    }
+
     /// Accesses scoping labels.
     pub fn labels(&self) -> &Labels {
         &self.labels
     }
 
     /// Adds a label into scope (potentially overwriting).
-    pub fn labeled<D: fmt::Display>(mut self, k: &'static str, v: D) -> Self {
-        self.labels.insert(k, format!("{}", v));
+    ///
+    /// Static strings and integers are stored without being formatted. Other values must
+    /// first be formatted into a `String`.
+    ///
+    /// Values are checked according to the registry's `LabelValues` policy. If the value is
+    /// rejected, the label is not added (and a warning is logged); see `try_labeled`.
+    pub fn labeled<V: Into<LabelValue>>(mut self, k: &'static str, v: V) -> Self {
+        match self.label_values.check(v.into()) {
+            Some(v) => {
+                Arc::make_mut(&mut self.labels).insert(k, v);
+            }
+            None => warn!("not adding label {}: its value is invalid", k),
+        }
+        self
+    }
+
+    /// Adds a label into scope (potentially overwriting), failing if the registry's
+    /// `LabelValues` policy rejects the value.
+    pub fn try_labeled<V: Into<LabelValue>>(mut self, k: &'static str, v: V) -> Result<Self, Error> {
+        let v = self.label_values.check(v.into()).ok_or(Error::InvalidLabelValue(k))?;
+        Arc::make_mut(&mut self.labels).insert(k, v);
+        Ok(self)
+    }
+
+    /// Removes an inherited label from scope, e.g. so that a per-request label is not
+    /// attached to long-lived metrics.
+    pub fn unlabeled(mut self, k: &str) -> Self {
+        if self.labels.get(k).is_some() {
+            Arc::make_mut(&mut self.labels).remove(k);
+        }
+        self
+    }
+
+    /// Removes all labels from scope except those named in `keys`.
+    pub fn retain_labels(mut self, keys: &[&str]) -> Self {
+        if self.labels.iter().any(|(k, _)| !keys.contains(&k)) {
+            Arc::make_mut(&mut self.labels).retain(|k, _| keys.contains(&k));
+        }
         self
     }
 
+    /// Returns a root scope (i.e. without this scope's prefix or labels) that shares this
+    /// scope's registry, e.g. for plugins that name their own metrics.
+    ///
+    /// Metrics created through the returned scope aren't tracked by an `Ephemeral` scope,
+    /// even if this scope is one. To share this scope's prefix and labels, clone it
+    /// instead: since label sets are shared copy-on-write, neither clone can affect the
+    /// other's labels.
+    pub fn detached(&self) -> Scope {
+        Scope {
+            labels: Arc::new(Labels::default()),
+            prefix: Arc::new(Prefix::Root),
+            registry: self.registry.clone(),
+            ephemeral: None,
+            label_values: self.label_values,
+        }
+    }
+
     /// Appends a prefix to the current scope.
-    pub fn prefixed(mut self, value: &'static str) -> Self {
-        let p = Prefix::Node {
-            prefix: self.prefix,
-            value,
-        };
-        self.prefix = Arc::new(p);
+    pub fn prefixed(self, value: &'static str) -> Self {
+        self.push_prefix(PrefixValue::Static(value))
+    }
+
+    /// Appends each of a `PrefixBuilder`'s segments to the current scope.
+    pub fn prefixed_by(self, prefix: &PrefixBuilder) -> Self {
+        prefix.values().iter().fold(self, |s, v| s.push_prefix(v.clone()))
+    }
+
+    fn push_prefix(mut self, value: PrefixValue) -> Self {
+        self.prefix = Arc::new(Prefix::node(self.prefix, value));
         self
     }
 
+    /// Returns a scope whose metrics are removed from the registry when it is dropped.
+    ///
+    /// See `Ephemeral`.
+    pub fn ephemeral(&self) -> Ephemeral {
+        Ephemeral::new(self)
+    }
+
+    /// Returns the key that identifies the metric `name` created through this scope.
+    pub fn key(&self, name: &'static str) -> Key {
+        Key::new(name, self.prefix.clone(), self.labels.clone())
+    }
+
+    /// Returns the key for a metric that is being created, tracking it if the scope is
+    /// ephemeral.
+    fn mk_key(&self, name: &'static str) -> Key {
+        let key = self.key(name);
+        if let Some(ref t) = self.ephemeral {
+            t.track(&key);
+        }
+        key
+    }
+
     /// Creates a Counter with the given name.
     pub fn counter(&self, name: &'static str) -> Counter {
-        let key = Key::new(name, self.prefix.clone(), self.labels.clone());
-        let mut reg = self.registry.lock().expect(
+        self.try_counter(name).expect("failed to create counter")
+    }
+
+    /// Creates a Counter with the given name, failing if the name is already registered
+    /// as another kind of metric (see `KindConflicts`).
+    pub fn try_counter(&self, name: &'static str) -> Result<Counter, Error> {
+        let key = self.mk_key(name);
+        self.find_or_register(key, |reg, key| reg.find_counter(key).map(Ok), |reg, key| {
+            reg.check_kind(&key, COUNTER)?;
+
+            let c = Arc::new(AtomicUsize::new(0));
+            let key = key.registered();
+            let counter = Counter(Arc::downgrade(&c), key.clone());
+            reg.hooks.registered(&key);
+            reg.counters.insert(key, c);
+            Ok(counter)
+        })
+    }
+
+    /// Creates a StripedCounter with the given name, for counters that are incremented
+    /// very frequently from many threads.
+    ///
+    /// It is exported as a Counter, and shares the Counter's series if one exists.
+    pub fn counter_striped(&self, name: &'static str) -> StripedCounter {
+        let counter = self.counter(name);
+        let striped = StripedCounter::new(&counter);
+        let mut reg = self.registry.write().expect(
             "failed to obtain lock on registry",
         );
+        reg.striped.push(striped.stripes());
+        striped
+    }
+
+    /// Creates a Gauge with the given name.
+    pub fn gauge(&self, name: &'static str) -> Gauge {
+        self.try_gauge(name).expect("failed to create gauge")
+    }
+
+    /// Creates a Gauge with the given name, failing if the name is already registered as
+    /// another kind of metric (see `KindConflicts`).
+    pub fn try_gauge(&self, name: &'static str) -> Result<Gauge, Error> {
+        let key = self.mk_key(name);
+        self.find_or_register(key, |reg, key| reg.find_gauge(key).map(Ok), |reg, key| {
+            reg.check_kind(&key, GAUGE)?;
+
+            let g = Arc::new(AtomicUsize::new(0));
+            let key = key.registered();
+            let gauge = Gauge(Arc::downgrade(&g), key.clone());
+            reg.hooks.registered(&key);
+            reg.gauges.insert(key, g);
+            Ok(gauge)
+        })
+    }
 
-        if let Some(c) = reg.counters.get(&key) {
-            return Counter(Arc::downgrade(c));
+    /// Creates a group of gauges, with the given names, that are updated together.
+    pub fn gauge_group(&self, names: &[&'static str]) -> GaugeGroup {
+        GaugeGroup {
+            gauges: names.iter().map(|n| self.gauge(n)).collect(),
+            registry: self.registry.clone(),
         }
+    }
 
-        let c = Arc::new(AtomicUsize::new(0));
-        let counter = Counter(Arc::downgrade(&c));
-        reg.counters.insert(key, c);
-        counter
+    /// Creates a Ratio of the counters `numerator` and `denominator`.
+    pub fn ratio(&self, numerator: &'static str, denominator: &'static str) -> Ratio {
+        Ratio {
+            numerator: self.counter(numerator),
+            denominator: self.counter(denominator),
+            _gauge: None,
+        }
     }
 
-    /// Creates a Gauge with the given name.
-    pub fn gauge(&self, name: &'static str) -> Gauge {
-        let key = Key::new(name, self.prefix.clone(), self.labels.clone());
-        let mut reg = self.registry.lock().expect(
+    /// Creates a Ratio of the counters `numerator` and `denominator`, also exporting their
+    /// ratio, in parts per million, as the gauge `gauge`.
+    pub fn ratio_with_gauge(
+        &self,
+        numerator: &'static str,
+        denominator: &'static str,
+        gauge: &'static str,
+    ) -> Ratio {
+        let mut ratio = self.ratio(numerator, denominator);
+        let g = self.gauge(gauge);
+        let mut reg = self.registry.write().expect(
             "failed to obtain lock on registry",
         );
+        reg.ratios.push(ratio::RatioGauge {
+            numerator: ratio.numerator.0.clone(),
+            denominator: ratio.denominator.0.clone(),
+            gauge: g.0.clone(),
+        });
+        ratio._gauge = Some(g);
+        ratio
+    }
 
-        if let Some(g) = reg.gauges.get(&key) {
-            return Gauge(Arc::downgrade(g));
+    /// Creates a SampledGauge, exported as the gauges `name:count`, `name:sum`, `name:min`,
+    /// and `name:max`.
+    pub fn sampled_gauge(&self, name: &'static str) -> SampledGauge {
+        let scope = self.clone().prefixed(name);
+        let gauges = [
+            scope.gauge("count"),
+            scope.gauge("sum"),
+            scope.gauge("min"),
+            scope.gauge("max"),
+        ];
+        let samples = Arc::new(sampled::Samples::default());
+        let mut reg = self.registry.write().expect(
+            "failed to obtain lock on registry",
+        );
+        reg.sampled.push(sampled::SampledGauges {
+            key: gauges[0].1.clone(),
+            samples: Arc::downgrade(&samples),
+            gauges: [
+                gauges[0].0.clone(),
+                gauges[1].0.clone(),
+                gauges[2].0.clone(),
+                gauges[3].0.clone(),
+            ],
+        });
+        SampledGauge {
+            samples,
+            _gauges: gauges,
         }
+    }
+
+    /// Creates a Stopwatch, exported as a gauge of the time since it was started.
+    pub fn stopwatch(&self, name: &'static str, unit: TimeUnit) -> Stopwatch {
+        let sw = Stopwatch::new(self.gauge(name), unit);
+        let mut reg = self.registry.write().expect(
+            "failed to obtain lock on registry",
+        );
+        reg.stopwatches.push(sw.gauge());
+        sw
+    }
 
-        let g = Arc::new(AtomicUsize::new(0));
-        let gauge = Gauge(Arc::downgrade(&g));
-        reg.gauges.insert(key, g);
+    /// Creates a Gauge that is reset to zero each time it is taken (i.e. by `Reporter::take`,
+    /// but not `peek`).
+    ///
+    /// This suits gauges that accumulate over a reporting interval (e.g. bytes written since
+    /// the last report, via `incr`). Values are reset atomically as they are reported, so no
+    /// increments are lost. The gauge is pruned, as usual, once it is no longer referenced.
+    pub fn gauge_resetting(&self, name: &'static str) -> Gauge {
+        let gauge = self.gauge(name);
+        let reset = |reg: &Registry| reg.resetting.contains(&gauge.1);
+        if reset(&self.registry.read().expect("failed to obtain lock on registry")) {
+            return gauge;
+        }
+        let mut reg = self.registry.write().expect(
+            "failed to obtain lock on registry",
+        );
+        if !reset(&reg) {
+            reg.resetting.push(gauge.1.clone());
+        }
         gauge
     }
 
+    /// Registers a gauge with a constant value that is never pruned.
+    ///
+    /// Such series typically carry information in their labels (see `build_info`).
+    pub fn constant(&self, name: &'static str, v: usize) {
+        let gauge = self.gauge(name);
+        gauge.set(v);
+        let held = |reg: &Registry| reg.constants.iter().any(|c| c.0.ptr_eq(&gauge.0));
+        if held(&self.registry.read().expect("failed to obtain lock on registry")) {
+            return;
+        }
+        let mut reg = self.registry.write().expect(
+            "failed to obtain lock on registry",
+        );
+        if !held(&reg) {
+            reg.constants.push(gauge);
+        }
+    }
+
     /// Creates a Stat with the given name.
     ///
     /// The underlying histogram is automatically resized as values are added.
     pub fn stat(&self, name: &'static str) -> Stat {
-        let key = Key::new(name, self.prefix.clone(), self.labels.clone());
-        self.mk_stat(key, None)
+        self.stat_with_config(name, StatConfig::default())
+    }
+
+    /// Creates a Stat with the given name and configuration.
+    ///
+    /// If a Stat with the same name already exists in this scope with other bounds (or
+    /// another unit), a warning is logged and the existing Stat (and its bounds and unit)
+    /// is returned.
+    pub fn stat_with_config(&self, name: &'static str, config: StatConfig) -> Stat {
+        let key = self.mk_key(name);
+        self.mk_stat(key, config, false).expect("failed to create stat")
     }
 
+    /// Creates a Stat with the given name and configuration, failing if the configuration
+    /// is invalid.
+    ///
+    /// If a Stat with the same name already exists in this scope, it is returned and
+    /// `config` is not validated; but if `config` has bounds and the existing Stat was
+    /// created with other bounds (or none), this fails with `Error::BoundsConflict`, and
+    /// likewise with `Error::UnitConflict` for its unit.
+    pub fn try_stat_with_config(
+        &self,
+        name: &'static str,
+        config: StatConfig,
+    ) -> Result<Stat, Error> {
+        let key = self.mk_key(name);
+        self.mk_stat(key, config, true)
+    }
+
+    /// Creates a Timer that records durations in microseconds.
     pub fn timer_us(&self, name: &'static str) -> Timer {
-        Timer {
-            stat: self.stat(name),
-            unit: TimeUnit::Micros,
-        }
+        Timer { stat: self.stat_with_unit(name, TimeUnit::Micros) }
     }
 
+    /// Creates a Timer that records durations in milliseconds.
     pub fn timer_ms(&self, name: &'static str) -> Timer {
-        Timer {
-            stat: self.stat(name),
-            unit: TimeUnit::Millis,
-        }
+        Timer { stat: self.stat_with_unit(name, TimeUnit::Millis) }
     }
 
-    /// Creates a Stat with the given name and histogram paramters.
-    pub fn stat_with_bounds(&self, name: &'static str, low: u64, high: u64) -> Stat {
-        let key = Key::new(name, self.prefix.clone(), self.labels.clone());
-        self.mk_stat(key, Some((low, high)))
+    /// Creates a StatusCounter, which counts HTTP responses in counters labeled by status
+    /// class (`1xx` through `5xx`, or `other`).
+    pub fn status_counter(&self, name: &'static str) -> StatusCounter {
+        StatusCounter::new(self, name)
     }
 
-    fn mk_stat(&self, key: Key, bounds: Option<(u64, u64)>) -> Stat {
-        let mut reg = self.registry.lock().expect(
-            "failed to obtain lock on registry",
-        );
+    /// Creates a family of Timers, labeled by `label`, whose value may be chosen once the
+    /// timed operation completes. See `TimerFamily`.
+    pub fn timer_family(
+        &self,
+        name: &'static str,
+        label: &'static str,
+        unit: TimeUnit,
+    ) -> TimerFamily {
+        TimerFamily::new(self.clone(), name, label, unit)
+    }
 
-        if let Some(h) = reg.stats.get(&key) {
-            let histo = Arc::downgrade(h);
-            return Stat { histo, bounds };
+    /// Creates a TimedCounter, exported as the counter `name:requests` and the timer (in
+    /// milliseconds) `name:latency_ms`, so that each request is counted and timed by a
+    /// single `TimedCounter::observe`.
+    pub fn timed_counter(&self, name: &'static str) -> TimedCounter {
+        let scope = self.clone().prefixed(name);
+        TimedCounter {
+            counter: scope.counter("requests"),
+            timer: scope.timer_ms("latency_ms"),
         }
+    }
 
-        let h = Arc::new(Mutex::new(HistogramWithSum::new(bounds)));
-        let histo = Arc::downgrade(&h);
-        reg.stats.insert(key, h);
-        Stat { histo, bounds }
+    /// Creates a metric for each of `defs`.
+    pub fn bind(&self, defs: &[MetricDef]) -> def::BoundMetrics {
+        def::BoundMetrics::new(self, defs)
     }
-}
 
-/// Counts monotically.
-#[derive(Clone)]
-pub struct Counter(Weak<AtomicUsize>);
-impl Counter {
-    pub fn incr(&self, v: usize) {
-        if let Some(c) = self.0.upgrade() {
-            c.fetch_add(v, Ordering::AcqRel);
-        }
+    /// Creates a Stat that records `Duration`s in the given unit.
+    pub fn stat_with_unit(&self, name: &'static str, unit: TimeUnit) -> Stat {
+        self.stat_with_config(name, StatConfig::default().unit(unit))
     }
-}
 
-/// Captures an instantaneous value.
-#[derive(Clone)]
-pub struct Gauge(Weak<AtomicUsize>);
-impl Gauge {
-    pub fn incr(&self, v: usize) {
-        if let Some(g) = self.0.upgrade() {
+    /// Creates a Stat with the given name and histogram parameters.
+    pub fn stat_with_bounds(&self, name: &'static str, low: u64, high: u64) -> Stat {
+        self.stat_with_config(name, StatConfig::default().bounds(low, high))
+    }
+
+    /// Creates a Stat with the given name and histogram parameters, failing if the bounds
+    /// are invalid (or conflict with those of an existing Stat).
+    ///
+    /// `low` must be at least 1, and `high` must be at least `2 * low`.
+    pub fn try_stat_with_bounds(
+        &self,
+        name: &'static str,
+        low: u64,
+        high: u64,
+    ) -> Result<Stat, Error> {
+        self.try_stat_with_config(name, StatConfig::default().bounds(low, high))
+    }
+
+    /// Creates a Stat for latencies from 1us to 60s, recorded in microseconds.
+    pub fn latency_stat_us(&self, name: &'static str) -> Stat {
+        self.stat_with_config(name, StatConfig::latency_us())
+    }
+
+    /// Creates a Stat for latencies from 1ms to 60s, recorded in milliseconds.
+    pub fn latency_stat_ms(&self, name: &'static str) -> Stat {
+        self.stat_with_config(name, StatConfig::latency_ms())
+    }
+
+    /// Creates a Stat for sizes from 1B to 10GB.
+    pub fn bytes_stat(&self, name: &'static str) -> Stat {
+        self.stat_with_config(name, StatConfig::bytes())
+    }
+
+    /// Creates a SizeStat with the given name.
+    pub fn size_stat(&self, name: &'static str) -> SizeStat {
+        self.try_size_stat(name).expect("failed to create size stat")
+    }
+
+    /// Creates a SizeStat with the given name, failing if the name is already registered as
+    /// another kind of metric (see `KindConflicts`).
+    pub fn try_size_stat(&self, name: &'static str) -> Result<SizeStat, Error> {
+        let key = self.mk_key(name);
+        let find = |reg: &Registry, key: &Key| {
+            reg.sizes.get(key).map(|s| Ok(SizeStat(Arc::downgrade(s))))
+        };
+        self.find_or_register(key, find, |reg, key| {
+            reg.check_kind(&key, SIZE_STAT)?;
+
+            let s = Arc::new(size::SizeBuckets::default());
+            let stat = SizeStat(Arc::downgrade(&s));
+            let key = key.registered();
+            reg.hooks.registered(&key);
+            reg.sizes.insert(key, s);
+            Ok(stat)
+        })
+    }
+
+    /// Creates a Stat, or returns the existing Stat with the same key.
+    ///
+    /// If the existing Stat's bounds or unit differ from those requested, this fails if
+    /// `strict`, and otherwise adopts the existing bounds and unit.
+    fn mk_stat(&self, key: Key, config: StatConfig, strict: bool) -> Result<Stat, Error> {
+        let find = |reg: &Registry, key: &Key| reg.find_stat(key, &config, strict);
+        self.find_or_register(key, find, |reg, key| {
+            reg.check_kind(&key, STAT)?;
+
+            let c = Arc::new(StatCell::new(&config)?);
+            let cell = Arc::downgrade(&c);
+            let key = key.registered();
+            reg.hooks.registered(&key);
+            reg.stats.insert(key.clone(), c);
+            Ok(Stat {
+                cell,
+                key,
+                bounds: config.bounds,
+                unit: config.unit,
+                expected_interval: None,
+                capture: reg.capture.clone(),
+            })
+        })
+    }
+
+    /// Returns the metric registered for `key`, as found by `find`, or else registers it
+    /// with `register`.
+    ///
+    /// Existing metrics are looked up under the registry's read lock, so that handles for
+    /// them needn't contend with each other. The write lock is only taken to register a
+    /// metric, and `find` is checked again under it, since another thread may have
+    /// registered the metric in the meantime.
+    fn find_or_register<T, F, R>(&self, key: Key, find: F, register: R) -> Result<T, Error>
+    where
+        F: Fn(&Registry, &Key) -> Option<Result<T, Error>>,
+        R: FnOnce(&mut Registry, Key) -> Result<T, Error>,
+    {
+        {
+            let reg = self.registry.read().expect(
+                "failed to obtain lock on registry",
+            );
+            if let Some(found) = find(&reg, &key) {
+                return found;
+            }
+        }
+
+        let mut reg = self.registry.write().expect(
+            "failed to obtain lock on registry",
+        );
+        if let Some(found) = find(&reg, &key) {
+            return found;
+        }
+        register(&mut reg, key)
+    }
+}
+
+/// Counts monotically.
+#[derive(Clone)]
+pub struct Counter(Weak<AtomicUsize>, Arc<Key>);
+impl Counter {
+    /// Identifies the counter.
+    pub fn key(&self) -> &Arc<Key> {
+        &self.1
+    }
+
+    pub fn incr(&self, v: usize) {
+        if let Some(c) = self.0.upgrade() {
+            c.fetch_add(v, Ordering::AcqRel);
+        }
+    }
+
+    /// Resets the counter to zero.
+    ///
+    /// Counters are expected to only increase, so this is intended for isolating test cases
+    /// rather than for use by instrumented code.
+    pub fn reset(&self) {
+        if let Some(c) = self.0.upgrade() {
+            c.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Captures an instantaneous value.
+#[derive(Clone)]
+pub struct Gauge(Weak<AtomicUsize>, Arc<Key>);
+impl Gauge {
+    /// Identifies the gauge.
+    pub fn key(&self) -> &Arc<Key> {
+        &self.1
+    }
+
+    pub fn incr(&self, v: usize) {
+        if let Some(g) = self.0.upgrade() {
             g.fetch_add(v, Ordering::AcqRel);
         } else {
             debug!("gauge dropped");
@@ -257,11 +1266,137 @@ impl Gauge {
             debug!("gauge dropped");
         }
     }
+
+    /// Sets the gauge to a duration, in the given unit.
+    pub fn set_duration(&self, d: Duration, unit: TimeUnit) {
+        self.set(unit.convert(d) as usize);
+    }
+
+    /// Applies a signed delta to the gauge.
+    ///
+    /// The gauge's value is clamped at zero (and at `usize::MAX`) rather than wrapping.
+    pub fn add_signed(&self, delta: i64) {
+        if let Some(g) = self.0.upgrade() {
+            let _ = g.fetch_update(Ordering::AcqRel, Ordering::Acquire, |v| {
+                Some(apply_signed(v, delta))
+            });
+        } else {
+            debug!("gauge dropped");
+        }
+    }
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.0.upgrade().map(|c| c.load(Ordering::Acquire));
+        f.debug_struct("Counter")
+            .field("key", &format_args!("{}", self.1))
+            .field("value", &value)
+            .finish()
+    }
+}
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.1, f)
+    }
+}
+
+impl fmt::Debug for Gauge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.0.upgrade().map(|g| g.load(Ordering::Acquire));
+        f.debug_struct("Gauge")
+            .field("key", &format_args!("{}", self.1))
+            .field("value", &value)
+            .finish()
+    }
+}
+
+impl fmt::Display for Gauge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.1, f)
+    }
+}
+
+/// A set of related gauges that are updated together.
+///
+/// Gauges in a group are set while the registry is locked for writing, so reports (which
+/// are taken while the registry is locked) never observe a partially-updated group.
+#[derive(Clone)]
+pub struct GaugeGroup {
+    gauges: Vec<Gauge>,
+    registry: Arc<RwLock<Registry>>,
+}
+
+impl GaugeGroup {
+    /// Sets each gauge in the group to the corresponding value.
+    ///
+    /// Panics if the number of values differs from the number of gauges.
+    pub fn set(&self, values: &[usize]) {
+        assert_eq!(
+            values.len(),
+            self.gauges.len(),
+            "gauge group values must match its gauges"
+        );
+        let _reg = self.registry.write().expect(
+            "failed to obtain lock on registry",
+        );
+        for (g, v) in self.gauges.iter().zip(values) {
+            g.set(*v);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.gauges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gauges.is_empty()
+    }
 }
 
-/// Histograms hold up to 4 significant figures.
+fn apply_signed(v: usize, delta: i64) -> usize {
+    let abs = delta.unsigned_abs();
+    let abs = if abs > usize::MAX as u64 {
+        usize::MAX
+    } else {
+        abs as usize
+    };
+    if delta < 0 {
+        v.saturating_sub(abs)
+    } else {
+        v.saturating_add(abs)
+    }
+}
+
+/// By default, histograms hold up to 4 significant figures.
 const HISTOGRAM_PRECISION: u32 = 4;
 
+/// Presets hold up to 3 significant figures, i.e. values are tracked to within 0.1%.
+const PRESET_PRECISION: u32 = 3;
+
+/// How far beyond its observed range an adaptive Stat's fitted bounds extend, as a factor.
+const ADAPTIVE_MARGIN: u64 = 2;
+
+/// The most exemplars retained by each Stat; older exemplars are discarded.
+pub const MAX_EXEMPLARS: usize = 8;
+
+/// Links a recorded value to the trace in which it was observed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exemplar {
+    pub value: u64,
+    pub trace_id: String,
+    pub timestamp: SystemTime,
+}
+
+/// Retains the most recent `MAX_EXEMPLARS` exemplars.
+fn push_exemplar(exemplars: &mut VecDeque<Exemplar>, exemplar: Exemplar) {
+    if exemplars.len() == MAX_EXEMPLARS {
+        exemplars.pop_front();
+    }
+    exemplars.push_back(exemplar);
+}
+
 /// Tracks a distribution of values with their sum.
 ///
 /// `hdrsample::Histogram` does not track a sum by default; but prometheus expects a `sum`
@@ -270,34 +1405,121 @@ const HISTOGRAM_PRECISION: u32 = 4;
 pub struct HistogramWithSum {
     histogram: Histogram<usize>,
     sum: u64,
+    /// Values at `STANDARD_PERCENTILES`, computed at most once per snapshot.
+    percentiles: OnceLock<[u64; 6]>,
+    overflow: StatOverflow,
+    /// The number of values that exceeded the histogram's upper bound.
+    overflowed: u64,
+    /// Whether the histogram should be refitted to the values it observes before it is next
+    /// cleared (see `StatConfig::adaptive`).
+    adaptive: bool,
+    /// Recent exemplars, oldest first.
+    exemplars: VecDeque<Exemplar>,
+    /// When the histogram was created or last cleared.
+    created: SystemTime,
+    /// The bounds the histogram was created with, if any (rather than those it was fitted
+    /// or resized to).
+    bounds: Option<(u64, u64)>,
 }
 
+/// Percentiles that are computed once per histogram, when first accessed, so that they
+/// needn't be recomputed by each exporter that renders a report.
+pub const STANDARD_PERCENTILES: [f64; 6] = [50.0, 90.0, 95.0, 99.0, 99.9, 99.99];
+
 impl HistogramWithSum {
     /// Constructs a new `HistogramWithSum`, possibly with bounds.
-    fn new(bounds: Option<(u64, u64)>) -> Self {
+    fn new(bounds: Option<(u64, u64)>, precision: u32) -> Self {
+        Self::try_new(bounds, precision).expect("failed to create histogram")
+    }
+
+    fn try_new(bounds: Option<(u64, u64)>, precision: u32) -> Result<Self, Error> {
         let h = match bounds {
-            None => Histogram::<usize>::new(HISTOGRAM_PRECISION),
-            Some((l, h)) => Histogram::<usize>::new_with_bounds(l, h, HISTOGRAM_PRECISION),
+            None => Histogram::<usize>::new(precision),
+            Some((l, h)) => {
+                // Guard against overflow in hdrsample's own validation.
+                if l.checked_mul(2).map(|l2| h < l2).unwrap_or(true) {
+                    return Err(Error::InvalidHistogram(
+                        "highest trackable value must be >= 2 * lowest discernible value",
+                    ));
+                }
+                Histogram::<usize>::new_with_bounds(l, h, precision)
+            }
         };
-        let histogram = h.expect("failed to create histogram");
-        HistogramWithSum { histogram, sum: 0 }
+        let histogram = h.map_err(Error::InvalidHistogram)?;
+        Ok(HistogramWithSum {
+            histogram,
+            sum: 0,
+            percentiles: OnceLock::new(),
+            overflow: StatOverflow::Drop,
+            overflowed: 0,
+            adaptive: false,
+            exemplars: VecDeque::new(),
+            created: SystemTime::now(),
+            bounds,
+        })
+    }
+
+    /// Replaces the (empty) histogram with one bounded to `[min, max]`, with a margin, so
+    /// that narrow distributions don't track buckets for values they never observe.
+    ///
+    /// The fitted histogram still resizes to hold larger values.
+    fn fit(&mut self, min: u64, max: u64) {
+        let low = cmp::max(1, min / ADAPTIVE_MARGIN);
+        let high = cmp::max(max.saturating_mul(ADAPTIVE_MARGIN), low.saturating_mul(2));
+        match HistogramWithSum::try_new(Some((low, high)), self.histogram.sigfig()) {
+            Ok(mut h) => {
+                h.set_overflow(StatOverflow::Resize);
+                h.bounds = self.bounds;
+                *self = h;
+            }
+            Err(e) => {
+                error!("failed to fit histogram to [{}, {}]: {:?}", low, high, e);
+                self.adaptive = false;
+            }
+        }
+    }
+
+    /// Sets how values greater than the histogram's upper bound are handled.
+    fn set_overflow(&mut self, overflow: StatOverflow) {
+        self.overflow = overflow;
+        self.histogram.auto(overflow == StatOverflow::Resize);
     }
 
     /// Record a value to
     fn record(&mut self, v: u64) {
-        if let Err(e) = self.histogram.record(v) {
-            error!("failed to add value to histogram: {:?}", e);
-        }
-        if v >= ::std::u64::MAX - self.sum {
-            self.sum = ::std::u64::MAX
-        } else {
-            self.sum += v;
+        self.record_n(v, 1);
+    }
+
+    /// Records `n` occurrences of a value.
+    fn record_n(&mut self, v: u64, n: u64) {
+        let mut v = v;
+        if let Err(e) = self.histogram.record_n(v, n as usize) {
+            self.overflowed = self.overflowed.saturating_add(n);
+            if self.overflow != StatOverflow::Clamp {
+                error!("failed to add value to histogram: {:?}", e);
+                return;
+            }
+            v = self.histogram.high();
+            if let Err(e) = self.histogram.record_n(v, n as usize) {
+                error!("failed to add value to histogram: {:?}", e);
+                return;
+            }
         }
+        self.sum = self.sum.saturating_add(v.saturating_mul(n));
+        self.percentiles.take();
     }
 
     pub fn histogram(&self) -> &Histogram<usize> {
         &self.histogram
     }
+
+    /// Approximates the memory used by the histogram, in bytes.
+    ///
+    /// This is dominated by the histogram's counts, of which there are more for higher
+    /// precisions and for wider ranges of values.
+    pub fn memory_bytes(&self) -> usize {
+        mem::size_of::<Self>() + self.histogram.len() * mem::size_of::<usize>()
+    }
     pub fn count(&self) -> u64 {
         self.histogram.count()
     }
@@ -311,346 +1533,2195 @@ impl HistogramWithSum {
         self.sum
     }
 
-    pub fn clear(&mut self) {
-        self.histogram.reset();
-        self.sum = 0;
+    /// Returns the most recent exemplars recorded since the Stat was last cleared, oldest
+    /// first.
+    pub fn exemplars(&self) -> impl Iterator<Item = &Exemplar> {
+        self.exemplars.iter()
     }
-}
 
-/// Caputres a distribution of values.
-#[derive(Clone)]
-pub struct Stat {
-    histo: Weak<Mutex<HistogramWithSum>>,
-    bounds: Option<(u64, u64)>,
-}
+    /// Returns the number of values that exceeded the Stat's upper bound, and were either
+    /// clamped or dropped (see `StatOverflow`).
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
 
-impl Stat {
-    pub fn add(&self, v: u64) {
-        if let Some(h) = self.histo.upgrade() {
-            let mut histo = h.lock().expect("failed to obtain lock for stat");
-            histo.record(v);
+    /// Returns the number of recorded values less than or equal to `v` (to within the
+    /// histogram's precision).
+    pub fn count_below(&self, v: u64) -> u64 {
+        if self.histogram.count() == 0 {
+            return 0;
         }
+        self.histogram.count_between(0, v).unwrap_or(0) as u64
     }
 
-    pub fn add_values(&mut self, vs: &[u64]) {
-        if let Some(h) = self.histo.upgrade() {
-            let mut histo = h.lock().expect("failed to obtain lock for stat");
-            for v in vs {
-                histo.record(*v)
+    /// Returns the fraction (from 0.0 to 1.0) of recorded values less than or equal to `v`
+    /// (to within the histogram's precision).
+    ///
+    /// If no values have been recorded, 1.0 is returned.
+    pub fn fraction_below(&self, v: u64) -> f64 {
+        self.histogram.percentile_below(v) / 100.0
+    }
+
+    /// Returns the value at percentile `p` (from 0.0 to 100.0).
+    ///
+    /// Values at `STANDARD_PERCENTILES` are cached.
+    pub fn percentile(&self, p: f64) -> u64 {
+        match STANDARD_PERCENTILES.iter().position(|s| *s == p) {
+            Some(i) => self.standard_percentiles()[i],
+            None => self.histogram.value_at_percentile(p),
+        }
+    }
+
+    /// Returns the values at each of `STANDARD_PERCENTILES`.
+    pub fn standard_percentiles(&self) -> &[u64; 6] {
+        self.percentiles.get_or_init(|| {
+            let mut vs = [0; 6];
+            for (v, p) in vs.iter_mut().zip(&STANDARD_PERCENTILES) {
+                *v = self.histogram.value_at_percentile(*p);
+            }
+            vs
+        })
+    }
+
+    /// Summarizes the histogram, so that consumers needn't interpret it directly.
+    pub fn summary(&self) -> StatSummary {
+        let count = self.count();
+        if count == 0 {
+            return StatSummary::default();
+        }
+        let mut quantiles = [(0.0, 0); 6];
+        for ((q, v), (p, pv)) in quantiles
+            .iter_mut()
+            .zip(STANDARD_PERCENTILES.iter().zip(self.standard_percentiles()))
+        {
+            *q = *p;
+            *v = *pv;
+        }
+        StatSummary {
+            count,
+            sum: self.sum,
+            min: self.min(),
+            max: self.max(),
+            mean: self.histogram.mean(),
+            stddev: self.histogram.stdev(),
+            quantiles,
+        }
+    }
+
+    /// Adds `other`'s values to this histogram, e.g. to combine the same stat from
+    /// several registries.
+    ///
+    /// Values beyond this histogram's bounds are handled as if they were recorded here (see
+    /// `StatOverflow`). Exemplars are combined, keeping the most recent.
+    pub fn merge(&mut self, other: &HistogramWithSum) {
+        if self.histogram.add(&other.histogram).is_err() {
+            // Values are summed exactly below, rather than as they are recorded here.
+            let sum = self.sum;
+            for v in other.histogram.iter_recorded() {
+                self.record_n(v.value(), v.count_at_value() as u64);
             }
+            self.sum = sum;
         }
+        self.sum = self.sum.saturating_add(other.sum);
+        self.overflowed = self.overflowed.saturating_add(other.overflowed);
+        let mut exemplars = self.exemplars
+            .drain(..)
+            .chain(other.exemplars.iter().cloned())
+            .collect::<Vec<_>>();
+        exemplars.sort_by_key(|e| e.timestamp);
+        for e in exemplars {
+            push_exemplar(&mut self.exemplars, e);
+        }
+        self.created = cmp::min(self.created, other.created);
+        self.percentiles.take();
+    }
+
+    pub fn clear(&mut self) {
+        self.histogram.reset();
+        self.sum = 0;
+        self.overflowed = 0;
+        self.percentiles.take();
+        self.exemplars.clear();
+        self.created = SystemTime::now();
+    }
+
+    /// When the histogram started counting values, i.e. when it was created or last
+    /// cleared (e.g. by `Reporter::take`).
+    pub fn created(&self) -> SystemTime {
+        self.created
     }
 }
 
-#[derive(Clone)]
-pub struct Timer {
-    stat: Stat,
-    unit: TimeUnit,
+/// Summarizes a Stat's values.
+///
+/// If no values were recorded, all fields are zero.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StatSummary {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stddev: f64,
+    /// Pairs of percentiles (from `STANDARD_PERCENTILES`) and their values.
+    pub quantiles: [(f64, u64); 6],
 }
-#[derive(Copy, Clone)]
-pub enum TimeUnit {
-    Millis,
-    Micros,
+
+/// Selects how a Stat's values are stored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum StatBackend {
+    /// A high-precision histogram, guarded by a mutex.
+    #[default]
+    Histogram,
+    /// A bounded, lower-precision histogram of atomic counters, so that recording a value
+    /// never locks.
+    ///
+    /// Values are tracked to within 12.5%. Values greater than the Stat's upper bound are
+    /// counted at the upper bound.
+    AtomicBuckets,
+    /// A DDSketch, guarded by a mutex (see the `sketch` module).
+    ///
+    /// Quantiles are tracked to within a relative error of `10^-precision` (1% by
+    /// default), using far less memory than a histogram when values span many orders of
+    /// magnitude. Values greater than the Stat's upper bound are counted at the upper bound.
+    #[cfg(feature = "sketch")]
+    Sketch,
 }
-impl Timer {
-    pub fn record_since(&self, t0: Instant) {
-        self.stat.add(to_u64(t0, self.unit));
+
+/// Selects what a Stat does with values greater than its upper bound.
+///
+/// Values that are clamped or dropped are counted (see `HistogramWithSum::overflowed`), and
+/// exported by `prometheus` as `<name>_overflowed`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatOverflow {
+    /// Values are dropped. This is the default for `StatBackend::Histogram`.
+    Drop,
+    /// Values are recorded as the upper bound. This is the default for
+    /// `StatBackend::AtomicBuckets`.
+    Clamp,
+    /// The histogram grows to hold the value, as if the Stat were unbounded.
+    ///
+    /// `StatBackend::AtomicBuckets` cannot grow, so clamps values instead.
+    Resize,
+}
+
+/// Configures a Stat.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StatConfig {
+    bounds: Option<(u64, u64)>,
+    unit: Option<TimeUnit>,
+    precision: Option<u32>,
+    backend: StatBackend,
+    overflow: Option<StatOverflow>,
+    adaptive: bool,
+}
+
+impl StatConfig {
+    /// Configures a Stat for latencies from 1us to 60s, recorded in microseconds.
+    pub fn latency_us() -> Self {
+        StatConfig::default()
+            .bounds(1, 60_000_000)
+            .unit(TimeUnit::Micros)
+            .precision(PRESET_PRECISION)
     }
 
-    pub fn time<F>(&self, fut: F) -> Timed<F>
-    where
-        F: Future + 'static,
-    {
-        let stat = self.stat.clone();
-        let unit = self.unit;
-        let f = futures::lazy(move || {
-            // Start timing once the future is actually being invoked (and not
-            // when the object is created).
-            let t0 = Timing::start();
-            fut.then(move |v| {
-                stat.add(to_u64(t0, unit));
-                v
-            })
-        });
-        Timed(Box::new(f))
+    /// Configures a Stat for latencies from 1ms to 60s, recorded in milliseconds.
+    pub fn latency_ms() -> Self {
+        StatConfig::default()
+            .bounds(1, 60_000)
+            .unit(TimeUnit::Millis)
+            .precision(PRESET_PRECISION)
+    }
+
+    /// Configures a Stat for sizes from 1B to 10GB.
+    pub fn bytes() -> Self {
+        StatConfig::default()
+            .bounds(1, 10_000_000_000)
+            .precision(PRESET_PRECISION)
+    }
+
+    /// Bounds the values recorded by the Stat.
+    pub fn bounds(mut self, low: u64, high: u64) -> Self {
+        self.bounds = Some((low, high));
+        self
+    }
+
+    /// Sets the number of significant figures (from 0 to 5) tracked by the Stat's histogram.
+    ///
+    /// Has no effect on `StatBackend::AtomicBuckets`. A `StatBackend::Sketch` tracks
+    /// values to within a relative error of `10^-sigfig`.
+    pub fn precision(mut self, sigfig: u32) -> Self {
+        self.precision = Some(sigfig);
+        self
+    }
+
+    /// Sets the unit in which the Stat records durations.
+    pub fn unit(mut self, unit: TimeUnit) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    /// Selects how the Stat's values are stored.
+    pub fn backend(mut self, backend: StatBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Selects what the Stat does with values greater than its upper bound.
+    pub fn overflow(mut self, overflow: StatOverflow) -> Self {
+        self.overflow = Some(overflow);
+        self
     }
-}
 
-fn to_u64(t0: Instant, unit: TimeUnit) -> u64 {
-    match unit {
-        TimeUnit::Millis => t0.elapsed_ms(),
-        TimeUnit::Micros => t0.elapsed_us(),
+    /// Fits the Stat's histogram to the values observed in its first reporting interval.
+    ///
+    /// An unbounded Stat grows to hold the largest value it records, tracking buckets from
+    /// 1 upward. An adaptive Stat is instead re-created, when its first non-empty report is
+    /// taken, with bounds fitted to the observed minimum and maximum (with a margin), which
+    /// uses considerably less memory for narrow distributions. It still resizes to hold
+    /// larger values thereafter, but values below its fitted lower bound lose precision.
+    ///
+    /// Has no effect on bounded Stats or on `StatBackend::AtomicBuckets`.
+    pub fn adaptive(mut self) -> Self {
+        self.adaptive = true;
+        self
     }
 }
 
-pub struct Timed<F: Future>(Box<Future<Item = F::Item, Error = F::Error>>);
-impl<F: Future> Future for Timed<F> {
-    type Item = F::Item;
-    type Error = F::Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+/// Stores a Stat's values in the registry.
+pub struct StatCell {
+    values: StatValues,
+    /// The unit of durations recorded by the Stat, if any.
+    unit: Option<TimeUnit>,
+}
+
+/// A Stat's values, as stored by its backend.
+enum StatValues {
+    Histogram(Mutex<HistogramWithSum>),
+    AtomicBuckets(atomic_stat::AtomicHistogram),
+    #[cfg(feature = "sketch")]
+    Sketch(Mutex<sketch::SketchCell>),
+}
+
+impl StatValues {
+    fn new(config: &StatConfig) -> Result<StatValues, Error> {
+        match config.backend {
+            StatBackend::Histogram => {
+                let precision = config.precision.unwrap_or(HISTOGRAM_PRECISION);
+                let mut h = HistogramWithSum::try_new(config.bounds, precision)?;
+                if config.bounds.is_some() {
+                    h.set_overflow(config.overflow.unwrap_or(StatOverflow::Drop));
+                } else {
+                    h.adaptive = config.adaptive;
+                }
+                Ok(StatValues::Histogram(Mutex::new(h)))
+            }
+            StatBackend::AtomicBuckets => {
+                let clamp = config.overflow != Some(StatOverflow::Drop);
+                Ok(StatValues::AtomicBuckets(
+                    atomic_stat::AtomicHistogram::new(config.bounds, clamp),
+                ))
+            }
+            #[cfg(feature = "sketch")]
+            StatBackend::Sketch => {
+                let precision = config.precision.unwrap_or(sketch::SKETCH_PRECISION);
+                let clamp = config.overflow != Some(StatOverflow::Drop);
+                let s = sketch::SketchCell::new(config.bounds, precision, clamp)?;
+                Ok(StatValues::Sketch(Mutex::new(s)))
+            }
+        }
     }
+
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use test::Bencher;
+impl StatCell {
+    fn new(config: &StatConfig) -> Result<StatCell, Error> {
+        Ok(StatCell {
+            values: StatValues::new(config)?,
+            unit: config.unit,
+        })
+    }
 
-    static DEFAULT_METRIC_NAME: &'static str = "a_sufficiently_long_name";
+    /// The unit the Stat was configured with, if any.
+    fn unit(&self) -> Option<TimeUnit> {
+        self.unit
+    }
 
-    #[bench]
-    fn bench_scope_clone(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        b.iter(move || { let _ = metrics.clone(); });
+    /// The bounds the Stat was configured with, if any.
+    fn bounds(&self) -> Option<(u64, u64)> {
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                h.lock().expect("failed to obtain lock for stat").bounds
+            }
+            StatValues::AtomicBuckets(ref h) => h.bounds(),
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => s.lock().expect("failed to obtain lock for stat").bounds(),
+        }
     }
 
-    #[bench]
-    fn bench_scope_label(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        b.iter(move || { let _ = metrics.clone().labeled("foo", "bar"); });
+    fn record(&self, vs: &[u64]) {
+        self.record_iter(vs.iter().cloned())
     }
 
-    #[bench]
-    fn bench_scope_clone_x1000(b: &mut Bencher) {
-        let scopes = mk_scopes(1000, "bench_scope_clone_x1000");
-        b.iter(move || for scope in &scopes {
-            let _ = scope.clone();
-        });
+    /// Records each value, acquiring the histogram's lock only once.
+    fn record_iter<I: Iterator<Item = u64>>(&self, vs: I) {
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                let mut histo = h.lock().expect("failed to obtain lock for stat");
+                for v in vs {
+                    histo.record(v);
+                }
+            }
+            StatValues::AtomicBuckets(ref h) => {
+                for v in vs {
+                    h.record(v);
+                }
+            }
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => {
+                let mut sketch = s.lock().expect("failed to obtain lock for stat");
+                for v in vs {
+                    sketch.record(v);
+                }
+            }
+        }
     }
 
-    #[bench]
-    fn bench_scope_label_x1000(b: &mut Bencher) {
-        let scopes = mk_scopes(1000, "bench_scope_label_x1000");
+    /// Records `v`, as well as the values that would have been recorded had measurement
+    /// not been delayed beyond `expected_interval` (i.e. `v - expected_interval`,
+    /// `v - 2 * expected_interval`, and so on while at least `expected_interval`).
+    ///
+    /// This is equivalent to hdrsample's `record_correct`, but respects the Stat's overflow
+    /// behavior and maintains its sum.
+    fn record_corrected(&self, v: u64, expected_interval: u64) {
+        let each = |record: &mut dyn FnMut(u64)| {
+            record(v);
+            if expected_interval == 0 {
+                return;
+            }
+            let mut missing = v.saturating_sub(expected_interval);
+            while missing >= expected_interval {
+                record(missing);
+                missing -= expected_interval;
+            }
+        };
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                let mut histo = h.lock().expect("failed to obtain lock for stat");
+                each(&mut |v| histo.record(v));
+            }
+            StatValues::AtomicBuckets(ref h) => each(&mut |v| h.record(v)),
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => {
+                let mut sketch = s.lock().expect("failed to obtain lock for stat");
+                each(&mut |v| sketch.record(v));
+            }
+        }
+    }
+
+    /// Records `v`, retaining `exemplar` as one of the Stat's recent exemplars.
+    fn record_exemplar(&self, v: u64, expected_interval: Option<u64>, exemplar: Exemplar) {
+        match expected_interval {
+            Some(i) => self.record_corrected(v, i),
+            None => self.record(&[v]),
+        }
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                let mut histo = h.lock().expect("failed to obtain lock for stat");
+                push_exemplar(&mut histo.exemplars, exemplar);
+            }
+            StatValues::AtomicBuckets(ref h) => h.push_exemplar(exemplar),
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => {
+                s.lock().expect("failed to obtain lock for stat").push_exemplar(exemplar);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                h.lock().expect("failed to obtain lock for stat").clear();
+            }
+            StatValues::AtomicBuckets(ref h) => {
+                h.snapshot(true);
+            }
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => {
+                s.lock().expect("failed to obtain lock for stat").clear();
+            }
+        }
+    }
+
+    /// Approximates the memory used by the Stat's values, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                h.lock().expect("failed to obtain lock for stat").memory_bytes()
+            }
+            StatValues::AtomicBuckets(ref h) => h.memory_bytes(),
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => {
+                s.lock().expect("failed to obtain lock for stat").memory_bytes()
+            }
+        }
+    }
+
+    /// Copies the Stat's current values, optionally clearing them.
+    pub fn snapshot(&self, clear: bool) -> HistogramWithSum {
+        match self.values {
+            StatValues::Histogram(ref h) => {
+                let mut orig = h.lock().expect("failed to obtain lock for stat");
+                let snap = orig.clone();
+                if clear {
+                    orig.clear();
+                    if orig.adaptive && snap.count() > 0 {
+                        orig.fit(snap.min(), snap.max());
+                    }
+                }
+                snap
+            }
+            StatValues::AtomicBuckets(ref h) => h.snapshot(clear),
+            #[cfg(feature = "sketch")]
+            StatValues::Sketch(ref s) => {
+                s.lock().expect("failed to obtain lock for stat").snapshot(clear)
+            }
+        }
+    }
+}
+
+/// Caputres a distribution of values.
+#[derive(Clone)]
+pub struct Stat {
+    cell: Weak<StatCell>,
+    key: Arc<Key>,
+    bounds: Option<(u64, u64)>,
+    unit: Option<TimeUnit>,
+    /// If set, values are corrected for coordinated omission.
+    expected_interval: Option<u64>,
+    capture: Option<Arc<test::Samples>>,
+}
+
+impl Stat {
+    /// Identifies the stat.
+    pub fn key(&self) -> &Arc<Key> {
+        &self.key
+    }
+
+    /// The bounds the Stat was created with, if any.
+    ///
+    /// A handle on an existing Stat has that Stat's bounds, regardless of those requested.
+    pub fn bounds(&self) -> Option<(u64, u64)> {
+        self.bounds
+    }
+
+    pub fn add(&self, v: u64) {
+        if let Some(c) = self.cell.upgrade() {
+            self.captured(&[v]);
+            match self.expected_interval {
+                Some(i) => c.record_corrected(v, i),
+                None => c.record(&[v]),
+            }
+        }
+    }
+
+    /// Records `v`, along with an exemplar linking it to the trace `trace_id`.
+    ///
+    /// Each Stat retains up to `MAX_EXEMPLARS` of its most recent exemplars until it is
+    /// next cleared. Exemplars are exported by `prometheus::openmetrics`.
+    pub fn add_with_exemplar(&self, v: u64, trace_id: &str) {
+        if let Some(c) = self.cell.upgrade() {
+            let exemplar = Exemplar {
+                value: v,
+                trace_id: trace_id.to_string(),
+                timestamp: SystemTime::now(),
+            };
+            self.captured(&[v]);
+            c.record_exemplar(v, self.expected_interval, exemplar);
+        }
+    }
+
+    pub fn add_values(&mut self, vs: &[u64]) {
+        if let Some(c) = self.cell.upgrade() {
+            self.captured(vs);
+            match self.expected_interval {
+                Some(i) => for v in vs {
+                    c.record_corrected(*v, i)
+                },
+                None => c.record(vs),
+            }
+        }
+    }
+
+    /// Discards the Stat's values.
+    ///
+    /// Values are otherwise cleared when a report is taken, so this is intended for
+    /// isolating test cases rather than for use by instrumented code.
+    pub fn clear(&self) {
+        if let Some(c) = self.cell.upgrade() {
+            c.clear();
+        }
+    }
+
+    /// Returns a handle on this Stat that corrects values for coordinated omission.
+    ///
+    /// When a load generator waits for each (slow) response before sending the next
+    /// request, the requests it would otherwise have sent in the meantime are never
+    /// measured, so tail latencies are understated. If requests are expected every
+    /// `expected_interval`, each value greater than that interval is recorded along with the
+    /// values that the omitted requests would have observed: `v - expected_interval`,
+    /// `v - 2 * expected_interval`, and so on.
+    ///
+    /// This is only meaningful for load tests; it should not be used for measuring
+    /// production traffic. An interval of 0 disables correction.
+    pub fn corrected(mut self, expected_interval: u64) -> Stat {
+        self.expected_interval = if expected_interval == 0 {
+            None
+        } else {
+            Some(expected_interval)
+        };
+        self
+    }
+
+    /// Records a `Duration`, converted to the Stat's time unit.
+    ///
+    /// Only Stats created with a time unit (e.g. via `Scope::timer_us`) may record
+    /// durations. Otherwise, the value is dropped (and debug builds panic), since there is
+    /// no way to know how the stat's other values are scaled.
+    pub fn add_duration(&self, d: Duration) {
+        match self.unit {
+            Some(unit) => self.add(unit.convert(d)),
+            None => {
+                debug_assert!(false, "durations may only be added to stats with a unit");
+                error!("dropping duration recorded on a stat without a unit");
+            }
+        }
+    }
+
+    /// Records each `Duration`, converted to the Stat's time unit, acquiring the Stat's lock
+    /// only once.
+    ///
+    /// As with `add_duration`, the Stat must have been created with a time unit.
+    pub fn add_durations<I: IntoIterator<Item = Duration>>(&self, ds: I) {
+        let unit = match self.unit {
+            Some(unit) => unit,
+            None => {
+                debug_assert!(false, "durations may only be added to stats with a unit");
+                error!("dropping durations recorded on a stat without a unit");
+                return;
+            }
+        };
+        if let Some(c) = self.cell.upgrade() {
+            let vs = ds.into_iter().map(|d| unit.convert(d)).inspect(|v| self.captured(&[*v]));
+            match self.expected_interval {
+                Some(i) => for v in vs {
+                    c.record_corrected(v, i)
+                },
+                None => c.record_iter(vs),
+            }
+        }
+    }
+
+    /// The unit of durations recorded by this Stat, if any.
+    pub fn unit(&self) -> Option<TimeUnit> {
+        self.unit
+    }
+
+    /// Retains recorded values, if the registry captures them.
+    fn captured(&self, vs: &[u64]) {
+        if let Some(ref samples) = self.capture {
+            samples.push(&self.key, vs);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Timer {
+    stat: Stat,
+}
+
+/// A request counter paired with a latency timer. See `Scope::timed_counter`.
+#[derive(Clone, Debug)]
+pub struct TimedCounter {
+    counter: Counter,
+    timer: Timer,
+}
+
+impl TimedCounter {
+    pub fn counter(&self) -> &Counter {
+        &self.counter
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// Counts a request that took `d`.
+    pub fn observe(&self, d: Duration) {
+        self.counter.incr(1);
+        self.timer.record(d);
+    }
+
+    /// Counts a request that started at `t0`.
+    pub fn observe_since(&self, t0: Instant) {
+        self.observe(t0.elapsed());
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimeUnit {
+    Millis,
+    Micros,
+}
+impl TimeUnit {
+    fn convert(self, d: Duration) -> u64 {
+        match self {
+            TimeUnit::Millis => d.elapsed_ms(),
+            TimeUnit::Micros => d.elapsed_us(),
+        }
+    }
+}
+impl fmt::Debug for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let count = self.cell.upgrade().map(|c| c.snapshot(false).count());
+        f.debug_struct("Stat")
+            .field("key", &format_args!("{}", self.key))
+            .field("count", &count)
+            .field("unit", &self.unit)
+            .finish()
+    }
+}
+
+impl fmt::Display for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.key, f)
+    }
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Timer").field(&self.stat).finish()
+    }
+}
+
+impl fmt::Display for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.stat, f)
+    }
+}
+
+impl Timer {
+    /// Identifies the timer.
+    pub fn key(&self) -> &Arc<Key> {
+        self.stat.key()
+    }
+
+    /// Returns a handle on this Timer that corrects durations for coordinated omission,
+    /// given the interval at which operations are expected to start.
+    ///
+    /// See `Stat::corrected`.
+    pub fn corrected(self, expected_interval: Duration) -> Timer {
+        let interval = match self.stat.unit {
+            Some(unit) => unit.convert(expected_interval),
+            None => 0,
+        };
+        Timer { stat: self.stat.corrected(interval) }
+    }
+
+    pub fn record_since(&self, t0: Instant) {
+        self.stat.add_duration(t0.elapsed());
+    }
+
+    pub fn record(&self, d: Duration) {
+        self.stat.add_duration(d);
+    }
+
+    /// Records each of `ds`, acquiring the underlying Stat's lock only once.
+    pub fn record_each(&self, ds: &[Duration]) {
+        self.stat.add_durations(ds.iter().cloned());
+    }
+
+    /// Records the time taken for `fut` to complete, measured from when it is first polled.
+    #[cfg(feature = "async")]
+    pub fn time<F>(&self, fut: F) -> Timed<F>
+    where
+        F: Future,
+    {
+        Timed {
+            inner: fut,
+            stat: self.stat.clone(),
+            t0: None,
+        }
+    }
+}
+
+/// A future that records the time taken for its inner future to complete.
+///
+/// `Timed<F>` is `Send` (and `Sync`) when `F` is.
+#[cfg(feature = "async")]
+pub struct Timed<F> {
+    inner: F,
+    stat: Stat,
+    t0: Option<Instant>,
+}
+
+#[cfg(feature = "async")]
+impl<F> Timed<F> {
+    /// Stops timing, returning the inner future.
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F: Future> Future for Timed<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Start timing once the future is actually being invoked (and not
+        // when the object is created).
+        let t0 = *self.t0.get_or_insert_with(Instant::now);
+        let res = self.inner.poll();
+        match res {
+            Ok(Async::NotReady) => {}
+            _ => self.stat.add_duration(t0.elapsed()),
+        }
+        res
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F: fmt::Debug> fmt::Debug for Timed<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtest::Bencher;
+    use std::thread;
+
+    static DEFAULT_METRIC_NAME: &str = "a_sufficiently_long_name";
+
+    #[bench]
+    fn bench_scope_clone(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        b.iter(move || { let _ = metrics.clone(); });
+    }
+
+    #[bench]
+    fn bench_scope_label(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        b.iter(move || { let _ = metrics.clone().labeled("foo", "bar"); });
+    }
+
+    #[bench]
+    fn bench_scope_clone_x1000(b: &mut Bencher) {
+        let scopes = mk_scopes(1000, "bench_scope_clone_x1000");
+        b.iter(move || for scope in &scopes {
+            let _ = scope.clone();
+        });
+    }
+
+    #[bench]
+    fn bench_scope_label_x1000(b: &mut Bencher) {
+        let scopes = mk_scopes(1000, "bench_scope_label_x1000");
         b.iter(move || for scope in &scopes {
             let _ = scope.clone().labeled("foo", "bar");
         });
     }
 
-    #[bench]
-    fn bench_counter_create(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        b.iter(move || { let _ = metrics.counter(DEFAULT_METRIC_NAME); });
+    #[bench]
+    fn bench_counter_create(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        b.iter(move || { let _ = metrics.counter(DEFAULT_METRIC_NAME); });
+    }
+
+    #[bench]
+    fn bench_gauge_create(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        b.iter(move || { let _ = metrics.gauge(DEFAULT_METRIC_NAME); });
+    }
+
+    #[bench]
+    fn bench_stat_create(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        b.iter(move || { let _ = metrics.stat(DEFAULT_METRIC_NAME); });
+    }
+
+    #[bench]
+    fn bench_counter_lookup_deep(b: &mut Bencher) {
+        // Lookups of existing metrics hash the Key once, at construction, regardless of
+        // how deep the prefix is.
+        let (metrics, _) = super::new();
+        let metrics = (0..16).fold(metrics.labeled("foo", "bar").labeled("bah", "baz"), |m, _| {
+            m.prefixed("a_sufficiently_long_prefix")
+        });
+        let _c = metrics.counter(DEFAULT_METRIC_NAME);
+        b.iter(move || { let _ = metrics.counter(DEFAULT_METRIC_NAME); });
+    }
+
+    #[bench]
+    fn bench_counter_create_x1000(b: &mut Bencher) {
+        let scopes = mk_scopes(1000, "bench_counter_create_x1000");
+        b.iter(move || for scope in &scopes {
+            scope.counter(DEFAULT_METRIC_NAME);
+        });
+    }
+
+    #[bench]
+    fn bench_gauge_create_x1000(b: &mut Bencher) {
+        let scopes = mk_scopes(1000, "bench_gauge_create_x1000");
+        b.iter(move || for scope in &scopes {
+            scope.gauge(DEFAULT_METRIC_NAME);
+        });
+    }
+
+    #[bench]
+    fn bench_stat_create_x1000(b: &mut Bencher) {
+        let scopes = mk_scopes(1000, "bench_stat_create_x1000");
+        b.iter(move || for scope in &scopes {
+            scope.stat(DEFAULT_METRIC_NAME);
+        });
+    }
+
+    #[bench]
+    fn bench_counter_update(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        let c = metrics.counter(DEFAULT_METRIC_NAME);
+        b.iter(move || c.incr(1));
+    }
+
+    #[bench]
+    fn bench_counter_striped_update(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        let c = metrics.counter_striped(DEFAULT_METRIC_NAME);
+        b.iter(move || c.incr(1));
+    }
+
+    #[bench]
+    fn bench_gauge_update(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        let g = metrics.gauge(DEFAULT_METRIC_NAME);
+        b.iter(move || g.set(1));
+    }
+
+    #[bench]
+    fn bench_stat_update(b: &mut Bencher) {
+        let (metrics, _) = super::new();
+        let s = metrics.stat(DEFAULT_METRIC_NAME);
+        b.iter(move || s.add(1));
+    }
+
+    #[bench]
+    fn bench_counter_update_x1000(b: &mut Bencher) {
+        let counters: Vec<Counter> = mk_scopes(1000, "bench_counter_update_x1000")
+            .iter()
+            .map(|s| s.counter(DEFAULT_METRIC_NAME))
+            .collect();
+        b.iter(move || for c in &counters {
+            c.incr(1)
+        });
+    }
+
+    #[bench]
+    fn bench_gauge_update_x1000(b: &mut Bencher) {
+        let gauges: Vec<Gauge> = mk_scopes(1000, "bench_gauge_update_x1000")
+            .iter()
+            .map(|s| s.gauge(DEFAULT_METRIC_NAME))
+            .collect();
+        b.iter(move || for g in &gauges {
+            g.set(1)
+        });
+    }
+
+    #[bench]
+    fn bench_stat_update_x1000(b: &mut Bencher) {
+        let stats: Vec<Stat> = mk_scopes(1000, "bench_stat_update_x1000")
+            .iter()
+            .map(|s| s.stat(DEFAULT_METRIC_NAME))
+            .collect();
+        b.iter(move || for s in &stats {
+            s.add(1)
+        });
+    }
+
+    #[bench]
+    fn bench_stat_add_x1000(b: &mut Bencher) {
+        let s = {
+            let (metrics, _) = super::new();
+            metrics.stat(DEFAULT_METRIC_NAME)
+        };
+        b.iter(move || for i in 0..1000 {
+            s.add(i)
+        });
+    }
+
+    /// The number of threads contending in each iteration of a contention benchmark.
+    const CONTENDING_THREADS: usize = 4;
+
+    /// Benchmarks `CONTENDING_THREADS` threads each invoking `f` 1000 times.
+    ///
+    /// Each iteration includes the cost of spawning the threads, so contention benchmarks
+    /// are only comparable with each other.
+    fn bench_contended<F: Fn(usize, u64) + Sync>(b: &mut Bencher, f: F) {
+        b.iter(|| {
+            thread::scope(|s| for t in 0..CONTENDING_THREADS {
+                let f = &f;
+                s.spawn(move || for i in 0..1000 {
+                    f(t, i)
+                });
+            })
+        });
+    }
+
+    #[bench]
+    fn bench_counter_incr_contended(b: &mut Bencher) {
+        let (metrics, _reporter) = super::new();
+        let c = metrics.counter(DEFAULT_METRIC_NAME);
+        bench_contended(b, |_, _| c.incr(1));
+    }
+
+    #[bench]
+    fn bench_counter_striped_incr_contended(b: &mut Bencher) {
+        let (metrics, _reporter) = super::new();
+        let c = metrics.counter_striped(DEFAULT_METRIC_NAME);
+        bench_contended(b, |_, _| c.incr(1));
+    }
+
+    #[bench]
+    fn bench_stat_add_contended(b: &mut Bencher) {
+        let (metrics, _reporter) = super::new();
+        let s = metrics.stat(DEFAULT_METRIC_NAME);
+        bench_contended(b, |_, i| s.add(i));
+    }
+
+    #[bench]
+    fn bench_stat_add_contended_atomic(b: &mut Bencher) {
+        let (metrics, _reporter) = super::new();
+        let config = StatConfig::default().backend(StatBackend::AtomicBuckets);
+        let s = metrics.stat_with_config(DEFAULT_METRIC_NAME, config);
+        bench_contended(b, |_, i| s.add(i));
+    }
+
+    #[bench]
+    fn bench_stat_add_distinct(b: &mut Bencher) {
+        let (metrics, _reporter) = super::new();
+        let stats = (0..CONTENDING_THREADS)
+            .map(|t| metrics.clone().labeled("thread", t).stat(DEFAULT_METRIC_NAME))
+            .collect::<Vec<_>>();
+        bench_contended(b, |t, i| stats[t].add(i));
+    }
+
+    fn mk_scopes(n: usize, name: &'static str) -> Vec<Scope> {
+        let (metrics, _) = super::new();
+        let metrics = metrics.prefixed("t").labeled("test_name", name).labeled(
+            "total_iterations",
+            n,
+        );
+        (0..n)
+            .map(|i| metrics.clone().labeled("iteration", i))
+            .collect()
+    }
+
+    #[test]
+    fn test_report_peek() {
+        let (metrics, reporter) = super::new();
+        let metrics = metrics.labeled("joy", "painting");
+
+        let happy_accidents = metrics.counter("happy_accidents");
+        let paint_level = metrics.gauge("paint_level");
+        let mut stroke_len = metrics.stat("stroke_len");
+
+        happy_accidents.incr(1);
+        paint_level.set(2);
+        stroke_len.add_values(&[1, 2, 3]);
+
+        {
+            let report = reporter.peek();
+            {
+                let k = report
+                    .counters()
+                    .keys()
+                    .find(|k| k.name() == "happy_accidents")
+                    .expect("expected counter: happy_accidents");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.counters().get(k), Some(&1));
+            }
+            {
+                let k = report
+                    .gauges()
+                    .keys()
+                    .find(|k| k.name() == "paint_level")
+                    .expect("expected gauge: paint_level");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.gauges().get(k), Some(&2));
+            }
+            assert_eq!(
+                report.gauges().keys().find(|k| k.name() == "brush_width"),
+                None
+            );
+            {
+                let k = report
+                    .stats()
+                    .keys()
+                    .find(|k| k.name() == "stroke_len")
+                    .expect("expected stat: stroke_len");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
+            }
+            assert_eq!(report.stats().keys().find(|k| k.name() == "tree_len"), None);
+        }
+
+        drop(paint_level);
+        let brush_width = metrics.gauge("brush_width");
+        let mut tree_len = metrics.stat("tree_len");
+
+        happy_accidents.incr(2);
+        brush_width.set(5);
+        stroke_len.add_values(&[1, 2, 3]);
+        tree_len.add_values(&[3, 4, 5]);
+
+        {
+            let report = reporter.peek();
+            {
+                let k = report
+                    .counters()
+                    .keys()
+                    .find(|k| k.name() == "happy_accidents")
+                    .expect("expected counter: happy_accidents");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.counters().get(k), Some(&3));
+            }
+            {
+                let k = report
+                    .gauges()
+                    .keys()
+                    .find(|k| k.name() == "paint_level")
+                    .expect("expected gauge: paint_level");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.gauges().get(k), Some(&2));
+            }
+            {
+                let k = report
+                    .gauges()
+                    .keys()
+                    .find(|k| k.name() == "brush_width")
+                    .expect("expected gauge: brush_width");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.gauges().get(k), Some(&5));
+            }
+            {
+                let k = report
+                    .stats()
+                    .keys()
+                    .find(|k| k.name() == "stroke_len")
+                    .expect("expected stat: stroke_len");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
+            }
+            {
+                let k = report
+                    .stats()
+                    .keys()
+                    .find(|k| k.name() == "tree_len")
+                    .expect("expected stat: tree_len");
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_gauge_set_duration() {
+        let (metrics, reporter) = super::new();
+        let lag = metrics.gauge("lag");
+        lag.set_duration(Duration::new(2, 500_000_000), TimeUnit::Millis);
+        assert_eq!(reporter.peek().get_gauge("lag", &[]), Some(2_500));
+        lag.set_duration(Duration::from_micros(1_234), TimeUnit::Micros);
+        assert_eq!(reporter.peek().get_gauge("lag", &[]), Some(1_234));
+    }
+
+    #[test]
+    fn test_timed_counter() {
+        let (metrics, reporter) = super::new();
+        let http = metrics
+            .labeled("status", 200)
+            .timed_counter("http");
+        http.observe(Duration::from_millis(3));
+        http.observe(Duration::from_millis(5));
+
+        let report = reporter.peek();
+        assert_eq!(report.get_counter("requests", &[("status", "200")]), Some(2));
+        let h = report.get_stat("latency_ms", &[("status", "200")]).unwrap();
+        assert_eq!((h.count(), h.max()), (2, 5));
+        // Both names are derived from the one given, so they can't collide or drift apart.
+        assert_eq!(http.counter().key().prefix().values(), vec!["http"]);
+        assert_eq!(http.timer().key().prefix(), http.counter().key().prefix());
+    }
+
+    #[test]
+    fn test_gauge_add_signed() {
+        let (metrics, reporter) = super::new();
+        let backlog = metrics.gauge("backlog");
+
+        backlog.add_signed(5);
+        backlog.add_signed(-3);
+        {
+            let report = reporter.peek();
+            let k = report
+                .gauges()
+                .keys()
+                .find(|k| k.name() == "backlog")
+                .expect("expected gauge: backlog");
+            assert_eq!(report.gauges().get(k), Some(&2));
+        }
+
+        // Underflow is clamped at zero rather than wrapping.
+        backlog.add_signed(-10);
+        {
+            let report = reporter.peek();
+            let k = report
+                .gauges()
+                .keys()
+                .find(|k| k.name() == "backlog")
+                .expect("expected gauge: backlog");
+            assert_eq!(report.gauges().get(k), Some(&0));
+        }
+
+        backlog.set(usize::MAX - 1);
+        backlog.add_signed(10);
+        {
+            let report = reporter.peek();
+            let k = report
+                .gauges()
+                .keys()
+                .find(|k| k.name() == "backlog")
+                .expect("expected gauge: backlog");
+            assert_eq!(report.gauges().get(k), Some(&usize::MAX));
+        }
+    }
+
+    #[test]
+    fn test_apply_signed() {
+        assert_eq!(apply_signed(0, 0), 0);
+        assert_eq!(apply_signed(3, 4), 7);
+        assert_eq!(apply_signed(3, -4), 0);
+        assert_eq!(apply_signed(3, i64::MIN), 0);
+        assert_eq!(apply_signed(3, i64::MAX), 3 + i64::MAX as usize);
+    }
+
+    #[test]
+    fn test_stat_add_duration() {
+        let (metrics, reporter) = super::new();
+        let us = metrics.timer_us("latency_us");
+        let ms = metrics.stat_with_unit("latency_ms", TimeUnit::Millis);
+        assert_eq!(ms.unit(), Some(TimeUnit::Millis));
+
+        us.record(Duration::from_millis(3));
+        ms.add_duration(Duration::from_millis(3));
+
+        let report = reporter.peek();
+        for (k, h) in report.stats() {
+            match k.name() {
+                "latency_us" => assert_eq!(h.max(), 3_000),
+                "latency_ms" => assert_eq!(h.max(), 3),
+                name => panic!("unexpected stat: {}", name),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_stat_add_duration_without_unit() {
+        let (metrics, _) = super::new();
+        metrics.stat("unitless").add_duration(Duration::from_millis(3));
+    }
+
+    #[test]
+    fn test_scope_detached() {
+        let (metrics, reporter) = super::new();
+        let ours = metrics.clone().prefixed("studio").labeled("joy", "painting");
+        let detached = ours.detached();
+        assert!(detached.labels().is_empty());
+        assert_eq!(*detached.prefix, Prefix::Root);
+        detached.counter("canvases").incr(1);
+        assert_eq!(reporter.get_counter_by_key(&metrics.key("canvases")), Some(1));
+
+        // Clones share labels until either adds one.
+        let theirs = ours.clone();
+        assert!(Arc::ptr_eq(&ours.labels, &theirs.labels));
+
+        let theirs = theirs.labeled("plugin", "trees");
+        let ours = ours.labeled("brush", "fan");
+        assert_eq!(ours.labels().get("plugin"), None);
+        assert_eq!(theirs.labels().get("brush"), None);
+        assert_eq!(theirs.labels().get("joy"), Some(&"painting".into()));
+
+        // Keys share their scope's labels.
+        let _c = ours.counter("happy_accidents");
+        let reg = ours.registry.read().unwrap();
+        let (k, _) = reg.counters.get_key_value(&ours.key("happy_accidents")).unwrap();
+        assert!(Arc::ptr_eq(&k.labels, &ours.labels));
+    }
+
+    #[test]
+    fn test_key_hash() {
+        let (metrics, _) = super::new();
+        let a = metrics.clone().prefixed("a").labeled("x", 1);
+        let b = metrics.prefixed("a").labeled("x", 1);
+        let ka = Key::new("n", a.prefix.clone(), a.labels.clone());
+        let kb = Key::new("n", b.prefix.clone(), b.labels.clone());
+        assert_eq!(ka, kb);
+        assert_eq!(ka.hash, kb.hash);
+
+        let kc = Key::new("n", b.prefixed("c").prefix, a.labels.clone());
+        assert!(ka != kc);
+        assert!(ka.hash != kc.hash);
+    }
+
+    #[test]
+    fn test_report_shares_keys() {
+        let (metrics, reporter) = super::new();
+        let _c = metrics.labeled("joy", "painting").counter("happy_accidents");
+
+        let r0 = reporter.peek();
+        let r1 = reporter.peek();
+        let k0 = r0.counters().keys().next().expect("expected counter");
+        let k1 = r1.counters().keys().next().expect("expected counter");
+        assert!(Arc::ptr_eq(k0, k1));
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_report_view() {
+        let (metrics, reporter) = super::new();
+        let metrics = metrics.labeled("joy", "painting");
+        metrics.counter("happy_accidents").incr(1);
+        metrics.gauge("paint_level").set(2);
+        metrics.stat("stroke_len").add_values(&[1, 2, 3]);
+        let (_c, _g, _s) = (
+            metrics.counter("happy_accidents"),
+            metrics.gauge("paint_level"),
+            metrics.stat("stroke_len"),
+        );
+
+        let viewed = reporter.with_report(|view| {
+            let mut out = String::new();
+            prometheus::write_view(&mut out, view).unwrap();
+            out
+        });
+        assert_eq!(viewed, prometheus::string(&reporter.peek()).unwrap());
+    }
+
+    #[test]
+    fn test_peek_light() {
+        let (metrics, reporter) = super::new();
+        let mut reporter = reporter.with_light_reports();
+        let requests = metrics.counter("requests");
+        let mut latency = metrics.stat("latency_ms");
+        requests.incr(2);
+        latency.add_values(&[3, 5]);
+
+        // Summaries are cached by full reports.
+        let light = reporter.peek_light();
+        assert_eq!(light.get_counter("requests", &[]), Some(2));
+        assert!(light.stat_summaries().is_empty());
+        reporter.peek();
+        let s = *reporter.peek_light().get_stat_summary("latency_ms", &[]).unwrap();
+        assert_eq!((s.count, s.sum, s.max), (2, 8, 5));
+
+        // Counters are current, but summaries are those of the last full report.
+        requests.incr(1);
+        latency.add(7);
+        let light = reporter.peek_light();
+        assert_eq!(light.get_counter("requests", &[]), Some(3));
+        assert_eq!(light.get_stat_summary("latency_ms", &[]).unwrap().count, 2);
+        reporter.take();
+        assert_eq!(reporter.peek_light().get_stat_summary("latency_ms", &[]).unwrap().count, 3);
+
+        drop(latency);
+        reporter.take();
+        reporter.take();
+        assert!(reporter.peek_light().stat_summaries().is_empty());
+        let (_, plain) = super::new();
+        assert!(plain.peek_light().stat_summaries().is_empty());
+    }
+
+    #[test]
+    fn test_report_series_budget() {
+        let (metrics, reporter) = super::new();
+        let mut reporter = reporter.with_series_budget(4);
+
+        let _handles = (0..3)
+            .map(|i| metrics.clone().labeled("id", i).counter("exploding"))
+            .collect::<Vec<_>>();
+        let _g = metrics.gauge("paint_level");
+        let _s = metrics.stat("stroke_len");
+
+        // 3 "exploding" + 1 gauge + 1 stat + 1 "truncated_series" = 6 series.
+        let report = reporter.peek();
+        assert_eq!(report.len(), 3);
+        assert_eq!(report.counters().keys().find(|k| k.name() == "exploding"), None);
+
+        // Peeks aren't counted, so that peeking often doesn't inflate the count.
+        let truncated = |report: &Report| {
+            let k = report
+                .counters()
+                .keys()
+                .find(|k| k.name() == "truncated_series")
+                .expect("expected counter: truncated_series");
+            report.counters()[k]
+        };
+        assert_eq!(truncated(&reporter.peek()), 0);
+
+        // The next report reflects the series dropped from a taken one.
+        assert_eq!(reporter.take().len(), 3);
+        assert_eq!(truncated(&reporter.peek()), 3);
+        assert_eq!(truncated(&reporter.peek()), 3);
+    }
+
+    #[test]
+    fn test_stat_count_below() {
+        let (metrics, reporter) = super::new();
+        let latency = metrics.stat("latency_ms");
+        {
+            let report = reporter.peek();
+            let h = report.stats().values().next().expect("expected stat");
+            assert_eq!(h.count_below(250), 0);
+            assert_eq!(h.fraction_below(250), 1.0);
+        }
+
+        for v in 1..101 {
+            latency.add(v * 5);
+        }
+        let report = reporter.peek();
+        let h = report.stats().values().next().expect("expected stat");
+        assert_eq!(h.count_below(0), 0);
+        assert_eq!(h.count_below(250), 50);
+        assert_eq!(h.count_below(10_000), 100);
+        assert_eq!(h.fraction_below(250), 0.5);
+        assert_eq!(h.fraction_below(10_000), 1.0);
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_stat_atomic_buckets() {
+        let (metrics, mut reporter) = super::new();
+        let config = StatConfig::default().backend(StatBackend::AtomicBuckets);
+        let mut latency = metrics.stat_with_config("latency_ms", config);
+        latency.add_values(&[1, 10, 100]);
+
+        let out = reporter.with_report(|view| {
+            let mut out = String::new();
+            prometheus::write_view(&mut out, view).unwrap();
+            out
+        });
+        assert!(out.contains("latency_ms_count 3\n"));
+        assert!(out.contains("latency_ms_sum 111\n"));
+
+        let report = reporter.take();
+        let h = report.stats().values().next().expect("expected stat");
+        assert_eq!(h.count(), 3);
+        assert_eq!(h.sum(), 111);
+        assert_eq!(h.min(), 1);
+        assert_eq!(h.count_below(10), 2);
+
+        let report = reporter.take();
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(0));
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_timed() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let (metrics, reporter) = super::new();
+        let timer = metrics.timer_us("latency_us");
+        let timed = timer.time(futures::future::ok::<_, ()>(7));
+        assert_send_sync(&timed);
+        assert_eq!(format!("{:?}", timed), format!("{:?}", futures::future::ok::<_, ()>(7)));
+        assert_eq!(timed.wait(), Ok(7));
+
+        let failed = timer.time(futures::future::err::<(), _>("oops"));
+        assert_eq!(failed.wait(), Err("oops"));
+
+        let unpolled = timer.time(futures::future::ok::<_, ()>(8));
+        assert_eq!(unpolled.into_inner().wait(), Ok(8));
+
+        let report = reporter.peek();
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(2));
+    }
+
+    #[test]
+    fn test_stat_presets() {
+        let (metrics, reporter) = super::new();
+        let latency = metrics.latency_stat_us("latency_us");
+        assert_eq!(latency.unit(), Some(TimeUnit::Micros));
+        latency.add_duration(Duration::from_secs(30));
+        assert_eq!(metrics.latency_stat_ms("latency_ms").unit(), Some(TimeUnit::Millis));
+        let bytes = metrics.bytes_stat("payload_bytes");
+        assert_eq!(bytes.unit(), None);
+        bytes.add(5_000_000_000);
+
+        let report = reporter.peek();
+        let mut stats = report.stats().values();
+        let h = stats.next().expect("expected latency stat");
+        assert_eq!(h.count(), 1);
+        assert!(h.max() >= 30_000_000);
+        assert!(h.max() - 30_000_000 <= 30_000);
+        stats.next().expect("expected latency stat");
+        let h = stats.next().expect("expected bytes stat");
+        assert_eq!(h.count(), 1);
+        assert!(h.max() - 5_000_000_000 <= 5_000_000);
+    }
+
+    #[test]
+    fn test_report_take_subtree() {
+        let (metrics, mut reporter) = super::new();
+        let http = metrics.clone().prefixed("http");
+        http.clone().prefixed("client").stat("latency_ms").add(3);
+        http.counter("requests").incr(1);
+        let _https = metrics.clone().prefixed("https").counter("requests");
+        let conns = metrics.stat("conns");
+        conns.add(2);
+
+        let report = reporter.take_subtree(&["http"]);
+        assert_eq!(report.stats().len(), 1);
+        assert_eq!(report.counters().len(), 1);
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(1));
+
+        // Metrics outside the subtree are neither cleared nor pruned.
+        let report = reporter.peek();
+        assert_eq!(report.counters().len(), 1);
+        assert_eq!(report.stats().len(), 1);
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(1));
+
+        let http = Prefix::node(Arc::new(Prefix::Root), "http".into());
+        assert!(http.starts_with(&[]));
+        assert!(!http.starts_with(&["http", "client"]));
+    }
+
+    #[test]
+    fn test_builder_hooks() {
+        let registered = Arc::new(AtomicUsize::new(0));
+        let pruned = Arc::new(AtomicUsize::new(0));
+        let (metrics, mut reporter) = {
+            let registered = registered.clone();
+            let pruned = pruned.clone();
+            Builder::new()
+                .on_register(move |_| { registered.fetch_add(1, Ordering::SeqCst); })
+                .on_prune(move |k| {
+                    assert_eq!(k.name(), "dropped");
+                    pruned.fetch_add(1, Ordering::SeqCst);
+                })
+                .build()
+        };
+
+        let _kept = metrics.counter("kept");
+        let _also_kept = metrics.counter("kept");
+        metrics.gauge("dropped");
+        metrics.stat("dropped");
+        metrics.size_stat("dropped");
+        assert_eq!(registered.load(Ordering::SeqCst), 4);
+
+        reporter.take();
+        assert_eq!(pruned.load(Ordering::SeqCst), 3);
+        reporter.take();
+        assert_eq!(pruned.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_try_stat_with_bounds() {
+        let (metrics, reporter) = super::new();
+        assert!(metrics.try_stat_with_bounds("ok", 1, 1_000).is_ok());
+        assert!(metrics.try_stat_with_bounds("zero", 0, 1_000).is_err());
+        assert!(metrics.try_stat_with_bounds("narrow", 10, 15).is_err());
+        assert!(metrics.try_stat_with_bounds("huge", u64::MAX, u64::MAX).is_err());
+        let imprecise = StatConfig::default().precision(6);
+        assert_eq!(
+            metrics.try_stat_with_config("imprecise", imprecise).err(),
+            Some(Error::InvalidHistogram(
+                "number of significant digits must be between 0 and 5",
+            ))
+        );
+        assert_eq!(reporter.peek().stats().len(), 1);
+    }
+
+    #[test]
+    fn test_stat_bounds_reuse() {
+        let (metrics, _reporter) = super::new();
+        let a = metrics.stat_with_bounds("latency", 1, 1_000);
+        assert_eq!(a.bounds(), Some((1, 1_000)));
+        assert_eq!(metrics.stat("latency").bounds(), Some((1, 1_000)));
+        assert!(metrics.try_stat_with_bounds("latency", 1, 1_000).is_ok());
+        assert_eq!(
+            metrics.try_stat_with_bounds("latency", 1, 10_000).err(),
+            Some(Error::BoundsConflict {
+                name: "latency",
+                existing: Some((1, 1_000)),
+                requested: (1, 10_000),
+            })
+        );
+        // Otherwise, the existing bounds are adopted.
+        let b = metrics.stat_with_bounds("latency", 1, 10_000);
+        assert_eq!(b.bounds(), Some((1, 1_000)));
+
+        let atomic = StatConfig::default().bounds(1, 1_000).backend(StatBackend::AtomicBuckets);
+        let _c = metrics.stat_with_config("atomic", atomic);
+        assert_eq!(metrics.stat("atomic").bounds(), Some((1, 1_000)));
+        let _d = metrics.stat("unbounded");
+        assert!(metrics.try_stat_with_bounds("unbounded", 1, 1_000).is_err());
+    }
+
+    #[test]
+    fn test_stat_unit_reuse() {
+        let (metrics, reporter) = super::new();
+        let ms = metrics.timer_ms("latency");
+        let us = StatConfig::default().unit(TimeUnit::Micros);
+        assert_eq!(
+            metrics.try_stat_with_config("latency", us).err(),
+            Some(Error::UnitConflict {
+                name: "latency",
+                existing: Some(TimeUnit::Millis),
+                requested: TimeUnit::Micros,
+            })
+        );
+        // Otherwise, the existing unit is adopted, so durations are recorded consistently.
+        let us = metrics.timer_us("latency");
+        assert_eq!(metrics.stat("latency").unit(), Some(TimeUnit::Millis));
+        ms.record(Duration::from_millis(3));
+        us.record(Duration::from_millis(5));
+        let h = reporter.peek().get_stat("latency", &[]).cloned().unwrap();
+        assert_eq!((h.count(), h.max()), (2, 5));
+
+        let _plain = metrics.stat("plain");
+        let ms = StatConfig::default().unit(TimeUnit::Millis);
+        assert_eq!(
+            metrics.try_stat_with_config("plain", ms).err().map(|e| e.to_string()),
+            Some("plain has unit None, not Millis".into())
+        );
+    }
+
+    #[test]
+    fn test_key_created_on_registration() {
+        let (metrics, _reporter) = super::new();
+        assert_eq!(metrics.key("requests").created(), UNIX_EPOCH);
+
+        let c0 = metrics.counter("requests");
+        assert!(c0.key().created() > UNIX_EPOCH);
+        ::std::thread::sleep(Duration::from_millis(2));
+        // Handles for an existing metric share the registry's key, and its creation time.
+        let c1 = metrics.counter("requests");
+        assert!(Arc::ptr_eq(c0.key(), c1.key()));
+        let s0 = metrics.stat("latency");
+        let s1 = metrics.stat("latency");
+        assert!(Arc::ptr_eq(s0.key(), s1.key()));
+        assert!(s0.key().created() > c0.key().created());
+    }
+
+    #[test]
+    fn test_lookup_under_read_lock() {
+        let (metrics, _reporter) = super::new();
+        let c = metrics.counter("requests");
+        let s = metrics.stat("latency");
+        metrics.constant("build", 1);
+
+        // Existing metrics are found without the write lock, so this would otherwise block.
+        let _reg = metrics.registry.read().unwrap();
+        assert!(Arc::ptr_eq(metrics.counter("requests").key(), c.key()));
+        assert!(Arc::ptr_eq(metrics.stat("latency").key(), s.key()));
+        metrics.constant("build", 1);
+
+        // Handles registered concurrently share a single metric.
+        let (metrics, reporter) = super::new();
+        let threads = (0..8)
+            .map(|_| {
+                let m = metrics.clone();
+                ::std::thread::spawn(move || m.counter("racy").incr(1))
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let _c = metrics.counter("racy");
+        assert_eq!(reporter.peek().counters().len(), 1);
+    }
+
+    #[test]
+    fn test_gauge_group() {
+        let (metrics, reporter) = super::new();
+        let heap = metrics.prefixed("heap").gauge_group(&["used", "free", "total"]);
+        assert_eq!(heap.len(), 3);
+        heap.set(&[3, 5, 8]);
+
+        let report = reporter.peek();
+        let values = report.gauges().values().cloned().collect::<Vec<_>>();
+        assert_eq!(values, vec![3, 5, 8]);
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_report_transforms() {
+        let (metrics, reporter) = super::new();
+        let srv = metrics.clone().prefixed("rt").prefixed("int").prefixed("srv");
+        let _a = srv.clone().labeled("instance", "a").labeled("port", 4140).counter("requests");
+        let _b = srv.labeled("instance", "b").gauge("conns");
+        let _c = metrics.prefixed("process").stat("cpu_ms");
+
+        let mut report = reporter.peek();
+        report.retain(|k| k.name() != "cpu_ms");
+        report.map_labels(|labels| {
+            let mut labels = labels.clone();
+            labels.remove("instance");
+            labels
+        });
+        report.rename_prefix(&["rt", "int"], &["router"]);
+
+        assert!(report.stats().is_empty());
+        assert_eq!(
+            prometheus::string(&report).unwrap(),
+            "router:srv:requests{port=\"4140\"} 0\nrouter:srv:conns 0\n"
+        );
+    }
+
+    #[test]
+    fn test_report_relabel_merge() {
+        let (a, a_reporter) = super::new();
+        let (b, b_reporter) = super::new();
+        let ok = a.labeled("status", 200);
+        ok.counter("requests").incr(1);
+        ok.gauge("conns").set(2);
+        let _latency = b.clone().labeled("component", "old").stat("latency_ms");
+        b.counter("requests").incr(3);
+
+        let mut report = a_reporter.peek();
+        report.relabel("component", "a");
+        {
+            // Series that shared labels still do.
+            let counter = report.counters().keys().next().unwrap();
+            let gauge = report.gauges().keys().next().unwrap();
+            assert!(::std::ptr::eq(counter.labels(), gauge.labels()));
+            assert_eq!(counter.labels().get("component"), Some(&"a".into()));
+        }
+        let mut other = b_reporter.peek();
+        other.relabel("component", "b");
+        report.merge(other);
+
+        assert_eq!(report.len(), 4);
+        assert_eq!(report.get_counter("requests", &[("component", "a"), ("status", "200")]), Some(1));
+        assert_eq!(report.get_gauge("conns", &[("component", "a"), ("status", "200")]), Some(2));
+        assert_eq!(report.get_counter("requests", &[("component", "b")]), Some(3));
+        assert!(report.get_stat("latency_ms", &[("component", "b")]).is_some());
     }
 
-    #[bench]
-    fn bench_gauge_create(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        b.iter(move || { let _ = metrics.gauge(DEFAULT_METRIC_NAME); });
+    #[test]
+    fn test_report_merge_conflicts() {
+        let mk = |requests, conns, latency: &[u64]| {
+            let (metrics, reporter) = super::new();
+            metrics.counter("requests").incr(requests);
+            metrics.gauge("conns").set(conns);
+            let mut stat = metrics.stat_with_bounds("latency_ms", 1, 1_000);
+            stat.add_values(latency);
+            metrics.size_stat("payload_bytes").add(1_024);
+            reporter.peek()
+        };
+
+        let mut replaced = mk(1, 2, &[3]);
+        replaced.merge(mk(4, 5, &[6]));
+        assert_eq!(replaced.get_counter("requests", &[]), Some(4));
+        assert_eq!(replaced.get_stat("latency_ms", &[]).unwrap().count(), 1);
+
+        let mut combined = mk(1, 2, &[3]);
+        combined.merge_with(mk(4, 5, &[6, 1_000_000]), MergeConflicts::Combine).unwrap();
+        assert_eq!(combined.len(), 4);
+        assert_eq!(combined.get_counter("requests", &[]), Some(5));
+        assert_eq!(combined.get_gauge("conns", &[]), Some(5));
+        let h = combined.get_stat("latency_ms", &[]).unwrap();
+        assert_eq!((h.count(), h.sum(), h.overflowed()), (2, 9, 1));
+        let size = combined.sizes().values().next().unwrap();
+        assert_eq!((size.count(), size.sum()), (2, 2_048));
+
+        let mut rejected = mk(1, 2, &[3]);
+        let err = rejected.merge_with(mk(4, 5, &[6]), MergeConflicts::Reject).unwrap_err();
+        assert!(err.is_exact());
+        assert_eq!(err.to_string(), "counter requests is in both reports");
+        assert_eq!(rejected.get_counter("requests", &[]), Some(1));
+
+        // Series that differ only by prefix are kept by `merge`, and otherwise resolved by
+        // the policy.
+        let (metrics, reporter) = super::new();
+        metrics.clone().prefixed("a").counter("requests").incr(7);
+        metrics.clone().prefixed("a").gauge("conns").set(8);
+        let mut prefixed = mk(1, 2, &[3]);
+        prefixed.merge(reporter.peek());
+        assert_eq!(prefixed.counters().len(), 2);
+        let mut replaced = mk(1, 2, &[3]);
+        replaced.merge_with(reporter.peek(), MergeConflicts::Replace).unwrap();
+        let counters = replaced.counters().iter().collect::<Vec<_>>();
+        assert_eq!(counters.len(), 1);
+        assert_eq!((counters[0].0.to_string(), *counters[0].1), ("a:requests".into(), 7));
+        let mut combined = mk(usize::MAX - 1, 2, &[3]);
+        combined.merge_with(reporter.peek(), MergeConflicts::Combine).unwrap();
+        assert_eq!(combined.len(), 4);
+        assert_eq!(combined.get_counter("requests", &[]), Some(usize::MAX));
+        assert_eq!(combined.gauges().values().collect::<Vec<_>>(), vec![&8]);
+        assert_eq!(combined.gauges().keys().next().unwrap().to_string(), "conns");
+        let mut rejected = mk(1, 2, &[3]);
+        let err = rejected.merge_with(reporter.peek(), MergeConflicts::Reject).unwrap_err();
+        assert!(!err.is_exact());
+        assert_eq!(err.to_string(), "counter a:requests differs only by prefix from requests");
     }
 
-    #[bench]
-    fn bench_stat_create(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        b.iter(move || { let _ = metrics.stat(DEFAULT_METRIC_NAME); });
+    #[test]
+    fn test_report_take_kinds() {
+        let (metrics, mut reporter) = super::new();
+        metrics.counter("requests").incr(1);
+        let latency = metrics.stat("latency_ms");
+        latency.add(3);
+
+        let report = reporter.take_kinds(KindMask::COUNTERS | KindMask::GAUGES);
+        assert_eq!(report.counters().len(), 1);
+        assert!(report.stats().is_empty());
+
+        // Stats were neither cleared nor pruned; the dropped counter was.
+        drop(latency);
+        let report = reporter.peek();
+        assert!(report.counters().is_empty());
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(1));
+
+        let report = reporter.take_kinds(KindMask::STATS);
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(1));
+        assert!(reporter.peek().is_empty());
+
+        assert!(KindMask::ALL.contains(KindMask::SIZES | KindMask::STATS));
+        assert!(!KindMask::STATS.contains(KindMask::ALL));
     }
 
-    #[bench]
-    fn bench_counter_create_x1000(b: &mut Bencher) {
-        let scopes = mk_scopes(1000, "bench_counter_create_x1000");
-        b.iter(move || for scope in &scopes {
-            scope.counter(DEFAULT_METRIC_NAME);
-        });
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_scope_with_registry() {
+        let registry = Arc::new(RwLock::new(Registry::new()));
+        let a = Scope::with_registry(registry.clone()).prefixed("a");
+        let b = Scope::with_registry(registry.clone()).prefixed("b");
+        a.counter("requests").incr(1);
+        b.counter("requests").incr(2);
+
+        let out = prometheus::string(&Reporter::new(registry).peek()).unwrap();
+        assert_eq!(out, "a:requests 1\nb:requests 2\n");
     }
 
-    #[bench]
-    fn bench_gauge_create_x1000(b: &mut Bencher) {
-        let scopes = mk_scopes(1000, "bench_gauge_create_x1000");
-        b.iter(move || for scope in &scopes {
-            scope.gauge(DEFAULT_METRIC_NAME);
-        });
+    #[test]
+    fn test_stat_percentiles() {
+        let (metrics, mut reporter) = super::new();
+        let latency = metrics.stat("latency_ms");
+        for v in 1..1001 {
+            latency.add(v);
+        }
+
+        let report = reporter.take();
+        let h = report.stats().values().next().expect("expected stat");
+        assert_eq!(h.percentile(50.0), 500);
+        assert_eq!(h.percentile(99.0), 990);
+        assert_eq!(h.percentile(75.0), 750);
+        assert_eq!(h.standard_percentiles(), &[500, 900, 950, 990, 999, 1000]);
+
+        // The cache is discarded as values are recorded.
+        let mut h = h.clone();
+        h.record(2_000);
+        assert_eq!(h.percentile(99.99), 2_000);
+        h.clear();
+        assert_eq!(h.percentile(50.0), 0);
     }
 
-    #[bench]
-    fn bench_stat_create_x1000(b: &mut Bencher) {
-        let scopes = mk_scopes(1000, "bench_stat_create_x1000");
-        b.iter(move || for scope in &scopes {
-            scope.stat(DEFAULT_METRIC_NAME);
-        });
+    #[test]
+    fn test_compact() {
+        let registry = Arc::new(RwLock::new(Registry::new()));
+        let metrics = Scope::with_registry(registry.clone());
+        let mut reporter = Reporter::new(registry.clone());
+        let capacity = || registry.read().unwrap().counters.capacity();
+
+        let keep = metrics.counter("requests");
+        let conns = (0..2000)
+            .map(|i| metrics.clone().labeled("conn", i).counter("bytes"))
+            .collect::<Vec<_>>();
+        assert!(capacity() >= 2001);
+
+        // Storage is shrunk once it is mostly empty.
+        drop(conns);
+        reporter.take();
+        assert!(capacity() < COMPACT_MIN_CAPACITY);
+
+        let conns = (0..100)
+            .map(|i| metrics.clone().labeled("conn", i).counter("bytes"))
+            .collect::<Vec<_>>();
+        drop(conns);
+        reporter.take();
+        let before = capacity();
+        reporter.compact();
+        assert!(capacity() < before);
+        keep.incr(1);
+        assert_eq!(reporter.take().counters().values().next(), Some(&1));
     }
 
-    #[bench]
-    fn bench_counter_update(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        let c = metrics.counter(DEFAULT_METRIC_NAME);
-        b.iter(move || c.incr(1));
+    #[test]
+    fn test_stat_overflow() {
+        let (metrics, reporter) = super::new();
+        let mk = |name, overflow| {
+            let config = StatConfig::default().bounds(1, 1_000).overflow(overflow);
+            let stat = metrics.stat_with_config(name, config);
+            stat.add(10);
+            stat.add(1_000_000);
+            stat
+        };
+        let _dropped = mk("dropped", StatOverflow::Drop);
+        let _clamped = mk("clamped", StatOverflow::Clamp);
+        let _resized = mk("resized", StatOverflow::Resize);
+
+        let report = reporter.peek();
+        let dropped = report.get_stat("dropped", &[]).unwrap();
+        assert_eq!((dropped.count(), dropped.max(), dropped.overflowed()), (1, 10, 1));
+        let clamped = report.get_stat("clamped", &[]).unwrap();
+        assert_eq!(clamped.count(), 2);
+        assert_eq!(clamped.max(), clamped.histogram().high());
+        assert_eq!(clamped.overflowed(), 1);
+        let resized = report.get_stat("resized", &[]).unwrap();
+        assert_eq!(resized.count(), 2);
+        assert!(resized.max() >= 1_000_000);
+        assert_eq!(resized.sum(), 1_000_010);
+
+        #[cfg(feature = "prometheus")]
+        {
+            let out = prometheus::string(&report).unwrap();
+            assert!(out.contains("\ndropped_overflowed 1\n"));
+            assert!(!out.contains("resized_overflowed"));
+        }
     }
 
-    #[bench]
-    fn bench_gauge_update(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        let g = metrics.gauge(DEFAULT_METRIC_NAME);
-        b.iter(move || g.set(1));
+    #[test]
+    fn test_stat_summary() {
+        let (metrics, reporter) = super::new();
+        let latency = metrics.stat("latency_ms");
+        for v in &[2, 4, 4, 4, 5, 5, 7, 9] {
+            latency.add(*v);
+        }
+        metrics.stat("idle_ms");
+
+        let report = reporter.peek();
+        let summaries = report.stat_summaries().collect::<Vec<_>>();
+        let s = summaries[0].1;
+        assert_eq!((s.count, s.sum, s.min, s.max), (8, 40, 2, 9));
+        assert_eq!(s.mean, 5.0);
+        assert_eq!(s.stddev, 2.0);
+        assert_eq!(s.quantiles[0], (50.0, 4));
+        assert_eq!(s.quantiles[5], (99.99, 9));
+        assert_eq!(summaries[1].1, StatSummary::default());
     }
 
-    #[bench]
-    fn bench_stat_update(b: &mut Bencher) {
-        let (metrics, _) = super::new();
-        let s = metrics.stat(DEFAULT_METRIC_NAME);
-        b.iter(move || s.add(1));
+    #[test]
+    fn test_report_take_and_retain() {
+        let (metrics, mut reporter) = super::new();
+        metrics.counter("requests").incr(1);
+        metrics.stat("latency_ms").add(3);
+        metrics.counter("errors").incr(1);
+
+        let keep = [metrics.key("requests"), metrics.key("latency_ms")];
+        let report = reporter.take_and_retain(&keep);
+        assert_eq!(report.len(), 3);
+
+        // Unreferenced metrics that were not retained are pruned; retained stats are cleared.
+        let report = reporter.take_and_retain(&keep);
+        assert_eq!(report.get_counter("requests", &[]), Some(1));
+        assert_eq!(report.get_stat("latency_ms", &[]).map(|h| h.count()), Some(0));
+        assert_eq!(report.get_counter("errors", &[]), None);
+
+        reporter.take();
+        assert!(reporter.take().is_empty());
     }
 
-    #[bench]
-    fn bench_counter_update_x1000(b: &mut Bencher) {
-        let counters: Vec<Counter> = mk_scopes(1000, "bench_counter_update_x1000")
-            .iter()
-            .map(|s| s.counter(DEFAULT_METRIC_NAME))
-            .collect();
-        b.iter(move || for c in &counters {
-            c.incr(1)
-        });
+    #[test]
+    fn test_cardinality() {
+        let (metrics, reporter) = super::new();
+        let http = metrics.clone().prefixed("http");
+        let mut handles = Vec::new();
+        for path in 0..10 {
+            for status in &[200, 500] {
+                let scope = http.clone().labeled("path", path).labeled("status", *status);
+                handles.push(scope.counter("requests"));
+            }
+        }
+        handles.push(metrics.counter("requests"));
+        let _conns = http.gauge("conns");
+
+        let cardinality = reporter.cardinality();
+        assert_eq!(cardinality.len(), 3);
+        let c = &cardinality[0];
+        assert_eq!(c.prefix.values(), vec!["http"]);
+        assert_eq!((c.name, c.series), ("requests", 20));
+        assert_eq!(c.labels, vec![("path", 10), ("status", 2)]);
+        assert_eq!(cardinality[1].series, 1);
+        assert!(cardinality[1].labels.is_empty());
     }
 
-    #[bench]
-    fn bench_gauge_update_x1000(b: &mut Bencher) {
-        let gauges: Vec<Gauge> = mk_scopes(1000, "bench_gauge_update_x1000")
-            .iter()
-            .map(|s| s.gauge(DEFAULT_METRIC_NAME))
-            .collect();
-        b.iter(move || for g in &gauges {
-            g.set(1)
-        });
+    #[test]
+    fn test_stat_corrected() {
+        let (metrics, reporter) = super::new();
+        let stat = metrics.stat("latency_ms").corrected(10);
+        stat.add(5);
+        stat.add(35);
+        let timer = metrics.timer_ms("timer_ms").corrected(Duration::from_millis(10));
+        timer.record(Duration::from_millis(30));
+
+        let report = reporter.peek();
+        let h = report.get_stat("latency_ms", &[]).unwrap();
+        // 35 is recorded along with 25 and 15.
+        assert_eq!((h.count(), h.sum(), h.min(), h.max()), (4, 80, 5, 35));
+        let h = report.get_stat("timer_ms", &[]).unwrap();
+        assert_eq!((h.count(), h.sum()), (3, 60));
     }
 
-    #[bench]
-    fn bench_stat_update_x1000(b: &mut Bencher) {
-        let stats: Vec<Stat> = mk_scopes(1000, "bench_stat_update_x1000")
-            .iter()
-            .map(|s| s.stat(DEFAULT_METRIC_NAME))
-            .collect();
-        b.iter(move || for s in &stats {
-            s.add(1)
-        });
+    #[test]
+    fn test_record_durations() {
+        let (metrics, reporter) = super::new();
+        let timer = metrics.timer_us("latency_us");
+        timer.record_each(&[Duration::from_micros(5), Duration::from_millis(2)]);
+        let stat = metrics.latency_stat_ms("latency_ms");
+        stat.add_durations((1..4).map(Duration::from_millis));
+        let corrected = metrics.timer_ms("corrected_ms").corrected(Duration::from_millis(10));
+        corrected.record_each(&[Duration::from_millis(30)]);
+
+        let report = reporter.peek();
+        let h = report.get_stat("latency_us", &[]).unwrap();
+        assert_eq!((h.count(), h.sum()), (2, 2_005));
+        let h = report.get_stat("latency_ms", &[]).unwrap();
+        assert_eq!((h.count(), h.sum()), (3, 6));
+        let h = report.get_stat("corrected_ms", &[]).unwrap();
+        assert_eq!((h.count(), h.sum()), (3, 60));
     }
 
-    #[bench]
-    fn bench_stat_add_x1000(b: &mut Bencher) {
-        let s = {
-            let (metrics, _) = super::new();
-            metrics.stat(DEFAULT_METRIC_NAME)
-        };
-        b.iter(move || for i in 0..1000 {
-            s.add(i)
-        });
+    #[test]
+    fn test_reset() {
+        let (metrics, reporter) = super::new();
+        let requests = metrics.counter("requests");
+        let latency = metrics.stat("latency_ms");
+        let conns = metrics.gauge("conns");
+        requests.incr(3);
+        latency.add(5);
+        conns.set(2);
+
+        requests.reset();
+        latency.clear();
+        let report = reporter.peek();
+        assert_eq!(report.get_counter("requests", &[]), Some(0));
+        assert_eq!(report.get_stat("latency_ms", &[]).map(|h| h.count()), Some(0));
+        assert_eq!(report.get_gauge("conns", &[]), Some(2));
+
+        requests.incr(1);
+        latency.add(5);
+        metrics.size_stat("payload_bytes").add(10);
+        reporter.reset_all();
+        let report = reporter.peek();
+        assert_eq!(report.get_counter("requests", &[]), Some(0));
+        assert_eq!(report.get_gauge("conns", &[]), Some(0));
+        assert_eq!(report.get_stat("latency_ms", &[]).map(|h| h.count()), Some(0));
+        assert!(report.sizes().values().all(|s| s.count() == 0));
+
+        requests.incr(1);
+        assert_eq!(reporter.peek().get_counter("requests", &[]), Some(1));
     }
 
-    fn mk_scopes(n: usize, name: &str) -> Vec<Scope> {
-        let (metrics, _) = super::new();
-        let metrics = metrics.prefixed("t").labeled("test_name", name).labeled(
-            "total_iterations",
-            n,
+    #[test]
+    fn test_kind_conflicts() {
+        // By default, a key may be registered as several kinds.
+        let (metrics, reporter) = super::new();
+        metrics.counter("requests").incr(1);
+        metrics.gauge("requests").set(2);
+        assert_eq!(reporter.peek().len(), 2);
+
+        let (metrics, reporter) = Builder::new().kind_conflicts(KindConflicts::Reuse).build();
+        let counter = metrics.counter("requests");
+        counter.incr(1);
+        metrics.gauge("requests").incr(2);
+        let report = reporter.peek();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report.get_counter("requests", &[]), Some(3));
+        assert_eq!(
+            metrics.try_stat_with_config("requests", StatConfig::default()).err(),
+            Some(Error::KindConflict {
+                name: "requests",
+                existing: "counter",
+                requested: "stat",
+            })
         );
-        (0..n)
-            .map(|i| metrics.clone().labeled("iteration", format!("{}", i)))
-            .collect()
+
+        let (metrics, reporter) = Builder::new().kind_conflicts(KindConflicts::Reject).build();
+        let stat = metrics.stat("latency");
+        let err = metrics.try_gauge("latency").expect_err("expected conflict");
+        assert_eq!(err.to_string(), "latency is a stat, not a gauge");
+        assert!(metrics.try_size_stat("latency").is_err());
+        assert!(metrics.clone().labeled("a", 1).try_gauge("latency").is_ok());
+        assert_eq!(reporter.peek().len(), 2);
+        drop(stat);
     }
 
     #[test]
-    fn test_report_peek() {
+    fn test_handle_identity() {
+        let (metrics, _reporter) = super::new();
+        let metrics = metrics.prefixed("http").labeled("status", 200);
+        let requests = metrics.counter("requests");
+        requests.incr(3);
+        let conns = metrics.gauge("conns");
+        let latency = metrics.timer_ms("latency_ms");
+
+        assert_eq!(requests.key().name(), "requests");
+        assert_eq!(**requests.key(), metrics.key("requests"));
+        assert_eq!(requests.to_string(), "http:requests{status=\"200\"}");
+        assert_eq!(
+            format!("{:?}", requests),
+            "Counter { key: http:requests{status=\"200\"}, value: Some(3) }"
+        );
+        assert_eq!(conns.to_string(), "http:conns{status=\"200\"}");
+        assert_eq!(latency.key().name(), "latency_ms");
+        assert!(format!("{:?}", latency).contains("count: Some(0)"));
+
+        // Handles whose metrics were removed are still identifiable.
+        let eph = metrics.ephemeral();
+        let leaked = eph.counter("leaked");
+        drop(eph);
+        assert_eq!(
+            format!("{:?}", leaked),
+            "Counter { key: http:leaked{status=\"200\"}, value: None }"
+        );
+    }
+
+    #[test]
+    fn test_unlabeled() {
         let (metrics, reporter) = super::new();
-        let metrics = metrics.labeled("joy", "painting");
+        let req = metrics
+            .labeled("service", "users")
+            .labeled("method", "GET")
+            .labeled("request_id", 7);
+        req.counter("handled").incr(1);
+        req.clone().unlabeled("request_id").counter("requests").incr(1);
+        req.clone().unlabeled("missing").counter("unchanged").incr(1);
+        req.clone().retain_labels(&["service"]).gauge("conns").set(2);
+
+        let report = reporter.peek();
+        assert_eq!(
+            report.get_counter(
+                "handled",
+                &[("service", "users"), ("method", "GET"), ("request_id", "7")],
+            ),
+            Some(1)
+        );
+        assert_eq!(
+            report.get_counter("requests", &[("service", "users"), ("method", "GET")]),
+            Some(1)
+        );
+        assert_eq!(
+            report.get_counter(
+                "unchanged",
+                &[("service", "users"), ("method", "GET"), ("request_id", "7")],
+            ),
+            Some(1)
+        );
+        assert_eq!(report.get_gauge("conns", &[("service", "users")]), Some(2));
+    }
 
-        let happy_accidents = metrics.counter("happy_accidents");
-        let paint_level = metrics.gauge("paint_level");
-        let mut stroke_len = metrics.stat("stroke_len");
+    #[test]
+    fn test_reporter_get() {
+        let (metrics, mut reporter) = super::new();
+        let ok = metrics.clone().labeled("status", 200);
+        ok.counter("requests").incr(3);
+        let requests = metrics.counter("requests");
+        requests.incr(1);
+        metrics.gauge("conns").set(2);
+        let mut latency = ok.stat("latency_ms");
+        latency.add_values(&[1, 2, 3]);
+
+        assert_eq!(reporter.get_counter("requests", &[("status", "200")]), Some(3));
+        assert_eq!(reporter.get_counter("requests", &[]), Some(1));
+        assert_eq!(reporter.get_counter("requests", &[("status", "500")]), None);
+        assert_eq!(reporter.get_gauge("conns", &[]), Some(2));
+        let summary = reporter
+            .get_stat_summary("latency_ms", &[("status", "200")])
+            .expect("expected stat");
+        assert_eq!((summary.count, summary.sum, summary.max), (3, 6, 3));
+        assert_eq!(reporter.get_stat_summary("latency_ms", &[]), None);
+
+        // Lookups don't clear stats.
+        let summary = reporter.get_stat_summary("latency_ms", &[("status", "200")]);
+        assert_eq!(summary.map(|s| s.count), Some(3));
+        drop(latency);
+        reporter.take();
+        assert_eq!(reporter.get_stat_summary("latency_ms", &[("status", "200")]), None);
+        assert_eq!(reporter.get_counter("requests", &[]), Some(1));
+    }
 
-        happy_accidents.incr(1);
-        paint_level.set(2);
-        stroke_len.add_values(&[1, 2, 3]);
+    #[test]
+    fn test_reporter_get_by_key() {
+        let (metrics, reporter) = super::new();
+        let http = metrics.clone().prefixed("http");
+        let grpc = metrics.clone().prefixed("grpc");
+        http.counter("requests").incr(1);
+        grpc.counter("requests").incr(2);
+        grpc.gauge("conns").set(3);
+        grpc.stat("latency_ms").add(4);
+
+        assert_eq!(reporter.get_counter_by_key(&http.key("requests")), Some(1));
+        assert_eq!(reporter.get_counter_by_key(&grpc.key("requests")), Some(2));
+        assert_eq!(reporter.get_counter_by_key(&metrics.key("requests")), None);
+        assert_eq!(reporter.get_gauge_by_key(&grpc.key("conns")), Some(3));
+        assert_eq!(reporter.get_gauge_by_key(&http.key("conns")), None);
+        let summary = reporter.get_stat_summary_by_key(&grpc.key("latency_ms")).unwrap();
+        assert_eq!((summary.count, summary.max), (1, 4));
+    }
 
-        {
-            let report = reporter.peek();
-            {
-                let k = report
-                    .counters()
-                    .keys()
-                    .find(|k| k.name() == "happy_accidents")
-                    .expect("expected counter: happy_accidents");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.counters().get(&k), Some(&1));
-            }
-            {
-                let k = report
-                    .gauges()
-                    .keys()
-                    .find(|k| k.name() == "paint_level")
-                    .expect("expected gauge: paint_level");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.gauges().get(&k), Some(&2));
-            }
-            assert_eq!(
-                report.gauges().keys().find(|k| k.name() == "brush_width"),
-                None
-            );
-            {
-                let k = report
-                    .stats()
-                    .keys()
-                    .find(|k| k.name() == "stroke_len")
-                    .expect("expected stat: stroke_len");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
-            }
-            assert_eq!(report.stats().keys().find(|k| k.name() == "tree_len"), None);
-        }
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_final_flush() {
+        let (metrics, reporter) = super::new();
+        let requests = metrics.counter("requests");
+        requests.incr(2);
+        let stat = metrics.stat("latency_ms");
+        stat.add(3);
+
+        let flush = reporter.final_flush(Vec::new(), csv::Csv::new());
+        // Nothing is taken until the future is polled.
+        requests.incr(1);
+        let out = String::from_utf8(flush.wait().unwrap()).unwrap();
+        assert!(out.contains("\n,requests,{},counter,3,"), "{}", out);
+        assert!(out.contains("\n,latency_ms,{},stat,,1,3,"), "{}", out);
+    }
 
-        drop(paint_level);
-        let brush_width = metrics.gauge("brush_width");
-        let mut tree_len = metrics.stat("tree_len");
+    #[test]
+    fn test_snapshot_threads() {
+        let (metrics, reporter) = super::new();
+        let mut reporter = reporter.with_snapshot_threads(4);
+        let stats = (0..1_024)
+            .map(|i| {
+                let mut s = metrics.clone().labeled("i", i).stat("latency_ms");
+                s.add_values(&vec![5; i % 7]);
+                s
+            })
+            .collect::<Vec<_>>();
 
-        happy_accidents.incr(2);
-        brush_width.set(5);
-        stroke_len.add_values(&[1, 2, 3]);
-        tree_len.add_values(&[3, 4, 5]);
+        let report = reporter.peek();
+        assert_eq!(report.stats().len(), stats.len());
+        for (i, h) in report.stats().values().enumerate() {
+            assert_eq!(h.count() as usize, i % 7);
+        }
 
-        {
-            let report = reporter.peek();
-            {
-                let k = report
-                    .counters()
-                    .keys()
-                    .find(|k| k.name() == "happy_accidents")
-                    .expect("expected counter: happy_accidents");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.counters().get(&k), Some(&3));
-            }
-            {
-                let k = report
-                    .gauges()
-                    .keys()
-                    .find(|k| k.name() == "paint_level")
-                    .expect("expected gauge: paint_level");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.gauges().get(&k), Some(&2));
-            }
-            {
-                let k = report
-                    .gauges()
-                    .keys()
-                    .find(|k| k.name() == "brush_width")
-                    .expect("expected gauge: brush_width");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.gauges().get(&k), Some(&5));
-            }
-            {
-                let k = report
-                    .stats()
-                    .keys()
-                    .find(|k| k.name() == "stroke_len")
-                    .expect("expected stat: stroke_len");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
-            }
-            {
-                let k = report
-                    .stats()
-                    .keys()
-                    .find(|k| k.name() == "tree_len")
-                    .expect("expected stat: tree_len");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
-            }
+        let report = reporter.take();
+        assert_eq!(report.stats().len(), stats.len());
+        for (i, (k, h)) in report.stats().iter().enumerate() {
+            assert_eq!(k.labels().get("i"), Some(&i.into()));
+            assert_eq!(h.count() as usize, i % 7);
         }
+        assert!(reporter.peek().stats().values().all(|h| h.count() == 0));
     }
 
     #[test]
@@ -672,8 +3743,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "happy_accidents")
                     .expect("expected counter: happy_accidents");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.counters().get(&k), Some(&1));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.counters().get(k), Some(&1));
             }
             {
                 let k = report
@@ -681,8 +3752,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "paint_level")
                     .expect("expected gauge");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.gauges().get(&k), Some(&2));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.gauges().get(k), Some(&2));
             }
             assert_eq!(
                 report.gauges().keys().find(|k| k.name() == "brush_width"),
@@ -694,8 +3765,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "stroke_len")
                     .expect("expected stat");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
             }
             assert_eq!(report.stats().keys().find(|k| k.name() == "tree_len"), None);
             {
@@ -704,8 +3775,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "stroke_len")
                     .expect("expected stat");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
             }
         }
 
@@ -719,8 +3790,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "happy_accidents")
                     .expect("expected counter: happy_accidents");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(counters.get(&k), Some(&1));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(counters.get(k), Some(&1));
             }
             {
                 let k = report
@@ -728,8 +3799,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "paint_level")
                     .expect("expected gauge");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.gauges().get(&k), Some(&2));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.gauges().get(k), Some(&2));
             }
             {
                 let k = report
@@ -737,8 +3808,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "stroke_len")
                     .expect("expected stat");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
             }
         }
 
@@ -755,8 +3826,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "happy_accidents")
                     .expect("expected counter: happy_accidents");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.counters().get(&k), Some(&3));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.counters().get(k), Some(&3));
             }
             assert_eq!(
                 report.gauges().keys().find(|k| k.name() == "paint_level"),
@@ -768,8 +3839,8 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "brush_width")
                     .expect("expected gauge");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert_eq!(report.gauges().get(&k), Some(&5));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert_eq!(report.gauges().get(k), Some(&5));
             }
             assert_eq!(
                 report.stats().keys().find(|k| k.name() == "stroke_len"),
@@ -781,9 +3852,170 @@ mod tests {
                     .keys()
                     .find(|k| k.name() == "tree_len")
                     .expect("expeced stat");
-                assert_eq!(k.labels.get("joy"), Some(&"painting".to_string()));
-                assert!(report.stats().contains_key(&k));
+                assert_eq!(k.labels.get("joy"), Some(&"painting".into()));
+                assert!(report.stats().contains_key(k));
             }
         }
     }
+
+    #[test]
+    fn test_adaptive_stat() {
+        let (metrics, mut reporter) = super::new();
+        let fixed = metrics.stat("fixed");
+        let adaptive = metrics.stat_with_config("adaptive", StatConfig::default().adaptive());
+        let record = || for v in 1_000_000..1_000_100 {
+            fixed.add(v);
+            adaptive.add(v);
+        };
+        let len = |report: &Report, name| report.get_stat(name, &[]).unwrap().histogram().len();
+
+        record();
+        let report = reporter.take();
+        assert_eq!(len(&report, "fixed"), len(&report, "adaptive"));
+
+        // After the first interval, the adaptive stat is fitted to the values it observed.
+        record();
+        // It still records values outside of its fitted bounds.
+        adaptive.add(10);
+        adaptive.add(5_000_000);
+        let report = reporter.take();
+        let (fixed_len, adaptive_len) = (len(&report, "fixed"), len(&report, "adaptive"));
+        assert!(adaptive_len * 2 < fixed_len, "{} vs {}", adaptive_len, fixed_len);
+        let h = report.get_stat("adaptive", &[]).unwrap();
+        assert_eq!(h.count(), 102);
+        assert!(h.max() >= 5_000_000);
+        assert!(h.min() < 500_000, "{}", h.min());
+    }
+
+    #[test]
+    fn test_memory_report() {
+        let (metrics, reporter) = super::new();
+        let reporter = reporter.with_memory_gauge();
+        let narrow = metrics.stat_with_bounds("narrow", 1, 100);
+        let wide = metrics.stat("wide");
+        narrow.add(10);
+        wide.add(1_000_000_000);
+        let atomic = metrics.stat_with_config(
+            "atomic",
+            StatConfig::default().backend(StatBackend::AtomicBuckets),
+        );
+        atomic.add(1);
+
+        let memory = reporter.memory_report();
+        let names = memory.stats.iter().map(|(k, _)| k.name()).collect::<Vec<_>>();
+        assert_eq!(names[0], "wide");
+        assert_eq!(memory.stats.len(), 3);
+        let bytes = |name| memory.stats.iter().find(|&(k, _)| k.name() == name).unwrap().1;
+        assert!(bytes("narrow") < bytes("wide"));
+        assert_eq!(
+            memory.total_bytes,
+            bytes("narrow") + bytes("wide") + bytes("atomic")
+        );
+
+        let report = reporter.peek();
+        let h = report.get_stat("wide", &[]).unwrap();
+        assert!(h.memory_bytes() >= h.histogram().len() * mem::size_of::<usize>());
+        let gauge = reporter.get_gauge("stat_memory_bytes", &[]).unwrap();
+        assert_eq!(gauge, memory.total_bytes);
+    }
+
+    #[test]
+    fn test_gauge_resetting() {
+        let (metrics, mut reporter) = super::new();
+        let written = metrics.gauge_resetting("bytes_written");
+        let conns = metrics.gauge("conns");
+        written.incr(10);
+        conns.set(3);
+
+        assert_eq!(reporter.peek().get_gauge("bytes_written", &[]), Some(10));
+        let report = reporter.take();
+        assert_eq!(report.get_gauge("bytes_written", &[]), Some(10));
+        assert_eq!(report.get_gauge("conns", &[]), Some(3));
+
+        written.incr(4);
+        let report = reporter.take();
+        assert_eq!(report.get_gauge("bytes_written", &[]), Some(4));
+        assert_eq!(report.get_gauge("conns", &[]), Some(3));
+        assert_eq!(reporter.take().get_gauge("bytes_written", &[]), Some(0));
+
+        // Re-registering returns the same gauge, which is pruned once dropped.
+        let again = metrics.gauge_resetting("bytes_written");
+        drop(written);
+        again.incr(1);
+        assert_eq!(reporter.take().get_gauge("bytes_written", &[]), Some(1));
+        drop(again);
+        // As with other series, the final value is reported as the gauge is pruned.
+        assert_eq!(reporter.take().get_gauge("bytes_written", &[]), Some(0));
+        assert_eq!(reporter.take().get_gauge("bytes_written", &[]), None);
+        assert!(metrics.registry.read().unwrap().resetting.is_empty());
+    }
+
+    #[test]
+    fn test_series_diff() {
+        let (metrics, mut reporter) = super::new();
+        let requests = metrics.counter("requests");
+        let conns = metrics.gauge("conns");
+        requests.incr(1);
+        conns.set(2);
+        let prev = reporter.take();
+        assert_eq!(prev.series_added(&prev).count(), 0);
+
+        drop(conns);
+        let routed = metrics.clone().labeled("route", "users").counter("requests");
+        let latency = metrics.stat("latency_ms");
+        routed.incr(1);
+        latency.add(3);
+        // The gauge is pruned by this take, so it is only absent from the next.
+        reporter.take();
+        let report = reporter.take();
+
+        let keys = |it: &mut dyn Iterator<Item = Series>| {
+            let mut keys = it.map(|s| s.key().to_string()).collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            keys(&mut report.series_added(&prev)),
+            vec!["latency_ms", "requests{route=\"users\"}"]
+        );
+        let removed = report.series_removed(&prev).collect::<Vec<_>>();
+        assert_eq!(removed.len(), 1);
+        match removed[0] {
+            Series::Gauge(k, v) => assert_eq!((k.name(), v), ("conns", 2)),
+            _ => panic!("expected a gauge"),
+        }
+        drop((requests, routed, latency));
+    }
+
+    #[test]
+    fn test_label_values() {
+        let (metrics, reporter) = Builder::new()
+            .label_values(LabelValues::Sanitize { max_len: 8 })
+            .build();
+        metrics.clone().labeled("path", "/a\nb\u{0}").counter("requests").incr(1);
+        metrics.clone().labeled("path", "/abcdéfgh").counter("requests").incr(1);
+        metrics.clone().labeled("status", 200).counter("requests").incr(1);
+        let mut values = reporter
+            .peek()
+            .counters()
+            .keys()
+            .flat_map(|k| k.labels().iter().map(|(_, v)| v.to_string()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        values.sort();
+        // Values are truncated to 8 bytes, including replacement characters (of 3 bytes).
+        assert_eq!(values, vec!["/abcdéf", "/a\u{fffd}b", "200"]);
+
+        let (metrics, reporter) = Builder::new()
+            .label_values(LabelValues::Reject { max_len: 8 })
+            .build();
+        let metrics = metrics.labeled("path", "/");
+        assert_eq!(
+            metrics.clone().try_labeled("path", "/a\nb").err(),
+            Some(Error::InvalidLabelValue("path"))
+        );
+        assert!(metrics.clone().try_labeled("path", "/ab").is_ok());
+        // The inherited label is kept.
+        metrics.labeled("path", "/abcdefgh").counter("requests").incr(1);
+        assert_eq!(reporter.peek().get_counter("requests", &[("path", "/")]), Some(1));
+    }
 }
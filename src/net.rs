@@ -0,0 +1,208 @@
+//! Instruments transports, so that proxies may measure per-connection I/O.
+//!
+//! ```
+//! extern crate tacho;
+//!
+//! use std::io::{Cursor, Read};
+//!
+//! fn main() {
+//!     let (metrics, reporter) = tacho::new();
+//!     // Handles are looked up once, and are shared by each connection's transport.
+//!     let io = tacho::net::IoMetrics::new(&metrics.prefixed("conn"));
+//!
+//!     let mut conn = io.instrument(Cursor::new(b"hello".to_vec()));
+//!     let mut buf = Vec::new();
+//!     conn.read_to_end(&mut buf).unwrap();
+//!     drop(conn);
+//!
+//!     let report = reporter.peek();
+//!     assert_eq!(report.get_counter("bytes_read", &[]), Some(5));
+//!     assert_eq!(report.get_stat("duration_ms", &[]).unwrap().count(), 1);
+//! }
+//! ```
+//!
+//! Each transport records:
+//!
+//! - `bytes_read` and `bytes_written`: counters of bytes transferred;
+//! - `read_us` and `write_us`: the latency of each read and write (including flushes) that
+//!   completes. Calls that would block are not recorded, since no I/O was performed;
+//! - `duration_ms`: the lifetime of the connection, recorded when the transport is dropped.
+
+use super::{Counter, Scope, Timer};
+use futures::Poll;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Handles on the metrics recorded by `Instrumented` transports.
+///
+/// Creating handles requires a lookup in the registry, so `IoMetrics` should be created
+/// once (e.g. per listener) and shared by its connections.
+#[derive(Clone, Debug)]
+pub struct IoMetrics {
+    bytes_read: Counter,
+    bytes_written: Counter,
+    read_us: Timer,
+    write_us: Timer,
+    duration_ms: Timer,
+}
+
+impl IoMetrics {
+    pub fn new(scope: &Scope) -> IoMetrics {
+        IoMetrics {
+            bytes_read: scope.counter("bytes_read"),
+            bytes_written: scope.counter("bytes_written"),
+            read_us: scope.timer_us("read_us"),
+            write_us: scope.timer_us("write_us"),
+            duration_ms: scope.timer_ms("duration_ms"),
+        }
+    }
+
+    /// Wraps a transport, recording its I/O into these metrics.
+    pub fn instrument<T>(&self, io: T) -> Instrumented<T> {
+        Instrumented {
+            inner: io,
+            metrics: self.clone(),
+            t0: Instant::now(),
+        }
+    }
+}
+
+/// A transport that records its I/O. See the module documentation.
+pub struct Instrumented<T> {
+    inner: T,
+    metrics: IoMetrics,
+    t0: Instant,
+}
+
+impl<T> Instrumented<T> {
+    /// Wraps a transport, recording its I/O into `scope`.
+    ///
+    /// This looks up each metric in the registry; see `IoMetrics::instrument`.
+    pub fn new(io: T, scope: &Scope) -> Instrumented<T> {
+        IoMetrics::new(scope).instrument(io)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// Records the latency of a call, unless it would have blocked.
+fn timed<T, F>(timer: &Timer, f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    let t0 = Instant::now();
+    let res = f();
+    match res {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        _ => timer.record_since(t0),
+    }
+    res
+}
+
+impl<T: Read> Read for Instrumented<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        let n = timed(&self.metrics.read_us, || inner.read(buf))?;
+        self.metrics.bytes_read.incr(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Instrumented<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        let n = timed(&self.metrics.write_us, || inner.write(buf))?;
+        self.metrics.bytes_written.incr(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        timed(&self.metrics.write_us, || inner.flush())
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Instrumented<T> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Instrumented<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+impl<T> Drop for Instrumented<T> {
+    fn drop(&mut self) {
+        self.metrics.duration_ms.record_since(self.t0);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Instrumented<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Instrumented")
+            .field("inner", &self.inner)
+            .field("metrics", &self.metrics)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Fails each call with `WouldBlock`.
+    struct Blocked;
+
+    impl Read for Blocked {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn test_instrumented() {
+        fn assert_async<T: AsyncRead + AsyncWrite>(_: &T) {}
+
+        let (metrics, reporter) = ::new();
+        let io = IoMetrics::new(&metrics);
+        let mut conn = io.instrument(Cursor::new(Vec::new()));
+        assert_async(&conn);
+        conn.write_all(b"hello").unwrap();
+        conn.flush().unwrap();
+        conn.get_mut().set_position(1);
+        let mut buf = [0; 3];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ell");
+
+        let mut blocked = Instrumented::new(Blocked, &metrics);
+        assert!(blocked.read(&mut buf).is_err());
+
+        {
+            let report = reporter.peek();
+            assert_eq!(report.get_counter("bytes_written", &[]), Some(5));
+            assert_eq!(report.get_counter("bytes_read", &[]), Some(3));
+            // A write and a flush.
+            assert_eq!(report.get_stat("write_us", &[]).unwrap().count(), 2);
+            // The blocked read is not recorded.
+            assert_eq!(report.get_stat("read_us", &[]).unwrap().count(), 1);
+            assert_eq!(report.get_stat("duration_ms", &[]).unwrap().count(), 0);
+        }
+
+        drop(conn);
+        drop(blocked);
+        let report = reporter.peek();
+        assert_eq!(report.get_stat("duration_ms", &[]).unwrap().count(), 2);
+    }
+}
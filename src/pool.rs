@@ -0,0 +1,156 @@
+//! A standard set of metrics for connection pools.
+//!
+//! ```
+//! use std::time::Instant;
+//!
+//! let (metrics, reporter) = tacho::new();
+//! let pool = tacho::pool::Metrics::new(&metrics.prefixed("pool"));
+//! pool.opened();
+//!
+//! let t0 = Instant::now();
+//! {
+//!     // ... wait for an idle connection ...
+//!     let _checkout = pool.checkout(t0);
+//!     assert_eq!(reporter.peek().get_gauge("in_use", &[]), Some(1));
+//! }
+//! assert_eq!(reporter.peek().get_gauge("idle", &[]), Some(1));
+//! ```
+//!
+//! The metrics are:
+//!
+//! - `size`, `idle`, and `in_use`: gauges of the connections in the pool;
+//! - `checkouts`, `timeouts`, and `errors`: counters of checkouts that succeeded, timed
+//!   out, or failed;
+//! - `checkout_wait_us`: the time waited for each successful checkout.
+//!
+//! Gauges are decremented with saturation, so mismatched updates do not wrap.
+
+use super::{Counter, Gauge, Scope, Timer};
+use std::time::Instant;
+
+/// Handles on a connection pool's metrics.
+///
+/// Handles are looked up once, when `Metrics` is created, and are shared by its clones.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    size: Gauge,
+    idle: Gauge,
+    in_use: Gauge,
+    checkouts: Counter,
+    timeouts: Counter,
+    errors: Counter,
+    checkout_wait_us: Timer,
+}
+
+impl Metrics {
+    pub fn new(scope: &Scope) -> Metrics {
+        Metrics {
+            size: scope.gauge("size"),
+            idle: scope.gauge("idle"),
+            in_use: scope.gauge("in_use"),
+            checkouts: scope.counter("checkouts"),
+            timeouts: scope.counter("timeouts"),
+            errors: scope.counter("errors"),
+            checkout_wait_us: scope.timer_us("checkout_wait_us"),
+        }
+    }
+
+    /// Records that an (idle) connection was added to the pool.
+    pub fn opened(&self) {
+        self.size.incr(1);
+        self.idle.incr(1);
+    }
+
+    /// Records that an idle connection was removed from the pool.
+    pub fn closed(&self) {
+        self.size.add_signed(-1);
+        self.idle.add_signed(-1);
+    }
+
+    /// Records that an idle connection was checked out, after waiting since `t0`.
+    ///
+    /// The connection is in use until the returned guard is dropped, when it is idle again
+    /// (or until it is discarded).
+    pub fn checkout(&self, t0: Instant) -> CheckoutGuard {
+        self.checkout_wait_us.record_since(t0);
+        self.checkouts.incr(1);
+        self.idle.add_signed(-1);
+        self.in_use.incr(1);
+        CheckoutGuard {
+            metrics: self.clone(),
+            returned: true,
+        }
+    }
+
+    /// Records that a checkout timed out.
+    pub fn timeout(&self) {
+        self.timeouts.incr(1);
+    }
+
+    /// Records that a checkout failed, e.g. because a connection could not be established.
+    pub fn error(&self) {
+        self.errors.incr(1);
+    }
+}
+
+/// Marks a connection as in use, until it is dropped.
+#[derive(Debug)]
+pub struct CheckoutGuard {
+    metrics: Metrics,
+    returned: bool,
+}
+
+impl CheckoutGuard {
+    /// Records that the connection was closed rather than returned to the pool.
+    pub fn discard(mut self) {
+        self.returned = false;
+    }
+}
+
+impl Drop for CheckoutGuard {
+    fn drop(&mut self) {
+        self.metrics.in_use.add_signed(-1);
+        if self.returned {
+            self.metrics.idle.incr(1);
+        } else {
+            self.metrics.size.add_signed(-1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_metrics() {
+        let (metrics, reporter) = ::new();
+        let pool = Metrics::new(&metrics);
+        let gauges = || {
+            let report = reporter.peek();
+            let g = |name| report.get_gauge(name, &[]).unwrap();
+            (g("size"), g("idle"), g("in_use"))
+        };
+
+        pool.opened();
+        pool.opened();
+        let a = pool.checkout(Instant::now());
+        let b = pool.checkout(Instant::now());
+        assert_eq!(gauges(), (2, 0, 2));
+        pool.timeout();
+        drop(a);
+        assert_eq!(gauges(), (2, 1, 1));
+        b.discard();
+        assert_eq!(gauges(), (1, 1, 0));
+        pool.closed();
+        pool.closed();
+        assert_eq!(gauges(), (0, 0, 0));
+        pool.error();
+
+        let report = reporter.peek();
+        assert_eq!(report.get_counter("checkouts", &[]), Some(2));
+        assert_eq!(report.get_counter("timeouts", &[]), Some(1));
+        assert_eq!(report.get_counter("errors", &[]), Some(1));
+        assert_eq!(report.get_stat("checkout_wait_us", &[]).unwrap().count(), 2);
+    }
+}
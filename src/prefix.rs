@@ -0,0 +1,206 @@
+//! Typed prefixes for router metrics, e.g. `rt:int:srv:0_0_0_0:4140`.
+//!
+//! ```
+//! use tacho::PrefixBuilder;
+//!
+//! let (metrics, reporter) = tacho::new();
+//! let addr = "0.0.0.0:4140".parse().unwrap();
+//! let srv = metrics.prefixed_by(&PrefixBuilder::router("int").server(addr));
+//! let _conns = srv.gauge("conns");
+//!
+//! let report = reporter.peek();
+//! let key = report.gauges().keys().next().unwrap();
+//! assert_eq!(key.to_string(), "rt:int:srv:0_0_0_0:4140:conns");
+//! ```
+
+use super::Prefix;
+use std::cmp;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A prefix segment.
+///
+/// Most segments are static, but those formatted at runtime (e.g. addresses and client ids)
+/// are owned, so that each is freed once no metric's key refers to it. Segments are
+/// compared (and hashed) by their values.
+#[derive(Clone)]
+pub enum PrefixValue {
+    Static(&'static str),
+    Owned(Arc<str>),
+}
+
+impl PrefixValue {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            PrefixValue::Static(s) => s,
+            PrefixValue::Owned(ref s) => s,
+        }
+    }
+}
+
+impl fmt::Display for PrefixValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for PrefixValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl PartialEq for PrefixValue {
+    fn eq(&self, other: &PrefixValue) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for PrefixValue {}
+
+impl<'a> PartialEq<&'a str> for PrefixValue {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialOrd for PrefixValue {
+    fn partial_cmp(&self, other: &PrefixValue) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PrefixValue {
+    fn cmp(&self, other: &PrefixValue) -> cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for PrefixValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl From<&'static str> for PrefixValue {
+    fn from(s: &'static str) -> PrefixValue {
+        PrefixValue::Static(s)
+    }
+}
+
+impl From<String> for PrefixValue {
+    fn from(s: String) -> PrefixValue {
+        PrefixValue::Owned(s.into())
+    }
+}
+
+/// Builds a prefix chain for a router and its servers and clients.
+///
+/// Dynamic segments (addresses and client ids) are formatted here, so that every exporter
+/// sees the same names: `.` is replaced with `_`, as is `:` within an IP address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixBuilder {
+    values: Vec<PrefixValue>,
+}
+
+impl PrefixBuilder {
+    /// Starts a prefix for the router with the given label, i.e. `rt:<label>`.
+    pub fn router(label: &'static str) -> PrefixBuilder {
+        PrefixBuilder { values: vec!["rt".into(), label.into()] }
+    }
+
+    /// Appends a server, i.e. `srv:<ip>:<port>`.
+    pub fn server(self, addr: SocketAddr) -> PrefixBuilder {
+        self.push("srv").push_dynamic(format_addr(&addr))
+    }
+
+    /// Appends a client, i.e. `clnt:<id>`.
+    pub fn client(self, id: &str) -> PrefixBuilder {
+        self.push("clnt").push_dynamic(format_segment(id))
+    }
+
+    /// Appends a service, i.e. `service:<path>`.
+    pub fn service(self, path: &str) -> PrefixBuilder {
+        self.push("service").push_dynamic(format_segment(path))
+    }
+
+    /// Appends a static segment.
+    pub fn push(mut self, value: &'static str) -> PrefixBuilder {
+        self.values.push(value.into());
+        self
+    }
+
+    /// The prefix's segments, from the root.
+    pub fn values(&self) -> &[PrefixValue] {
+        &self.values
+    }
+
+    /// Builds the prefix chain, from the root.
+    pub fn build(&self) -> Arc<Prefix> {
+        self.values.iter().fold(Arc::new(Prefix::Root), |p, v| {
+            Arc::new(Prefix::node(p, v.clone()))
+        })
+    }
+
+    fn push_dynamic(mut self, value: String) -> PrefixBuilder {
+        self.values.push(value.into());
+        self
+    }
+}
+
+/// Formats an address as a prefix segment, e.g. `0_0_0_0:4140`.
+pub fn format_addr(addr: &SocketAddr) -> String {
+    let ip = addr.ip().to_string().replace(['.', ':'], "_");
+    format!("{}:{}", ip, addr.port())
+}
+
+/// Formats a dynamic value as a prefix segment, replacing `.` with `_`.
+fn format_segment(value: &str) -> String {
+    value.replace('.', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_builder() {
+        let v4: SocketAddr = "0.0.0.0:4140".parse().unwrap();
+        let v6: SocketAddr = "[::1]:4141".parse().unwrap();
+        let srv = PrefixBuilder::router("int").server(v4);
+        assert_eq!(srv.values(), &["rt", "int", "srv", "0_0_0_0:4140"]);
+        assert_eq!(format_addr(&v6), "__1:4141");
+
+        let clnt = PrefixBuilder::router("int").client("$/inet/127.1/8080");
+        assert_eq!(clnt.values(), &["rt", "int", "clnt", "$/inet/127_1/8080"]);
+
+        let (metrics, reporter) = ::new();
+        let _a = metrics.clone().prefixed("rt").prefixed("int").prefixed("srv")
+            .prefixed("0_0_0_0:4140").counter("requests");
+        let b = metrics.prefixed_by(&srv).counter("requests");
+        b.incr(1);
+        let report = reporter.peek();
+        assert_eq!(report.counters().len(), 1);
+        assert_eq!(report.counters().keys().next().unwrap().prefix(), &srv.build());
+    }
+    #[test]
+    fn test_dynamic_segments_freed() {
+        let clnt = PrefixBuilder::router("int").client("$/inet/127.1/8080");
+        let segment = match clnt.values()[3] {
+            PrefixValue::Owned(ref s) => Arc::downgrade(s),
+            PrefixValue::Static(_) => panic!("dynamic segments should be owned"),
+        };
+
+        let (metrics, mut reporter) = ::new();
+        let requests = metrics.prefixed_by(&clnt).counter("requests");
+        drop(clnt);
+        assert!(segment.upgrade().is_some());
+
+        // Once the metric is pruned, its key (and so its prefix) is freed.
+        drop(requests);
+        reporter.take();
+        assert!(segment.upgrade().is_none());
+    }
+}
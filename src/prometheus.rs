@@ -1,12 +1,22 @@
-use super::Report;
+use super::{Formatter, HistogramWithSum, Key, LabelValue, Labels, Report, ReportView, Series,
+            SizeSnapshot};
+use super::format::with_fmt;
 use hdrsample::Histogram;
 use std::fmt;
+use std::io;
+use std::str;
 use std::sync::Arc;
 
+pub mod native;
+pub mod openmetrics;
+mod validate;
+
+pub use self::validate::{validate, ValidationError};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
 pub fn string(report: &Report) -> Result<String, fmt::Error> {
-    let mut out = String::with_capacity(8 * 1024);
-    write(&mut out, report)?;
-    Ok(out)
+    Config::default().string(report)
 }
 
 /// Renders a `Report` for Prometheus.
@@ -14,40 +24,271 @@ pub fn write<W>(out: &mut W, report: &Report) -> fmt::Result
 where
     W: fmt::Write,
 {
-    for (k, v) in report.counters() {
-        let name = FmtName::new(k.prefix(), k.name());
-        write_metric(out, &name, &k.labels().into(), v)?;
+    Config::default().write(out, report)
+}
+
+/// Renders a `ReportView` for Prometheus.
+///
+/// Formats directly from the registry, without building a snapshot.
+pub fn write_view<W>(out: &mut W, view: &ReportView) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    Config::default().write_view(out, view)
+}
+
+/// Configures how reports are rendered.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    const_labels: Labels,
+    /// Divisors applied to the values of stats, by name.
+    stat_scales: Vec<(&'static str, f64)>,
+    /// Whether reports are rendered in the OpenMetrics text format.
+    open_metrics: bool,
+}
+
+impl Config {
+    /// Adds a label to every series, e.g. to identify the host or instance.
+    ///
+    /// Constant labels are added as reports are rendered, so they needn't be stored in
+    /// every series' key. A series' own label takes precedence over a constant label of
+    /// the same name.
+    pub fn const_label<V: Into<LabelValue>>(mut self, k: &'static str, v: V) -> Self {
+        self.const_labels.insert(k, v.into());
+        self
     }
 
-    for (k, v) in report.gauges() {
-        let name = FmtName::new(k.prefix(), k.name());
-        write_metric(out, &name, &k.labels().into(), v)?;
+    /// Exports the values of stats named `name` divided by `divisor`, as floats.
+    ///
+    /// For example, a timer that records microseconds may be exported in seconds with a
+    /// divisor of `1_000_000.0`. Bucket bounds, `min`, `max`, and `sum` are all scaled;
+    /// counts are not.
+    pub fn scale_stat(mut self, name: &'static str, divisor: f64) -> Self {
+        assert!(divisor > 0.0, "divisor must be positive");
+        self.stat_scales.retain(|&(n, _)| n != name);
+        self.stat_scales.push((name, divisor));
+        self
     }
 
-    for (k, h) in report.stats() {
-        let name = FmtName::new(k.prefix(), k.name());
-        let labels = k.labels().into();
-        let count = h.count();
-        write_metric(out, &format_args!("{}_{}", name, "count"), &labels, &count)?;
-        if count > 0 {
-            write_buckets(out, &name, &labels, h.histogram())?;
-            write_metric(out, &format_args!("{}_{}", name, "min"), &labels, &h.min())?;
-            write_metric(out, &format_args!("{}_{}", name, "max"), &labels, &h.max())?;
-            write_metric(out, &format_args!("{}_{}", name, "sum"), &labels, &h.sum())?;
+    /// Renders reports in the strict OpenMetrics 1.0 text format (see the `openmetrics`
+    /// module), e.g. for scrapers that validate it.
+    ///
+    /// Only `write`, `string`, and the `Formatter` implementation are affected; series and
+    /// views are always rendered in the Prometheus text format, since OpenMetrics requires
+    /// each family's series to be grouped.
+    pub fn open_metrics(mut self) -> Self {
+        self.open_metrics = true;
+        self
+    }
+
+    /// Returns the content type of rendered reports.
+    pub fn content_type(&self) -> &'static str {
+        if self.open_metrics {
+            openmetrics::CONTENT_TYPE
+        } else {
+            CONTENT_TYPE
+        }
+    }
+
+    pub fn string(&self, report: &Report) -> Result<String, fmt::Error> {
+        let mut out = String::with_capacity(8 * 1024);
+        self.write(&mut out, report)?;
+        Ok(out)
+    }
+
+    /// Renders a `Report` for Prometheus.
+    pub fn write<W>(&self, out: &mut W, report: &Report) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        if self.open_metrics {
+            return openmetrics::write_with(out, report, self);
+        }
+        for series in report.iter_series() {
+            self.write_series(out, series)?;
+        }
+        Ok(())
+    }
+
+    /// Renders a single series, e.g. as yielded by `Report::iter_series`.
+    ///
+    /// This allows a large report to be rendered incrementally, or in parts.
+    pub fn write_series<W>(&self, out: &mut W, series: Series) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        match series {
+            Series::Counter(k, v) | Series::Gauge(k, v) => self.write_value(out, k, &v),
+            Series::Stat(k, h) => self.write_stat(out, k, h),
+            Series::Size(k, s) => self.write_size(out, k, s),
+        }
+    }
+
+    /// Renders a `ReportView` for Prometheus.
+    ///
+    /// Formats directly from the registry, without building a snapshot.
+    pub fn write_view<W>(&self, out: &mut W, view: &ReportView) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        for (k, v) in view.counters() {
+            self.write_value(out, k, &v)?;
+        }
+
+        for (k, v) in view.gauges() {
+            self.write_value(out, k, &v)?;
+        }
+
+        for (k, h) in view.stats() {
+            self.write_stat(out, k, &h)?;
+        }
+
+        for (k, b) in view.sizes() {
+            self.write_size(out, k, &b.snapshot(false))?;
+        }
+
+        Ok(())
+    }
+
+    fn labels<'a>(&'a self, k: &'a Key) -> FmtLabels<'a> {
+        FmtLabels {
+            base: k.labels(),
+            rendered: &rendered(k).labels,
+            consts: &self.const_labels,
+            extra: None,
+        }
+    }
+
+    fn write_value<W>(&self, out: &mut W, k: &Key, v: &usize) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        write_value(out, k, &self.labels(k), *v)
+    }
+
+    /// Returns the divisor applied to a stat's values, if any.
+    fn scale(&self, k: &Key) -> Option<f64> {
+        self.stat_scales
+            .iter()
+            .find(|&&(n, _)| n == k.name())
+            .map(|&(_, d)| d)
+    }
+
+    fn write_stat<W>(&self, out: &mut W, k: &Key, h: &HistogramWithSum) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        write_stat(out, k, &self.labels(k), h, self.scale(k))
+    }
+
+    fn write_size<W>(&self, out: &mut W, k: &Key, s: &SizeSnapshot) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        write_size(out, k, &self.labels(k), s)
+    }
+}
+
+impl Formatter for Config {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        with_fmt(out, |mut out| Config::write(self, &mut out, report))
+    }
+}
+
+/// A key's name and labels, rendered for Prometheus.
+///
+/// Rendering names and labels dominates the cost of formatting a report, so this is done
+/// once per key, and cached on the key.
+#[derive(Clone, Debug)]
+pub struct Rendered {
+    name: String,
+    /// The key's labels, without braces (e.g. `a="1", b="2"`).
+    labels: String,
+}
+
+fn rendered(k: &Key) -> &Rendered {
+    k.rendered.get_or_init(|| {
+        let mut labels = String::new();
+        for (i, (n, v)) in k.labels().iter().enumerate() {
+            if i > 0 {
+                labels.push_str(", ");
+            }
+            write_label(&mut labels, n, v).expect("writing to a String cannot fail");
         }
+        Rendered {
+            name: FmtName::new(k.prefix(), k.name()).to_string(),
+            labels,
+        }
+    })
+}
+
+fn write_value<W>(out: &mut W, k: &Key, labels: &FmtLabels, v: usize) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    write_metric(out, &rendered(k).name, "", labels, Scaled(v as u64, None))
+}
+
+fn write_stat<W>(
+    out: &mut W,
+    k: &Key,
+    labels: &FmtLabels,
+    h: &HistogramWithSum,
+    scale: Option<f64>,
+) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let name = &rendered(k).name;
+    let count = h.count();
+    write_metric(out, name, "_count", labels, Scaled(count, None))?;
+    if count > 0 {
+        write_buckets(out, name, labels, h.histogram(), scale)?;
+        write_metric(out, name, "_min", labels, Scaled(h.min(), scale))?;
+        write_metric(out, name, "_max", labels, Scaled(h.max(), scale))?;
+        write_metric(out, name, "_sum", labels, Scaled(h.sum(), scale))?;
+    }
+    let overflowed = h.overflowed();
+    if overflowed > 0 {
+        write_metric(out, name, "_overflowed", labels, Scaled(overflowed, None))?;
     }
+    Ok(())
+}
 
+fn write_size<W>(out: &mut W, k: &Key, labels: &FmtLabels, s: &SizeSnapshot) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    let name = &rendered(k).name;
+    let count = s.count();
+    write_metric(out, name, "_count", labels, Scaled(count, None))?;
+    if count > 0 {
+        // Omit empty buckets above the largest recorded value.
+        let mut accum = 0;
+        for (le, n) in s.buckets() {
+            accum += n;
+            match le {
+                Some(le) => write_bucket(out, name, labels, Le::Value(Scaled(le, None)), accum)?,
+                None => write_bucket(out, name, labels, Le::Inf, accum)?,
+            }
+            if accum == count && le.is_some() {
+                write_bucket(out, name, labels, Le::Inf, accum)?;
+                break;
+            }
+        }
+        write_metric(out, name, "_sum", labels, Scaled(s.sum(), None))?;
+    }
     Ok(())
 }
 
-fn write_buckets<N, W>(
+fn write_buckets<W>(
     out: &mut W,
-    name: &N,
+    name: &str,
     labels: &FmtLabels,
     h: &Histogram<usize>,
+    scale: Option<f64>,
 ) -> fmt::Result
 where
-    N: fmt::Display,
     W: fmt::Write,
 {
     // `Histogram` tracks buckets as a sequence of minimum values and incremental counts,
@@ -59,62 +300,115 @@ where
     let mut count = 0;
     for bucket in h.iter_recorded() {
         if count > 0 {
-            write_bucket(out, name, labels, &(bucket.value() - 1), accum)?;
+            let le = Scaled(bucket.value() - 1, scale);
+            write_bucket(out, name, labels, Le::Value(le), accum as u64)?;
         }
         count = bucket.count_at_value();
         accum += count;
     }
     if count > 0 {
         // Be explicit about the last bucket.
-        write_bucket(out, name, labels, &h.max(), accum)?;
+        write_bucket(out, name, labels, Le::Value(Scaled(h.max(), scale)), accum as u64)?;
     }
     if accum > 0 {
         // Required to tell prom that the total count.
-        write_bucket(out, name, labels, &"+Inf", accum)?;
+        write_bucket(out, name, labels, Le::Inf, accum as u64)?;
     }
     Ok(())
 }
 
-fn write_bucket<N, M, W>(
-    out: &mut W,
-    name: &N,
-    labels: &FmtLabels,
-    le: &M,
-    count: usize,
-) -> fmt::Result
+fn write_bucket<W>(out: &mut W, name: &str, labels: &FmtLabels, le: Le, count: u64) -> fmt::Result
 where
-    N: fmt::Display,
-    M: fmt::Display,
     W: fmt::Write,
 {
-    write_metric(
-        out,
-        &format_args!("{}_bucket", name),
-        &labels.with_extra("le", format_args!("{}", le)),
-        &count,
-    )
+    write_metric(out, name, "_bucket", &labels.with_extra("le", le), Scaled(count, None))
 }
 
-fn write_metric<W, N, V>(out: &mut W, name: &N, labels: &FmtLabels, v: &V) -> fmt::Result
+/// Writes a sample, e.g. `name_suffix{labels} value`.
+///
+/// Each part is written directly, rather than with `write!`, since this is called for
+/// every line of a report.
+fn write_metric<W>(out: &mut W, name: &str, suffix: &str, labels: &FmtLabels, v: Scaled) -> fmt::Result
 where
     W: fmt::Write,
-    N: fmt::Display,
-    V: fmt::Display,
 {
-    writeln!(out, "{}{} {}", name, labels, v)
+    out.write_str(name)?;
+    out.write_str(suffix)?;
+    labels.write_to(out)?;
+    out.write_char(' ')?;
+    v.write_to(out)?;
+    out.write_char('\n')
 }
 
 fn write_prefix<W>(out: &mut W, prefix: Arc<super::Prefix>) -> fmt::Result
 where
     W: fmt::Write,
 {
-    if let super::Prefix::Node { ref prefix, value } = *prefix {
-        write_prefix(out, prefix.clone())?;
-        write!(out, "{}:", value)?;
+    if let super::Prefix::Node(ref node) = *prefix {
+        let leading = match **node.prefix() {
+            super::Prefix::Root => true,
+            super::Prefix::Node(_) => false,
+        };
+        write_prefix(out, node.prefix().clone())?;
+        write_name(out, node.value().as_str(), leading)?;
+        out.write_char(':')?;
     }
     Ok(())
 }
 
+/// Formats a value, divided by a scale (as a float) if one is given.
+#[derive(Clone, Copy)]
+struct Scaled(u64, Option<f64>);
+
+impl Scaled {
+    fn write_to<W: fmt::Write>(self, out: &mut W) -> fmt::Result {
+        match self.1 {
+            Some(d) => write!(out, "{}", self.0 as f64 / d),
+            None => write_u64(out, self.0),
+        }
+    }
+}
+
+/// Writes an integer, without the overhead of `fmt`.
+fn write_u64<W: fmt::Write>(out: &mut W, mut v: u64) -> fmt::Result {
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (v % 10) as u8;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    out.write_str(str::from_utf8(&buf[i..]).expect("digits are ASCII"))
+}
+
+/// A bucket's upper bound.
+#[derive(Clone, Copy)]
+enum Le {
+    Value(Scaled),
+    Inf,
+}
+
+impl Le {
+    fn write_to<W: fmt::Write>(self, out: &mut W) -> fmt::Result {
+        match self {
+            Le::Value(v) => v.write_to(out),
+            Le::Inf => out.write_str("+Inf"),
+        }
+    }
+}
+
+impl fmt::Display for Scaled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Some(d) => fmt::Display::fmt(&(self.0 as f64 / d), f),
+            None => fmt::Display::fmt(&self.0, f),
+        }
+    }
+}
+
 /// Formats a prefixed name.
 struct FmtName<'a> {
     prefix: &'a Arc<super::Prefix>,
@@ -129,61 +423,383 @@ impl<'a> FmtName<'a> {
 
 impl<'a> fmt::Display for FmtName<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let leading = match **self.prefix {
+            super::Prefix::Root => true,
+            super::Prefix::Node(_) => false,
+        };
         write_prefix(f, self.prefix.clone())?;
-        write!(f, "{}", self.name)?;
-        Ok(())
+        write_name(f, self.name, leading)
+    }
+}
+
+/// Writes part of a metric name, replacing characters that may not appear in names with `_`.
+fn write_name<W: fmt::Write>(out: &mut W, s: &str, leading: bool) -> fmt::Result {
+    if validate::is_metric_name(s) {
+        return out.write_str(s);
+    }
+    for (i, c) in s.chars().enumerate() {
+        let valid = c.is_ascii_alphabetic() || c == '_' || c == ':' ||
+            (c.is_ascii_digit() && !(leading && i == 0));
+        out.write_char(if valid { c } else { '_' })?;
+    }
+    if s.is_empty() && leading {
+        out.write_char('_')?;
+    }
+    Ok(())
+}
+
+/// Writes a label name, replacing characters that may not appear in names with `_`.
+fn write_label_name<W: fmt::Write>(f: &mut W, s: &str) -> fmt::Result {
+    if validate::is_label_name(s) {
+        return f.write_str(s);
+    }
+    for (i, c) in s.chars().enumerate() {
+        let valid = c.is_ascii_alphabetic() || c == '_' || (c.is_ascii_digit() && i > 0);
+        f.write_char(if valid { c } else { '_' })?;
     }
+    if s.is_empty() {
+        f.write_char('_')?;
+    }
+    Ok(())
 }
 
-impl<'a> From<&'a super::Labels> for FmtLabels<'a> {
-    fn from(base: &'a super::Labels) -> Self {
-        FmtLabels { base, extra: None }
+/// Writes a quoted label value, escaping backslashes, quotes, and newlines.
+fn write_label_value<W: fmt::Write>(f: &mut W, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    if s.contains(&['\\', '"', '\n'][..]) {
+        for c in s.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+    } else {
+        f.write_str(s)?;
     }
+    f.write_char('"')
 }
 
 /// Formats labels.
 struct FmtLabels<'a> {
     /// Labels from the original Key.
-    base: &'a super::Labels,
-    /// An export-specific label (for buckets, etc).
-    extra: Option<(&'static str, fmt::Arguments<'a>)>,
+    base: &'a Labels,
+    /// `base`, rendered (see `Rendered`).
+    rendered: &'a str,
+    /// Labels added to every series, unless overridden by `base`.
+    consts: &'a Labels,
+    /// An export-specific label (for buckets, etc), which takes precedence over others.
+    extra: Option<(&'static str, Le)>,
 }
 
 impl<'a> FmtLabels<'a> {
     fn is_empty(&self) -> bool {
-        self.base.is_empty() && self.extra.is_none()
+        self.base.is_empty() && self.consts.is_empty() && self.extra.is_none()
     }
 
     /// Creates a new FmtLabels sharing a common `base` with a new copy of `extra`.
-    fn with_extra(&'a self, k: &'static str, v: fmt::Arguments<'a>) -> FmtLabels<'a> {
+    fn with_extra(&'a self, k: &'static str, v: Le) -> FmtLabels<'a> {
         FmtLabels {
             base: self.base,
+            rendered: self.rendered,
+            consts: self.consts,
             extra: Some((k, v)),
         }
     }
-}
 
-impl<'a> fmt::Display for FmtLabels<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fn write_to<W: fmt::Write>(&self, f: &mut W) -> fmt::Result {
         if self.is_empty() {
             return Ok(());
         }
 
         let mut first = true;
-        write!(f, "{{")?;
+        f.write_char('{')?;
         if let Some((k, v)) = self.extra {
-            write!(f, "{}=\"{}\"", k, v)?;
+            // Extra labels are numeric, so needn't be escaped.
+            f.write_str(k)?;
+            f.write_str("=\"")?;
+            v.write_to(f)?;
+            f.write_char('"')?;
             first = false;
         }
-        for (k, v) in self.base.iter() {
+        let extra = self.extra.map(|(k, _)| k);
+        if extra.is_some_and(|k| self.base.get(k).is_some()) {
+            // The extra label overrides one of the key's, so the rendered labels can't be used.
+            for (k, v) in self.base.iter() {
+                if Some(k) == extra {
+                    continue;
+                }
+                if !first {
+                    f.write_str(", ")?;
+                }
+                write_label(f, k, v)?;
+                first = false;
+            }
+        } else if !self.rendered.is_empty() {
             if !first {
-                write!(f, ", ")?;
+                f.write_str(", ")?;
             }
-            write!(f, "{}=\"{}\"", k, v)?;
+            f.write_str(self.rendered)?;
             first = false;
         }
-        write!(f, "}}")?;
+        for (k, v) in self.consts.iter() {
+            if self.base.get(k).is_some() || Some(k) == extra {
+                continue;
+            }
+            if !first {
+                f.write_str(", ")?;
+            }
+            write_label(f, k, v)?;
+            first = false;
+        }
+        f.write_char('}')
+    }
+}
 
-        Ok(())
+fn write_label<W: fmt::Write>(f: &mut W, k: &str, v: &LabelValue) -> fmt::Result {
+    write_label_name(f, k)?;
+    f.write_char('=')?;
+    v.with_str(|v| write_label_value(f, v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtest::Bencher;
+
+    #[test]
+    fn test_const_labels() {
+        let (metrics, reporter) = ::new();
+        metrics.clone().labeled("instance", "b").counter("requests").incr(1);
+        metrics.stat("latency_ms").add(7);
+
+        let config = Config::default()
+            .const_label("instance", "a")
+            .const_label("host", "h1");
+        let out = config.string(&reporter.peek()).unwrap();
+        assert_eq!(
+            out,
+            "requests{instance=\"b\", host=\"h1\"} 1\n\
+             latency_ms_count{host=\"h1\", instance=\"a\"} 1\n\
+             latency_ms_bucket{le=\"7\", host=\"h1\", instance=\"a\"} 1\n\
+             latency_ms_bucket{le=\"+Inf\", host=\"h1\", instance=\"a\"} 1\n\
+             latency_ms_min{host=\"h1\", instance=\"a\"} 7\n\
+             latency_ms_max{host=\"h1\", instance=\"a\"} 7\n\
+             latency_ms_sum{host=\"h1\", instance=\"a\"} 7\n"
+        );
+    }
+
+    #[test]
+    fn test_write_size() {
+        let (metrics, reporter) = ::new();
+        let payload = metrics.size_stat("payload_bytes");
+        payload.add(3);
+        payload.add(4);
+        payload.add(7);
+
+        let out = string(&reporter.peek()).unwrap();
+        assert_eq!(
+            out,
+            "payload_bytes_count 3\n\
+             payload_bytes_bucket{le=\"1\"} 0\n\
+             payload_bytes_bucket{le=\"2\"} 0\n\
+             payload_bytes_bucket{le=\"4\"} 2\n\
+             payload_bytes_bucket{le=\"8\"} 3\n\
+             payload_bytes_bucket{le=\"+Inf\"} 3\n\
+             payload_bytes_sum 14\n"
+        );
+    }
+
+    #[test]
+    fn test_scale_stat() {
+        let (metrics, reporter) = ::new();
+        let mut latency = metrics.stat("latency_us");
+        latency.add_values(&[500, 123_456]);
+        metrics.stat("size").add(3);
+
+        let config = Config::default().scale_stat("latency_us", 1_000_000.0);
+        let out = config.string(&reporter.peek()).unwrap();
+        assert!(validate(&out).is_ok(), "{}", out);
+        assert!(out.starts_with("latency_us_count 2\n"));
+        assert!(out.contains("latency_us_min 0.0005\n"));
+        assert!(out.contains("latency_us_sum 0.123956\n"));
+        // Unscaled stats are still written as integers.
+        assert!(out.contains("size_sum 3\n"));
+        for line in out.lines().filter(|l| l.starts_with("latency_us_bucket")) {
+            let le = line.split('"').nth(1).unwrap();
+            assert!(le == "+Inf" || le.parse::<f64>().unwrap() < 0.2, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_write_series_paginated() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("a").incr(1);
+        metrics.counter("b").incr(2);
+        metrics.gauge("c").set(3);
+        metrics.stat("d");
+
+        let report = reporter.peek();
+        assert_eq!(report.iter_series().count(), report.len());
+
+        let config = Config::default();
+        let mut pages = Vec::new();
+        for page in 0..2 {
+            let mut out = String::new();
+            for series in report.iter_series().skip(page * 2).take(2) {
+                config.write_series(&mut out, series).unwrap();
+            }
+            pages.push(out);
+        }
+        assert_eq!(pages[0], "a 1\nb 2\n");
+        assert_eq!(pages.concat(), string(&report).unwrap());
+    }
+
+    #[test]
+    fn test_escape() {
+        let (metrics, reporter) = ::new();
+        metrics
+            .prefixed("http-1")
+            .labeled("path", "/a\"b\\c\nd")
+            .labeled("content-type", "json")
+            .counter("2xx")
+            .incr(1);
+
+        let out = string(&reporter.peek()).unwrap();
+        assert_eq!(
+            out,
+            "http_1:2xx{content_type=\"json\", path=\"/a\\\"b\\\\c\\nd\"} 1\n"
+        );
+        assert_eq!(validate(&out), Ok(()));
+    }
+
+    #[test]
+    fn test_write_u64() {
+        for &v in &[0, 7, 10, 1_000_001, u64::MAX] {
+            let mut out = String::new();
+            write_u64(&mut out, v).unwrap();
+            assert_eq!(out, v.to_string());
+        }
+    }
+
+    /// A xorshift generator, so that randomized tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn string(&mut self) -> &'static str {
+            const CHARS: &[char] = &[
+                'a', 'Z', '0', '9', '_', ':', '-', '.', ' ', '"', '\\', '\n', '\t', '{', '}',
+                '=', ',', '#', 'é', '\u{0}',
+            ];
+            let len = self.below(8);
+            let s = (0..len)
+                .map(|_| CHARS[self.below(CHARS.len() as u64) as usize])
+                .collect::<String>();
+            Box::leak(s.into_boxed_str())
+        }
+
+        /// Returns label names, which are sanitized but not deduplicated.
+        fn label_name(&mut self) -> &'static str {
+            const NAMES: &[&str] = &["a", "le", "content-type", "2xx", "é"];
+            NAMES[self.below(NAMES.len() as u64) as usize]
+        }
+
+        /// Returns values of widely varying magnitudes.
+        fn value(&mut self) -> u64 {
+            let bits = self.below(64);
+            self.next() >> bits
+        }
+    }
+
+    #[test]
+    fn test_write_is_valid() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..50 {
+            let (metrics, reporter) = ::new();
+            let mut metrics = metrics.prefixed(rng.string());
+            for _ in 0..rng.below(3) {
+                metrics = metrics.labeled(rng.label_name(), rng.string());
+            }
+
+            metrics.counter(rng.string()).incr(rng.value() as usize);
+            metrics.gauge(rng.string()).set(rng.value() as usize);
+            let stat = metrics.stat(rng.string());
+            let atomic = metrics.stat_with_config(
+                rng.string(),
+                ::StatConfig::default().backend(::StatBackend::AtomicBuckets),
+            );
+            #[cfg(feature = "sketch")]
+            let sketch = metrics.stat_with_config(
+                rng.string(),
+                ::StatConfig::default().backend(::StatBackend::Sketch),
+            );
+            let size = metrics.size_stat(rng.string());
+            for _ in 0..rng.below(20) {
+                let v = rng.value();
+                stat.add(v);
+                atomic.add(v);
+                #[cfg(feature = "sketch")]
+                sketch.add(v);
+                size.add(v);
+            }
+
+            let config = Config::default().const_label(rng.label_name(), rng.string());
+            let out = config.string(&reporter.peek()).unwrap();
+            if let Err(e) = validate(&out) {
+                panic!("{}:\n{}", e, out);
+            }
+        }
+    }
+
+    /// Builds a report with 50k series: 20k each of counters and gauges, and 10k stats.
+    fn mk_report(metrics: &::Scope) -> (Vec<::Counter>, Vec<::Gauge>, Vec<::Stat>) {
+        let metrics = metrics.clone().prefixed("bench").labeled("service", "users");
+        let mut handles = (Vec::new(), Vec::new(), Vec::new());
+        for i in 0..10_000 {
+            let scope = metrics.clone().labeled("endpoint", i).labeled("status", 200);
+            for name in &["requests", "failures"] {
+                let c = scope.counter(name);
+                c.incr(i);
+                handles.0.push(c);
+            }
+            for name in &["in_flight", "queued"] {
+                let g = scope.gauge(name);
+                g.set(i);
+                handles.1.push(g);
+            }
+            let mut stat = scope.stat("latency_ms");
+            stat.add_values(&[1, i as u64 % 100, 250]);
+            handles.2.push(stat);
+        }
+        handles
+    }
+
+    // Building the report takes several seconds without optimizations, so this only runs
+    // with `cargo bench`.
+    #[bench]
+    #[cfg_attr(debug_assertions, ignore)]
+    fn bench_write_50k(b: &mut Bencher) {
+        let (metrics, reporter) = ::new();
+        let _handles = mk_report(&metrics);
+        let report = reporter.peek();
+        assert_eq!(report.len(), 50_000);
+        let mut out = String::with_capacity(16 << 20);
+        b.iter(|| {
+            out.clear();
+            write(&mut out, &report).unwrap();
+            out.len()
+        });
     }
 }
@@ -0,0 +1,374 @@
+//! Renders reports in Prometheus' protobuf exposition format, with stats exported as
+//! native histograms.
+//!
+//! Native histograms have sparse, exponential buckets, so each stat is exported as a single
+//! series rather than as one series per bucket. Stats use schema 3, i.e. each bucket's upper
+//! bound is 2^(1/8) (about 9%) greater than the last. Size stats already have power-of-two
+//! buckets, and use schema 0; values of 0 are counted with 1, and values greater than 2^40
+//! are counted in the bucket above 2^40.
+//!
+//! The output is a sequence of length-delimited `io.prometheus.client.MetricFamily`
+//! messages, and should be served with `CONTENT_TYPE`.
+
+use super::rendered;
+use super::super::{Formatter, HistogramWithSum, Key, Labels, Report, SizeSnapshot};
+use ordermap::OrderMap;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+pub const CONTENT_TYPE: &str = "application/vnd.google.protobuf; \
+                                proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// The schema used for stats.
+const STAT_SCHEMA: i32 = 3;
+
+// `MetricType`s.
+const COUNTER: u64 = 0;
+const GAUGE: u64 = 1;
+const HISTOGRAM: u64 = 4;
+
+/// Renders reports as native histograms via the `Formatter` interface.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Native;
+
+impl Formatter for Native {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        out.write_all(&bytes(report))
+    }
+}
+
+pub fn bytes(report: &Report) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 * 1024);
+    write(&mut out, report);
+    out
+}
+
+/// Renders a `Report` as length-delimited `MetricFamily` messages.
+pub fn write(out: &mut Vec<u8>, report: &Report) {
+    // Series are grouped into families by name.
+    let mut families = Families::new();
+
+    for (k, v) in report.counters() {
+        let metrics = match family(&mut families, k, COUNTER) {
+            Some(m) => m,
+            None => continue,
+        };
+        message(metrics, 4, |m| {
+            write_labels(m, k);
+            message(m, 3, |c| double(c, 1, *v as f64));
+        });
+    }
+
+    for (k, v) in report.gauges() {
+        let metrics = match family(&mut families, k, GAUGE) {
+            Some(m) => m,
+            None => continue,
+        };
+        message(metrics, 4, |m| {
+            write_labels(m, k);
+            message(m, 2, |g| double(g, 1, *v as f64));
+        });
+    }
+
+    for (k, h) in report.stats() {
+        let metrics = match family(&mut families, k, HISTOGRAM) {
+            Some(m) => m,
+            None => continue,
+        };
+        message(metrics, 4, |m| {
+            write_labels(m, k);
+            message(m, 7, |n| write_stat(n, h));
+        });
+    }
+
+    for (k, s) in report.sizes() {
+        let metrics = match family(&mut families, k, HISTOGRAM) {
+            Some(m) => m,
+            None => continue,
+        };
+        message(metrics, 4, |m| {
+            write_labels(m, k);
+            message(m, 7, |n| write_size(n, s));
+        });
+    }
+
+    for (name, Family { kind, metrics, .. }) in families {
+        let mut f = Vec::with_capacity(name.len() + metrics.len() + 8);
+        bytes_field(&mut f, 1, name.as_bytes());
+        uint64(&mut f, 3, kind);
+        f.extend_from_slice(&metrics);
+        varint(out, f.len() as u64);
+        out.extend_from_slice(&f);
+    }
+}
+
+/// Families, by name.
+type Families<'k> = OrderMap<String, Family<'k>>;
+
+/// A family's type, the label sets of its series, and its encoded `Metric`s.
+struct Family<'k> {
+    kind: u64,
+    series: HashSet<&'k Labels>,
+    metrics: Vec<u8>,
+}
+
+/// Returns the encoded `Metric`s of `k`'s family, or `None` (with a warning) if `k` can't
+/// be added to it, i.e. if the family has another type or already has `k`'s labels.
+fn family<'a, 'k>(
+    families: &'a mut Families<'k>,
+    k: &'k Key,
+    kind: u64,
+) -> Option<&'a mut Vec<u8>> {
+    let name = rendered(k).name.clone();
+    let family = families.entry(name).or_insert_with(|| Family {
+        kind,
+        series: HashSet::new(),
+        metrics: Vec::new(),
+    });
+    if family.kind != kind {
+        let (kind, existing) = (kind_name(kind), kind_name(family.kind));
+        warn!("dropped {} {}, whose name is used by a {}", kind, k, existing);
+        return None;
+    }
+    if !family.series.insert(k.labels()) {
+        warn!("dropped {} {}, whose name and labels are in use", kind_name(kind), k);
+        return None;
+    }
+    Some(&mut family.metrics)
+}
+
+fn kind_name(kind: u64) -> &'static str {
+    match kind {
+        COUNTER => "counter",
+        GAUGE => "gauge",
+        _ => "histogram",
+    }
+}
+
+fn write_labels(m: &mut Vec<u8>, k: &Key) {
+    for (name, v) in k.labels() {
+        message(m, 1, |l| {
+            bytes_field(l, 1, name.as_bytes());
+            bytes_field(l, 2, v.to_string().as_bytes());
+        });
+    }
+}
+
+fn write_stat(out: &mut Vec<u8>, h: &HistogramWithSum) {
+    let mut zeros = 0;
+    let mut buckets = BTreeMap::new();
+    for v in h.histogram().iter_recorded() {
+        let n = v.count_at_value() as u64;
+        match v.value() {
+            0 => zeros += n,
+            v => *buckets.entry(index(v, STAT_SCHEMA)).or_insert(0) += n,
+        }
+    }
+    write_native(out, h.count(), h.sum(), STAT_SCHEMA, zeros, &buckets);
+}
+
+fn write_size(out: &mut Vec<u8>, s: &SizeSnapshot) {
+    let mut buckets = BTreeMap::new();
+    for (i, (_, n)) in s.buckets().enumerate() {
+        if n > 0 {
+            buckets.insert(i as i32, n);
+        }
+    }
+    write_native(out, s.count(), s.sum(), 0, 0, &buckets);
+}
+
+/// Writes the fields of a native `Histogram` message.
+fn write_native(
+    out: &mut Vec<u8>,
+    count: u64,
+    sum: u64,
+    schema: i32,
+    zeros: u64,
+    buckets: &BTreeMap<i32, u64>,
+) {
+    uint64(out, 1, count);
+    double(out, 2, sum as f64);
+    sint(out, 5, i64::from(schema));
+    // The zero threshold is always written, so that empty histograms are still recognized
+    // as native histograms.
+    double(out, 6, 0.0);
+    uint64(out, 7, zeros);
+    let (spans, deltas) = spans(buckets);
+    for (offset, len) in spans {
+        message(out, 12, |s| {
+            sint(s, 1, i64::from(offset));
+            uint64(s, 2, u64::from(len));
+        });
+    }
+    for d in deltas {
+        sint(out, 13, d);
+    }
+}
+
+/// Returns the index of the bucket holding `v` (which must be positive).
+///
+/// Bucket `i` holds values in `(base^(i-1), base^i]`, where `base = 2^(2^-schema)`.
+fn index(v: u64, schema: i32) -> i32 {
+    ((v as f64).log2() * f64::from(1 << schema)).ceil() as i32
+}
+
+/// Encodes sparse buckets as spans of consecutive buckets (each offset from the end of the
+/// prior span) and counts, each relative to the prior bucket's.
+fn spans(buckets: &BTreeMap<i32, u64>) -> (Vec<(i32, u32)>, Vec<i64>) {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut deltas = Vec::with_capacity(buckets.len());
+    let mut next = 0;
+    let mut prior = 0;
+    for (&i, &n) in buckets {
+        match spans.last_mut() {
+            Some(&mut (_, ref mut len)) if i == next => *len += 1,
+            _ => spans.push((i - next, 1)),
+        }
+        next = i + 1;
+        deltas.push(n as i64 - prior as i64);
+        prior = n;
+    }
+    (spans, deltas)
+}
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn tag(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    varint(out, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+fn uint64(out: &mut Vec<u8>, field: u32, v: u64) {
+    tag(out, field, 0);
+    varint(out, v);
+}
+
+fn sint(out: &mut Vec<u8>, field: u32, v: i64) {
+    tag(out, field, 0);
+    varint(out, ((v << 1) ^ (v >> 63)) as u64);
+}
+
+fn double(out: &mut Vec<u8>, field: u32, v: f64) {
+    tag(out, field, 1);
+    out.extend_from_slice(&v.to_bits().to_le_bytes());
+}
+
+fn bytes_field(out: &mut Vec<u8>, field: u32, v: &[u8]) {
+    tag(out, field, 2);
+    varint(out, v.len() as u64);
+    out.extend_from_slice(v);
+}
+
+fn message<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, field: u32, f: F) {
+    let mut m = Vec::new();
+    f(&mut m);
+    bytes_field(out, field, &m);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        assert_eq!(index(1, 0), 0);
+        assert_eq!(index(2, 0), 1);
+        assert_eq!(index(3, 0), 2);
+        assert_eq!(index(4, 0), 2);
+        assert_eq!(index(1, 3), 0);
+        assert_eq!(index(2, 3), 8);
+        assert_eq!(index(3, 3), 13);
+        assert_eq!(index(1 << 20, 3), 160);
+    }
+
+    #[test]
+    fn test_spans() {
+        let buckets = vec![(-1, 2), (0, 3), (1, 1), (4, 5)].into_iter().collect();
+        assert_eq!(
+            spans(&buckets),
+            (vec![(-1, 3), (2, 1)], vec![2, 1, -2, 4])
+        );
+        assert_eq!(spans(&BTreeMap::new()), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut out = Vec::new();
+        varint(&mut out, 300);
+        assert_eq!(out, vec![0xac, 0x02]);
+
+        let mut out = Vec::new();
+        sint(&mut out, 1, -1);
+        sint(&mut out, 1, 1);
+        assert_eq!(out, vec![0x08, 0x01, 0x08, 0x02]);
+    }
+
+    #[test]
+    fn test_write() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("requests").incr(2);
+        let mut latency = metrics.stat("latency_us");
+        latency.add_values(&[0, 1, 2]);
+
+        let out = bytes(&reporter.peek());
+
+        let mut requests = Vec::new();
+        bytes_field(&mut requests, 1, b"requests");
+        uint64(&mut requests, 3, COUNTER);
+        message(&mut requests, 4, |m| message(m, 3, |c| double(c, 1, 2.0)));
+
+        let mut latency = Vec::new();
+        bytes_field(&mut latency, 1, b"latency_us");
+        uint64(&mut latency, 3, HISTOGRAM);
+        message(&mut latency, 4, |m| {
+            message(m, 7, |h| {
+                uint64(h, 1, 3);
+                double(h, 2, 3.0);
+                sint(h, 5, 3);
+                double(h, 6, 0.0);
+                uint64(h, 7, 1);
+                message(h, 12, |s| {
+                    sint(s, 1, 0);
+                    uint64(s, 2, 1);
+                });
+                message(h, 12, |s| {
+                    sint(s, 1, 7);
+                    uint64(s, 2, 1);
+                });
+                sint(h, 13, 1);
+                sint(h, 13, 0);
+            })
+        });
+
+        let mut expected = Vec::new();
+        varint(&mut expected, requests.len() as u64);
+        expected.extend_from_slice(&requests);
+        varint(&mut expected, latency.len() as u64);
+        expected.extend_from_slice(&latency);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_kind_conflicts() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("conns").incr(2);
+        metrics.gauge("conns").set(3);
+        metrics.stat("conns").add(1);
+
+        // Only the counter is encoded in the `conns` family.
+        let out = bytes(&reporter.peek());
+        let mut conns = Vec::new();
+        bytes_field(&mut conns, 1, b"conns");
+        uint64(&mut conns, 3, COUNTER);
+        message(&mut conns, 4, |m| message(m, 3, |c| double(c, 1, 2.0)));
+        let mut expected = Vec::new();
+        varint(&mut expected, conns.len() as u64);
+        expected.extend_from_slice(&conns);
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,558 @@
+//! Renders reports in the OpenMetrics 1.0 text format, annotating stats' buckets with
+//! exemplars.
+//!
+//! Unlike the Prometheus text format, each metric family is preceded by its type (and, if
+//! its name ends with a base unit such as `_seconds` or `_bytes`, its unit), counters'
+//! samples are suffixed by `_total`, bucket bounds are canonical floats, and the output
+//! ends with `# EOF`:
+//!
+//! ```text
+//! # TYPE http:requests counter
+//! http:requests_total{status="200"} 12
+//! http:requests_created{status="200"} 1499999000.000
+//! # TYPE http:latency_seconds histogram
+//! # UNIT http:latency_seconds seconds
+//! http:latency_seconds_bucket{le="0.01"} 1 # {trace_id="4bf92f3577b34da6"} 0.01 1500000000.000
+//! http:latency_seconds_bucket{le="+Inf"} 1
+//! http:latency_seconds_count 1
+//! http:latency_seconds_sum 0.01
+//! http:latency_seconds_created 1499999940.000
+//! # EOF
+//! ```
+//!
+//! Counters and stats are followed by a `_created` sample, so that scrapers can detect
+//! resets: a counter's is when it was registered (see `Key::created`), and a stat's is
+//! when it was created or last cleared, e.g. by `Reporter::take` (see
+//! `HistogramWithSum::created`).
+//!
+//! Each bucket is annotated with the most recent of its stat's exemplars (see
+//! `Stat::add_with_exemplar`) that falls within it. Stats' `min`, `max`, and `overflowed`
+//! series are not part of an OpenMetrics histogram, and are omitted.
+//!
+//! OpenMetrics forbids a name from being used by more than one family. So, a series whose
+//! name is already used by a family of another kind is dropped, with a warning, rather than
+//! rendered invalidly; as is a gauge whose name is that of another family's samples (e.g.
+//! `latency_count`, when there is a stat named `latency`).
+//!
+//! Reports may also be rendered in this format via `Config::open_metrics`, which applies
+//! the config's constant labels and scales.
+
+use super::{rendered, write_label, write_label_value, Config, Scaled};
+use super::super::{Exemplar, Formatter, HistogramWithSum, Key, Labels, Report, SizeSnapshot};
+use super::super::format::with_fmt;
+use ordermap::OrderMap;
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Suffixes that OpenMetrics recognizes as (base) units.
+const UNITS: [&str; 9] = [
+    "seconds",
+    "bytes",
+    "ratio",
+    "meters",
+    "volts",
+    "amperes",
+    "joules",
+    "grams",
+    "celsius",
+];
+
+const COUNTER: &str = "counter";
+const GAUGE: &str = "gauge";
+const HISTOGRAM: &str = "histogram";
+
+/// Renders reports in the OpenMetrics text format via the `Formatter` interface.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OpenMetrics;
+
+impl Formatter for OpenMetrics {
+    fn write(&self, out: &mut dyn io::Write, report: &Report) -> io::Result<()> {
+        with_fmt(out, |mut out| write(&mut out, report))
+    }
+}
+
+pub fn string(report: &Report) -> Result<String, fmt::Error> {
+    let mut out = String::with_capacity(8 * 1024);
+    write(&mut out, report)?;
+    Ok(out)
+}
+
+/// Renders a `Report` in the OpenMetrics text format.
+pub fn write<W>(out: &mut W, report: &Report) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    write_with(out, report, &Config::default())
+}
+
+/// Renders a `Report` in the OpenMetrics text format, with the constant labels and scales
+/// from `config`.
+pub(super) fn write_with<W>(out: &mut W, report: &Report, config: &Config) -> fmt::Result
+where
+    W: fmt::Write,
+{
+    // A family's samples must be contiguous, so series are grouped by name.
+    let mut families = Families::new();
+    let consts = &config.const_labels;
+
+    for (k, v) in report.counters() {
+        let name = rendered(k).name.clone();
+        let name = name.trim_end_matches("_total");
+        if let Some(out) = family(&mut families, name, COUNTER, k) {
+            let labels = FmtLabels::new(k, consts, None);
+            writeln!(out, "{}_total{} {}", name, labels, v)?;
+            writeln!(out, "{}_created{} {}", name, labels, Timestamp(k.created()))?;
+        }
+    }
+
+    for (k, v) in report.gauges() {
+        let name = rendered(k).name.clone();
+        if let Some(out) = family(&mut families, &name, GAUGE, k) {
+            writeln!(out, "{}{} {}", name, FmtLabels::new(k, consts, None), v)?;
+        }
+    }
+
+    for (k, h) in report.stats() {
+        let name = rendered(k).name.clone();
+        if let Some(out) = family(&mut families, &name, HISTOGRAM, k) {
+            write_stat(out, &name, k, consts, h, config.scale(k))?;
+        }
+    }
+
+    for (k, s) in report.sizes() {
+        let name = rendered(k).name.clone();
+        if let Some(out) = family(&mut families, &name, HISTOGRAM, k) {
+            write_size(out, &name, k, consts, s)?;
+        }
+    }
+
+    // Families' names are distinct, but their samples' names may not be. Counters' and
+    // histograms' samples are claimed first, so that a conflicting gauge is dropped.
+    let mut samples = HashSet::new();
+    for (name, family) in &families {
+        if family.kind != GAUGE {
+            let suffixes: &[&str] = match family.kind {
+                COUNTER => &["_total", "_created"],
+                _ => &["_bucket", "_count", "_sum", "_created"],
+            };
+            samples.extend(suffixes.iter().map(|s| format!("{}{}", name, s)));
+        }
+    }
+
+    for (name, family) in &families {
+        if family.kind == GAUGE && samples.contains(name) {
+            warn!("dropped gauge family {}, whose name is used by another family", name);
+            continue;
+        }
+        writeln!(out, "# TYPE {} {}", name, family.kind)?;
+        if let Some(unit) = unit(name) {
+            writeln!(out, "# UNIT {} {}", name, unit)?;
+        }
+        out.write_str(&family.samples)?;
+    }
+    out.write_str("# EOF\n")
+}
+
+/// Families, by name.
+type Families<'a> = OrderMap<String, Family<'a>>;
+
+struct Family<'a> {
+    kind: &'static str,
+    /// The label sets of the family's series, which must be distinct.
+    series: HashSet<&'a Labels>,
+    samples: String,
+}
+
+/// Returns the buffer into which the series identified by `k` should be rendered, or `None`
+/// if it conflicts with an existing series.
+fn family<'a, 'k>(
+    families: &'a mut Families<'k>,
+    name: &str,
+    kind: &'static str,
+    k: &'k Key,
+) -> Option<&'a mut String> {
+    let family = families.entry(name.to_string()).or_insert_with(|| Family {
+        kind,
+        series: HashSet::new(),
+        samples: String::new(),
+    });
+    if family.kind != kind {
+        warn!("dropped {} {}, whose name is used by a {}", kind, k, family.kind);
+        return None;
+    }
+    // E.g. the counters `requests` and `requests_total` are both rendered as `requests_total`.
+    if !family.series.insert(k.labels()) {
+        warn!("dropped {} {}, whose name and labels are in use", kind, k);
+        return None;
+    }
+    Some(&mut family.samples)
+}
+
+/// Returns the unit that a family's name ends with, if any.
+fn unit(name: &str) -> Option<&'static str> {
+    let (_, suffix) = name.rsplit_once('_')?;
+    UNITS.iter().find(|&&u| u == suffix).cloned()
+}
+
+fn write_stat(
+    out: &mut String,
+    name: &str,
+    k: &Key,
+    consts: &Labels,
+    h: &HistogramWithSum,
+    scale: Option<f64>,
+) -> fmt::Result {
+    // As in the Prometheus text format, each recorded value's bucket ends just below the
+    // next recorded value.
+    let mut buckets = Vec::new();
+    let mut accum = 0;
+    let mut count = 0;
+    for bucket in h.histogram().iter_recorded() {
+        if count > 0 {
+            buckets.push((bucket.value() - 1, accum));
+        }
+        count = bucket.count_at_value() as u64;
+        accum += count;
+    }
+    if count > 0 {
+        buckets.push((h.max(), accum));
+    }
+
+    let mut low = None;
+    for &(le, n) in &buckets {
+        let labels = FmtLabels::new(k, consts, Some(Le::Value(le, scale)));
+        write!(out, "{}_bucket{} {}", name, labels, n)?;
+        let within = |e: &&Exemplar| low.is_none_or(|l| e.value > l) && e.value <= le;
+        write_exemplar(out, h.exemplars().filter(within).last(), scale)?;
+        low = Some(le);
+    }
+    let labels = FmtLabels::new(k, consts, Some(Le::Inf));
+    write!(out, "{}_bucket{} {}", name, labels, h.count())?;
+    let above = |e: &&Exemplar| low.is_none_or(|l| e.value > l);
+    write_exemplar(out, h.exemplars().filter(above).last(), scale)?;
+
+    let labels = FmtLabels::new(k, consts, None);
+    writeln!(out, "{}_count{} {}", name, labels, h.count())?;
+    writeln!(out, "{}_sum{} {}", name, labels, Scaled(h.sum(), scale))?;
+    writeln!(out, "{}_created{} {}", name, labels, Timestamp(h.created()))
+}
+
+fn write_size(
+    out: &mut String,
+    name: &str,
+    k: &Key,
+    consts: &Labels,
+    s: &SizeSnapshot,
+) -> fmt::Result {
+    let count = s.count();
+    let mut accum = 0;
+    for (le, n) in s.buckets() {
+        accum += n;
+        if let Some(le) = le {
+            let labels = FmtLabels::new(k, consts, Some(Le::Value(le, None)));
+            writeln!(out, "{}_bucket{} {}", name, labels, accum)?;
+            // Omit empty buckets above the largest recorded value.
+            if accum == count {
+                break;
+            }
+        }
+    }
+    let labels = FmtLabels::new(k, consts, Some(Le::Inf));
+    writeln!(out, "{}_bucket{} {}", name, labels, count)?;
+    let labels = FmtLabels::new(k, consts, None);
+    writeln!(out, "{}_count{} {}", name, labels, count)?;
+    writeln!(out, "{}_sum{} {}", name, labels, s.sum())
+}
+
+/// Ends a sample's line, annotating it with an exemplar if there is one.
+///
+/// The exemplar's value is scaled as its bucket's bound is, so that it falls within it.
+fn write_exemplar(
+    out: &mut String,
+    exemplar: Option<&Exemplar>,
+    scale: Option<f64>,
+) -> fmt::Result {
+    if let Some(e) = exemplar {
+        let ts = Timestamp(e.timestamp);
+        let v = Scaled(e.value, scale);
+        write!(out, " # {{trace_id={}}} {} {}", TraceId(&e.trace_id), v, ts)?;
+    }
+    out.write_char('\n')
+}
+
+/// Formats a time as seconds since the epoch, with millisecond precision.
+struct Timestamp(SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ts = self.0
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        write!(f, "{:.3}", ts)
+    }
+}
+
+/// A bucket's upper bound, formatted as a canonical float (e.g. `10.0`).
+#[derive(Copy, Clone)]
+enum Le {
+    Value(u64, Option<f64>),
+    Inf,
+}
+
+impl fmt::Display for Le {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Le::Value(v, scale) => {
+                let v = scale.map(|d| v as f64 / d).unwrap_or(v as f64);
+                if v.fract() == 0.0 {
+                    write!(f, "{:.1}", v)
+                } else {
+                    write!(f, "{}", v)
+                }
+            }
+            Le::Inf => f.write_str("+Inf"),
+        }
+    }
+}
+
+/// Formats a key's labels and constant labels, with an optional `le` label, separated by
+/// commas (without spaces, as OpenMetrics requires).
+struct FmtLabels<'a> {
+    base: &'a Labels,
+    consts: &'a Labels,
+    le: Option<Le>,
+}
+
+impl<'a> FmtLabels<'a> {
+    fn new(k: &'a Key, consts: &'a Labels, le: Option<Le>) -> FmtLabels<'a> {
+        FmtLabels {
+            base: k.labels(),
+            consts,
+            le,
+        }
+    }
+}
+
+impl fmt::Display for FmtLabels<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reserved = |k: &str| k == "le" && self.le.is_some();
+        let base = self.base.iter().filter(|&(k, _)| !reserved(k));
+        let consts = self
+            .consts
+            .iter()
+            .filter(|&(k, _)| self.base.get(k).is_none() && !reserved(k));
+        let mut labels = base.chain(consts).peekable();
+        if labels.peek().is_none() && self.le.is_none() {
+            return Ok(());
+        }
+
+        f.write_char('{')?;
+        let mut first = true;
+        for (k, v) in labels {
+            if !first {
+                f.write_char(',')?;
+            }
+            write_label(f, k, v)?;
+            first = false;
+        }
+        if let Some(le) = self.le {
+            if !first {
+                f.write_char(',')?;
+            }
+            write!(f, "le=\"{}\"", le)?;
+        }
+        f.write_char('}')
+    }
+}
+
+/// Formats a quoted, escaped trace ID.
+struct TraceId<'a>(&'a str);
+
+impl fmt::Display for TraceId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_label_value(f, self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let (metrics, reporter) = ::new();
+        let http = metrics.prefixed("http");
+        http.clone().labeled("status", 200).counter("requests_total").incr(12);
+        http.clone().labeled("status", 500).counter("requests_total").incr(1);
+        http.gauge("conns").set(3);
+        let latency = http.stat("latency_ms");
+        latency.add_with_exemplar(10, "a");
+        latency.add_with_exemplar(10, "b");
+        latency.add(20);
+        latency.add_with_exemplar(30, "c\"d");
+        http.size_stat("payload_bytes").add(3);
+
+        let out = string(&reporter.peek()).unwrap();
+        let lines = out.lines().filter(|l| !l.contains("_created")).collect::<Vec<_>>();
+        assert_eq!(
+            &lines[..6],
+            &[
+                "# TYPE http:requests counter",
+                "http:requests_total{status=\"200\"} 12",
+                "http:requests_total{status=\"500\"} 1",
+                "# TYPE http:conns gauge",
+                "http:conns 3",
+                "# TYPE http:latency_ms histogram",
+            ]
+        );
+        // The most recent exemplar in each bucket annotates it.
+        assert!(
+            lines[6].starts_with("http:latency_ms_bucket{le=\"19.0\"} 2 # {trace_id=\"b\"} 10 ")
+        );
+        assert_eq!(lines[7], "http:latency_ms_bucket{le=\"29.0\"} 3");
+        assert!(lines[8].starts_with(
+            "http:latency_ms_bucket{le=\"30.0\"} 4 # {trace_id=\"c\\\"d\"} 30 "
+        ));
+        assert_eq!(lines[9], "http:latency_ms_bucket{le=\"+Inf\"} 4");
+        assert_eq!(lines[10], "http:latency_ms_count 4");
+        assert_eq!(lines[11], "http:latency_ms_sum 70");
+        assert_eq!(
+            &lines[12..],
+            &[
+                "# TYPE http:payload_bytes histogram",
+                "# UNIT http:payload_bytes bytes",
+                "http:payload_bytes_bucket{le=\"1.0\"} 0",
+                "http:payload_bytes_bucket{le=\"2.0\"} 0",
+                "http:payload_bytes_bucket{le=\"4.0\"} 1",
+                "http:payload_bytes_bucket{le=\"+Inf\"} 1",
+                "http:payload_bytes_count 1",
+                "http:payload_bytes_sum 3",
+                "# EOF",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let (metrics, reporter) = ::new();
+        metrics.counter("requests").incr(1);
+        // Rendered as `requests_total`, like the counter above.
+        metrics.counter("requests_total").incr(2);
+        metrics.gauge("requests").set(3);
+        // Shares its name with the `latency` histogram's count.
+        metrics.gauge("latency_count").set(1);
+        metrics.stat("latency").add(1);
+
+        let out = string(&reporter.peek()).unwrap();
+        assert_eq!(
+            out.lines().filter(|l| l.starts_with("# TYPE")).collect::<Vec<_>>(),
+            vec!["# TYPE requests counter", "# TYPE latency histogram"]
+        );
+        assert!(out.contains("\nrequests_total 1\n"));
+        assert!(!out.contains("requests_total 2"));
+        assert!(!out.contains("requests 3"));
+        assert!(out.contains("\nlatency_count 1\nlatency_sum 1\nlatency_created "));
+    }
+
+    #[test]
+    fn test_config() {
+        let (metrics, reporter) = ::new();
+        metrics.labeled("le", "x").stat("latency_seconds").add(1_500);
+        let config = Config::default()
+            .const_label("instance", "a")
+            .scale_stat("latency_seconds", 1_000.0)
+            .open_metrics();
+        assert_eq!(config.content_type(), CONTENT_TYPE);
+
+        let out = config.string(&reporter.peek()).unwrap();
+        let lines = out.lines().filter(|l| !l.contains("_created")).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "# TYPE latency_seconds histogram",
+                "# UNIT latency_seconds seconds",
+                "latency_seconds_bucket{instance=\"a\",le=\"1.5\"} 1",
+                "latency_seconds_bucket{instance=\"a\",le=\"+Inf\"} 1",
+                "latency_seconds_count{le=\"x\",instance=\"a\"} 1",
+                "latency_seconds_sum{le=\"x\",instance=\"a\"} 1.5",
+                "# EOF",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scaled_exemplars() {
+        let (metrics, reporter) = ::new();
+        let latency = metrics.stat("latency_seconds");
+        latency.add_with_exemplar(10, "a");
+        latency.add_with_exemplar(1_500, "b");
+        let config = Config::default()
+            .scale_stat("latency_seconds", 1_000.0)
+            .open_metrics();
+
+        let out = config.string(&reporter.peek()).unwrap();
+        let lines = out.lines().filter(|l| l.contains("_bucket")).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        // Exemplars are scaled as their buckets' bounds are.
+        let a = "latency_seconds_bucket{le=\"1.499\"} 1 # {trace_id=\"a\"} 0.01 ";
+        let b = "latency_seconds_bucket{le=\"1.5\"} 2 # {trace_id=\"b\"} 1.5 ";
+        assert!(lines[0].starts_with(a), "{}", lines[0]);
+        assert!(lines[1].starts_with(b), "{}", lines[1]);
+        assert_eq!(lines[2], "latency_seconds_bucket{le=\"+Inf\"} 2");
+    }
+
+    #[test]
+    fn test_exemplars_bounded() {
+        let (metrics, mut reporter) = ::new();
+        let stat = metrics.stat("latency_ms");
+        for i in 0..20 {
+            stat.add_with_exemplar(i, &i.to_string());
+        }
+        let report = reporter.take();
+        let h = report.get_stat("latency_ms", &[]).unwrap();
+        let ids = h.exemplars().map(|e| e.trace_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["12", "13", "14", "15", "16", "17", "18", "19"]);
+
+        // Exemplars are cleared with the stat.
+        stat.add(1);
+        let report = reporter.take();
+        assert_eq!(report.get_stat("latency_ms", &[]).unwrap().exemplars().count(), 0);
+    }
+
+    #[test]
+    fn test_created() {
+        let t0 = SystemTime::now();
+        let (metrics, mut reporter) = ::new();
+        let requests = metrics.counter("requests");
+        let latency = metrics.stat("latency");
+        requests.incr(1);
+        latency.add(1);
+        let created = |out: &str, name: &str| {
+            let line = out.lines()
+                .find(|l| l.starts_with(name))
+                .expect("expected a created sample");
+            let secs = line.rsplit(' ').next().unwrap().parse::<f64>().unwrap();
+            UNIX_EPOCH + ::std::time::Duration::from_millis((secs * 1_000.0).round() as u64)
+        };
+        // Timestamps are rendered to the millisecond.
+        let ms = ::std::time::Duration::from_millis(1);
+
+        let out = string(&reporter.take()).unwrap();
+        let requests_created = created(&out, "requests_created ");
+        assert!(requests_created + ms >= t0);
+        let latency_created = created(&out, "latency_created ");
+        assert!(latency_created + ms >= t0);
+
+        // Stats are cleared as they are taken, but counters are not.
+        ::std::thread::sleep(::std::time::Duration::from_millis(5));
+        let out = string(&reporter.take()).unwrap();
+        assert_eq!(created(&out, "requests_created "), requests_created);
+        assert!(created(&out, "latency_created ") > latency_created);
+        drop((requests, latency));
+    }
+}
@@ -0,0 +1,229 @@
+//! Checks text against the Prometheus exposition format.
+//!
+//! `validate` is meant for tests (e.g. in downstream CI), to catch output that a Prometheus
+//! server would refuse to scrape. It checks each line's syntax, but not the relationships
+//! between lines (e.g. that a histogram's buckets are cumulative).
+
+use std::error;
+use std::fmt;
+
+/// Describes the first line of a report that is not valid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The line number, counting from 1.
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid exposition at line {}: {}", self.line, self.reason)
+    }
+}
+
+impl error::Error for ValidationError {}
+
+/// Checks that `text` is valid in the Prometheus text exposition format.
+pub fn validate(text: &str) -> Result<(), ValidationError> {
+    if !text.is_empty() && !text.ends_with('\n') {
+        return Err(ValidationError {
+            line: text.lines().count(),
+            reason: "missing trailing newline",
+        });
+    }
+    for (i, line) in text.split_terminator('\n').enumerate() {
+        let res = if line.starts_with('#') {
+            comment(line)
+        } else if line.trim().is_empty() {
+            Ok(())
+        } else {
+            sample(line)
+        };
+        res.map_err(|reason| ValidationError { line: i + 1, reason })?;
+    }
+    Ok(())
+}
+
+/// Checks `# HELP` and `# TYPE` lines; other comments are ignored.
+fn comment(line: &str) -> Result<(), &'static str> {
+    let mut words = line[1..].split_whitespace();
+    match words.next() {
+        Some("HELP") => {
+            match words.next() {
+                Some(name) if is_metric_name(name) => Ok(()),
+                _ => Err("invalid metric name in HELP"),
+            }
+        }
+        Some("TYPE") => {
+            match words.next() {
+                Some(name) if is_metric_name(name) => {}
+                _ => return Err("invalid metric name in TYPE"),
+            }
+            match words.next() {
+                Some("counter") | Some("gauge") | Some("histogram") | Some("summary") |
+                Some("untyped") => {}
+                _ => return Err("invalid metric type"),
+            }
+            match words.next() {
+                None => Ok(()),
+                Some(_) => Err("unexpected text after TYPE"),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks a sample line: `name{labels} value [timestamp]`.
+fn sample(line: &str) -> Result<(), &'static str> {
+    let line = line.trim_start_matches(&[' ', '\t'][..]);
+    let end = line.find(&['{', ' ', '\t'][..]).unwrap_or(line.len());
+    if !is_metric_name(&line[..end]) {
+        return Err("invalid metric name");
+    }
+
+    let mut rest = &line[end..];
+    if rest.starts_with('{') {
+        rest = labels(&rest[1..])?;
+    }
+
+    let mut words = rest.split_whitespace();
+    match words.next() {
+        Some(v) if is_value(v) => {}
+        Some(_) => return Err("invalid value"),
+        None => return Err("missing value"),
+    }
+    match words.next() {
+        None => {}
+        Some(ts) if ts.parse::<i64>().is_ok() => {}
+        Some(_) => return Err("invalid timestamp"),
+    }
+    match words.next() {
+        None => Ok(()),
+        Some(_) => Err("unexpected text after sample"),
+    }
+}
+
+/// Checks a label set (following its opening brace), returning the text after it.
+fn labels(mut s: &str) -> Result<&str, &'static str> {
+    let mut names: Vec<&str> = Vec::new();
+    loop {
+        s = s.trim_start();
+        if let Some(rest) = s.strip_prefix('}') {
+            return Ok(rest);
+        }
+
+        let end = s.find(|c: char| c == '=' || c.is_whitespace())
+            .ok_or("unterminated labels")?;
+        let name = &s[..end];
+        if !is_label_name(name) {
+            return Err("invalid label name");
+        }
+        if names.contains(&name) {
+            return Err("duplicate label name");
+        }
+        names.push(name);
+
+        s = s[end..].trim_start();
+        s = s.strip_prefix('=').ok_or("expected '=' after label name")?;
+        s = s.trim_start();
+        s = s.strip_prefix('"').ok_or("expected quoted label value")?;
+        s = label_value(s)?;
+
+        s = s.trim_start();
+        if let Some(rest) = s.strip_prefix(',') {
+            s = rest;
+        } else if !s.starts_with('}') {
+            return Err("expected ',' or '}' after label value");
+        }
+    }
+}
+
+/// Checks a label value (following its opening quote), returning the text after it.
+fn label_value(s: &str) -> Result<&str, &'static str> {
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok(&s[i + 1..]),
+            '\\' => {
+                match chars.next() {
+                    Some((_, '\\')) | Some((_, '"')) | Some((_, 'n')) => {}
+                    _ => return Err("invalid escape in label value"),
+                }
+            }
+            '\n' => return Err("newline in label value"),
+            _ => {}
+        }
+    }
+    Err("unterminated label value")
+}
+
+/// Matches `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+pub(super) fn is_metric_name(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    match bytes.next() {
+        Some(b) if b.is_ascii_alphabetic() || b == b'_' || b == b':' => {}
+        _ => return false,
+    }
+    bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b':')
+}
+
+/// Matches `[a-zA-Z_][a-zA-Z0-9_]*`.
+pub(super) fn is_label_name(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    match bytes.next() {
+        Some(b) if b.is_ascii_alphabetic() || b == b'_' => {}
+        _ => return false,
+    }
+    bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+fn is_value(s: &str) -> bool {
+    match s {
+        "+Inf" | "-Inf" | "NaN" => true,
+        _ => {
+            // Rust also parses "inf" and "nan", which Prometheus does not accept.
+            s.bytes().any(|b| b.is_ascii_digit()) && s.parse::<f64>().is_ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(""), Ok(()));
+        assert_eq!(
+            validate(
+                "# HELP requests Requests served.\n\
+                 # TYPE requests counter\n\
+                 requests{status=\"200\", path=\"/a\\\"b\\\\\\n\",} 12\n\
+                 \n\
+                 latency_ms_bucket{le=\"+Inf\"} 3 1500000000000\n\
+                 rt:conns -Inf\n\
+                 ratio 1.5e-3\n",
+            ),
+            Ok(())
+        );
+
+        let invalid = |text: &str| validate(text).unwrap_err().reason;
+        assert_eq!(invalid("requests 1"), "missing trailing newline");
+        assert_eq!(invalid("1requests 1\n"), "invalid metric name");
+        assert_eq!(invalid("http-requests 1\n"), "invalid metric name");
+        assert_eq!(invalid("requests\n"), "missing value");
+        assert_eq!(invalid("requests one\n"), "invalid value");
+        assert_eq!(invalid("requests inf\n"), "invalid value");
+        assert_eq!(invalid("requests 1 2 3\n"), "unexpected text after sample");
+        assert_eq!(invalid("requests{a:b=\"1\"} 1\n"), "invalid label name");
+        assert_eq!(invalid("requests{a=\"1\",a=\"2\"} 1\n"), "duplicate label name");
+        assert_eq!(invalid("requests{a=1} 1\n"), "expected quoted label value");
+        assert_eq!(invalid("requests{a=\"\\t\"} 1\n"), "invalid escape in label value");
+        assert_eq!(invalid("requests{a=\"1\" b=\"2\"} 1\n"), "expected ',' or '}' after label value");
+        assert_eq!(invalid("requests{a=\"1\n\"} 1\n"), "unterminated label value");
+        assert_eq!(invalid("# TYPE requests counters\n"), "invalid metric type");
+
+        let err = validate("requests 1\nrequests{ 1\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+}
@@ -0,0 +1,97 @@
+//! Pairs of counters that are reported together, e.g. cache hits and lookups.
+//!
+//! A `Ratio` owns a numerator and a denominator counter, created from the same scope (so
+//! they share labels) and dropped together (so they are pruned together). Optionally, the
+//! ratio of the two (since the counters were created) is computed as each report is taken
+//! and exported as a gauge, in parts per million.
+
+use super::{Counter, Gauge};
+use std::sync::Weak;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts events (the denominator) and the subset of them that satisfy some condition (the
+/// numerator).
+#[derive(Clone, Debug)]
+pub struct Ratio {
+    pub(super) numerator: Counter,
+    pub(super) denominator: Counter,
+    /// Held so that the ratio gauge is exported for as long as the Ratio is in use.
+    pub(super) _gauge: Option<Gauge>,
+}
+
+impl Ratio {
+    /// Counts an event, and counts it in the numerator if `hit` is true.
+    pub fn incr(&self, hit: bool) {
+        if hit {
+            self.numerator.incr(1);
+        }
+        self.denominator.incr(1);
+    }
+
+    /// Adds to both counters.
+    pub fn add(&self, numerator: usize, denominator: usize) {
+        self.numerator.incr(numerator);
+        self.denominator.incr(denominator);
+    }
+
+    pub fn numerator(&self) -> &Counter {
+        &self.numerator
+    }
+
+    pub fn denominator(&self) -> &Counter {
+        &self.denominator
+    }
+}
+
+/// Computes a ratio gauge from its counters, stored in the registry.
+pub struct RatioGauge {
+    pub(super) numerator: Weak<AtomicUsize>,
+    pub(super) denominator: Weak<AtomicUsize>,
+    pub(super) gauge: Weak<AtomicUsize>,
+}
+
+impl RatioGauge {
+    /// Updates the gauge, returning false if the gauge is no longer in use.
+    pub fn update(&self) -> bool {
+        // Once the Ratio is dropped, only this handle refers to the gauge.
+        if self.gauge.weak_count() <= 1 {
+            return false;
+        }
+        match (self.gauge.upgrade(), self.numerator.upgrade(), self.denominator.upgrade()) {
+            (Some(g), Some(n), Some(d)) => {
+                let n = n.load(Ordering::Acquire) as f64;
+                let d = d.load(Ordering::Acquire) as f64;
+                let ppm = if d > 0.0 {
+                    (n / d * 1_000_000.0).round() as usize
+                } else {
+                    0
+                };
+                g.store(ppm, Ordering::Release);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_ratio() {
+        let (metrics, mut reporter) = ::new();
+        let hits = metrics.ratio_with_gauge("cache_hits", "cache_lookups", "cache_hit_ppm");
+        hits.incr(true);
+        hits.incr(false);
+        hits.incr(false);
+        hits.add(1, 1);
+
+        let out = ::prometheus::string(&reporter.peek()).unwrap();
+        assert_eq!(out, "cache_hits 2\ncache_lookups 4\ncache_hit_ppm 500000\n");
+
+        // Both counters and the gauge are pruned together.
+        drop(hits);
+        reporter.take();
+        assert!(reporter.peek().is_empty());
+    }
+}
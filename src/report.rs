@@ -1,83 +1,977 @@
-use super::{Key, HistogramWithSum, Registry, CounterMap, GaugeMap, StatMap};
+use super::{Counter, Formatter, Gauge, Key, HistogramWithSum, LabelValue, Labels, Prefix,
+            PrefixValue, Registry, Scope, StatValues, CounterStorage, GaugeStorage, SizeSnapshot,
+            SizeStorage, StatStorage, StatSummary};
+use super::{COUNTER, GAUGE, SIZE_STAT, STAT};
+use super::size::SizeBuckets;
+use super::heatmap::{Heatmap, Heatmaps};
+use super::history::{Histories, History};
+use super::transform::{GaugeTransform, Transforms};
+#[cfg(feature = "async")]
+use super::watch::Watch;
+#[cfg(feature = "async")]
+use futures::{Async, Future, Poll};
+use super::watch::Watcher;
 use ordermap::OrderMap;
-use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::error;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ops::{BitOr, Deref};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
 
-type ReportCounterMap = OrderMap<Key, usize>;
-type ReportGaugeMap = OrderMap<Key, usize>;
-type ReportStatMap = OrderMap<Key, HistogramWithSum>;
+type ReportCounterMap = OrderMap<Arc<Key>, usize>;
+type ReportGaugeMap = OrderMap<Arc<Key>, usize>;
+type ReportStatMap = OrderMap<Arc<Key>, HistogramWithSum>;
+type ReportSizeMap = OrderMap<Arc<Key>, SizeSnapshot>;
+type ReportHeatmapMap = OrderMap<Arc<Key>, Heatmap>;
+type SummaryMap = OrderMap<Arc<Key>, StatSummary>;
 
-pub fn new(registry: Arc<Mutex<Registry>>) -> Reporter {
-    Reporter(registry)
+/// The fewest stats snapshotted by each thread when snapshots are parallelized.
+const MIN_STATS_PER_THREAD: usize = 256;
+
+#[derive(Clone)]
+pub struct Reporter {
+    registry: Arc<RwLock<Registry>>,
+    budget: Option<Budget>,
+    /// Shared by all clones of the Reporter.
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+    /// Shared by all clones of the Reporter.
+    transforms: Arc<Mutex<Transforms>>,
+    /// The number of threads used to snapshot stats.
+    snapshot_threads: usize,
+    /// If set, updated with the memory used by all stats as each report is obtained.
+    memory: Option<Gauge>,
+    /// If set, the stats' summaries as of the last full report, for light reports. Shared
+    /// by all clones of the Reporter.
+    summaries: Option<Arc<Mutex<SummaryMap>>>,
 }
 
+/// Limits the number of series in each report.
 #[derive(Clone)]
-pub struct Reporter(Arc<Mutex<Registry>>);
+struct Budget {
+    max_series: usize,
+    /// Counts series dropped from reports.
+    truncated: Counter,
+}
 
 impl Reporter {
+    /// Creates a Reporter for the given registry.
+    pub fn new(registry: Arc<RwLock<Registry>>) -> Reporter {
+        Reporter {
+            registry,
+            budget: None,
+            watchers: Arc::new(Mutex::new(Vec::new())),
+            transforms: Arc::new(Mutex::new(Transforms::default())),
+            snapshot_threads: 1,
+            memory: None,
+            summaries: None,
+        }
+    }
+
+    /// Limits each report to at most `max_series` series.
+    ///
+    /// When a report exceeds its budget, entire metric families (all series sharing a
+    /// prefix and name) are dropped from it, highest-cardinality first, so that a single
+    /// runaway label cannot blow up the report. Series dropped from taken reports are
+    /// counted by the `tacho:truncated_series` counter; peeks are truncated likewise, but
+    /// aren't counted, so that peeking doesn't inflate the count.
+    ///
+    /// Truncation only affects reports; the registry itself is not modified.
+    pub fn with_series_budget(mut self, max_series: usize) -> Self {
+        let truncated = Scope::root(self.registry.clone())
+            .prefixed("tacho")
+            .counter("truncated_series");
+        self.budget = Some(Budget {
+            max_series,
+            truncated,
+        });
+        self
+    }
+
+    /// Exports the approximate memory used by all stats, in bytes, as the
+    /// `tacho:stat_memory_bytes` gauge, which is updated as each report is obtained.
+    ///
+    /// Measuring a stat briefly holds its lock, so this adds a little to the cost of each
+    /// report. See `memory_report` for a per-stat breakdown.
+    pub fn with_memory_gauge(mut self) -> Self {
+        let gauge = Scope::root(self.registry.clone())
+            .prefixed("tacho")
+            .gauge("stat_memory_bytes");
+        self.memory = Some(gauge);
+        self
+    }
+
+    /// Snapshots stats on up to `threads` threads.
+    ///
+    /// Each stat is cloned (and, when taken, cleared) under its own lock, so for registries
+    /// with thousands of histograms this may substantially reduce the time spent holding the
+    /// registry's lock. Small registries are always snapshotted on the calling thread.
+    pub fn with_snapshot_threads(mut self, threads: usize) -> Self {
+        self.snapshot_threads = cmp::max(threads, 1);
+        self
+    }
+
+    /// Caches each stat's summary as each full report is obtained (by `peek` or `take`), so
+    /// that `peek_light` may report stats without snapshotting their histograms.
+    pub fn with_light_reports(mut self) -> Self {
+        self.summaries = Some(Arc::new(Mutex::new(SummaryMap::new())));
+        self
+    }
+
+    /// Exports gauges under `prefix` (and, if given, with the name `name`) according to
+    /// `transform`, e.g. as their change since the previous report was taken.
+    ///
+    /// Each gauge is transformed according to the first matching rule, so more specific
+    /// rules should be added first. An empty prefix matches all gauges. Transforms apply to
+    /// reports obtained by `peek` and `take` (but not to views from `with_report`).
+    pub fn with_gauge_transform(
+        self,
+        prefix: &[&'static str],
+        name: Option<&'static str>,
+        transform: GaugeTransform,
+    ) -> Self {
+        self.transforms.lock().unwrap().add(prefix, name, transform);
+        self
+    }
+
+    /// Retains per-interval bucket counts for each stat over the last `intervals` calls
+    /// to `take`.
+    ///
+    /// Values are counted into buckets with the given (inclusive) upper `bounds`. The
+    /// resulting heatmaps are available via `Report::heatmap`.
+    pub fn with_heatmap(self, intervals: usize, bounds: Vec<u64>) -> Self {
+        {
+            let mut registry = self.registry.write().unwrap();
+            registry.heatmaps = Some(Heatmaps::new(intervals, bounds));
+        }
+        self
+    }
+
+    /// Retains each counter's increase and each stat's summary over each interval closed by
+    /// `take` (or `take_kinds`), for up to `retention`.
+    ///
+    /// Histories are available via `history` and `write_history_json`.
+    pub fn with_history(self, retention: Duration) -> Self {
+        {
+            let mut registry = self.registry.write().unwrap();
+            registry.history = Some(Histories::new(retention));
+        }
+        self
+    }
+
+    /// Returns the recent history of the counter or stat with the given name and (exactly
+    /// the given) labels, if history is enabled.
+    pub fn history(&self, name: &str, labels: &[(&str, &str)]) -> Option<History> {
+        let registry = self.registry.read().unwrap();
+        let history = registry.history.as_ref()?;
+        find(history.iter(), name, labels).cloned()
+    }
+
+    /// Renders the recent history of all counters and stats as JSON, e.g. for an admin
+    /// endpoint. Renders an empty array if history is not enabled.
+    pub fn write_history_json<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        let registry = self.registry.read().unwrap();
+        match registry.history {
+            Some(ref history) => history.write_json(out),
+            None => out.write_str("[]"),
+        }
+    }
+
+    /// Returns a future that resolves once `predicate` holds for a report.
+    ///
+    /// Predicates are evaluated against each report obtained from this `Reporter` (or its
+    /// clones), after the series budget is applied. See the `watch` module for common
+    /// predicates.
+    #[cfg(feature = "async")]
+    pub fn watch<P>(&self, predicate: P) -> Watch
+    where
+        P: FnMut(&Report) -> bool + Send + 'static,
+    {
+        let (w, watch) = Watcher::once(predicate);
+        self.watchers.lock().unwrap().push(w);
+        watch
+    }
+
+    /// Returns a future that takes a final report and writes it to `out`, e.g. so that a
+    /// service's last interval is pushed when it shuts down gracefully:
+    ///
+    /// ```ignore
+    /// let flush = shutdown.then(move |_| reporter.final_flush(conn, prometheus::Config::default()));
+    /// ```
+    ///
+    /// The report is taken when the future is first polled. The future resolves to `out`
+    /// once the report has been written and `out` has been flushed.
+    #[cfg(feature = "async")]
+    pub fn final_flush<W, F>(self, out: W, formatter: F) -> FinalFlush<W, F>
+    where
+        W: io::Write,
+        F: Formatter,
+    {
+        FinalFlush {
+            reporter: self,
+            sink: Some((out, formatter)),
+        }
+    }
+
+    /// Invokes `f` with each report for which `predicate` holds.
+    pub fn on_watch<P, F>(&self, predicate: P, f: F)
+    where
+        P: FnMut(&Report) -> bool + Send + 'static,
+        F: FnMut(&Report) + Send + 'static,
+    {
+        self.watchers.lock().unwrap().push(Watcher::each(predicate, f));
+    }
+
     /// Obtains a read-only view of a metrics report without clearing the underlying state.
+    ///
+    /// Peeks only hold the registry's lock for reading, so concurrent peeks do not block
+    /// each other (though they do briefly block the creation of new metrics).
     pub fn peek(&self) -> Report {
-        let registry = self.0.lock().unwrap();
-        Report {
-            counters: snap_counters(&registry.counters),
-            gauges: snap_gauges(&registry.gauges),
-            stats: snap_stats(&registry.stats, false),
+        let mut report = {
+            let registry = self.registry.read().unwrap();
+            registry.update_derived();
+            self.update_memory(&registry);
+            Report {
+                counters: snap_counters(&registry.counters, &|_| true),
+                gauges: snap_gauges(&registry.gauges, &|_| true),
+                stats: snap_stats(&registry.stats, &|_| true, false, self.snapshot_threads),
+                sizes: snap_sizes(&registry.sizes, &|_| true, false),
+                heatmaps: snap_heatmaps(&registry.heatmaps),
+            }
+        };
+        self.cache_summaries(&report.stats, &|_| true);
+        self.transform_gauges(&mut report, None);
+        self.enforce_budget(&mut report, false);
+        self.check_watchers(&report);
+        report
+    }
+
+    /// Obtains the current counters and gauges, and each stat's summary as of the last full
+    /// report, without clearing any state.
+    ///
+    /// Stats' histograms are neither snapshotted nor summarized, so light reports are cheap
+    /// enough for frequent scrapes, while full reports are obtained less often. Summaries
+    /// are only cached if the reporter was created `with_light_reports`; otherwise, light
+    /// reports have none. Gauge transforms, the series budget, and watches apply only to
+    /// full reports.
+    pub fn peek_light(&self) -> LightReport {
+        let (counters, gauges) = {
+            let registry = self.registry.read().unwrap();
+            registry.update_derived();
+            (
+                snap_counters(&registry.counters, &|_| true),
+                snap_gauges(&registry.gauges, &|_| true),
+            )
+        };
+        let summaries = match self.summaries {
+            Some(ref s) => s.lock().unwrap().clone(),
+            None => SummaryMap::new(),
+        };
+        LightReport {
+            counters,
+            gauges,
+            summaries,
         }
     }
 
+    /// Invokes `f` with a view of the registry, without copying any metrics.
+    ///
+    /// Unlike `peek`, no snapshot is allocated; but the registry is locked for reading
+    /// (preventing the creation of new metrics) until `f` returns, so `f` should be quick.
+    pub fn with_report<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&ReportView) -> T,
+    {
+        let registry = self.registry.read().unwrap();
+        registry.update_derived();
+        f(&ReportView(&registry))
+    }
+
+    /// Returns the current value of the counter with the given key, without snapshotting
+    /// any other metrics.
+    ///
+    /// Keys may be obtained from `Scope::key`, so this is a single hash lookup. Like
+    /// `peek`, the registry is only locked for reading. Gauge transforms and the series
+    /// budget are not applied.
+    pub fn get_counter_by_key(&self, key: &Key) -> Option<usize> {
+        let registry = self.registry.read().unwrap();
+        registry.counters.get(key).map(|v| v.load(Ordering::Acquire))
+    }
+
+    /// Returns the current value of the gauge with the given key, without snapshotting any
+    /// other metrics.
+    pub fn get_gauge_by_key(&self, key: &Key) -> Option<usize> {
+        let registry = self.registry.read().unwrap();
+        registry.gauges.get(key).map(|v| v.load(Ordering::Acquire))
+    }
+
+    /// Summarizes the current values of the stat with the given key, without snapshotting
+    /// any other metrics.
+    pub fn get_stat_summary_by_key(&self, key: &Key) -> Option<StatSummary> {
+        let registry = self.registry.read().unwrap();
+        registry.stats.get(key).map(|cell| cell.snapshot(false).summary())
+    }
+
+    /// Returns the current value of a counter with the given name and (exactly the given)
+    /// labels.
+    ///
+    /// This scans every counter, and ignores prefixes: if counters with the same name and
+    /// labels have different prefixes (e.g. `http:requests` and `grpc:requests`), any one
+    /// of them may be returned. It suits tests; `get_counter_by_key` is unambiguous.
+    pub fn get_counter(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        let registry = self.registry.read().unwrap();
+        find(registry.counters.iter(), name, labels).map(|v| v.load(Ordering::Acquire))
+    }
+
+    /// Returns the current value of a gauge with the given name and (exactly the given)
+    /// labels. Like `get_counter`, this is ambiguous if prefixes differ; see
+    /// `get_gauge_by_key`.
+    pub fn get_gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        let registry = self.registry.read().unwrap();
+        find(registry.gauges.iter(), name, labels).map(|v| v.load(Ordering::Acquire))
+    }
+
+    /// Summarizes a stat with the given name and (exactly the given) labels. Like
+    /// `get_counter`, this is ambiguous if prefixes differ; see `get_stat_summary_by_key`.
+    pub fn get_stat_summary(&self, name: &str, labels: &[(&str, &str)]) -> Option<StatSummary> {
+        let registry = self.registry.read().unwrap();
+        find(registry.stats.iter(), name, labels).map(|cell| cell.snapshot(false).summary())
+    }
+
     /// Obtains a Report and removes unused metrics.
     pub fn take(&mut self) -> Report {
-        let mut registry = self.0.lock().unwrap();
+        self.take_matching(&|_| true, KindMask::ALL, true, &[])
+    }
 
-        let report = Report {
-            counters: snap_counters(&registry.counters),
-            gauges: snap_gauges(&registry.gauges),
-            stats: snap_stats(&registry.stats, true),
-        };
+    /// Obtains a Report and removes unused metrics, except for those identified by `keep`.
+    ///
+    /// Retained metrics are cleared as usual, but remain registered even if no handles
+    /// refer to them, so that they are reported continuously (e.g. as zero) and needn't be
+    /// reallocated when next used. Keys may be obtained from `Scope::key` or from a
+    /// previous report.
+    pub fn take_and_retain(&mut self, keep: &[Key]) -> Report {
+        self.take_matching(&|_| true, KindMask::ALL, true, keep)
+    }
 
-        // Drop unreferenced metrics.
-        registry.counters.retain(|_, v| Arc::weak_count(v) > 0);
-        registry.gauges.retain(|_, v| Arc::weak_count(v) > 0);
-        registry.stats.retain(|_, v| Arc::weak_count(v) > 0);
+    /// Describes the cardinality of each metric family (i.e. the series sharing a prefix and
+    /// name), in order of descending cardinality.
+    ///
+    /// This identifies which families, and which of their labels, are responsible for a
+    /// large number of series, without rendering a report.
+    pub fn cardinality(&self) -> Vec<Cardinality> {
+        let registry = self.registry.read().unwrap();
+        let keys = registry
+            .counters
+            .iter()
+            .map(|(k, _)| k)
+            .chain(registry.gauges.iter().map(|(k, _)| k))
+            .chain(registry.stats.iter().map(|(k, _)| k))
+            .chain(registry.sizes.iter().map(|(k, _)| k));
 
+        type Family<'a> = (HashSet<&'a Labels>, BTreeMap<&'static str, HashSet<&'a LabelValue>>);
+        let mut families = BTreeMap::<(&'static str, Arc<Prefix>), Family>::new();
+        for k in keys {
+            let family = families.entry((k.name(), k.prefix().clone())).or_default();
+            family.0.insert(k.labels());
+            for (lk, lv) in k.labels() {
+                family.1.entry(lk).or_default().insert(lv);
+            }
+        }
+
+        let mut cardinality = families
+            .into_iter()
+            .map(|((name, prefix), (series, values))| {
+                let mut labels = values
+                    .into_iter()
+                    .map(|(k, vs)| (k, vs.len()))
+                    .collect::<Vec<_>>();
+                labels.sort_by_key(|&(k, n)| (cmp::Reverse(n), k));
+                Cardinality {
+                    prefix,
+                    name,
+                    series: series.len(),
+                    labels,
+                }
+            })
+            .collect::<Vec<_>>();
+        cardinality.sort_by_key(|c| cmp::Reverse(c.series));
+        cardinality
+    }
+
+    /// Approximates the memory used by each stat, in order of descending usage.
+    ///
+    /// Memory is dominated by histograms' counts, so this identifies the stats for which
+    /// bounds, precision, or `StatConfig::adaptive` would be most worthwhile.
+    pub fn memory_report(&self) -> MemoryReport {
+        let registry = self.registry.read().unwrap();
+        let mut stats = registry
+            .stats
+            .iter()
+            .map(|(k, c)| (k.clone(), c.memory_bytes()))
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|&(_, bytes)| cmp::Reverse(bytes));
+        MemoryReport {
+            total_bytes: stats.iter().map(|&(_, bytes)| bytes).sum(),
+            stats,
+        }
+    }
+
+    /// Resets every metric: counters and gauges are set to zero, and stats are cleared.
+    ///
+    /// No metrics are pruned, so existing handles remain usable. This is intended for
+    /// isolating test cases (or for maintenance), rather than for regular reporting.
+    pub fn reset_all(&self) {
+        let registry = self.registry.write().unwrap();
+        for (_, c) in registry.counters.iter() {
+            c.store(0, Ordering::Release);
+        }
+        for (_, g) in registry.gauges.iter() {
+            g.store(0, Ordering::Release);
+        }
+        for (_, s) in registry.stats.iter() {
+            s.clear();
+        }
+        for (_, s) in registry.sizes.iter() {
+            s.snapshot(true);
+        }
+    }
+
+    /// Releases memory held by the registry for metrics that have since been pruned.
+    ///
+    /// `take` releases memory only once much of the registry's capacity is unused; this
+    /// may be called to reclaim memory immediately, e.g. after a burst of high-cardinality
+    /// metrics has been pruned.
+    pub fn compact(&self) {
+        self.registry.write().unwrap().compact(true);
+    }
+
+    /// Obtains a Report of only the given kinds of metrics, clearing and removing unused
+    /// metrics of those kinds while leaving all other metrics untouched.
+    ///
+    /// This allows, e.g., stats to be reported less frequently than counters and gauges:
+    ///
+    /// ```ignore
+    /// let frequent = reporter.take_kinds(KindMask::COUNTERS | KindMask::GAUGES);
+    /// ```
+    ///
+    /// Heatmaps are advanced whenever stats are taken; history is recorded for the kinds
+    /// taken.
+    pub fn take_kinds(&mut self, kinds: KindMask) -> Report {
+        self.take_matching(&|_| true, kinds, true, &[])
+    }
+
+    /// Obtains a Report of only the metrics under `prefix`, clearing and removing unused
+    /// metrics under `prefix` while leaving all other metrics untouched.
+    ///
+    /// `prefix` lists prefix values from the root, i.e. `&["http", "client"]` matches
+    /// metrics created by `scope.prefixed("http").prefixed("client")` (and its
+    /// descendants). Heatmaps and history are not advanced.
+    pub fn take_subtree(&mut self, prefix: &[&str]) -> Report {
+        self.take_matching(
+            &|k| k.prefix().starts_with(prefix),
+            KindMask::ALL,
+            false,
+            &[],
+        )
+    }
+
+    /// Snapshots and clears metrics of the given kinds that match, optionally closing a
+    /// heatmap and history interval. Unused metrics are pruned, unless they are in `keep`.
+    fn take_matching(
+        &mut self,
+        matches: &dyn Fn(&Key) -> bool,
+        kinds: KindMask,
+        close_interval: bool,
+        keep: &[Key],
+    ) -> Report {
+        let mut report = {
+            let mut registry = self.registry.write().unwrap();
+            if kinds.contains(KindMask::GAUGES) {
+                registry.take_derived(matches);
+            } else {
+                registry.update_derived();
+            }
+            self.update_memory(&registry);
+
+            let mut report = Report {
+                counters: ReportCounterMap::new(),
+                gauges: ReportGaugeMap::new(),
+                stats: ReportStatMap::new(),
+                sizes: ReportSizeMap::new(),
+                heatmaps: ReportHeatmapMap::new(),
+            };
+            if kinds.contains(KindMask::COUNTERS) {
+                report.counters = snap_counters(&registry.counters, matches);
+            }
+            if kinds.contains(KindMask::GAUGES) {
+                report.gauges = snap_gauges(&registry.gauges, matches);
+                // Resetting gauges are re-read as they are zeroed, so that no updates are
+                // lost between the snapshot and the reset.
+                for k in registry.resetting.iter().filter(|k| matches(k)) {
+                    if let Some(v) = registry.gauges.get(k) {
+                        report.gauges.insert(k.clone(), v.swap(0, Ordering::AcqRel));
+                    }
+                }
+            }
+            if kinds.contains(KindMask::STATS) {
+                report.stats = snap_stats(&registry.stats, matches, true, self.snapshot_threads);
+                self.cache_summaries(&report.stats, matches);
+                if let Some(ref mut heatmaps) = registry.heatmaps {
+                    if close_interval {
+                        heatmaps.record(&report.stats);
+                    }
+                    report.heatmaps = heatmaps.snapshot();
+                    report.heatmaps.retain(|k, _| matches(k));
+                }
+            }
+            if kinds.contains(KindMask::SIZES) {
+                report.sizes = snap_sizes(&registry.sizes, matches, true);
+            }
+            if close_interval {
+                if let Some(ref mut history) = registry.history {
+                    history.record(
+                        kinds.contains(KindMask::COUNTERS).then_some(&report.counters),
+                        kinds.contains(KindMask::STATS).then_some(&report.stats),
+                    );
+                }
+            }
+
+            // Drop unreferenced metrics.
+            let hooks = registry.hooks.clone();
+            let unused = |k: &Key, n| {
+                let unused = n == 0 && matches(k) && !keep.contains(k);
+                if unused {
+                    hooks.pruned(k);
+                }
+                unused
+            };
+            if kinds.contains(KindMask::COUNTERS) {
+                registry.counters.retain(&mut |k, v| !unused(k, Arc::weak_count(v)));
+            }
+            if kinds.contains(KindMask::GAUGES) {
+                registry.gauges.retain(&mut |k, v| !unused(k, Arc::weak_count(v)));
+                let Registry { ref gauges, ref mut resetting, .. } = *registry;
+                resetting.retain(|k| gauges.get(k).is_some());
+            }
+            if kinds.contains(KindMask::STATS) {
+                registry.stats.retain(&mut |k, v| !unused(k, Arc::weak_count(v)));
+            }
+            if kinds.contains(KindMask::SIZES) {
+                registry.sizes.retain(&mut |k, v| !unused(k, Arc::weak_count(v)));
+            }
+            registry.compact(false);
+
+            report
+        };
+        if kinds.contains(KindMask::GAUGES) {
+            self.transform_gauges(&mut report, Some(matches));
+        }
+        self.enforce_budget(&mut report, true);
+        self.check_watchers(&report);
         report
     }
+
+    /// Applies gauge transforms to a report. If the report was taken, `taken` selects the
+    /// gauges that were taken, and their values are retained for the next report.
+    fn transform_gauges(&self, report: &mut Report, taken: Option<&dyn Fn(&Key) -> bool>) {
+        let mut transforms = self.transforms.lock().unwrap();
+        if transforms.is_empty() {
+            return;
+        }
+        transforms.apply(&mut report.gauges, taken.is_some());
+        if let Some(matches) = taken {
+            transforms.forget(&report.gauges, matches);
+        }
+    }
+
+    /// Replaces the cached summaries of stats selected by `matches` with those in `stats`.
+    fn cache_summaries(&self, stats: &ReportStatMap, matches: &dyn Fn(&Key) -> bool) {
+        if let Some(ref summaries) = self.summaries {
+            let mut summaries = summaries.lock().unwrap();
+            summaries.retain(|k, _| !matches(k));
+            summaries.extend(stats.iter().map(|(k, h)| (k.clone(), h.summary())));
+        }
+    }
+
+    /// Checks each watcher against `report`.
+    ///
+    /// Watchers are checked without holding their lock, so that callbacks may obtain
+    /// reports and add watches. Reports obtained meanwhile (including by those callbacks)
+    /// aren't checked.
+    fn check_watchers(&self, report: &Report) {
+        let mut watchers = mem::take(&mut *self.watchers.lock().unwrap());
+        if watchers.is_empty() {
+            return;
+        }
+        watchers.retain_mut(|w| w.check(report));
+        let mut current = self.watchers.lock().unwrap();
+        // Watchers added while these were checked follow them.
+        watchers.append(&mut current);
+        *current = watchers;
+    }
+
+    fn update_memory(&self, registry: &Registry) {
+        if let Some(ref gauge) = self.memory {
+            gauge.set(registry.stats.iter().map(|(_, c)| c.memory_bytes()).sum());
+        }
+    }
+
+    /// Truncates a report to the series budget. If the report was `taken`, dropped series
+    /// are counted.
+    fn enforce_budget(&self, report: &mut Report, taken: bool) {
+        if let Some(ref budget) = self.budget {
+            let dropped = truncate(report, budget.max_series);
+            if dropped > 0 && taken {
+                warn!("dropped {} series from report", dropped);
+                budget.truncated.incr(dropped);
+            }
+        }
+    }
+}
+
+/// Writes a final report, as returned by `Reporter::final_flush`.
+#[cfg(feature = "async")]
+pub struct FinalFlush<W, F> {
+    reporter: Reporter,
+    sink: Option<(W, F)>,
+}
+
+#[cfg(feature = "async")]
+impl<W: io::Write, F: Formatter> Future for FinalFlush<W, F> {
+    type Item = W;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<W, io::Error> {
+        let (mut out, formatter) = self.sink.take().expect("polled after completion");
+        self.reporter.take().to_writer(&mut out, formatter)?;
+        out.flush()?;
+        Ok(Async::Ready(out))
+    }
+}
+
+/// Determines how `Report::merge_with` resolves series of the same kind, name, and labels
+/// in both reports, whether they have the same key or differ only by prefix.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum MergeConflicts {
+    /// The merged report's series replace existing series with the same key. Series that
+    /// differ only by prefix are kept as distinct series, and a warning is logged for each.
+    ///
+    /// This is what `Report::merge` does.
+    #[default]
+    ReplaceExact,
+    /// The merged report's series replace the existing series, whatever their prefixes.
+    Replace,
+    /// The merged report's series are combined into the existing series (keeping the
+    /// existing key): counters are summed, stats and size stats are merged, and gauges are
+    /// replaced, i.e. the merged report's values are taken as the latest.
+    Combine,
+    /// Merging fails.
+    Reject,
+}
+
+/// Series in both reports passed to `Report::merge_with`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeConflict {
+    /// The kind of metric, e.g. "counter".
+    pub kind: &'static str,
+    pub existing: Arc<Key>,
+    pub merged: Arc<Key>,
+}
+
+impl MergeConflict {
+    /// Returns true if the series have the same key, rather than differing by prefix.
+    pub fn is_exact(&self) -> bool {
+        self.existing == self.merged
+    }
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_exact() {
+            write!(f, "{} {} is in both reports", self.kind, self.existing)
+        } else {
+            write!(f, "{} {} differs only by prefix from {}", self.kind, self.merged, self.existing)
+        }
+    }
+}
+
+impl error::Error for MergeConflict {}
+
+/// Describes the series in a metric family, as returned by `Reporter::cardinality`.
+#[derive(Clone, Debug)]
+pub struct Cardinality {
+    pub prefix: Arc<Prefix>,
+    pub name: &'static str,
+    /// The number of distinct label sets.
+    pub series: usize,
+    /// Each label, with its number of distinct values, in order of descending variety.
+    pub labels: Vec<(&'static str, usize)>,
+}
+
+/// Describes the memory used by stats, as returned by `Reporter::memory_report`.
+#[derive(Clone, Debug)]
+pub struct MemoryReport {
+    /// The approximate memory used by all stats, in bytes.
+    pub total_bytes: usize,
+    /// Each stat, with its approximate memory usage in bytes, in order of descending usage.
+    pub stats: Vec<(Arc<Key>, usize)>,
+}
+
+/// Selects kinds of metrics, e.g. for `Reporter::take_kinds`.
+///
+/// Masks may be combined with `|`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KindMask(u8);
+
+impl KindMask {
+    pub const COUNTERS: KindMask = KindMask(1);
+    pub const GAUGES: KindMask = KindMask(1 << 1);
+    pub const STATS: KindMask = KindMask(1 << 2);
+    pub const SIZES: KindMask = KindMask(1 << 3);
+    pub const ALL: KindMask = KindMask(0b1111);
+
+    /// Returns true if all kinds in `other` are in this mask.
+    pub fn contains(self, other: KindMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for KindMask {
+    type Output = KindMask;
+    fn bitor(self, other: KindMask) -> KindMask {
+        KindMask(self.0 | other.0)
+    }
+}
+
+/// Drops metric families from `report` until it has at most `max_series` series.
+///
+/// Families are dropped in order of descending cardinality (ties are broken by name and
+/// prefix, so that truncation is deterministic). Returns the number of series dropped.
+fn truncate(report: &mut Report, max_series: usize) -> usize {
+    let len = report.len();
+    if len <= max_series {
+        return 0;
+    }
+
+    let mut families = BTreeMap::<(&'static str, Arc<Prefix>), usize>::new();
+    {
+        let keys = report
+            .counters
+            .keys()
+            .chain(report.gauges.keys())
+            .chain(report.stats.keys())
+            .chain(report.sizes.keys());
+        for k in keys {
+            *families.entry((k.name(), k.prefix().clone())).or_insert(0) += 1;
+        }
+    }
+    let mut families = families.into_iter().collect::<Vec<_>>();
+    families.sort_by_key(|&(ref family, n)| (cmp::Reverse(n), family.clone()));
+
+    let mut remaining = len;
+    let mut dropped = BTreeSet::new();
+    for (family, n) in families {
+        if remaining <= max_series {
+            break;
+        }
+        remaining -= n;
+        dropped.insert(family);
+    }
+
+    let keep = |k: &Arc<Key>| !dropped.contains(&(k.name(), k.prefix().clone()));
+    report.counters.retain(|k, _| keep(k));
+    report.gauges.retain(|k, _| keep(k));
+    report.stats.retain(|k, _| keep(k));
+    report.sizes.retain(|k, _| keep(k));
+    report.heatmaps.retain(|k, _| keep(k));
+    len - remaining
 }
 
-fn snap_counters(counters: &CounterMap) -> ReportCounterMap {
+fn snap_counters(counters: &CounterStorage, matches: &dyn Fn(&Key) -> bool) -> ReportCounterMap {
     let mut snap = ReportCounterMap::with_capacity(counters.len());
-    for (k, v) in &*counters {
+    for (k, v) in counters.iter().filter(|&(k, _)| matches(k)) {
         let v = v.load(Ordering::Acquire);
         snap.insert(k.clone(), v);
     }
     snap
 }
 
-fn snap_gauges(gauges: &GaugeMap) -> ReportGaugeMap {
+fn snap_gauges(gauges: &GaugeStorage, matches: &dyn Fn(&Key) -> bool) -> ReportGaugeMap {
     let mut snap = ReportGaugeMap::with_capacity(gauges.len());
-    for (k, v) in &*gauges {
+    for (k, v) in gauges.iter().filter(|&(k, _)| matches(k)) {
         let v = v.load(Ordering::Acquire);
         snap.insert(k.clone(), v);
     }
     snap
 }
 
-fn snap_stats(stats: &StatMap, clear: bool) -> ReportStatMap {
-    let mut snap = ReportStatMap::with_capacity(stats.len());
-    for (k, ptr) in &*stats {
-        let mut orig = ptr.lock().unwrap();
-        snap.insert(k.clone(), orig.clone());
-        if clear {
-            orig.clear();
-        }
+fn snap_heatmaps(heatmaps: &Option<Heatmaps>) -> ReportHeatmapMap {
+    match *heatmaps {
+        Some(ref h) => h.snapshot(),
+        None => ReportHeatmapMap::new(),
+    }
+}
+
+fn snap_sizes(
+    sizes: &SizeStorage,
+    matches: &dyn Fn(&Key) -> bool,
+    clear: bool,
+) -> ReportSizeMap {
+    let mut snap = ReportSizeMap::with_capacity(sizes.len());
+    for (k, b) in sizes.iter().filter(|&(k, _)| matches(k)) {
+        snap.insert(k.clone(), b.snapshot(clear));
     }
     snap
 }
 
+fn snap_stats(
+    stats: &StatStorage,
+    matches: &dyn Fn(&Key) -> bool,
+    clear: bool,
+    threads: usize,
+) -> ReportStatMap {
+    let cells = stats
+        .iter()
+        .filter(|&(k, _)| matches(k))
+        .collect::<Vec<_>>();
+    let threads = cmp::min(threads, cells.len() / MIN_STATS_PER_THREAD);
+    if threads <= 1 {
+        return cells
+            .into_iter()
+            .map(|(k, cell)| (k.clone(), cell.snapshot(clear)))
+            .collect();
+    }
+
+    let chunk = cells.len().div_ceil(threads);
+    thread::scope(|s| {
+        let handles = cells
+            .chunks(chunk)
+            .map(|cells| {
+                s.spawn(move || {
+                    cells
+                        .iter()
+                        .map(|&(k, cell)| (k.clone(), cell.snapshot(clear)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut snap = ReportStatMap::with_capacity(cells.len());
+        for h in handles {
+            snap.extend(h.join().expect("failed to snapshot stats"));
+        }
+        snap
+    })
+}
+
+/// A stat's histogram, as seen through a `ReportView`.
+pub enum StatRef<'a> {
+    Locked(MutexGuard<'a, HistogramWithSum>),
+    Copied(Box<HistogramWithSum>),
+}
+
+impl<'a> Deref for StatRef<'a> {
+    type Target = HistogramWithSum;
+    fn deref(&self) -> &HistogramWithSum {
+        match *self {
+            StatRef::Locked(ref h) => h,
+            StatRef::Copied(ref h) => h,
+        }
+    }
+}
+
+/// A read-only view of the registry's current values.
+///
+/// Obtained via `Reporter::with_report`.
+pub struct ReportView<'a>(&'a Registry);
+impl<'a> ReportView<'a> {
+    pub fn counters(&self) -> impl Iterator<Item = (&'a Arc<Key>, usize)> + 'a {
+        self.0.counters.iter().map(|(k, v)| (k, v.load(Ordering::Acquire)))
+    }
+    pub fn gauges(&self) -> impl Iterator<Item = (&'a Arc<Key>, usize)> + 'a {
+        self.0.gauges.iter().map(|(k, v)| (k, v.load(Ordering::Acquire)))
+    }
+    /// Iterates over stats, locking each histogram as it is visited.
+    ///
+    /// Stats that are not backed by a locked histogram are copied.
+    pub fn stats(&self) -> impl Iterator<Item = (&'a Arc<Key>, StatRef<'a>)> + 'a {
+        self.0.stats.iter().map(|(k, c)| {
+            let h = match c.values {
+                StatValues::Histogram(ref h) => StatRef::Locked(h.lock().unwrap()),
+                _ => StatRef::Copied(Box::new(c.snapshot(false))),
+            };
+            (k, h)
+        })
+    }
+    pub fn sizes(&self) -> impl Iterator<Item = (&'a Arc<Key>, &'a SizeBuckets)> + 'a {
+        self.0.sizes.iter().map(|(k, b)| (k, &**b))
+    }
+}
+
+/// A series in a `Report`, as yielded by `Report::iter_series`.
+#[derive(Clone, Copy)]
+pub enum Series<'a> {
+    Counter(&'a Arc<Key>, usize),
+    Gauge(&'a Arc<Key>, usize),
+    Stat(&'a Arc<Key>, &'a HistogramWithSum),
+    Size(&'a Arc<Key>, &'a SizeSnapshot),
+}
+
+impl<'a> Series<'a> {
+    pub fn key(&self) -> &'a Arc<Key> {
+        match *self {
+            Series::Counter(k, _) | Series::Gauge(k, _) | Series::Stat(k, _) |
+            Series::Size(k, _) => k,
+        }
+    }
+}
+
+/// Counters, gauges, and cached stat summaries, as returned by `Reporter::peek_light`.
+#[derive(Clone, Debug)]
+pub struct LightReport {
+    counters: ReportCounterMap,
+    gauges: ReportGaugeMap,
+    summaries: SummaryMap,
+}
+
+impl LightReport {
+    pub fn counters(&self) -> &ReportCounterMap {
+        &self.counters
+    }
+    pub fn gauges(&self) -> &ReportGaugeMap {
+        &self.gauges
+    }
+    /// Each stat's summary as of the last full report.
+    pub fn stat_summaries(&self) -> &OrderMap<Arc<Key>, StatSummary> {
+        &self.summaries
+    }
+
+    /// Returns the value of the counter with the given name and (exactly the given) labels.
+    pub fn get_counter(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        find(self.counters.iter(), name, labels).cloned()
+    }
+
+    /// Returns the value of the gauge with the given name and (exactly the given) labels.
+    pub fn get_gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        find(self.gauges.iter(), name, labels).cloned()
+    }
+
+    /// Returns the cached summary of the stat with the given name and (exactly the given)
+    /// labels.
+    pub fn get_stat_summary(&self, name: &str, labels: &[(&str, &str)]) -> Option<&StatSummary> {
+        find(self.summaries.iter(), name, labels)
+    }
+}
+
 pub struct Report {
     counters: ReportCounterMap,
     gauges: ReportGaugeMap,
     stats: ReportStatMap,
+    sizes: ReportSizeMap,
+    heatmaps: ReportHeatmapMap,
 }
 impl Report {
     pub fn counters(&self) -> &ReportCounterMap {
@@ -89,10 +983,293 @@ impl Report {
     pub fn stats(&self) -> &ReportStatMap {
         &self.stats
     }
+    /// Iterates over summaries of each stat.
+    pub fn stat_summaries<'a>(&'a self) -> impl Iterator<Item = (&'a Arc<Key>, StatSummary)> + 'a {
+        self.stats.iter().map(|(k, h)| (k, h.summary()))
+    }
+    pub fn sizes(&self) -> &ReportSizeMap {
+        &self.sizes
+    }
+    /// Renders the report with `formatter`, e.g. `&prometheus::Config::default()`.
+    pub fn to_writer<F: Formatter>(&self, out: &mut dyn io::Write, formatter: F) -> io::Result<()> {
+        formatter.write(out, self)
+    }
+
+    /// Iterates over every series in the report: counters, then gauges, stats, and sizes.
+    ///
+    /// This allows exporters to stream or paginate large reports (e.g. with `skip` and
+    /// `take`) without handling each kind of metric separately.
+    pub fn iter_series<'a>(&'a self) -> impl Iterator<Item = Series<'a>> + 'a {
+        self.counters
+            .iter()
+            .map(|(k, v)| Series::Counter(k, *v))
+            .chain(self.gauges.iter().map(|(k, v)| Series::Gauge(k, *v)))
+            .chain(self.stats.iter().map(|(k, h)| Series::Stat(k, h)))
+            .chain(self.sizes.iter().map(|(k, s)| Series::Size(k, s)))
+    }
+
+    /// Iterates over the series in this report that are not in `prev`, e.g. the previous
+    /// snapshot.
+    ///
+    /// Series are matched by kind and key, so a key that changes kind is both added and
+    /// removed. This allows exporters that track their own state (e.g. staleness markers) to
+    /// update it incrementally. Keys are shared with the registry, so each lookup is a hash
+    /// probe and (usually) a pointer comparison.
+    pub fn series_added<'a>(&'a self, prev: &'a Report) -> impl Iterator<Item = Series<'a>> + 'a {
+        self.iter_series().filter(move |s| !prev.contains_series(s))
+    }
+
+    /// Iterates over the series in `prev` that are not in this report, with their values in
+    /// `prev`. See `series_added`.
+    pub fn series_removed<'a>(&'a self, prev: &'a Report) -> impl Iterator<Item = Series<'a>> + 'a {
+        prev.series_added(self)
+    }
+
+    fn contains_series(&self, s: &Series) -> bool {
+        match *s {
+            Series::Counter(k, _) => self.counters.contains_key(k),
+            Series::Gauge(k, _) => self.gauges.contains_key(k),
+            Series::Stat(k, _) => self.stats.contains_key(k),
+            Series::Size(k, _) => self.sizes.contains_key(k),
+        }
+    }
+
+    /// Returns a stat's recent bucket counts, if heatmaps are enabled.
+    pub fn heatmap(&self, key: &Key) -> Option<&Heatmap> {
+        self.heatmaps.get(key)
+    }
     pub fn is_empty(&self) -> bool {
-        self.counters.is_empty() && self.gauges.is_empty() && self.stats.is_empty()
+        self.counters.is_empty() && self.gauges.is_empty() && self.stats.is_empty() &&
+            self.sizes.is_empty()
     }
     pub fn len(&self) -> usize {
-        self.counters.len() + self.gauges.len() + self.stats.len()
+        self.counters.len() + self.gauges.len() + self.stats.len() + self.sizes.len()
+    }
+
+    /// Returns the value of the counter with the given name and (exactly the given) labels.
+    pub fn get_counter(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        find(self.counters.iter(), name, labels).cloned()
+    }
+
+    /// Returns the value of the gauge with the given name and (exactly the given) labels.
+    pub fn get_gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        find(self.gauges.iter(), name, labels).cloned()
+    }
+
+    /// Returns the stat with the given name and (exactly the given) labels.
+    pub fn get_stat(&self, name: &str, labels: &[(&str, &str)]) -> Option<&HistogramWithSum> {
+        find(self.stats.iter(), name, labels)
+    }
+
+    /// Retains only the series for which `f` returns true.
+    pub fn retain<F: FnMut(&Key) -> bool>(&mut self, mut f: F) {
+        self.counters.retain(|k, _| f(k));
+        self.gauges.retain(|k, _| f(k));
+        self.stats.retain(|k, _| f(k));
+        self.sizes.retain(|k, _| f(k));
+        self.heatmaps.retain(|k, _| f(k));
+    }
+
+    /// Replaces each series' labels with those returned by `f`.
+    ///
+    /// If relabeling causes series of the same kind to share a key, the last such series is
+    /// kept.
+    pub fn map_labels<F: FnMut(&Labels) -> Labels>(&mut self, mut f: F) {
+        self.rekey(&mut |k| {
+            let labels = f(k.labels());
+            if labels == *k.labels() {
+                return None;
+            }
+            Some(Key::new(k.name(), k.prefix().clone(), Arc::new(labels)))
+        });
+    }
+
+    /// Adds the label `k` with value `v` to each series (replacing any existing value), e.g.
+    /// to identify the component a report came from before it is merged with others.
+    ///
+    /// Series that shared a label set continue to share one, so each distinct set of
+    /// labels is copied only once.
+    pub fn relabel<V: Into<LabelValue>>(&mut self, k: &'static str, v: V) {
+        let v = v.into();
+        let mut relabeled = HashMap::<Arc<Labels>, Arc<Labels>>::new();
+        self.rekey(&mut |key| {
+            let labels = relabeled
+                .entry(key.labels.clone())
+                .or_insert_with(|| {
+                    let mut labels = (*key.labels).clone();
+                    labels.insert(k, v.clone());
+                    Arc::new(labels)
+                })
+                .clone();
+            Some(Key::new(key.name(), key.prefix().clone(), labels))
+        });
+    }
+
+    /// Adds `other`'s series to this report, e.g. to export the reports of several
+    /// registries together.
+    ///
+    /// Series in `other` replace those of the same kind and key in this report, so reports
+    /// from different sources should first be distinguished with `relabel`. See
+    /// `merge_with`.
+    pub fn merge(&mut self, other: Report) {
+        self.merge_with(other, MergeConflicts::ReplaceExact)
+            .expect("only MergeConflicts::Reject fails");
+    }
+
+    /// Adds `other`'s series to this report, resolving series of the same kind, name, and
+    /// labels in both according to `policy`.
+    ///
+    /// Series that differ only by prefix are treated as the same series, since they are
+    /// easily confused (e.g. by `get_counter`, or by consumers that ignore prefixes),
+    /// unless the policy is `MergeConflicts::ReplaceExact`. Heatmaps are always replaced.
+    ///
+    /// If merging fails, this report is unchanged.
+    pub fn merge_with(
+        &mut self,
+        other: Report,
+        policy: MergeConflicts,
+    ) -> Result<(), MergeConflict> {
+        let mut conflicts = Vec::new();
+        collisions(COUNTER, &self.counters, &other.counters, &mut conflicts);
+        collisions(GAUGE, &self.gauges, &other.gauges, &mut conflicts);
+        collisions(STAT, &self.stats, &other.stats, &mut conflicts);
+        collisions(SIZE_STAT, &self.sizes, &other.sizes, &mut conflicts);
+        for c in conflicts {
+            if policy == MergeConflicts::Reject {
+                return Err(c);
+            }
+            if policy == MergeConflicts::ReplaceExact && !c.is_exact() {
+                warn!("merged report has ambiguous series: {}", c);
+            }
+        }
+
+        merge_series(&mut self.counters, other.counters, policy, |c, v| {
+            *c = c.saturating_add(v)
+        });
+        merge_series(&mut self.gauges, other.gauges, policy, |g, v| *g = v);
+        merge_series(&mut self.stats, other.stats, policy, |s, h| s.merge(&h));
+        merge_series(&mut self.sizes, other.sizes, policy, |s, v| s.merge(&v));
+        self.heatmaps.extend(other.heatmaps);
+        Ok(())
+    }
+
+    /// Replaces the leading prefix values `from` with `to`, for each series whose prefix
+    /// starts with `from`.
+    ///
+    /// I.e. `rename_prefix(&["rt", "int"], &["router"])` renames `rt:int:srv:requests` to
+    /// `router:srv:requests`.
+    pub fn rename_prefix(&mut self, from: &[&str], to: &[&'static str]) {
+        self.rekey(&mut |k| {
+            if !k.prefix().starts_with(from) {
+                return None;
+            }
+            let rest = k.prefix().segments().into_iter().skip(from.len()).cloned();
+            let prefix = to.iter()
+                .map(|v| PrefixValue::Static(v))
+                .chain(rest)
+                .fold(Arc::new(Prefix::Root), |p, v| Arc::new(Prefix::node(p, v)));
+            Some(Key::new(k.name(), prefix, k.labels.clone()))
+        });
+    }
+
+    /// Replaces each key for which `f` returns a new key.
+    fn rekey(&mut self, f: &mut dyn FnMut(&Key) -> Option<Key>) {
+        rekey(&mut self.counters, f);
+        rekey(&mut self.gauges, f);
+        rekey(&mut self.stats, f);
+        rekey(&mut self.sizes, f);
+        rekey(&mut self.heatmaps, f);
+    }
+}
+
+/// Adds a conflict for each series in `theirs` with the same name and labels as one in
+/// `ours`.
+fn collisions<V>(
+    kind: &'static str,
+    ours: &OrderMap<Arc<Key>, V>,
+    theirs: &OrderMap<Arc<Key>, V>,
+    out: &mut Vec<MergeConflict>,
+) {
+    if ours.is_empty() || theirs.is_empty() {
+        return;
+    }
+    let mut series = HashMap::<(&str, &Labels), Vec<&Arc<Key>>>::new();
+    for k in ours.keys() {
+        series.entry((k.name(), k.labels())).or_default().push(k);
+    }
+    for k in theirs.keys() {
+        if let Some(existing) = series.get(&(k.name(), k.labels())) {
+            out.extend(existing.iter().map(|e| MergeConflict {
+                kind,
+                existing: (*e).clone(),
+                merged: k.clone(),
+            }));
+        }
+    }
+}
+
+/// Adds `theirs` to `ours`, resolving series with the same name and labels as an existing
+/// series according to `policy` (which must not be `Reject`).
+fn merge_series<V>(
+    ours: &mut OrderMap<Arc<Key>, V>,
+    theirs: OrderMap<Arc<Key>, V>,
+    policy: MergeConflicts,
+    combine: fn(&mut V, V),
+) {
+    if policy == MergeConflicts::ReplaceExact || ours.is_empty() {
+        ours.extend(theirs);
+        return;
+    }
+    // Series are resolved against this report's series before merging, so that series in
+    // `theirs` that differ only by prefix do not replace each other.
+    let keys = ours.keys().cloned().collect::<Vec<_>>();
+    let mut series = HashMap::<(&str, &Labels), Vec<&Arc<Key>>>::new();
+    for k in &keys {
+        series.entry((k.name(), k.labels())).or_default().push(k);
+    }
+    for (k, v) in theirs {
+        let existing = series.get(&(k.name(), k.labels())).map_or(&[][..], |e| &e[..]);
+        if policy == MergeConflicts::Combine {
+            let e = existing.iter().find(|e| ***e == k).or_else(|| existing.first());
+            if let Some(c) = e.and_then(|e| ours.get_mut(*e)) {
+                combine(c, v);
+                continue;
+            }
+        } else {
+            for e in existing {
+                ours.remove(*e);
+            }
+        }
+        ours.insert(k, v);
+    }
+}
+
+fn find<'a, V: 'a, I>(iter: I, name: &str, labels: &[(&str, &str)]) -> Option<&'a V>
+where
+    I: IntoIterator<Item = (&'a Arc<Key>, &'a V)>,
+{
+    iter.into_iter().find(|&(k, _)| is_series(k, name, labels)).map(|(_, v)| v)
+}
+
+/// Returns true if `k` has the given name and (exactly the given) labels.
+pub(super) fn is_series(k: &Key, name: &str, labels: &[(&str, &str)]) -> bool {
+    k.name() == name && k.labels().len() == labels.len() &&
+        labels.iter().all(|&(lk, lv)| {
+            k.labels().get(lk).map(|v| v.with_str(|v| v == lv)).unwrap_or(false)
+        })
+}
+
+fn rekey<V>(map: &mut OrderMap<Arc<Key>, V>, f: &mut dyn FnMut(&Key) -> Option<Key>) {
+    let old = mem::replace(map, OrderMap::with_capacity(map.len()));
+    for (k, v) in old {
+        let k = match f(&k) {
+            // The series is the same, so it keeps its creation time.
+            Some(mut new) => {
+                new.created = k.created;
+                Arc::new(new)
+            }
+            None => k,
+        };
+        map.insert(k, v);
     }
 }
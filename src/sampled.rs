@@ -0,0 +1,135 @@
+//! Gauges that are sampled many times per interval, e.g. a queue's length on each
+//! iteration of a loop.
+//!
+//! Rather than reporting the last value set, a `SampledGauge` accumulates the count, sum,
+//! minimum, and maximum of the values observed since the last report was taken. These are
+//! exported as the gauges `count`, `sum`, `min`, and `max`, prefixed by the sampled gauge's
+//! name (e.g. `queue_len:max`). Peeking at a report does not reset them.
+//!
+//! The four values are updated and reset independently, so a report taken while values
+//! are being observed may not reflect every observation consistently (e.g. `count` may
+//! include an observation that `sum` does not).
+
+use super::{Gauge, Key};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Accumulates observed values.
+pub struct Samples {
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Samples {
+    fn default() -> Samples {
+        Samples {
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Samples {
+    fn observe(&self, v: u64) {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        let _ = self.sum.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |s| Some(s.saturating_add(v)),
+        );
+        self.min.fetch_min(v, Ordering::AcqRel);
+        self.max.fetch_max(v, Ordering::AcqRel);
+    }
+}
+
+/// Accumulates values observed between reports.
+#[derive(Clone)]
+pub struct SampledGauge {
+    pub(super) samples: Arc<Samples>,
+    /// Held so that the exported gauges are not pruned while the SampledGauge is in use.
+    pub(super) _gauges: [Gauge; 4],
+}
+
+impl SampledGauge {
+    pub fn observe(&self, v: u64) {
+        self.samples.observe(v);
+    }
+}
+
+/// Exports a `SampledGauge`'s values as gauges, stored in the registry.
+pub struct SampledGauges {
+    pub(super) key: Arc<Key>,
+    pub(super) samples: Weak<Samples>,
+    /// The count, sum, min, and max gauges.
+    pub(super) gauges: [Weak<AtomicUsize>; 4],
+}
+
+impl SampledGauges {
+    /// Copies the samples into their gauges, optionally resetting them.
+    ///
+    /// Returns false if the SampledGauge is no longer in use.
+    pub fn update(&self, clear: bool) -> bool {
+        let samples = match self.samples.upgrade() {
+            Some(s) => s,
+            None => return false,
+        };
+        let load = |a: &AtomicU64, reset: u64| if clear {
+            a.swap(reset, Ordering::AcqRel)
+        } else {
+            a.load(Ordering::Acquire)
+        };
+        let count = load(&samples.count, 0);
+        let sum = load(&samples.sum, 0);
+        let min = load(&samples.min, u64::MAX);
+        let max = load(&samples.max, 0);
+        let values = if count == 0 {
+            [0, 0, 0, 0]
+        } else {
+            [count, sum, min, max]
+        };
+        for (g, v) in self.gauges.iter().zip(values.iter()) {
+            if let Some(g) = g.upgrade() {
+                g.store(*v as usize, Ordering::Release);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn test_sampled_gauge() {
+        let (metrics, mut reporter) = ::new();
+        let queue = metrics.prefixed("worker").sampled_gauge("queue_len");
+        for v in &[3, 1, 8] {
+            queue.observe(*v);
+        }
+
+        let out = ::prometheus::string(&reporter.peek()).unwrap();
+        assert_eq!(
+            out,
+            "worker:queue_len:count 3\n\
+             worker:queue_len:sum 12\n\
+             worker:queue_len:min 1\n\
+             worker:queue_len:max 8\n"
+        );
+
+        // Taking a report resets the samples.
+        assert_eq!(reporter.take().len(), 4);
+        queue.observe(5);
+        let report = reporter.take();
+        assert_eq!(report.gauges().values().cloned().collect::<Vec<_>>(), vec![1, 5, 5, 5]);
+        let report = reporter.take();
+        assert_eq!(report.gauges().values().cloned().collect::<Vec<_>>(), vec![0, 0, 0, 0]);
+
+        drop(queue);
+        reporter.take();
+        assert!(reporter.peek().is_empty());
+    }
+}
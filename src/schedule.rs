@@ -0,0 +1,84 @@
+//! Aligns reporting to wall-clock boundaries.
+//!
+//! When several instances report on the same period, aligning each report to a multiple of
+//! the period (e.g. every :00 and :30 seconds for a 30s period) causes their data points
+//! to line up in a TSDB. Since each delay is computed from the current wall-clock time,
+//! timer drift does not accumulate across reports.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Computes delays until successive wall-clock-aligned reporting boundaries.
+///
+/// ```ignore
+/// let mut schedule = AlignedSchedule::new(Duration::from_secs(30));
+/// loop {
+///     thread::sleep(schedule.next_delay());
+///     push(&reporter.take());
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct AlignedSchedule {
+    period: u128,
+    /// The index of the boundary most recently scheduled, if any.
+    last: Option<u128>,
+}
+
+impl AlignedSchedule {
+    /// Creates a schedule with boundaries at every multiple of `period` since the Unix epoch.
+    pub fn new(period: Duration) -> AlignedSchedule {
+        assert!(period > Duration::from_secs(0), "period must be positive");
+        AlignedSchedule {
+            period: period.as_nanos(),
+            last: None,
+        }
+    }
+
+    /// Returns how long to wait until the next boundary.
+    ///
+    /// A boundary is never scheduled twice, so a timer that fires slightly early does not
+    /// cause a second report for the same boundary.
+    pub fn next_delay(&mut self) -> Duration {
+        self.next_delay_at(SystemTime::now())
+    }
+
+    fn next_delay_at(&mut self, now: SystemTime) -> Duration {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let mut next = now / self.period + 1;
+        if let Some(last) = self.last {
+            if next <= last {
+                next = last + 1;
+            }
+        }
+        self.last = Some(next);
+        let nanos = next * self.period - now;
+        Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_aligned_schedule() {
+        let mut schedule = AlignedSchedule::new(Duration::from_secs(30));
+        assert_eq!(schedule.next_delay_at(at(100, 0)), Duration::from_secs(20));
+        // The timer fired late; the following boundary is still aligned.
+        assert_eq!(schedule.next_delay_at(at(120, 250)), Duration::from_millis(29_750));
+        // The timer fired early; the same boundary is not scheduled again.
+        assert_eq!(schedule.next_delay_at(at(149, 990)), Duration::from_millis(30_010));
+        // Boundaries missed entirely are skipped.
+        assert_eq!(schedule.next_delay_at(at(245, 0)), Duration::from_secs(25));
+
+        let mut schedule = AlignedSchedule::new(Duration::from_secs(30));
+        assert_eq!(schedule.next_delay_at(at(90, 0)), Duration::from_secs(30));
+        assert!(schedule.next_delay() <= Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,157 @@
+//! Distributions of sizes, bucketed by powers of two.
+//!
+//! For values like payload sizes, the precision of a `Stat`'s histogram is unnecessary. A
+//! `SizeStat` instead counts values into fixed buckets with upper bounds of 1, 2, 4, ...,
+//! 2^40, backed by an array of atomics so that recording never locks or allocates.
+
+use std::array;
+use std::sync::Weak;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The exponent of the largest finite bucket.
+const MAX_EXP: usize = 40;
+
+/// The number of buckets, including one for values greater than 2^MAX_EXP.
+pub const NUM_BUCKETS: usize = MAX_EXP + 2;
+
+/// Atomic counters backing a `SizeStat`.
+pub struct SizeBuckets {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    sum: AtomicU64,
+}
+
+impl Default for SizeBuckets {
+    fn default() -> SizeBuckets {
+        SizeBuckets {
+            buckets: array::from_fn(|_| AtomicU64::new(0)),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SizeBuckets {
+    fn record(&self, v: u64) {
+        self.buckets[index(v)].fetch_add(1, Ordering::AcqRel);
+        let _ = self.sum.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |s| Some(s.saturating_add(v)),
+        );
+    }
+
+    /// Copies the current counts, optionally resetting them.
+    pub fn snapshot(&self, clear: bool) -> SizeSnapshot {
+        let mut counts = [0; NUM_BUCKETS];
+        for (c, b) in counts.iter_mut().zip(self.buckets.iter()) {
+            *c = if clear {
+                b.swap(0, Ordering::AcqRel)
+            } else {
+                b.load(Ordering::Acquire)
+            };
+        }
+        let sum = if clear {
+            self.sum.swap(0, Ordering::AcqRel)
+        } else {
+            self.sum.load(Ordering::Acquire)
+        };
+        SizeSnapshot { counts, sum }
+    }
+}
+
+/// Returns the index of the smallest bucket with an upper bound of at least `v`.
+fn index(v: u64) -> usize {
+    if v <= 1 {
+        return 0;
+    }
+    let exp = (64 - (v - 1).leading_zeros()) as usize;
+    if exp > MAX_EXP { NUM_BUCKETS - 1 } else { exp }
+}
+
+/// Captures a distribution of sizes.
+#[derive(Clone)]
+pub struct SizeStat(pub(super) Weak<SizeBuckets>);
+
+impl SizeStat {
+    pub fn add(&self, v: u64) {
+        if let Some(b) = self.0.upgrade() {
+            b.record(v);
+        }
+    }
+}
+
+/// A point-in-time copy of a `SizeStat`'s buckets.
+#[derive(Clone, Debug)]
+pub struct SizeSnapshot {
+    counts: [u64; NUM_BUCKETS],
+    sum: u64,
+}
+
+impl SizeSnapshot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Adds `other`'s values to this snapshot.
+    pub fn merge(&mut self, other: &SizeSnapshot) {
+        for (c, o) in self.counts.iter_mut().zip(&other.counts) {
+            *c = c.saturating_add(*o);
+        }
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+
+    /// Iterates over buckets as pairs of upper bounds and (non-cumulative) counts.
+    ///
+    /// The upper bound of the final bucket is `None`, as it holds all values greater than
+    /// 2^40.
+    pub fn buckets<'a>(&'a self) -> impl Iterator<Item = (Option<u64>, u64)> + 'a {
+        self.counts.iter().enumerate().map(|(i, c)| {
+            let le = if i <= MAX_EXP { Some(1 << i) } else { None };
+            (le, *c)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        assert_eq!(index(0), 0);
+        assert_eq!(index(1), 0);
+        assert_eq!(index(2), 1);
+        assert_eq!(index(3), 2);
+        assert_eq!(index(4), 2);
+        assert_eq!(index(5), 3);
+        assert_eq!(index(1 << 40), 40);
+        assert_eq!(index((1 << 40) + 1), NUM_BUCKETS - 1);
+        assert_eq!(index(u64::MAX), NUM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_size_stat() {
+        let (metrics, mut reporter) = ::new();
+        let payload = metrics.size_stat("payload_bytes");
+        for v in &[1, 3, 4, 1024, 1 << 50] {
+            payload.add(*v);
+        }
+
+        let report = reporter.take();
+        let s = report.sizes().values().next().expect("expected size stat");
+        assert_eq!(s.count(), 5);
+        assert_eq!(s.sum(), 1 + 3 + 4 + 1024 + (1 << 50));
+        let nonzero = s.buckets().filter(|&(_, c)| c > 0).collect::<Vec<_>>();
+        assert_eq!(
+            nonzero,
+            vec![(Some(1), 1), (Some(4), 2), (Some(1024), 1), (None, 1)]
+        );
+
+        // Buckets are reset when taken.
+        let report = reporter.take();
+        assert_eq!(report.sizes().values().next().map(|s| s.count()), Some(0));
+    }
+}
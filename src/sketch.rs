@@ -0,0 +1,449 @@
+//! A compact, mergeable quantile sketch, as an alternative to hdrsample histograms.
+//!
+//! A `DDSketch` counts values into logarithmically-sized bins, so that every quantile it
+//! reports is within a fixed relative error of the true value. Since bins are only
+//! allocated between the smallest and largest values observed, a sketch of values
+//! spanning many orders of magnitude is much smaller than a histogram with the same
+//! bounds. Sketches with the same accuracy may be merged exactly, e.g. across hosts.
+//!
+//! A sketch has at most `MAX_BINS` bins. If its values span more, the lowest bins are
+//! collapsed into one, so that low quantiles lose accuracy but high quantiles (e.g. tail
+//! latencies) do not. At the default accuracy of 1%, values from 1 to 10^17 fit.
+//!
+//! Stats use a sketch when configured with `StatBackend::Sketch`:
+//!
+//! ```
+//! use tacho::{StatBackend, StatConfig};
+//!
+//! let (metrics, reporter) = tacho::new();
+//! let config = StatConfig::default().backend(StatBackend::Sketch);
+//! let latency = metrics.stat_with_config("latency_us", config);
+//! latency.add(1_234);
+//! let report = reporter.peek();
+//! let p50 = report.get_stat("latency_us", &[]).unwrap().standard_percentiles()[0];
+//! assert!(p50 >= 1_222 && p50 <= 1_246);
+//! ```
+//!
+//! Reports hold histograms, so each snapshot of a sketch is converted into a histogram of
+//! the sketch's bins. The snapshot's standard percentiles are computed from the sketch
+//! itself, so exporters render the sketch's quantiles.
+//!
+//! See "DDSketch: A Fast and Fully-Mergeable Quantile Sketch with Relative-Error
+//! Guarantees" (Masson, Rim, and Lee; VLDB 2019).
+
+use super::{push_exemplar, Error, Exemplar, HistogramWithSum, STANDARD_PERCENTILES};
+use std::cmp;
+use std::collections::VecDeque;
+use std::mem;
+use std::time::SystemTime;
+
+/// The most bins a sketch has.
+pub const MAX_BINS: usize = 2_048;
+
+/// The significant figures that Stats' sketches track by default, i.e. to within 1%.
+pub(super) const SKETCH_PRECISION: u32 = 2;
+
+/// Tracks quantiles of a distribution to within a relative error.
+#[derive(Clone, Debug)]
+pub struct DDSketch {
+    gamma: f64,
+    ln_gamma: f64,
+    /// Counts of the values in bins `offset..offset + bins.len()`.
+    bins: Vec<u64>,
+    offset: i32,
+    /// The number of zeros, which have no bin.
+    zeros: u64,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl DDSketch {
+    /// Creates a sketch whose quantiles are within `relative_accuracy` (e.g. 0.01 for 1%)
+    /// of the true values.
+    pub fn new(relative_accuracy: f64) -> Result<DDSketch, Error> {
+        if !(relative_accuracy > 0.0 && relative_accuracy < 1.0) {
+            return Err(Error::InvalidHistogram(
+                "relative accuracy must be between 0 and 1",
+            ));
+        }
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        Ok(DDSketch {
+            gamma,
+            ln_gamma: gamma.ln(),
+            bins: Vec::new(),
+            offset: 0,
+            zeros: 0,
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        })
+    }
+
+    pub fn relative_accuracy(&self) -> f64 {
+        (self.gamma - 1.0) / (self.gamma + 1.0)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// The smallest value recorded, or 0 if the sketch is empty.
+    pub fn min(&self) -> u64 {
+        if self.count == 0 { 0 } else { self.min }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn record(&mut self, v: u64) {
+        self.record_n(v, 1);
+    }
+
+    /// Records `n` occurrences of a value.
+    pub fn record_n(&mut self, v: u64, n: u64) {
+        if n == 0 {
+            return;
+        }
+        if v == 0 {
+            self.zeros += n;
+        } else {
+            let k = self.key(v);
+            *self.bin(k) += n;
+        }
+        self.count += n;
+        self.sum = self.sum.saturating_add(v.saturating_mul(n));
+        self.min = cmp::min(self.min, v);
+        self.max = cmp::max(self.max, v);
+    }
+
+    /// Adds `other`'s values to this sketch.
+    ///
+    /// Fails if the sketches have different accuracies.
+    pub fn merge(&mut self, other: &DDSketch) -> Result<(), Error> {
+        if self.gamma != other.gamma {
+            return Err(Error::InvalidHistogram(
+                "sketches with different accuracies cannot be merged",
+            ));
+        }
+        for (i, n) in other.bins.iter().enumerate().filter(|&(_, n)| *n > 0) {
+            *self.bin(other.offset + i as i32) += *n;
+        }
+        self.zeros += other.zeros;
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.min = cmp::min(self.min, other.min);
+        self.max = cmp::max(self.max, other.max);
+        Ok(())
+    }
+
+    /// Returns the value at quantile `q` (from 0 to 1), or `None` if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64) as u64;
+        if rank == 0 {
+            return Some(self.min);
+        }
+        if rank == self.count - 1 {
+            return Some(self.max);
+        }
+        let mut seen = self.zeros;
+        if rank < seen {
+            return Some(0);
+        }
+        for (v, n) in self.values() {
+            seen += n;
+            if rank < seen {
+                return Some(v);
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Discards all values.
+    pub fn clear(&mut self) {
+        self.bins.clear();
+        self.offset = 0;
+        self.zeros = 0;
+        self.count = 0;
+        self.sum = 0;
+        self.min = u64::MAX;
+        self.max = 0;
+    }
+
+    /// Approximates the memory used by the sketch, in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        mem::size_of::<Self>() + self.bins.capacity() * mem::size_of::<u64>()
+    }
+
+    /// Iterates over each non-empty bin's value and count, in ascending order (excluding
+    /// zeros).
+    ///
+    /// Each bin's value is within the sketch's relative accuracy of the values it counts,
+    /// and is never less than `min` or greater than `max`.
+    fn values<'a>(&'a self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        self.bins
+            .iter()
+            .enumerate()
+            .filter(|&(_, n)| *n > 0)
+            .map(move |(i, n)| {
+                let k = self.offset + i as i32;
+                let v = (2.0 * self.gamma.powi(k) / (self.gamma + 1.0)).round() as u64;
+                (cmp::max(self.min, cmp::min(self.max, v)), *n)
+            })
+    }
+
+    fn key(&self, v: u64) -> i32 {
+        ((v as f64).ln() / self.ln_gamma).ceil() as i32
+    }
+
+    /// Returns the bin for key `k`, growing the bins to hold it.
+    ///
+    /// If that would exceed `MAX_BINS`, the lowest bins are collapsed: keys below the
+    /// lowest bin are counted in it.
+    fn bin(&mut self, k: i32) -> &mut u64 {
+        if self.bins.is_empty() {
+            self.offset = k;
+            self.bins.push(0);
+            return &mut self.bins[0];
+        }
+        let top = self.offset + self.bins.len() as i32 - 1;
+        if k > top {
+            let low = cmp::max(self.offset, k - (MAX_BINS as i32 - 1));
+            if low > self.offset {
+                let n = cmp::min((low - self.offset) as usize, self.bins.len());
+                let collapsed = self.bins.drain(..n).sum::<u64>();
+                if self.bins.is_empty() {
+                    self.bins.push(0);
+                }
+                self.bins[0] += collapsed;
+                self.offset = low;
+            }
+            let len = (k - self.offset) as usize + 1;
+            self.bins.resize(len, 0);
+        } else if k < self.offset {
+            let low = cmp::max(k, top - (MAX_BINS as i32 - 1));
+            if low < self.offset {
+                let grow = (self.offset - low) as usize;
+                self.bins.splice(0..0, (0..grow).map(|_| 0));
+                self.offset = low;
+            }
+        }
+        let i = cmp::max(k, self.offset) - self.offset;
+        &mut self.bins[i as usize]
+    }
+}
+
+/// Backs a Stat with a `DDSketch`.
+pub struct SketchCell {
+    sketch: DDSketch,
+    /// Values greater than `high` are clamped (or dropped, unless `clamp`).
+    high: u64,
+    clamp: bool,
+    overflowed: u64,
+    exemplars: VecDeque<Exemplar>,
+    /// When the sketch was created or last cleared.
+    created: SystemTime,
+    /// The bounds the Stat was created with, if any.
+    bounds: Option<(u64, u64)>,
+    /// The significant figures tracked, so that snapshots are no more precise.
+    sigfig: u32,
+}
+
+impl SketchCell {
+    /// Creates a sketch that tracks values to `sigfig` significant figures (i.e. to
+    /// within a relative error of `10^-sigfig`).
+    pub(super) fn new(
+        bounds: Option<(u64, u64)>,
+        sigfig: u32,
+        clamp: bool,
+    ) -> Result<SketchCell, Error> {
+        if sigfig > 5 {
+            return Err(Error::InvalidHistogram(
+                "number of significant digits must be between 0 and 5",
+            ));
+        }
+        // With no significant figures, values are tracked to within an order of magnitude.
+        let sigfig = cmp::max(sigfig, 1);
+        let accuracy = 10f64.powi(-(sigfig as i32));
+        Ok(SketchCell {
+            sketch: DDSketch::new(accuracy)?,
+            high: bounds.map(|(_, h)| h).unwrap_or(u64::MAX),
+            clamp,
+            overflowed: 0,
+            exemplars: VecDeque::new(),
+            created: SystemTime::now(),
+            bounds,
+            sigfig,
+        })
+    }
+
+    pub(super) fn bounds(&self) -> Option<(u64, u64)> {
+        self.bounds
+    }
+
+    pub(super) fn record(&mut self, v: u64) {
+        if v > self.high {
+            self.overflowed += 1;
+            if self.clamp {
+                self.sketch.record(self.high);
+            }
+            return;
+        }
+        self.sketch.record(v);
+    }
+
+    pub(super) fn push_exemplar(&mut self, exemplar: Exemplar) {
+        push_exemplar(&mut self.exemplars, exemplar);
+    }
+
+    pub(super) fn memory_bytes(&self) -> usize {
+        self.sketch.memory_bytes() + mem::size_of::<Self>() - mem::size_of::<DDSketch>()
+    }
+
+    /// Converts the sketch into a histogram, optionally clearing it.
+    ///
+    /// The histogram only spans the sketch's values, at the sketch's precision.
+    pub(super) fn snapshot(&mut self, clear: bool) -> HistogramWithSum {
+        let high = cmp::max(2, self.sketch.max());
+        let mut h = HistogramWithSum::new(Some((1, high)), self.sigfig);
+        h.record_n(0, self.sketch.zeros);
+        for (v, n) in self.sketch.values() {
+            h.record_n(v, n);
+        }
+        h.sum = self.sketch.sum();
+        h.overflowed = self.overflowed;
+        h.exemplars = self.exemplars.clone();
+        h.created = self.created;
+        h.bounds = self.bounds;
+        if self.sketch.count() > 0 {
+            let mut ps = [0; 6];
+            for (p, q) in ps.iter_mut().zip(STANDARD_PERCENTILES.iter()) {
+                *p = self.sketch.quantile(q / 100.0).unwrap_or(0);
+            }
+            let _ = h.percentiles.set(ps);
+        }
+        if clear {
+            self.clear();
+        }
+        h
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.sketch.clear();
+        self.overflowed = 0;
+        self.exemplars.clear();
+        self.created = SystemTime::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within(v: u64, expected: u64, accuracy: f64) {
+        let err = (v as f64 - expected as f64).abs() / expected as f64;
+        assert!(err <= accuracy, "{} is not within {} of {}", v, accuracy, expected);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut s = DDSketch::new(0.01).unwrap();
+        assert_eq!(s.quantile(0.5), None);
+        for v in 1..10_001 {
+            s.record(v);
+        }
+        assert_eq!((s.count(), s.sum(), s.min(), s.max()), (10_000, 50_005_000, 1, 10_000));
+        for &(q, expected) in &[(0.5, 5_000), (0.9, 9_000), (0.99, 9_900), (0.999, 9_990)] {
+            assert_within(s.quantile(q).unwrap(), expected, 0.01);
+        }
+        assert_eq!(s.quantile(0.0), Some(1));
+        assert_eq!(s.quantile(1.0), Some(10_000));
+        // A histogram would need bins for every value up to the maximum.
+        assert!(s.memory_bytes() < 8 * 1_024, "{}", s.memory_bytes());
+
+        s.record_n(0, 20_000);
+        assert_eq!(s.quantile(0.5), Some(0));
+        s.clear();
+        assert_eq!(s.count(), 0);
+        assert_eq!(s.min(), 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = DDSketch::new(0.01).unwrap();
+        let mut b = DDSketch::new(0.01).unwrap();
+        for v in 1..501 {
+            a.record(v);
+            b.record(1_000_000 + v);
+        }
+        a.merge(&b).unwrap();
+        assert_eq!((a.count(), a.min(), a.max()), (1_000, 1, 1_000_500));
+        assert_within(a.quantile(0.25).unwrap(), 250, 0.01);
+        assert_within(a.quantile(0.75).unwrap(), 1_000_250, 0.01);
+
+        assert!(a.merge(&DDSketch::new(0.02).unwrap()).is_err());
+        assert!(DDSketch::new(0.0).is_err());
+    }
+
+    #[test]
+    fn test_sketch_cell() {
+        let mut c = SketchCell::new(Some((1, 1_000)), 2, true).unwrap();
+        for v in &[0, 10, 100, 5_000] {
+            c.record(*v);
+        }
+        let h = c.snapshot(true);
+        assert_eq!(h.count(), 4);
+        assert_eq!(h.sum(), 1_110);
+        assert_eq!(h.overflowed(), 1);
+        assert_eq!(h.standard_percentiles()[0], 10);
+        // The snapshot is as precise as the sketch.
+        assert_within(h.max(), 1_000, 0.01);
+        assert_eq!(c.snapshot(false).count(), 0);
+        assert!(SketchCell::new(None, 6, true).is_err());
+    }
+
+    #[test]
+    fn test_bins_bounded() {
+        // Values from 1us to 60s fit at the default accuracy.
+        let mut c = SketchCell::new(None, SKETCH_PRECISION, true).unwrap();
+        let mut v = 1;
+        while v < 60_000_000 {
+            c.record(v);
+            v = v * 11 / 10 + 1;
+        }
+        c.record(60_000_000);
+        assert!(c.sketch.bins.len() < 1_000, "{}", c.sketch.bins.len());
+        let h = c.snapshot(false);
+        assert_within(h.max(), 60_000_000, 0.01);
+        assert!(h.memory_bytes() < 64 * 1_024, "{}", h.memory_bytes());
+
+        // At a higher accuracy, the lowest bins are collapsed, but high quantiles are kept.
+        let mut s = DDSketch::new(0.0001).unwrap();
+        for v in 1..1_001 {
+            s.record(v * 60_000);
+        }
+        s.record(1);
+        assert_eq!(s.bins.len(), MAX_BINS);
+        assert_eq!(s.count(), 1_001);
+        assert_within(s.quantile(0.99).unwrap(), 59_400_000, 0.0001);
+        // The extremes are exact, even if collapsed.
+        assert_eq!(s.quantile(0.0), Some(1));
+
+        // Collapsing also applies as values grow.
+        let mut s = DDSketch::new(0.0001).unwrap();
+        s.record(1);
+        s.record(u64::MAX);
+        assert_eq!(s.bins.len(), MAX_BINS);
+        assert_eq!(s.values().map(|(_, n)| n).sum::<u64>(), 2);
+    }
+}
@@ -0,0 +1,113 @@
+//! Service-level objectives.
+//!
+//! An SLO wraps a latency `Stat` and, as values are recorded, classifies each as a good or
+//! bad event. These events are exported as counters so that (multi-window) burn-rate
+//! alerts may be built from simple counter rates:
+//!
+//! ```text
+//! slo_events{slo="request_latency", outcome="good"}
+//! slo_events{slo="request_latency", outcome="bad"}
+//! slo_objective_ppm{slo="request_latency"}
+//! ```
+
+use super::{Counter, Gauge, Scope, Stat, TimeUnit};
+use std::time::Duration;
+
+/// Declares an SLO that `target` (from 0.0 to 1.0) of the latencies recorded to the stat
+/// `name` are at most `threshold`.
+///
+/// Latencies are recorded in microseconds.
+pub fn latency_under(
+    scope: &Scope,
+    name: &'static str,
+    threshold: Duration,
+    target: f64,
+) -> LatencySlo {
+    let unit = TimeUnit::Micros;
+    let stat = scope.stat_with_unit(name, unit);
+
+    let scope = scope.clone().labeled("slo", name);
+    let good = scope.clone().labeled("outcome", "good").counter("slo_events");
+    let bad = scope.clone().labeled("outcome", "bad").counter("slo_events");
+    let objective = scope.gauge("slo_objective_ppm");
+    objective.set(to_ppm(target));
+
+    LatencySlo {
+        stat,
+        threshold: unit.convert(threshold),
+        target,
+        good,
+        bad,
+        _objective: objective,
+    }
+}
+
+/// Records latencies, counting those that exceed the SLO's threshold.
+#[derive(Clone)]
+pub struct LatencySlo {
+    stat: Stat,
+    threshold: u64,
+    target: f64,
+    good: Counter,
+    bad: Counter,
+    /// Held so that the objective is exported for as long as the SLO is in use.
+    _objective: Gauge,
+}
+
+impl LatencySlo {
+    /// Records a latency.
+    pub fn record(&self, d: Duration) {
+        self.add(TimeUnit::Micros.convert(d));
+    }
+
+    /// Records a latency, in microseconds.
+    pub fn add(&self, us: u64) {
+        self.stat.add(us);
+        if us <= self.threshold {
+            self.good.incr(1);
+        } else {
+            self.bad.incr(1);
+        }
+    }
+
+    /// The fraction of events that are expected to be good.
+    pub fn target(&self) -> f64 {
+        self.target
+    }
+}
+
+fn to_ppm(target: f64) -> usize {
+    let t = target.clamp(0.0, 1.0);
+    (t * 1_000_000.0).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_under() {
+        let (metrics, reporter) = ::new();
+        let slo = latency_under(&metrics, "request_latency", Duration::from_millis(250), 0.99);
+        assert_eq!(slo.target(), 0.99);
+
+        slo.record(Duration::from_millis(100));
+        slo.record(Duration::from_millis(250));
+        slo.record(Duration::from_millis(300));
+
+        let report = reporter.peek();
+        let events = |outcome: &'static str| {
+            report
+                .counters()
+                .iter()
+                .find(|&(k, _)| k.labels().get("outcome") == Some(&outcome.into()))
+                .map(|(_, v)| *v)
+        };
+        assert_eq!(events("good"), Some(2));
+        assert_eq!(events("bad"), Some(1));
+
+        let objective = report.gauges().values().next();
+        assert_eq!(objective, Some(&990_000));
+        assert_eq!(report.stats().values().next().map(|h| h.count()), Some(3));
+    }
+}
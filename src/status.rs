@@ -0,0 +1,77 @@
+//! Counters of HTTP responses by status class, e.g. `responses{status_class="2xx"}`.
+//!
+//! ```
+//! let (metrics, reporter) = tacho::new();
+//! let responses = metrics.status_counter("responses");
+//! responses.record(200);
+//! responses.record(204);
+//! responses.record(503);
+//!
+//! let report = reporter.peek();
+//! assert_eq!(report.get_counter("responses", &[("status_class", "2xx")]), Some(2));
+//! assert_eq!(report.get_counter("responses", &[("status_class", "4xx")]), Some(0));
+//! ```
+
+use super::{Counter, Scope};
+
+/// The label that distinguishes status classes.
+pub const STATUS_CLASS: &str = "status_class";
+
+/// Each class's label value. Codes outside of 100-599 are counted as `other`.
+const CLASSES: [&str; 6] = ["1xx", "2xx", "3xx", "4xx", "5xx", "other"];
+
+/// Counts responses by status class.
+///
+/// A counter is created for each class up front, so recording a status is a single
+/// increment, without formatting or looking up labels. Each class is exported (as zero,
+/// until it is recorded) so that rates may be computed for every class.
+#[derive(Clone, Debug)]
+pub struct StatusCounter {
+    counters: [Counter; 6],
+}
+
+impl StatusCounter {
+    pub(super) fn new(scope: &Scope, name: &'static str) -> StatusCounter {
+        let class = |i: usize| scope.clone().labeled(STATUS_CLASS, CLASSES[i]).counter(name);
+        StatusCounter {
+            counters: [class(0), class(1), class(2), class(3), class(4), class(5)],
+        }
+    }
+
+    /// Counts a response with the given status code.
+    pub fn record(&self, status: u16) {
+        self.class(status).incr(1);
+    }
+
+    /// Returns the counter for a status code's class.
+    pub fn class(&self, status: u16) -> &Counter {
+        let i = match status {
+            100..=599 => usize::from(status / 100 - 1),
+            _ => CLASSES.len() - 1,
+        };
+        &self.counters[i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_counter() {
+        let (metrics, reporter) = ::new();
+        let responses = metrics.labeled("route", "/").status_counter("responses");
+        for &s in &[101, 200, 299, 302, 404, 499, 500, 0, 600] {
+            responses.record(s);
+        }
+
+        let report = reporter.peek();
+        let count = |class| {
+            report.get_counter("responses", &[("route", "/"), (STATUS_CLASS, class)])
+        };
+        let counts = CLASSES.iter().map(|c| count(c)).collect::<Vec<_>>();
+        assert_eq!(counts, vec![Some(1), Some(2), Some(1), Some(2), Some(1), Some(2)]);
+        let class = responses.class(404).key().labels().get(STATUS_CLASS).cloned();
+        assert_eq!(class, Some("4xx".into()));
+    }
+}
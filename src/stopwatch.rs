@@ -0,0 +1,131 @@
+//! Gauges of the time elapsed since an event, e.g. the last successful sync.
+//!
+//! A `Stopwatch`'s gauge is computed as each report is obtained, so it needn't be updated
+//! while the stopwatch runs:
+//!
+//! ```
+//! use tacho::TimeUnit;
+//!
+//! let (metrics, reporter) = tacho::new();
+//! let since_sync = metrics.stopwatch("since_sync_ms", TimeUnit::Millis);
+//! // ... after each successful sync ...
+//! since_sync.start();
+//! assert!(reporter.peek().get_gauge("since_sync_ms", &[]).is_some());
+//! ```
+
+use super::{Gauge, TimeUnit};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Exports the time since it was (re)started as a gauge.
+///
+/// Until it is started, the gauge is zero; once stopped, it holds the time elapsed when
+/// the stopwatch was stopped.
+#[derive(Clone)]
+pub struct Stopwatch {
+    started: Arc<Mutex<Option<Instant>>>,
+    gauge: Gauge,
+    unit: TimeUnit,
+}
+
+impl Stopwatch {
+    pub(super) fn new(gauge: Gauge, unit: TimeUnit) -> Stopwatch {
+        Stopwatch {
+            started: Arc::new(Mutex::new(None)),
+            gauge,
+            unit,
+        }
+    }
+
+    /// Starts the stopwatch, or restarts it from zero if it is running.
+    pub fn start(&self) {
+        *self.lock() = Some(Instant::now());
+        self.gauge.set(0);
+    }
+
+    /// Stops the stopwatch, holding its gauge at the time elapsed, and returns that time.
+    ///
+    /// Returns `None` if the stopwatch was not running.
+    pub fn stop(&self) -> Option<Duration> {
+        let elapsed = self.lock().take().map(|t0| t0.elapsed());
+        if let Some(d) = elapsed {
+            self.gauge.set_duration(d, self.unit);
+        }
+        elapsed
+    }
+
+    /// Returns the time since the stopwatch was started, if it is running.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.lock().map(|t0| t0.elapsed())
+    }
+
+    /// Returns a weak reference to the stopwatch, for the registry.
+    pub(super) fn gauge(&self) -> StopwatchGauge {
+        StopwatchGauge {
+            started: Arc::downgrade(&self.started),
+            gauge: self.gauge.0.clone(),
+            unit: self.unit,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.started.lock().expect("failed to obtain lock for stopwatch")
+    }
+}
+
+/// Updates a `Stopwatch`'s gauge, stored in the registry.
+pub struct StopwatchGauge {
+    started: Weak<Mutex<Option<Instant>>>,
+    gauge: Weak<AtomicUsize>,
+    unit: TimeUnit,
+}
+
+impl StopwatchGauge {
+    /// Sets the gauge to the time elapsed, if the stopwatch is running.
+    ///
+    /// Returns false if the Stopwatch is no longer in use.
+    pub fn update(&self) -> bool {
+        let started = match self.started.upgrade() {
+            Some(s) => s,
+            None => return false,
+        };
+        let t0 = *started.lock().expect("failed to obtain lock for stopwatch");
+        if let (Some(t0), Some(g)) = (t0, self.gauge.upgrade()) {
+            g.store(self.unit.convert(t0.elapsed()) as usize, Ordering::Release);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_stopwatch() {
+        let (metrics, mut reporter) = ::new();
+        let sw = metrics.stopwatch("since_sync_ms", TimeUnit::Millis);
+        assert_eq!(sw.elapsed(), None);
+        assert_eq!(reporter.peek().get_gauge("since_sync_ms", &[]), Some(0));
+
+        sw.start();
+        thread::sleep(Duration::from_millis(5));
+        let running = reporter.take().get_gauge("since_sync_ms", &[]).unwrap();
+        assert!(running >= 5, "{}", running);
+
+        let stopped = sw.stop().unwrap();
+        assert_eq!(sw.stop(), None);
+        thread::sleep(Duration::from_millis(5));
+        let held = reporter.take().get_gauge("since_sync_ms", &[]).unwrap();
+        assert_eq!(held as u128, stopped.as_millis());
+
+        sw.start();
+        assert!(reporter.take().get_gauge("since_sync_ms", &[]).unwrap() < running);
+
+        drop(sw);
+        reporter.take();
+        assert_eq!(reporter.take().get_gauge("since_sync_ms", &[]), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! Pluggable storage for registered metrics.
+//!
+//! The `Registry` keeps one `Storage` per kind of metric (counters, gauges, stats, etc). By
+//! default, each is an `OrderMap`, but alternative backends may be supplied via
+//! `tacho::with_storage`.
+//!
+//! Keys are shared (via `Arc`) between the registry and the reports it produces, so
+//! snapshots needn't copy keys.
+
+use super::Key;
+use ordermap::OrderMap;
+use std::mem;
+use std::sync::Arc;
+
+/// Stores the values backing a single kind of metric, keyed by `Key`.
+///
+/// Storage is always accessed under the registry's lock, so implementations need not be
+/// internally synchronized. Since reports are snapshotted under a shared lock, `&self`
+/// methods may be called concurrently, so implementations must be `Sync`.
+pub trait Storage<V>: Send + Sync {
+    /// Looks up the value registered for `key`.
+    fn get(&self, key: &Key) -> Option<&V>;
+
+    /// Looks up the value registered for `key`, along with the registry's own copy of the
+    /// key, so that handles may share it.
+    ///
+    /// By default, this scans `iter`; backends should override it with a direct lookup.
+    fn get_key_value(&self, key: &Key) -> Option<(&Arc<Key>, &V)> {
+        self.iter().find(|&(k, _)| **k == *key)
+    }
+
+    /// Registers a value for `key`, replacing any existing value.
+    fn insert(&mut self, key: Arc<Key>, value: V);
+
+    /// Returns the number of registered metrics.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over all registered metrics.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Arc<Key>, &'a V)> + 'a>;
+
+    /// Drops all metrics for which `keep` returns false.
+    fn retain(&mut self, keep: &mut dyn FnMut(&Key, &mut V) -> bool);
+
+    /// Returns the number of metrics that may be stored without reallocating.
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// Releases unused capacity, e.g. after many metrics have been pruned.
+    fn shrink_to_fit(&mut self) {}
+}
+
+impl<V: Send + Sync> Storage<V> for OrderMap<Arc<Key>, V> {
+    fn get(&self, key: &Key) -> Option<&V> {
+        OrderMap::get(self, key)
+    }
+
+    fn get_key_value(&self, key: &Key) -> Option<(&Arc<Key>, &V)> {
+        OrderMap::get_pair(self, key)
+    }
+
+    fn insert(&mut self, key: Arc<Key>, value: V) {
+        OrderMap::insert(self, key, value);
+    }
+
+    fn len(&self) -> usize {
+        OrderMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Arc<Key>, &'a V)> + 'a> {
+        Box::new(OrderMap::iter(self))
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&Key, &mut V) -> bool) {
+        OrderMap::retain(self, |k, v| keep(k, v))
+    }
+
+    fn capacity(&self) -> usize {
+        OrderMap::capacity(self)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        let old = mem::replace(self, OrderMap::with_capacity(self.len()));
+        for (k, v) in old {
+            OrderMap::insert(self, k, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A naive storage backend, used to exercise the `Storage` interface.
+    struct VecStorage<V>(Vec<(Arc<Key>, V)>);
+
+    impl<V: Send + Sync> Storage<V> for VecStorage<V> {
+        fn get(&self, key: &Key) -> Option<&V> {
+            self.0.iter().find(|(k, _)| **k == *key).map(|(_, v)| v)
+        }
+        fn insert(&mut self, key: Arc<Key>, value: V) {
+            self.0.retain(|(k, _)| *k != key);
+            self.0.push((key, value));
+        }
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+        fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Arc<Key>, &'a V)> + 'a> {
+            Box::new(self.0.iter().map(|(k, v)| (k, v)))
+        }
+        fn retain(&mut self, keep: &mut dyn FnMut(&Key, &mut V) -> bool) {
+            self.0.retain_mut(|(k, v)| keep(k, v))
+        }
+    }
+
+    #[test]
+    fn test_with_storage() {
+        let counters: VecStorage<Arc<AtomicUsize>> = VecStorage(Vec::new());
+        let (metrics, mut reporter) = ::with_storage(
+            Box::new(counters),
+            Box::new(OrderMap::new()),
+            Box::new(OrderMap::new()),
+            Box::new(OrderMap::new()),
+        );
+
+        let reqs = metrics.counter("requests");
+        metrics.counter("requests").incr(2);
+        reqs.incr(1);
+        {
+            let report = reporter.take();
+            assert_eq!(report.counters().len(), 1);
+            assert_eq!(report.counters().values().next(), Some(&3));
+        }
+
+        drop(reqs);
+        reporter.take();
+        assert!(reporter.take().is_empty());
+    }
+}
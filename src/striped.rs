@@ -0,0 +1,171 @@
+//! Counters that are incremented very frequently from many threads.
+//!
+//! Even relaxed atomic increments contend when many threads update the same counter, since
+//! each increment must own the counter's cache line. A `StripedCounter` spreads increments
+//! across cache-line-padded stripes, one per thread (modulo the number of stripes), so
+//! that each increment is a single, usually uncontended, atomic operation.
+//!
+//! The stripes are summed into an ordinary counter each time a report is peeked or taken,
+//! so a striped counter is exported like any other. When the last handle is dropped, its
+//! remaining increments are added to the counter, which is then pruned as usual.
+
+use super::{Counter, Key};
+use std::cell::Cell;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// The most stripes a counter may have.
+const MAX_STRIPES: usize = 64;
+
+/// Assigns each thread a stripe, round-robin, as it first increments a striped counter.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn stripe_index() -> usize {
+    STRIPE.with(|s| match s.get() {
+        Some(i) => i,
+        None => {
+            let i = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+            s.set(Some(i));
+            i
+        }
+    })
+}
+
+/// A counter on its own cache line.
+#[repr(align(64))]
+#[derive(Default)]
+struct Stripe(AtomicUsize);
+
+/// Accumulates increments until they are added to the counter.
+pub struct Stripes {
+    stripes: Box<[Stripe]>,
+    /// Held so that the counter is not pruned until the stripes are flushed.
+    counter: Weak<AtomicUsize>,
+}
+
+impl Stripes {
+    fn new(counter: Weak<AtomicUsize>) -> Stripes {
+        let n = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .next_power_of_two()
+            .min(MAX_STRIPES);
+        Stripes {
+            stripes: (0..n).map(|_| Stripe::default()).collect(),
+            counter,
+        }
+    }
+
+    /// Adds the stripes' increments to the counter.
+    fn flush(&self) {
+        let n = self.stripes
+            .iter()
+            .map(|s| s.0.swap(0, Ordering::AcqRel))
+            .fold(0usize, |a, n| a.wrapping_add(n));
+        if n > 0 {
+            if let Some(c) = self.counter.upgrade() {
+                c.fetch_add(n, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+impl Drop for Stripes {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// A Counter whose increments are spread across stripes. See the module documentation.
+///
+/// Each `Scope::counter_striped` call allocates stripes, so handles should be created once
+/// and cloned.
+#[derive(Clone)]
+pub struct StripedCounter {
+    stripes: Arc<Stripes>,
+    key: Arc<Key>,
+}
+
+impl StripedCounter {
+    pub(super) fn new(counter: &Counter) -> StripedCounter {
+        StripedCounter {
+            stripes: Arc::new(Stripes::new(counter.0.clone())),
+            key: counter.1.clone(),
+        }
+    }
+
+    /// Identifies the counter.
+    pub fn key(&self) -> &Arc<Key> {
+        &self.key
+    }
+
+    pub fn incr(&self, v: usize) {
+        let stripes = &self.stripes.stripes;
+        let i = stripe_index() & (stripes.len() - 1);
+        stripes[i].0.fetch_add(v, Ordering::Relaxed);
+    }
+
+    /// Returns a weak reference to the stripes, for the registry.
+    pub(super) fn stripes(&self) -> StripedCounters {
+        StripedCounters(Arc::downgrade(&self.stripes))
+    }
+}
+
+/// Sums a `StripedCounter`'s stripes into its counter, stored in the registry.
+pub struct StripedCounters(Weak<Stripes>);
+
+impl StripedCounters {
+    /// Adds the stripes' increments to the counter.
+    ///
+    /// Returns false if the StripedCounter is no longer in use.
+    pub fn update(&self) -> bool {
+        match self.0.upgrade() {
+            Some(s) => {
+                s.flush();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    #[test]
+    fn test_striped_counter() {
+        let (metrics, mut reporter) = ::new();
+        let requests = metrics.counter_striped("requests");
+        let plain = metrics.counter("requests");
+        plain.incr(1);
+
+        let threads = (0..4)
+            .map(|_| {
+                let requests = requests.clone();
+                thread::spawn(move || for _ in 0..1_000 {
+                    requests.incr(1);
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(reporter.peek().get_counter("requests", &[]), Some(4_001));
+
+        requests.incr(10);
+        assert_eq!(reporter.take().get_counter("requests", &[]), Some(4_011));
+
+        // Increments are flushed when the last handle is dropped.
+        requests.incr(5);
+        drop(requests);
+        drop(plain);
+        assert_eq!(reporter.take().get_counter("requests", &[]), Some(4_016));
+        assert_eq!(reporter.take().get_counter("requests", &[]), None);
+    }
+}
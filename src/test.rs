@@ -0,0 +1,253 @@
+//! Assertions for testing instrumented code.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate tacho;
+//!
+//! fn serve(metrics: &tacho::Scope) {
+//!     let ok = metrics.clone().labeled("status", 200);
+//!     let latency = metrics.stat("latency_ms");
+//!     for ms in &[3, 1, 4] {
+//!         ok.counter("requests").incr(1);
+//!         latency.add(*ms);
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let (metrics, reporter) = tacho::new();
+//!     serve(&metrics);
+//!     let report = reporter.peek();
+//!     assert_counter!(report, "requests", &[("status", "200")], 3);
+//!     assert_stat_count!(report, "latency_ms", &[], 3);
+//! }
+//! ```
+//!
+//! Series are matched by name and by their complete set of labels. When an assertion fails,
+//! the series of the same name that were reported are listed.
+//!
+//! Reports only hold aggregates, so `capture` creates a registry that also retains every
+//! value recorded to its stats:
+//!
+//! ```
+//! let (metrics, capture) = tacho::test::capture();
+//! let latency = metrics.labeled("status", 200).stat("latency_ms");
+//! latency.add(3);
+//! latency.add(1);
+//! assert_eq!(capture.stat_values("latency_ms", &[("status", "200")]), vec![3, 1]);
+//! ```
+
+use super::{Builder, Key, Report, Reporter, Scope};
+use super::report::is_series;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Creates a registry that retains every value recorded to its stats, for precise
+/// assertions in unit tests.
+pub fn capture() -> (Scope, Capture) {
+    let samples = Arc::new(Samples::default());
+    let mut registry = Builder::new().build_registry();
+    registry.capture = Some(samples.clone());
+    let registry = Arc::new(RwLock::new(registry));
+    let scope = Scope::with_registry(registry.clone());
+    let capture = Capture {
+        reporter: Reporter::new(registry),
+        samples,
+    };
+    (scope, capture)
+}
+
+/// Inspects the metrics recorded by a `capture` registry.
+pub struct Capture {
+    reporter: Reporter,
+    samples: Arc<Samples>,
+}
+
+impl Capture {
+    /// Returns the value of the counter with the given name and (exactly the given) labels.
+    pub fn counter_value(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        self.reporter.get_counter(name, labels)
+    }
+
+    /// Returns the value of the gauge with the given name and (exactly the given) labels.
+    pub fn gauge_value(&self, name: &str, labels: &[(&str, &str)]) -> Option<usize> {
+        self.reporter.get_gauge(name, labels)
+    }
+
+    /// Returns each value recorded to the stat with the given name and (exactly the given)
+    /// labels, in the order they were recorded.
+    ///
+    /// Values are captured as they are passed to the stat (i.e. before any correction for
+    /// coordinated omission), and are retained when reports are taken.
+    pub fn stat_values(&self, name: &str, labels: &[(&str, &str)]) -> Vec<u64> {
+        let samples = self.samples.0.lock().expect("failed to obtain lock on samples");
+        samples
+            .iter()
+            .filter(|&(k, _)| is_series(k, name, labels))
+            .map(|&(_, v)| v)
+            .collect()
+    }
+
+    /// Discards all captured values.
+    pub fn clear(&self) {
+        self.samples.0.lock().expect("failed to obtain lock on samples").clear();
+    }
+
+    /// The registry's reporter, e.g. to take reports.
+    pub fn reporter(&mut self) -> &mut Reporter {
+        &mut self.reporter
+    }
+}
+
+/// Values recorded to stats, in order.
+#[derive(Default)]
+pub(super) struct Samples(Mutex<Vec<(Arc<Key>, u64)>>);
+
+impl Samples {
+    pub(super) fn push(&self, key: &Arc<Key>, vs: &[u64]) {
+        let mut samples = self.0.lock().expect("failed to obtain lock on samples");
+        samples.extend(vs.iter().map(|v| (key.clone(), *v)));
+    }
+}
+
+/// Asserts that a report has a counter with the given name, labels, and value.
+#[macro_export]
+macro_rules! assert_counter {
+    ($report:expr, $name:expr, $labels:expr, $value:expr) => {
+        $crate::test::assert_counter(&$report, $name, $labels, $value)
+    };
+}
+
+/// Asserts that a report has a stat with the given name and labels, holding `count` values.
+#[macro_export]
+macro_rules! assert_stat_count {
+    ($report:expr, $name:expr, $labels:expr, $count:expr) => {
+        $crate::test::assert_stat_count(&$report, $name, $labels, $count)
+    };
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_counter(report: &Report, name: &str, labels: &[(&str, &str)], value: usize) {
+    match report.get_counter(name, labels) {
+        Some(v) => {
+            assert_eq!(
+                v,
+                value,
+                "counter {}{} has the wrong value",
+                name,
+                fmt_labels(labels)
+            )
+        }
+        None => panic!(
+            "counter {}{} not found{}",
+            name,
+            fmt_labels(labels),
+            fmt_candidates(report.counters().keys(), name)
+        ),
+    }
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_stat_count(report: &Report, name: &str, labels: &[(&str, &str)], count: u64) {
+    match report.get_stat(name, labels) {
+        Some(h) => {
+            assert_eq!(
+                h.count(),
+                count,
+                "stat {}{} has the wrong count",
+                name,
+                fmt_labels(labels)
+            )
+        }
+        None => panic!(
+            "stat {}{} not found{}",
+            name,
+            fmt_labels(labels),
+            fmt_candidates(report.stats().keys(), name)
+        ),
+    }
+}
+
+fn fmt_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::from("{");
+    for (i, &(k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        let _ = write!(out, "{}=\"{}\"", k, v);
+    }
+    out.push('}');
+    out
+}
+
+/// Lists the labels of the series named `name`.
+fn fmt_candidates<'a, I>(keys: I, name: &str) -> String
+where
+    I: Iterator<Item = &'a Arc<Key>>,
+{
+    let mut out = String::new();
+    for k in keys.filter(|k| k.name() == name) {
+        let labels = k.labels()
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v))
+            .collect::<Vec<_>>();
+        let _ = write!(out, "\n  {}{{{}}}", name, labels.join(", "));
+    }
+    if out.is_empty() {
+        return String::from("; no series have that name");
+    }
+    format!("; series with that name:{}", out)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_assertions() {
+        let (metrics, reporter) = ::new();
+        let metrics = metrics.prefixed("http").labeled("status", 200);
+        metrics.counter("requests").incr(3);
+        metrics.stat("latency_ms").add_values(&[1, 2, 3]);
+        metrics.gauge("conns").set(2);
+
+        let report = reporter.peek();
+        assert_counter!(report, "requests", &[("status", "200")], 3);
+        assert_stat_count!(report, "latency_ms", &[("status", "200")], 3);
+        assert_eq!(report.get_gauge("conns", &[("status", "200")]), Some(2));
+        assert_eq!(report.get_counter("requests", &[]), None);
+        assert_eq!(report.get_counter("requests", &[("status", "500")]), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "series with that name:\n  requests{status=\"200\"}")]
+    fn test_assert_counter_missing() {
+        let (metrics, reporter) = ::new();
+        metrics.labeled("status", 200).counter("requests").incr(1);
+        assert_counter!(reporter.peek(), "requests", &[("status", "500")], 1);
+    }
+
+    #[test]
+    fn test_capture() {
+        use std::time::Duration;
+
+        let (metrics, mut capture) = ::test::capture();
+        let ok = metrics.labeled("status", 200);
+        ok.counter("requests").incr(2);
+        let latency = ok.timer_ms("latency_ms");
+        latency.record(Duration::from_millis(5));
+        let mut size = ok.stat("size");
+        size.add_values(&[3, 1]);
+        size.add(3);
+
+        assert_eq!(capture.counter_value("requests", &[("status", "200")]), Some(2));
+        assert_eq!(capture.stat_values("latency_ms", &[("status", "200")]), vec![5]);
+        assert_eq!(capture.stat_values("size", &[("status", "200")]), vec![3, 1, 3]);
+        assert!(capture.stat_values("size", &[]).is_empty());
+
+        // Values are retained when reports are taken, until cleared.
+        capture.reporter().take();
+        assert_eq!(capture.stat_values("size", &[("status", "200")]).len(), 3);
+        capture.clear();
+        assert!(capture.stat_values("size", &[("status", "200")]).is_empty());
+    }
+}
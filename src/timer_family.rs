@@ -0,0 +1,199 @@
+//! Timers labeled by the outcome of what they time.
+//!
+//! A latency's labels often depend on how an operation completed (e.g. its status code),
+//! which isn't known when timing starts. A `TimerFamily` selects the labeled Timer once the
+//! timed operation completes, either explicitly or, with the `async` feature, from a
+//! future's result (see `TimerFamily::time_with`):
+//!
+//! ```
+//! use std::time::Instant;
+//! use tacho::TimeUnit;
+//!
+//! let (metrics, reporter) = tacho::new();
+//! let latency = metrics.timer_family("latency_ms", "status", TimeUnit::Millis);
+//!
+//! let t0 = Instant::now();
+//! let status = 404; // ... once the response is ready ...
+//! latency.record_since(t0, status);
+//!
+//! let report = reporter.peek();
+//! assert_eq!(report.get_stat("latency_ms", &[("status", "404")]).unwrap().count(), 1);
+//! ```
+
+use super::{LabelValue, Scope, TimeUnit, Timer};
+#[cfg(feature = "async")]
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Timers sharing a name, distinguished by the value of one label.
+///
+/// Each label value's Timer is created on first use and then cached, so its Stat is not
+/// pruned while the family is in use. Label values should therefore be bounded, e.g. status
+/// codes rather than request ids.
+#[derive(Clone)]
+pub struct TimerFamily {
+    scope: Scope,
+    name: &'static str,
+    label: &'static str,
+    unit: TimeUnit,
+    timers: Arc<Mutex<HashMap<LabelValue, Timer>>>,
+}
+
+impl TimerFamily {
+    pub(super) fn new(
+        scope: Scope,
+        name: &'static str,
+        label: &'static str,
+        unit: TimeUnit,
+    ) -> TimerFamily {
+        TimerFamily {
+            scope,
+            name,
+            label,
+            unit,
+            timers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the Timer for a label value.
+    pub fn timer<V: Into<LabelValue>>(&self, v: V) -> Timer {
+        let v = v.into();
+        let mut timers = self.timers.lock().expect(
+            "failed to obtain lock on timer family",
+        );
+        if let Some(t) = timers.get(&v) {
+            return t.clone();
+        }
+        let stat = self.scope
+            .clone()
+            .labeled(self.label, v.clone())
+            .stat_with_unit(self.name, self.unit);
+        let t = Timer { stat };
+        timers.insert(v, t.clone());
+        t
+    }
+
+    pub fn record<V: Into<LabelValue>>(&self, d: Duration, v: V) {
+        self.timer(v).record(d);
+    }
+
+    pub fn record_since<V: Into<LabelValue>>(&self, t0: Instant, v: V) {
+        self.record(t0.elapsed(), v);
+    }
+
+    /// Records the time taken for `fut` to complete, measured from when it is first polled,
+    /// with the label value that `label` computes from its result.
+    #[cfg(feature = "async")]
+    pub fn time_with<F, L, V>(&self, fut: F, label: L) -> TimedWith<F, L>
+    where
+        F: Future,
+        L: FnOnce(Result<&F::Item, &F::Error>) -> V,
+        V: Into<LabelValue>,
+    {
+        TimedWith {
+            inner: fut,
+            family: self.clone(),
+            label: Some(label),
+            t0: None,
+        }
+    }
+}
+
+impl fmt::Debug for TimerFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TimerFamily")
+            .field("name", &self.name)
+            .field("label", &self.label)
+            .field("unit", &self.unit)
+            .finish()
+    }
+}
+
+/// A future that records the time taken for its inner future to complete, labeled by its
+/// result. See `TimerFamily::time_with`.
+#[cfg(feature = "async")]
+pub struct TimedWith<F, L> {
+    inner: F,
+    family: TimerFamily,
+    label: Option<L>,
+    t0: Option<Instant>,
+}
+
+#[cfg(feature = "async")]
+impl<F, L, V> Future for TimedWith<F, L>
+where
+    F: Future,
+    L: FnOnce(Result<&F::Item, &F::Error>) -> V,
+    V: Into<LabelValue>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let t0 = *self.t0.get_or_insert_with(Instant::now);
+        let res = self.inner.poll();
+        let v = match res {
+            Ok(Async::NotReady) => return res,
+            Ok(Async::Ready(ref item)) => self.label.take().map(|l| l(Ok(item))),
+            Err(ref e) => self.label.take().map(|l| l(Err(e))),
+        };
+        if let Some(v) = v {
+            self.family.record_since(t0, v);
+        }
+        res
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F: fmt::Debug, L> fmt::Debug for TimedWith<F, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_family() {
+        let (metrics, mut reporter) = ::new();
+        let latency = metrics.timer_family("latency_ms", "status", TimeUnit::Millis);
+        latency.record(Duration::from_millis(3), 200);
+        latency.record(Duration::from_millis(5), "200");
+        latency.record(Duration::from_millis(7), 503);
+
+        let report = reporter.take();
+        let ok = report.get_stat("latency_ms", &[("status", "200")]).unwrap();
+        assert_eq!((ok.count(), ok.max()), (2, 5));
+        assert_eq!(report.get_stat("latency_ms", &[("status", "503")]).unwrap().count(), 1);
+
+        // Cached timers are retained while the family is in use.
+        assert_eq!(reporter.take().stats().len(), 2);
+        drop(latency);
+        reporter.take();
+        assert!(reporter.take().stats().is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_time_with() {
+        use futures::future;
+
+        let (metrics, reporter) = ::new();
+        let latency = metrics.timer_family("latency_us", "outcome", TimeUnit::Micros);
+        let label = |r: Result<&u16, &&str>| match r {
+            Ok(_) => "success",
+            Err(_) => "failure",
+        };
+        assert_eq!(latency.time_with(future::ok(200), label).wait(), Ok(200));
+        assert_eq!(latency.time_with(future::err("boom"), label).wait(), Err("boom"));
+        assert_eq!(latency.time_with(future::ok(204), label).wait(), Ok(204));
+
+        let report = reporter.peek();
+        let count = |v| report.get_stat("latency_us", &[("outcome", v)]).unwrap().count();
+        assert_eq!((count("success"), count("failure")), (2, 1));
+    }
+}
@@ -5,7 +5,7 @@ pub trait Timing {
     fn elapsed_ms(&self) -> u64;
 }
 
-impl Timing {
+impl dyn Timing {
     pub fn start() -> Instant {
         Instant::now()
     }
@@ -13,10 +13,10 @@ impl Timing {
 
 impl Timing for Duration {
     fn elapsed_us(&self) -> u64 {
-        self.as_secs() as u64 * 1_000_000 + self.subsec_nanos() as u64 / 1_000
+        self.as_secs() * 1_000_000 + self.subsec_nanos() as u64 / 1_000
     }
     fn elapsed_ms(&self) -> u64 {
-        self.as_secs() as u64 * 1_000 + self.subsec_nanos() as u64 / 1_000_000
+        self.as_secs() * 1_000 + self.subsec_nanos() as u64 / 1_000_000
     }
 }
 
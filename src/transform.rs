@@ -0,0 +1,162 @@
+//! Transforms applied to gauges as reports are obtained.
+//!
+//! Some backends want the change in a gauge over each reporting interval (e.g. bytes
+//! freed by garbage collection) rather than its current value. A `Reporter` may be
+//! configured to export selected gauges as their change (`GaugeTransform::Delta`) or rate
+//! of change per second (`GaugeTransform::Rate`) since the previous `take`.
+//!
+//! Since reports hold unsigned values, decreases are reported as 0. A gauge's first
+//! report, when there is no previous value, is also 0.
+
+use super::Key;
+use ordermap::OrderMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Selects how a gauge's value is exported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GaugeTransform {
+    /// The gauge's current value.
+    Identity,
+    /// The change in the gauge's value since the previous report was taken.
+    Delta,
+    /// The change in the gauge's value per second since the previous report was taken,
+    /// rounded down.
+    Rate,
+}
+
+/// Selects gauges by prefix and, optionally, by name.
+struct Rule {
+    prefix: Vec<&'static str>,
+    name: Option<&'static str>,
+    transform: GaugeTransform,
+}
+
+#[derive(Default)]
+pub struct Transforms {
+    rules: Vec<Rule>,
+    /// The value of each transformed gauge when a report was last taken.
+    last: OrderMap<Arc<Key>, (usize, Instant)>,
+}
+
+impl Transforms {
+    pub fn add(
+        &mut self,
+        prefix: &[&'static str],
+        name: Option<&'static str>,
+        transform: GaugeTransform,
+    ) {
+        self.rules.push(Rule {
+            prefix: prefix.to_vec(),
+            name,
+            transform,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the transform of the first rule that matches `key`.
+    fn transform(&self, key: &Key) -> GaugeTransform {
+        self.rules
+            .iter()
+            .find(|r| {
+                r.name.map(|n| n == key.name()).unwrap_or(true) &&
+                    key.prefix().starts_with(&r.prefix)
+            })
+            .map(|r| r.transform)
+            .unwrap_or(GaugeTransform::Identity)
+    }
+
+    /// Transforms gauge values in place. If `update` is true, the raw values are retained
+    /// for the next report.
+    pub fn apply(&mut self, gauges: &mut OrderMap<Arc<Key>, usize>, update: bool) {
+        let now = Instant::now();
+        for (k, v) in gauges.iter_mut() {
+            let transform = self.transform(k);
+            if transform == GaugeTransform::Identity {
+                continue;
+            }
+            let raw = *v;
+            *v = match self.last.get(k) {
+                None => 0,
+                Some(&(prior, t0)) => {
+                    let delta = raw.saturating_sub(prior);
+                    match transform {
+                        GaugeTransform::Rate => {
+                            let secs = now.duration_since(t0).as_secs_f64();
+                            if secs > 0.0 {
+                                (delta as f64 / secs) as usize
+                            } else {
+                                0
+                            }
+                        }
+                        _ => delta,
+                    }
+                }
+            };
+            if update {
+                self.last.insert(k.clone(), (raw, now));
+            }
+        }
+    }
+
+    /// Forgets the values of gauges that match but were not reported, i.e. that have been
+    /// pruned.
+    pub fn forget(
+        &mut self,
+        reported: &OrderMap<Arc<Key>, usize>,
+        matches: &dyn Fn(&Key) -> bool,
+    ) {
+        self.last.retain(|k, _| !matches(k) || reported.contains_key(k));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_gauge_transforms() {
+        let (metrics, reporter) = ::new();
+        let mut reporter = reporter
+            .with_gauge_transform(&["gc"], Some("heap_bytes"), GaugeTransform::Identity)
+            .with_gauge_transform(&["gc"], None, GaugeTransform::Delta)
+            .with_gauge_transform(&[], Some("allocated_bytes"), GaugeTransform::Rate);
+        let gc = metrics.clone().prefixed("gc");
+        let freed = gc.gauge("freed_bytes");
+        let heap = gc.gauge("heap_bytes");
+        let allocated = metrics.gauge("allocated_bytes");
+        let conns = metrics.gauge("conns");
+
+        freed.set(100);
+        heap.set(1_000);
+        allocated.set(0);
+        conns.set(3);
+        let values = |r: &::Report| {
+            ["freed_bytes", "heap_bytes", "allocated_bytes", "conns"]
+                .iter()
+                .map(|n| r.gauges().iter().find(|&(k, _)| k.name() == *n).map(|(_, v)| *v))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(&reporter.take()), vec![Some(0), Some(1_000), Some(0), Some(3)]);
+
+        thread::sleep(Duration::from_millis(20));
+        freed.set(250);
+        allocated.set(1_000_000);
+        // Peeking does not advance the interval.
+        assert_eq!(reporter.peek().gauges()[&Arc::new(gc.key("freed_bytes"))], 150);
+        let report = reporter.take();
+        let v = values(&report);
+        assert_eq!(v[..2], [Some(150), Some(1_000)]);
+        let rate = v[2].unwrap();
+        assert!(rate > 0 && rate <= 50_000_000, "rate={}", rate);
+
+        // Decreases are reported as 0.
+        freed.set(50);
+        assert_eq!(values(&reporter.take())[0], Some(0));
+    }
+}
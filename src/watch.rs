@@ -0,0 +1,242 @@
+//! In-process alerting on reports.
+//!
+//! A watch evaluates a predicate against each report obtained from a `Reporter` (via
+//! `peek`, `take`, or `take_subtree`). When the predicate holds, the watch fires: either
+//! by resolving a `Watch` future (once, with the `async` feature), or by invoking a
+//! callback (each time).
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use tacho::watch;
+//!
+//! let (metrics, reporter) = tacho::new();
+//! let pending = metrics.gauge("pending");
+//!
+//! let overloaded = Arc::new(Mutex::new(false));
+//! let flag = overloaded.clone();
+//! reporter.on_watch(watch::gauge_above("pending", 1000), move |_| {
+//!     *flag.lock().unwrap() = true;
+//! });
+//!
+//! pending.set(1_001);
+//! reporter.peek();
+//! assert!(*overloaded.lock().unwrap());
+//! ```
+
+use super::Report;
+#[cfg(feature = "async")]
+use futures::{Future, Poll};
+#[cfg(feature = "async")]
+use futures::sync::oneshot;
+use std::time::Instant;
+
+/// Resolves when its predicate first holds for a report.
+///
+/// Fails if the `Reporter` (and all of its clones) are dropped first.
+#[cfg(feature = "async")]
+pub struct Watch(oneshot::Receiver<()>);
+
+#[cfg(feature = "async")]
+impl Future for Watch {
+    type Item = ();
+    type Error = oneshot::Canceled;
+    fn poll(&mut self) -> Poll<(), oneshot::Canceled> {
+        self.0.poll()
+    }
+}
+
+/// A predicate, with how to notify when it holds.
+pub(super) struct Watcher {
+    predicate: Box<dyn FnMut(&Report) -> bool + Send>,
+    notify: Notify,
+}
+
+enum Notify {
+    #[cfg(feature = "async")]
+    Once(Option<oneshot::Sender<()>>),
+    Each(Box<dyn FnMut(&Report) + Send>),
+}
+
+impl Watcher {
+    #[cfg(feature = "async")]
+    pub(super) fn once<P>(predicate: P) -> (Watcher, Watch)
+    where
+        P: FnMut(&Report) -> bool + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let w = Watcher {
+            predicate: Box::new(predicate),
+            notify: Notify::Once(Some(tx)),
+        };
+        (w, Watch(rx))
+    }
+
+    pub(super) fn each<P, F>(predicate: P, f: F) -> Watcher
+    where
+        P: FnMut(&Report) -> bool + Send + 'static,
+        F: FnMut(&Report) + Send + 'static,
+    {
+        Watcher {
+            predicate: Box::new(predicate),
+            notify: Notify::Each(Box::new(f)),
+        }
+    }
+
+    /// Evaluates the predicate, notifying if it holds.
+    ///
+    /// Returns false if the watcher is no longer needed.
+    pub(super) fn check(&mut self, report: &Report) -> bool {
+        #[cfg(feature = "async")]
+        if let Notify::Once(ref tx) = self.notify {
+            if tx.as_ref().map(|tx| tx.is_canceled()).unwrap_or(true) {
+                return false;
+            }
+        }
+
+        if !(self.predicate)(report) {
+            return true;
+        }
+        match self.notify {
+            #[cfg(feature = "async")]
+            Notify::Once(ref mut tx) => {
+                if let Some(tx) = tx.take() {
+                    let _ = tx.send(());
+                }
+                false
+            }
+            Notify::Each(ref mut f) => {
+                f(report);
+                true
+            }
+        }
+    }
+}
+
+/// Holds when the gauges named `name` sum to more than `threshold`.
+pub fn gauge_above(name: &'static str, threshold: usize) -> impl FnMut(&Report) -> bool {
+    move |report| {
+        let v: usize = report
+            .gauges()
+            .iter()
+            .filter(|&(k, _)| k.name() == name)
+            .map(|(_, v)| *v)
+            .sum();
+        v > threshold
+    }
+}
+
+/// Holds when the counters named `name` (summed) increase by more than `per_sec` per second
+/// between reports.
+///
+/// Never holds for the first report.
+pub fn counter_rate_above(name: &'static str, per_sec: f64) -> impl FnMut(&Report) -> bool {
+    let mut last: Option<(Instant, usize)> = None;
+    move |report| {
+        let now = Instant::now();
+        let v: usize = report
+            .counters()
+            .iter()
+            .filter(|&(k, _)| k.name() == name)
+            .map(|(_, v)| *v)
+            .sum();
+        let above = match last {
+            Some((t0, v0)) => {
+                let secs = now.duration_since(t0).as_secs_f64();
+                secs > 0.0 && v.saturating_sub(v0) as f64 / secs > per_sec
+            }
+            None => false,
+        };
+        last = Some((now, v));
+        above
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "async")]
+    use futures::executor::{self, Notify};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[cfg(feature = "async")]
+    struct NoopNotify;
+    #[cfg(feature = "async")]
+    impl Notify for NoopNotify {
+        fn notify(&self, _: usize) {}
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_watch() {
+        let (metrics, reporter) = ::new();
+        let pending = metrics.gauge("pending");
+        let mut fired = executor::spawn(reporter.watch(gauge_above("pending", 10)));
+        let notify = Arc::new(NoopNotify);
+
+        pending.set(10);
+        reporter.peek();
+        assert!(fired.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+        pending.set(11);
+        reporter.peek();
+        assert!(fired.poll_future_notify(&notify, 0).unwrap().is_ready());
+    }
+
+    #[test]
+    fn test_watch_each() {
+        let (metrics, mut reporter) = ::new();
+        let errors = metrics.counter("errors");
+        let fired = Arc::new(Mutex::new(0));
+        {
+            let fired = fired.clone();
+            reporter.on_watch(counter_rate_above("errors", 100.0), move |_| {
+                *fired.lock().unwrap() += 1;
+            });
+        }
+
+        errors.incr(1_000);
+        reporter.take();
+        assert_eq!(*fired.lock().unwrap(), 0);
+
+        thread::sleep(Duration::from_millis(10));
+        errors.incr(1_000);
+        reporter.take();
+        assert_eq!(*fired.lock().unwrap(), 1);
+
+        thread::sleep(Duration::from_millis(10));
+        reporter.take();
+        assert_eq!(*fired.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_watch_reentrant() {
+        let (metrics, reporter) = ::new();
+        let pending = metrics.gauge("pending");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        {
+            let seen = seen.clone();
+            let r = reporter.clone();
+            reporter.on_watch(gauge_above("pending", 10), move |_| {
+                // Callbacks may obtain reports and add watches.
+                seen.lock().unwrap().push(r.peek().get_gauge("pending", &[]));
+                r.on_watch(|_| false, |_| {});
+            });
+        }
+
+        pending.set(11);
+        reporter.peek();
+        reporter.peek();
+        assert_eq!(*seen.lock().unwrap(), vec![Some(11), Some(11)]);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_watch_canceled() {
+        let (_, reporter) = ::new();
+        let watch = reporter.watch(|_| true);
+        drop(reporter);
+        assert!(watch.wait().is_err());
+    }
+}